@@ -0,0 +1,43 @@
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Embeds the git commit and build time for `--version` and the startup log.
+///
+/// `GIT_HASH` overrides the commit, for builds from a source tarball or a container that has
+/// no `.git`, and `SOURCE_DATE_EPOCH` the build time, for reproducible builds.
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let git_hash = std::env::var("GIT_HASH")
+        .ok()
+        .filter(|h| !h.is_empty())
+        .or_else(git_hash)
+        .unwrap_or_else(|| "unknown".into());
+
+    let built = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        .unwrap_or_else(SystemTime::now);
+
+    println!("cargo:rustc-env=GIT_HASH={git_hash}");
+    println!(
+        "cargo:rustc-env=BUILD_TIMESTAMP={}",
+        humantime::format_rfc3339_seconds(built)
+    );
+}
+
+fn git_hash() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
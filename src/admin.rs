@@ -0,0 +1,306 @@
+use std::sync::Arc;
+
+use k8s_openapi::api::core::v1::{Pod, Service};
+use kube::api::ListParams;
+use kube::Api;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::socks::kube_client::{Health, KubeClient};
+use crate::socks::metrics::Metrics;
+use crate::socks::registry::Registry;
+
+/// Longest request line or header accepted, admin requests are tiny.
+const MAX_LINE_LEN: usize = 8 * 1024;
+
+/// Serves the admin HTTP endpoints until the listener fails.
+///
+/// * `GET /connections` - JSON list of the currently open SOCKS connections
+/// * `GET /readyz` - 200 while the API server is reachable, 503 while the client is being rebuilt
+/// * `GET /metrics` - Prometheus metrics
+/// * `GET /targets?namespace=<namespace>[&pods=true]` - JSON list of the addresses clients could
+///   connect to in the namespace
+pub async fn serve(
+    listener: TcpListener,
+    registry: Arc<Registry>,
+    metrics: Arc<Metrics>,
+    kube_client: Option<Arc<KubeClient>>,
+    config: Arc<Config>,
+) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let registry = registry.clone();
+        let metrics = metrics.clone();
+        let kube_client = kube_client.clone();
+        let config = config.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) =
+                handle(stream, &registry, &metrics, kube_client.as_deref(), &config).await
+            {
+                warn!(%peer_addr, error = ?e, "admin request failed");
+            }
+        });
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    registry: &Registry,
+    metrics: &Metrics,
+    kube_client: Option<&KubeClient>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    let request_line = read_line(&mut stream).await?;
+    // Headers are not used, read and discard them up to the blank line
+    while !read_line(&mut stream).await?.is_empty() {}
+
+    debug!(request_line, "admin request");
+
+    // Answered from the API server, unlike the other endpoints
+    let (status, content_type, body) = match targets_query(&request_line) {
+        Some(query) => targets(query, kube_client, config).await,
+        None => route(
+            &request_line,
+            registry,
+            metrics,
+            kube_client.map(KubeClient::health),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    stream.get_mut().write_all(response.as_bytes()).await?;
+    stream.get_mut().shutdown().await?;
+
+    Ok(())
+}
+
+async fn read_line(stream: &mut BufReader<TcpStream>) -> anyhow::Result<String> {
+    let mut line = String::new();
+    let read = (&mut *stream)
+        .take(MAX_LINE_LEN as u64)
+        .read_line(&mut line)
+        .await?;
+
+    if read == 0 || !line.ends_with('\n') {
+        anyhow::bail!("connection closed or line too long");
+    }
+
+    Ok(line.trim_end().to_string())
+}
+
+/// Content type of the Prometheus text exposition format.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+fn route(
+    request_line: &str,
+    registry: &Registry,
+    metrics: &Metrics,
+    health: Option<&Health>,
+) -> (&'static str, &'static str, String) {
+    let mut parts = request_line.split(' ');
+
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/connections")) => match serde_json::to_string(&registry.snapshot()) {
+            Ok(body) => ("200 OK", JSON_CONTENT_TYPE, body),
+            Err(e) => (
+                "500 Internal Server Error",
+                JSON_CONTENT_TYPE,
+                error_body(&e.to_string()),
+            ),
+        },
+        (Some("GET"), Some("/readyz")) => {
+            // Without a cluster there's nothing to be unhealthy
+            let info = health.map(Health::info);
+            let status = match info.as_ref().is_none_or(|i| i.healthy) {
+                true => "200 OK",
+                false => "503 Service Unavailable",
+            };
+            (
+                status,
+                JSON_CONTENT_TYPE,
+                serde_json::json!({ "kube_client": info }).to_string(),
+            )
+        }
+        (Some("GET"), Some("/metrics")) => ("200 OK", METRICS_CONTENT_TYPE, metrics.render()),
+        (Some("GET"), Some(_)) => ("404 Not Found", JSON_CONTENT_TYPE, error_body("not found")),
+        _ => (
+            "405 Method Not Allowed",
+            JSON_CONTENT_TYPE,
+            error_body("method not allowed"),
+        ),
+    }
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+/// The query of a `GET /targets` request.
+#[derive(Debug, PartialEq, Eq)]
+struct TargetsQuery {
+    namespace: Option<String>,
+    /// Also list each pod's declared ports
+    pods: bool,
+}
+
+/// The query, if `request_line` is a `GET /targets` request.
+fn targets_query(request_line: &str) -> Option<TargetsQuery> {
+    let mut parts = request_line.split(' ');
+    let (Some("GET"), Some(target)) = (parts.next(), parts.next()) else {
+        return None;
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/targets" {
+        return None;
+    }
+
+    let mut parsed = TargetsQuery {
+        namespace: None,
+        pods: false,
+    };
+    for (key, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+        match key {
+            "namespace" => parsed.namespace = Some(value.to_string()),
+            "pods" => parsed.pods = value == "true",
+            _ => {}
+        }
+    }
+    Some(parsed)
+}
+
+async fn targets(
+    query: TargetsQuery,
+    kube_client: Option<&KubeClient>,
+    config: &Config,
+) -> (&'static str, &'static str, String) {
+    let Some(kube_client) = kube_client else {
+        return (
+            "404 Not Found",
+            JSON_CONTENT_TYPE,
+            error_body("no cluster to list targets in, serving static hosts"),
+        );
+    };
+    let Some(namespace) = query.namespace.filter(|n| is_dns_label(n)) else {
+        return (
+            "400 Bad Request",
+            JSON_CONTENT_TYPE,
+            error_body("namespace must be given, as a DNS label"),
+        );
+    };
+    if !config.namespace_allowed(None, &namespace) {
+        return (
+            "403 Forbidden",
+            JSON_CONTENT_TYPE,
+            error_body("namespace isn't allowed by allow-namespaces"),
+        );
+    }
+
+    let client = kube_client.get();
+    let services: Api<Service> = Api::namespaced(client.clone(), &namespace);
+    let mut addresses = match services.list(&ListParams::default()).await {
+        Ok(list) => list
+            .items
+            .iter()
+            .flat_map(|s| service_addresses(s, &namespace, config))
+            .collect::<Vec<_>>(),
+        Err(e) => return lookup_failed(e),
+    };
+
+    if query.pods {
+        let pods: Api<Pod> = Api::namespaced(client, &namespace);
+        match pods.list(&ListParams::default()).await {
+            Ok(list) => addresses.extend(
+                list.items
+                    .iter()
+                    .flat_map(|p| pod_addresses(p, &namespace, config)),
+            ),
+            Err(e) => return lookup_failed(e),
+        }
+    }
+
+    (
+        "200 OK",
+        JSON_CONTENT_TYPE,
+        serde_json::json!(addresses).to_string(),
+    )
+}
+
+fn lookup_failed(e: kube::Error) -> (&'static str, &'static str, String) {
+    warn!(error = ?e, "failed to list targets");
+    (
+        "502 Bad Gateway",
+        JSON_CONTENT_TYPE,
+        error_body(&e.to_string()),
+    )
+}
+
+/// `<service>.<namespace>.svc.<cluster-domain>:<port>` for each TCP port of `service` clients
+/// may connect to, and the `_<name>._tcp.` form for named ones.
+fn service_addresses(service: &Service, namespace: &str, config: &Config) -> Vec<String> {
+    let name = service.metadata.name.as_deref().unwrap_or_default();
+    if config.name_denied(name) {
+        return vec![];
+    }
+    let host = format!("{name}.{namespace}.svc.{}", config.cluster_domain);
+
+    let mut addresses = vec![];
+    for port in service.spec.iter().flat_map(|s| s.ports.iter().flatten()) {
+        let number = match u16::try_from(port.port) {
+            Ok(number) if is_tcp(port.protocol.as_deref()) && config.port_allowed(number) => number,
+            _ => continue,
+        };
+
+        addresses.push(format!("{host}:{number}"));
+        if let Some(ref port_name) = port.name {
+            addresses.push(format!("_{port_name}._tcp.{host}:{number}"));
+        }
+    }
+    addresses
+}
+
+/// `<pod>.<namespace>.pod.<cluster-domain>:<port>` for each TCP port `pod`'s containers declare
+/// that clients may connect to.
+fn pod_addresses(pod: &Pod, namespace: &str, config: &Config) -> Vec<String> {
+    let name = pod.metadata.name.as_deref().unwrap_or_default();
+    if config.name_denied(name) {
+        return vec![];
+    }
+    let host = format!("{name}.{namespace}.pod.{}", config.cluster_domain);
+
+    pod.spec
+        .iter()
+        .flat_map(|s| s.containers.iter())
+        .flat_map(|c| c.ports.iter().flatten())
+        .filter(|p| is_tcp(p.protocol.as_deref()))
+        .filter_map(|p| u16::try_from(p.container_port).ok())
+        .filter(|p| *p != 0 && config.port_allowed(*p))
+        .map(|p| format!("{host}:{p}"))
+        .collect()
+}
+
+/// Port-forwards only carry TCP, which is the default protocol.
+fn is_tcp(protocol: Option<&str>) -> bool {
+    protocol.is_none_or(|p| p == "TCP")
+}
+
+fn is_dns_label(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,298 @@
+mod route {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::super::*;
+
+    #[test]
+    fn lists_connections() {
+        let registry = Arc::new(Registry::default());
+        let _conn = registry.register(SocketAddr::from((Ipv4Addr::LOCALHOST, 50000)).into());
+
+        let (status, _, body) = route(
+            "GET /connections HTTP/1.1",
+            &registry,
+            &Metrics::default(),
+            Some(&Health::default()),
+        );
+
+        assert_eq!(status, "200 OK");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json[0]["peer_addr"], "127.0.0.1:50000");
+        assert_eq!(json[0]["target"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn unknown_path_is_not_found() {
+        let (status, _, _) = route(
+            "GET /nope HTTP/1.1",
+            &Registry::default(),
+            &Metrics::default(),
+            Some(&Health::default()),
+        );
+
+        assert_eq!(status, "404 Not Found");
+    }
+
+    #[test]
+    fn only_get_is_allowed() {
+        let (status, _, _) = route(
+            "POST /connections HTTP/1.1",
+            &Registry::default(),
+            &Metrics::default(),
+            Some(&Health::default()),
+        );
+
+        assert_eq!(status, "405 Method Not Allowed");
+    }
+
+    #[test]
+    fn ready_while_healthy() {
+        let (status, _, body) = route(
+            "GET /readyz HTTP/1.1",
+            &Registry::default(),
+            &Metrics::default(),
+            Some(&Health::default()),
+        );
+
+        assert_eq!(status, "200 OK");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["kube_client"]["healthy"], true);
+    }
+
+    #[test]
+    fn ready_without_a_cluster() {
+        let (status, _, body) = route(
+            "GET /readyz HTTP/1.1",
+            &Registry::default(),
+            &Metrics::default(),
+            None,
+        );
+
+        assert_eq!(status, "200 OK");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["kube_client"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn metrics_are_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics
+            .ready_wait
+            .observe(std::time::Duration::from_millis(200));
+
+        let (status, content_type, body) = route(
+            "GET /metrics HTTP/1.1",
+            &Registry::default(),
+            &metrics,
+            None,
+        );
+
+        assert_eq!(status, "200 OK");
+        assert_eq!(content_type, METRICS_CONTENT_TYPE);
+        assert!(
+            body.contains("socks_ready_wait_seconds_count 1\n"),
+            "{body}"
+        );
+    }
+}
+
+mod targets_query {
+    use super::super::*;
+
+    #[test]
+    fn namespace_and_pods() {
+        assert_eq!(
+            targets_query("GET /targets?namespace=apps&pods=true HTTP/1.1"),
+            Some(TargetsQuery {
+                namespace: Some("apps".into()),
+                pods: true,
+            })
+        );
+        assert_eq!(
+            targets_query("GET /targets HTTP/1.1"),
+            Some(TargetsQuery {
+                namespace: None,
+                pods: false,
+            })
+        );
+    }
+
+    #[test]
+    fn other_requests() {
+        assert_eq!(targets_query("GET /connections HTTP/1.1"), None);
+        assert_eq!(targets_query("GET /targetsx?namespace=apps HTTP/1.1"), None);
+        assert_eq!(targets_query("POST /targets?namespace=apps HTTP/1.1"), None);
+    }
+}
+
+mod service_addresses {
+    use super::super::*;
+
+    fn service(ports: serde_json::Value) -> Service {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "web", "namespace": "apps" },
+            "spec": { "ports": ports },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn plain_and_named_ports() {
+        let service = service(serde_json::json!([
+            { "name": "http", "port": 80 },
+            { "port": 9090 },
+        ]));
+
+        assert_eq!(
+            service_addresses(&service, "apps", &Config::default()),
+            [
+                "web.apps.svc.cluster.local:80",
+                "_http._tcp.web.apps.svc.cluster.local:80",
+                "web.apps.svc.cluster.local:9090",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_what_clients_may_connect_to() {
+        let service = service(serde_json::json!([
+            { "name": "dns", "port": 53, "protocol": "UDP" },
+            { "port": 22 },
+            { "port": 443 },
+        ]));
+        let config = Config {
+            deny_ports: vec!["22".parse().unwrap()],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            service_addresses(&service, "apps", &config),
+            ["web.apps.svc.cluster.local:443"]
+        );
+
+        let config = Config {
+            deny_names: vec!["web".parse().unwrap()],
+            ..Config::default()
+        };
+        assert!(service_addresses(&service, "apps", &config).is_empty());
+    }
+}
+
+mod pod_addresses {
+    use super::super::*;
+
+    #[test]
+    fn declared_tcp_ports() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "web-0", "namespace": "apps" },
+            "spec": { "containers": [
+                { "name": "web", "ports": [{ "containerPort": 8080 }] },
+                { "name": "dns", "ports": [{ "containerPort": 53, "protocol": "UDP" }] },
+            ] },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            pod_addresses(&pod, "apps", &Config::default()),
+            ["web-0.apps.pod.cluster.local:8080"]
+        );
+    }
+}
+
+mod targets {
+    use super::super::*;
+    use crate::fake_api::serve_json;
+
+    fn query(namespace: &str, pods: bool) -> TargetsQuery {
+        TargetsQuery {
+            namespace: Some(namespace.into()),
+            pods,
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_services_and_pods() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = kube::Client::try_from(kube::Config::new(
+            format!("http://{}", listener.local_addr().unwrap())
+                .parse()
+                .unwrap(),
+        ))
+        .unwrap();
+        let kube_client = KubeClient::new(client);
+
+        let services = r#"{"apiVersion":"v1","kind":"ServiceList","metadata":{},"items":[{"metadata":{"name":"web","namespace":"apps"},"spec":{"ports":[{"port":80}]}}]}"#;
+        let pods = r#"{"apiVersion":"v1","kind":"PodList","metadata":{},"items":[{"metadata":{"name":"web-0","namespace":"apps"},"spec":{"containers":[{"name":"web","ports":[{"containerPort":8080}]}]}}]}"#;
+        let config = Config::default();
+        let ((status, _, body), paths) = tokio::join!(
+            targets(query("apps", true), Some(&kube_client), &config),
+            async {
+                (
+                    serve_json(&listener, services).await,
+                    serve_json(&listener, pods).await,
+                )
+            }
+        );
+
+        assert_eq!(status, "200 OK");
+        let addresses: Vec<String> = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            addresses,
+            [
+                "web.apps.svc.cluster.local:80",
+                "web-0.apps.pod.cluster.local:8080"
+            ]
+        );
+        assert!(
+            paths.0.starts_with("/api/v1/namespaces/apps/services"),
+            "{paths:?}"
+        );
+        assert!(
+            paths.1.starts_with("/api/v1/namespaces/apps/pods"),
+            "{paths:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn namespace_must_be_a_dns_label() {
+        let client =
+            kube::Client::try_from(kube::Config::new("http://127.0.0.1:1".parse().unwrap()))
+                .unwrap();
+        let kube_client = KubeClient::new(client);
+
+        for namespace in ["", "../secrets", "Apps", "-apps"] {
+            let (status, _, _) = targets(
+                query(namespace, false),
+                Some(&kube_client),
+                &Config::default(),
+            )
+            .await;
+
+            assert_eq!(status, "400 Bad Request", "{namespace:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn namespace_must_be_allowed() {
+        // Nothing is listening, so this would fail differently if it asked the API server
+        let client =
+            kube::Client::try_from(kube::Config::new("http://127.0.0.1:1".parse().unwrap()))
+                .unwrap();
+        let kube_client = KubeClient::new(client);
+        let config = Config {
+            allow_namespaces: vec!["public".into()],
+            ..Config::default()
+        };
+
+        let (status, _, _) = targets(query("apps", false), Some(&kube_client), &config).await;
+
+        assert_eq!(status, "403 Forbidden");
+    }
+
+    #[tokio::test]
+    async fn without_a_cluster() {
+        let (status, _, _) = targets(query("apps", false), None, &Config::default()).await;
+
+        assert_eq!(status, "404 Not Found");
+    }
+}
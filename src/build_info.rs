@@ -0,0 +1,15 @@
+//! What was built and when, embedded by `build.rs`.
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("GIT_HASH");
+pub const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+
+/// Shown by `--version`.
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("GIT_HASH"),
+    ", built ",
+    env!("BUILD_TIMESTAMP"),
+    ")"
+);
@@ -0,0 +1,1440 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PORT: u16 = 1080;
+pub const DEFAULT_CLUSTER_DOMAIN: &str = "cluster.local";
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+pub const DEFAULT_FORWARD_PROBE_MS: u64 = 100;
+pub const DEFAULT_FORWARD_RETRIES: u32 = 2;
+pub const DEFAULT_CIRCUIT_BREAKER_WINDOW: u64 = 60;
+pub const DEFAULT_CIRCUIT_BREAKER_COOLDOWN: u64 = 30;
+pub const DEFAULT_NAMESPACE: &str = "default";
+pub const DEFAULT_FORWARD_RATE: f64 = 5.0;
+pub const DEFAULT_FORWARD_BURST: u32 = 10;
+pub const DEFAULT_READINESS_CONDITION: &str = "Ready";
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+pub const MAX_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_LIST_PAGE_SIZE: u32 = 500;
+pub const DEFAULT_CLIENT_METRICS_TOP: usize = 10;
+pub const MAX_CLIENT_METRICS_TOP: usize = 100;
+
+/// Logged in place of a secret.
+const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Errors {
+    #[error("Failed to read config file {0}: {1}")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Failed to parse TOML config file {0}: {1}")]
+    Toml(PathBuf, #[source] toml::de::Error),
+    #[error("Failed to parse YAML config file {0}: {1}")]
+    Yaml(PathBuf, #[source] serde_yaml::Error),
+    #[error("Invalid config - {0}")]
+    Invalid(String),
+}
+
+/// SOCKS5 authentication methods the server is willing to select.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    NotRequired,
+    UserPass,
+}
+
+/// What a SOCKS5 success reply gives as the bound address for a request by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplyAddress {
+    /// The IP of the pod the connection was forwarded to
+    PodIp,
+    /// The name and port exactly as requested
+    Requested,
+}
+
+/// How forwards to a resolved pod are opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardBackend {
+    /// A port-forward through the API server
+    PortForward,
+    /// A WebSocket to the companion endpoint at `websocket-url`
+    Websocket,
+}
+
+/// Version of the PROXY protocol header sent with `send-proxy-protocol`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyProtocol {
+    /// The human readable text header
+    V1,
+    /// The binary header
+    V2,
+}
+
+/// A way resolving or forwarding to a destination can fail, for picking the SOCKS5 reply.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    PodNotFound,
+    PodAmbiguous,
+    ServiceNotFound,
+    ServiceInvalid,
+    ServiceNoMatchingPods,
+    ServiceNoReadyPods,
+    NamedServicePodsNotFound,
+    EndpointNotFound,
+    WorkloadNotFound,
+    WorkloadInvalid,
+    WorkloadNoReadyPods,
+    NamespaceNotFound,
+    NamespaceAmbiguous,
+    PodIpNotFound,
+    NodeNotFound,
+    NodeNoHostNetworkPods,
+    PortNotFound,
+    ConnectionRefused,
+    RateLimited,
+    UnsupportedAddress,
+    ForwardFailed,
+    Forbidden,
+    LookupFailed,
+    HostNotMapped,
+    NameDenied,
+    NamespaceDenied,
+    ConnectTimeout,
+    ReadyWaitTimeout,
+    ForwardClosed,
+    ClusterNotFound,
+    TooManyConnections,
+    CircuitOpen,
+    ShuttingDown,
+}
+
+impl ErrorKind {
+    /// A short identifier for metric labels and the audit log, eg. `pod_not_found`, which stays
+    /// the same however the error's message is worded.
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorKind::PodNotFound => "pod_not_found",
+            ErrorKind::PodAmbiguous => "pod_ambiguous",
+            ErrorKind::ServiceNotFound => "service_not_found",
+            ErrorKind::ServiceInvalid => "service_invalid",
+            ErrorKind::ServiceNoMatchingPods => "service_no_matching_pods",
+            ErrorKind::ServiceNoReadyPods => "service_no_ready_pods",
+            ErrorKind::NamedServicePodsNotFound => "named_service_pods_not_found",
+            ErrorKind::EndpointNotFound => "endpoint_not_found",
+            ErrorKind::WorkloadNotFound => "workload_not_found",
+            ErrorKind::WorkloadInvalid => "workload_invalid",
+            ErrorKind::WorkloadNoReadyPods => "workload_no_ready_pods",
+            ErrorKind::NamespaceNotFound => "namespace_not_found",
+            ErrorKind::NamespaceAmbiguous => "namespace_ambiguous",
+            ErrorKind::PodIpNotFound => "pod_ip_not_found",
+            ErrorKind::NodeNotFound => "node_not_found",
+            ErrorKind::NodeNoHostNetworkPods => "node_no_host_network_pods",
+            ErrorKind::PortNotFound => "port_not_found",
+            ErrorKind::ConnectionRefused => "connection_refused",
+            ErrorKind::RateLimited => "rate_limited",
+            ErrorKind::UnsupportedAddress => "unsupported_address",
+            ErrorKind::ForwardFailed => "forward_failed",
+            ErrorKind::Forbidden => "forbidden",
+            ErrorKind::LookupFailed => "lookup_failed",
+            ErrorKind::HostNotMapped => "host_not_mapped",
+            ErrorKind::NameDenied => "name_denied",
+            ErrorKind::NamespaceDenied => "namespace_denied",
+            ErrorKind::ConnectTimeout => "connect_timeout",
+            ErrorKind::ReadyWaitTimeout => "ready_wait_timeout",
+            ErrorKind::ForwardClosed => "forward_closed",
+            ErrorKind::ClusterNotFound => "cluster_not_found",
+            ErrorKind::TooManyConnections => "too_many_connections",
+            ErrorKind::CircuitOpen => "circuit_open",
+            ErrorKind::ShuttingDown => "shutting_down",
+        }
+    }
+
+    /// The reply sent unless `error-replies` says otherwise.
+    pub fn default_reply(self) -> ErrorReply {
+        match self {
+            ErrorKind::PodNotFound
+            | ErrorKind::ServiceNotFound
+            | ErrorKind::ServiceNoMatchingPods
+            | ErrorKind::NamedServicePodsNotFound
+            | ErrorKind::EndpointNotFound
+            | ErrorKind::WorkloadNotFound
+            | ErrorKind::NamespaceNotFound
+            | ErrorKind::PodIpNotFound
+            | ErrorKind::NodeNotFound
+            | ErrorKind::HostNotMapped
+            | ErrorKind::ClusterNotFound => ErrorReply::HostUnreachable,
+            ErrorKind::ServiceNoReadyPods
+            | ErrorKind::WorkloadNoReadyPods
+            | ErrorKind::PortNotFound
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::CircuitOpen => ErrorReply::ConnectionRefused,
+            ErrorKind::NodeNoHostNetworkPods => ErrorReply::NetworkUnreachable,
+            ErrorKind::UnsupportedAddress => ErrorReply::AddressNotSupported,
+            ErrorKind::ConnectTimeout | ErrorKind::ReadyWaitTimeout => ErrorReply::TtlExpired,
+            ErrorKind::Forbidden | ErrorKind::NameDenied | ErrorKind::NamespaceDenied => {
+                ErrorReply::NotAllowed
+            }
+            ErrorKind::PodAmbiguous
+            | ErrorKind::ServiceInvalid
+            | ErrorKind::WorkloadInvalid
+            | ErrorKind::NamespaceAmbiguous
+            | ErrorKind::RateLimited
+            | ErrorKind::ForwardFailed
+            | ErrorKind::ForwardClosed
+            | ErrorKind::TooManyConnections
+            | ErrorKind::ShuttingDown
+            | ErrorKind::LookupFailed => ErrorReply::GeneralFailure,
+        }
+    }
+}
+
+/// SOCKS5 replies a failed request can be answered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorReply {
+    GeneralFailure,
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    AddressNotSupported,
+}
+
+/// An inclusive range of ports, given as `443` or `8000-8999`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl std::str::FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{range:?} must be a port or <start>-<end> range");
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+        let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+
+        if start > end {
+            return Err(invalid());
+        }
+
+        Ok(PortRange { start, end })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(range: String) -> Result<Self, Self::Error> {
+        range.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        match range.start == range.end {
+            true => range.start.to_string(),
+            false => format!("{}-{}", range.start, range.end),
+        }
+    }
+}
+
+/// A glob matched against a whole resource name, `*` matching any run of characters and `?` any
+/// one character.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct NamePattern(String);
+
+impl NamePattern {
+    pub fn matches(&self, name: &str) -> bool {
+        let pattern: Vec<char> = self.0.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+
+        // Where to resume after the last `*`, if the rest fails to match
+        let mut backtrack = None;
+        let (mut p, mut n) = (0, 0);
+        while n < name.len() {
+            match pattern.get(p) {
+                Some('*') => {
+                    backtrack = Some((p, n));
+                    p += 1;
+                }
+                Some(&c) if c == '?' || c == name[n] => {
+                    p += 1;
+                    n += 1;
+                }
+                _ => match backtrack {
+                    Some((star, from)) => {
+                        p = star + 1;
+                        n = from + 1;
+                        backtrack = Some((star, from + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+
+        pattern[p..].iter().all(|&c| c == '*')
+    }
+}
+
+impl std::str::FromStr for NamePattern {
+    type Err = std::convert::Infallible;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        Ok(NamePattern(pattern.to_string()))
+    }
+}
+
+impl From<String> for NamePattern {
+    fn from(pattern: String) -> Self {
+        NamePattern(pattern)
+    }
+}
+
+impl From<NamePattern> for String {
+    fn from(pattern: NamePattern) -> Self {
+        pattern.0
+    }
+}
+
+/// A pod port to keep a forward open to, given as `<namespace>/<pod>:<port>`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PrewarmTarget {
+    pub namespace: String,
+    pub pod: String,
+    pub port: u16,
+}
+
+impl std::fmt::Display for PrewarmTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}:{}", self.namespace, self.pod, self.port)
+    }
+}
+
+impl std::str::FromStr for PrewarmTarget {
+    type Err = String;
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{target:?} must be <namespace>/<pod>:<port>");
+        let (namespace, rest) = target.split_once('/').ok_or_else(invalid)?;
+        let (pod, port) = rest.rsplit_once(':').ok_or_else(invalid)?;
+
+        if namespace.is_empty() || pod.is_empty() || pod.contains('/') {
+            return Err(invalid());
+        }
+
+        Ok(PrewarmTarget {
+            namespace: namespace.into(),
+            pod: pod.into(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl TryFrom<String> for PrewarmTarget {
+    type Error = String;
+
+    fn try_from(target: String) -> Result<Self, Self::Error> {
+        target.parse()
+    }
+}
+
+impl From<PrewarmTarget> for String {
+    fn from(target: PrewarmTarget) -> Self {
+        target.to_string()
+    }
+}
+
+/// A kubeconfig context served alongside the primary cluster, given as `<context>` or
+/// `<name>=<context>`. Clients reach it by `name`, which must be usable as a DNS label, so context
+/// names that aren't, like EKS ARNs, need one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ClusterContext {
+    pub name: String,
+    pub context: String,
+}
+
+impl std::fmt::Display for ClusterContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name == self.context {
+            true => write!(f, "{}", self.context),
+            false => write!(f, "{}={}", self.name, self.context),
+        }
+    }
+}
+
+impl std::str::FromStr for ClusterContext {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, context) = value.split_once('=').unwrap_or((value, value));
+
+        let label = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+        if name.is_empty() || !name.chars().all(label) {
+            return Err(format!(
+                "{name:?} isn't a DNS label, name the context with <name>={context}"
+            ));
+        }
+        if context.is_empty() {
+            return Err(format!("{value:?} must be <context> or <name>=<context>"));
+        }
+
+        Ok(ClusterContext {
+            name: name.into(),
+            context: context.into(),
+        })
+    }
+}
+
+impl TryFrom<String> for ClusterContext {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ClusterContext> for String {
+    fn from(context: ClusterContext) -> Self {
+        context.to_string()
+    }
+}
+
+/// Command line flags.
+///
+/// Every flag here that has an equivalent in [`Config`] takes precedence over the value loaded
+/// from `--config`, which in turn takes precedence over the built-in defaults.
+#[derive(Debug, Default, clap::Parser)]
+#[command(about, long_about = None, version = crate::build_info::LONG_VERSION)]
+pub struct Cli {
+    /// Path to a TOML or YAML (by `.yaml`/`.yml` extension) config file
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, may be repeated. Replaces any addresses from the config file.
+    /// IPv6 zones may name an interface, eg. `[fe80::1%eth0]:1080`
+    #[arg(long, value_name = "ADDR", value_parser = parse_listen_addr)]
+    pub listen: Vec<SocketAddr>,
+
+    /// Also listen on a UNIX socket at this path, removed again on shutdown
+    #[arg(long, value_name = "PATH")]
+    pub listen_unix: Option<PathBuf>,
+
+    /// Retry binding each `--listen` address this many times, backing off in between. Once set,
+    /// addresses that still fail are skipped as long as another was bound
+    #[arg(long, value_name = "COUNT")]
+    pub bind_retries: Option<u32>,
+
+    /// DNS suffix the cluster uses, ie. the `cluster.local` in `svc.cluster.local`
+    #[arg(long, value_name = "DOMAIN")]
+    pub cluster_domain: Option<String>,
+
+    /// Namespace bare names like `myservice` are expanded into
+    #[arg(long, value_name = "NAMESPACE")]
+    pub default_namespace: Option<String>,
+
+    /// Domain appended to names that don't resolve as given, tried in order, may be repeated.
+    /// Defaults to `<default-namespace>.svc.<cluster-domain>` then `svc.<cluster-domain>`
+    #[arg(long = "search-domain", value_name = "DOMAIN")]
+    pub search_domains: Vec<String>,
+
+    /// Label key selecting the namespace for `<service>.<value>.nsl` addresses, eg. `team`
+    #[arg(long, value_name = "KEY")]
+    pub namespace_label: Option<String>,
+
+    /// New port-forwards per second allowed to any single pod and port
+    #[arg(long, value_name = "PER_SECOND")]
+    pub forward_rate: Option<f64>,
+
+    /// Number of new port-forwards to a single pod and port allowed in a burst
+    #[arg(long, value_name = "COUNT")]
+    pub forward_burst: Option<u32>,
+
+    /// Seconds to wait for a service pod to become ready when none are, 0 to fail immediately
+    #[arg(long, value_name = "SECONDS")]
+    pub wait_for_ready: Option<u64>,
+
+    /// Pods fetched per request when looking for a ready one, later pages are only fetched if no
+    /// earlier pod was ready. 0 fetches every matching pod at once
+    #[arg(long, value_name = "COUNT")]
+    pub list_page_size: Option<u32>,
+
+    /// Seconds to wait for a port-forward to be established before failing the connection, a
+    /// `kube-fwd-socks/connect-timeout` annotation on the pod or its service overrides it
+    #[arg(long, value_name = "SECONDS")]
+    pub connect_timeout: Option<u64>,
+
+    /// Milliseconds to wait for the pod to refuse a new forward before replying success, 0 to
+    /// skip the check
+    #[arg(long, value_name = "MILLISECONDS")]
+    pub forward_probe_ms: Option<u64>,
+
+    /// Keep a forward open to `<namespace>/<pod>:<port>` ready for the next client, may be
+    /// repeated
+    #[arg(long, value_name = "NAMESPACE/POD:PORT")]
+    pub prewarm: Vec<PrewarmTarget>,
+
+    /// Spread opening and replacing `--prewarm` forwards over this many milliseconds, rather
+    /// than asking the API server for them all at once, 0 to open them straight away
+    #[arg(long, value_name = "MILLISECONDS")]
+    pub prewarm_jitter_ms: Option<u64>,
+
+    /// Also serve the cluster of kubeconfig context `<CONTEXT>`, addressed with a
+    /// `.<NAME>.clusters` suffix in place of the cluster domain, may be repeated
+    #[arg(long = "context", value_name = "[NAME=]CONTEXT")]
+    pub contexts: Vec<ClusterContext>,
+
+    /// Other pods to try when a forward fails before the client is told it succeeded, for
+    /// addresses that can pick between several
+    #[arg(long, value_name = "COUNT")]
+    pub forward_retries: Option<u32>,
+
+    /// Fail requests for an address and port straight away once this many in a row have failed
+    /// to reach a pod, for --circuit-breaker-cooldown. 0 disables the breaker
+    #[arg(long, value_name = "COUNT")]
+    pub circuit_breaker_failures: Option<u32>,
+
+    /// Seconds the failures tripping a circuit breaker must happen within
+    #[arg(long, value_name = "SECONDS")]
+    pub circuit_breaker_window: Option<u64>,
+
+    /// Seconds a tripped circuit breaker fails requests for, before letting one through to see
+    /// whether the target recovered
+    #[arg(long, value_name = "SECONDS")]
+    pub circuit_breaker_cooldown: Option<u64>,
+
+    /// Pod condition that must be "True" for a pod to be picked
+    #[arg(long, value_name = "CONDITION")]
+    pub readiness_condition: Option<String>,
+
+    /// Pick pods whose named container is ready, rather than checking a pod condition
+    #[arg(long, value_name = "CONTAINER")]
+    pub readiness_container: Option<String>,
+
+    /// Pick any running pod regardless of its readiness condition
+    #[arg(long)]
+    pub ignore_readiness: bool,
+
+    /// Cap each direction of every connection at this many bytes per second, 0 for unlimited
+    #[arg(long, value_name = "BYTES_PER_SEC")]
+    pub rate_limit: Option<u64>,
+
+    /// Size of the buffer used for each direction of every connection, bigger favours throughput
+    /// and smaller saves memory with many connections
+    #[arg(long, value_name = "BYTES")]
+    pub buffer_size: Option<usize>,
+
+    /// Close every connection this many seconds after its forward starts, however busy, 0 for
+    /// no limit
+    #[arg(long, value_name = "SECONDS")]
+    pub max_connection_lifetime: Option<u64>,
+
+    /// Seconds connections are given to finish when shutting down, before those still open are
+    /// sent a failure reply if they're waiting for one and closed
+    #[arg(long, value_name = "SECONDS")]
+    pub shutdown_grace: Option<u64>,
+
+    /// Most connections handled at once, further clients wait for one to finish, 0 for no limit
+    #[arg(long, value_name = "COUNT")]
+    pub max_connections: Option<usize>,
+
+    /// Fail clients straight away when `--max-connections` are already being handled, rather
+    /// than have them wait
+    #[arg(long)]
+    pub reject_when_full: bool,
+
+    /// Threads handling connections, 0 for one per CPU or `TOKIO_WORKER_THREADS` if set
+    #[arg(long, value_name = "COUNT")]
+    pub worker_threads: Option<usize>,
+
+    /// How forwards are opened once a pod has been picked
+    #[arg(long, value_name = "BACKEND")]
+    pub forward_backend: Option<ForwardBackend>,
+
+    /// `ws://` or `wss://` URL of the companion endpoint for `--forward-backend websocket`, the
+    /// target is added as `namespace`, `pod` and `port` query parameters
+    #[arg(long, value_name = "URL")]
+    pub websocket_url: Option<String>,
+
+    /// Relay connections to this pod port as HTTP through the API server's `pods/proxy`
+    /// subresource instead of a port-forward, may be repeated. Only works for plain HTTP
+    #[arg(long = "api-proxy-port", value_name = "PORT")]
+    pub api_proxy_ports: Vec<u16>,
+
+    /// Send the pod a PROXY protocol header of this version ahead of the client's bytes, so it
+    /// can tell where the client connected from. Not sent on `--api-proxy-port` ports
+    #[arg(long, value_name = "VERSION")]
+    pub send_proxy_protocol: Option<ProxyProtocol>,
+
+    /// Only allow clients to connect to these ports, eg. `443` or `8000-8999`, may be repeated.
+    /// All ports are allowed when not given
+    #[arg(long = "allow-port", value_name = "PORTS")]
+    pub allow_ports: Vec<PortRange>,
+
+    /// Never allow clients to connect to these ports, may be repeated. Wins over --allow-port
+    #[arg(long = "deny-port", value_name = "PORTS")]
+    pub deny_ports: Vec<PortRange>,
+
+    /// Never forward to a service or pod whose name matches this glob, in any namespace, eg.
+    /// `vault` or `etcd-*`, may be repeated
+    #[arg(long = "deny-name", value_name = "GLOB")]
+    pub deny_names: Vec<NamePattern>,
+
+    /// Only forward into this namespace, may be repeated. Applies to clients without a
+    /// `user-namespaces` entry, which scopes each user on its own. Every namespace when not given
+    #[arg(long = "allow-namespace", value_name = "NAMESPACE")]
+    pub allow_namespaces: Vec<String>,
+
+    /// Allow connecting to a node's InternalIP, forwarded through a host network pod on the node
+    #[arg(long)]
+    pub allow_node_access: bool,
+
+    /// Allow `<pod>.pod.<cluster-domain>` without a namespace, looking the pod up in every
+    /// namespace, it fails if more than one has a pod by that name
+    #[arg(long)]
+    pub allow_cross_namespace_pod: bool,
+
+    /// Forward to any port of a pod addressed by name, not only those its containers declare,
+    /// for processes listening on a port the pod spec leaves out
+    #[arg(long)]
+    pub allow_undeclared_ports: bool,
+
+    /// Before forwarding to a service whose `targetPort` is a name, check the pod listens on the
+    /// port it names with a throwaway forward. Some are only declared for probes
+    #[arg(long)]
+    pub probe_named_target_ports: bool,
+
+    /// Address to serve the admin HTTP endpoints on, disabled when not set
+    #[arg(long, value_name = "ADDR")]
+    pub admin_listen: Option<SocketAddr>,
+
+    /// Client IPs connecting most whose connection counts `/metrics` reports, 0 for none
+    #[arg(long, value_name = "COUNT")]
+    pub client_metrics_top: Option<usize>,
+
+    /// Address to accept HTTP CONNECT proxy requests on, alongside the SOCKS listeners
+    #[arg(long, value_name = "ADDR")]
+    pub http_connect_listen: Option<SocketAddr>,
+
+    /// PEM certificate chain to terminate TLS with on the SOCKS listeners, requires --tls-key
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM CA bundle TLS clients must present a certificate signed by, its subject is then used
+    /// as the client's identity
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Also accept plaintext SOCKS on the TLS listeners, telling them apart by whether the
+    /// connection starts with a TLS record
+    #[arg(long, requires = "tls_cert")]
+    pub tls_auto_detect: bool,
+
+    /// `<namespace>/<name>` of a Secret holding user-pass credentials, usernames as keys and
+    /// passwords, or `sha256:<hex>` password hashes, as values
+    #[arg(long, value_name = "NAMESPACE/NAME")]
+    pub auth_secret: Option<String>,
+
+    /// Watch --auth-secret and reload credentials when it changes
+    #[arg(long, requires = "auth_secret")]
+    pub watch_auth_secret: bool,
+
+    /// Watch the pods connections are forwarded to, logging when they change, are deleted or are
+    /// recreated
+    #[arg(long)]
+    pub watch_target_pods: bool,
+
+    /// Serve `<name>` from `<host>:<port>` over plain TCP instead of resolving against a cluster,
+    /// may be repeated. For local development, no cluster is contacted when any are given
+    #[arg(long = "static-host", value_name = "NAME=HOST:PORT", value_parser = parse_static_host)]
+    pub static_hosts: Vec<(String, String)>,
+
+    /// SOCKS5 reply to send when a request fails with `<kind>`, may be repeated. Some clients
+    /// retry differently depending on the reply
+    #[arg(long = "error-reply", value_name = "KIND=REPLY", value_parser = parse_error_reply)]
+    pub error_replies: Vec<(ErrorKind, ErrorReply)>,
+
+    /// Append a JSON line per connection attempt to this file, for auditing
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Address given in SOCKS5 success replies to requests by name, clients disagree on which
+    /// they expect
+    #[arg(long, value_name = "FORM")]
+    pub reply_address: Option<ReplyAddress>,
+
+    /// SOCKS5 auth method to accept, may be repeated, most preferred first
+    #[arg(long = "auth-method", value_name = "METHOD")]
+    pub auth_methods: Vec<AuthMethod>,
+
+    /// Close SOCKS4 and 4a connections straight away, they can't authenticate
+    #[arg(long = "disable-socks4")]
+    pub disable_socks4: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(deserialize_with = "deserialize_listen_addrs")]
+    pub listen: Vec<SocketAddr>,
+    /// UNIX socket to listen on as well as `listen`
+    pub listen_unix: Option<PathBuf>,
+    /// Times binding a `listen` address is retried, failing at startup straight away when 0
+    pub bind_retries: u32,
+    pub cluster_domain: String,
+    pub default_namespace: String,
+    pub search_domains: Vec<String>,
+    /// Label key `.nsl` addresses select their namespace by
+    pub namespace_label: Option<String>,
+    pub forward_rate: f64,
+    pub forward_burst: u32,
+    pub wait_for_ready: u64,
+    /// Most pods listed per request when picking one, 0 for no limit
+    pub list_page_size: u32,
+    pub connect_timeout: u64,
+    pub forward_probe_ms: u64,
+    /// Times a failed forward is retried against another pod
+    pub forward_retries: u32,
+    /// Failures in a row that trip a circuit breaker, 0 for no breakers
+    pub circuit_breaker_failures: u32,
+    pub circuit_breaker_window: u64,
+    pub circuit_breaker_cooldown: u64,
+    /// Pod ports kept with a forward open, ready for the next client
+    pub prewarm: Vec<PrewarmTarget>,
+    /// Window prewarm forwards are opened and replaced across, 0 for straight away
+    pub prewarm_jitter_ms: u64,
+    /// Other clusters, addressed as `<address>.<name>.clusters`
+    pub contexts: Vec<ClusterContext>,
+    /// Pod condition type that must be "True" for a pod to count as ready
+    pub readiness_condition: String,
+    /// Container whose readiness counts instead of `readiness-condition`
+    pub readiness_container: Option<String>,
+    /// Count any running pod as ready, whatever its conditions
+    pub ignore_readiness: bool,
+    /// Bytes per second allowed in each direction of a connection, 0 for unlimited
+    pub rate_limit: u64,
+    /// Bytes read at a time in each direction of a connection
+    pub buffer_size: usize,
+    /// Seconds a connection may forward for before it's closed, 0 for no limit
+    pub max_connection_lifetime: u64,
+    /// Seconds connections may take to finish once shutting down, before they're drained
+    pub shutdown_grace: u64,
+    /// Connections handled at once, 0 for no limit
+    pub max_connections: usize,
+    /// Fail connections over `max_connections` instead of queueing them
+    pub reject_when_full: bool,
+    /// Runtime worker threads, 0 for tokio's default
+    pub worker_threads: usize,
+    pub forward_backend: ForwardBackend,
+    /// Companion endpoint forwards are tunnelled to with the `websocket` backend
+    pub websocket_url: Option<String>,
+    /// Pod ports relayed as HTTP through `pods/proxy` rather than port-forwarded
+    pub api_proxy_ports: Vec<u16>,
+    /// PROXY protocol header sent to pods ahead of the client's bytes
+    pub send_proxy_protocol: Option<ProxyProtocol>,
+    /// Ports clients may connect to, every port when empty
+    pub allow_ports: Vec<PortRange>,
+    /// Ports clients may never connect to, even if allowed
+    pub deny_ports: Vec<PortRange>,
+    /// Services and pods never forwarded to, in any namespace
+    pub deny_names: Vec<NamePattern>,
+    /// Namespaces clients without a `user_namespaces` entry may forward into, every one when empty
+    pub allow_namespaces: Vec<String>,
+    pub allow_node_access: bool,
+    /// Look up pods addressed without a namespace in every namespace
+    pub allow_cross_namespace_pod: bool,
+    /// Forward to ports of pods addressed by name that no container declares
+    pub allow_undeclared_ports: bool,
+    /// Check pods listen on the port a service's named `targetPort` maps to before forwarding
+    pub probe_named_target_ports: bool,
+    /// Address to serve the admin HTTP endpoints on
+    pub admin_listen: Option<SocketAddr>,
+    /// Client IPs `socks_connections_by_client` reports, the ones connecting most
+    pub client_metrics_top: usize,
+    /// Address to accept HTTP CONNECT proxy requests on
+    pub http_connect_listen: Option<SocketAddr>,
+    /// PEM certificate chain, when set with `tls-key` clients must connect using TLS
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// CA bundle for verifying client certificates, which are required when set
+    pub tls_client_ca: Option<PathBuf>,
+    /// Accept plaintext SOCKS alongside TLS on the same listeners
+    pub tls_auto_detect: bool,
+    /// Bound address in SOCKS5 success replies to requests by name, IP requests always get the pod IP
+    pub reply_address: ReplyAddress,
+    /// Accepted SOCKS5 auth methods, most preferred first
+    pub auth_methods: Vec<AuthMethod>,
+    /// Refuse SOCKS4 clients, which bypass `auth_methods`
+    pub disable_socks4: bool,
+    /// Username to password, or `sha256:<hex>` password hash, for the `user-pass` auth method
+    pub users: BTreeMap<String, String>,
+    /// Username to the namespaces they may forward into, in place of `allow_namespaces`, none
+    /// when empty
+    pub user_namespaces: BTreeMap<String, Vec<String>>,
+    /// `<namespace>/<name>` of a Secret with further `users`
+    pub auth_secret: Option<String>,
+    pub watch_auth_secret: bool,
+    /// Log changes to the pods being forwarded to
+    pub watch_target_pods: bool,
+    /// Name to `host:port`, when set these are served instead of resolving against a cluster
+    pub static_hosts: BTreeMap<String, String>,
+    /// Replies for failures that shouldn't get their default one
+    pub error_replies: BTreeMap<ErrorKind, ErrorReply>,
+    /// JSON lines file recording every connection attempt
+    pub audit_log: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec![
+                SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_PORT)),
+                SocketAddr::from((Ipv6Addr::LOCALHOST, DEFAULT_PORT)),
+            ],
+            listen_unix: None,
+            bind_retries: 0,
+            cluster_domain: DEFAULT_CLUSTER_DOMAIN.into(),
+            default_namespace: DEFAULT_NAMESPACE.into(),
+            search_domains: vec![],
+            namespace_label: None,
+            forward_rate: DEFAULT_FORWARD_RATE,
+            forward_burst: DEFAULT_FORWARD_BURST,
+            wait_for_ready: 0,
+            list_page_size: DEFAULT_LIST_PAGE_SIZE,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            forward_probe_ms: DEFAULT_FORWARD_PROBE_MS,
+            forward_retries: DEFAULT_FORWARD_RETRIES,
+            circuit_breaker_failures: 0,
+            circuit_breaker_window: DEFAULT_CIRCUIT_BREAKER_WINDOW,
+            circuit_breaker_cooldown: DEFAULT_CIRCUIT_BREAKER_COOLDOWN,
+            prewarm: vec![],
+            prewarm_jitter_ms: 0,
+            contexts: vec![],
+            readiness_condition: DEFAULT_READINESS_CONDITION.into(),
+            readiness_container: None,
+            ignore_readiness: false,
+            rate_limit: 0,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            max_connection_lifetime: 0,
+            shutdown_grace: 0,
+            max_connections: 0,
+            reject_when_full: false,
+            worker_threads: 0,
+            forward_backend: ForwardBackend::PortForward,
+            websocket_url: None,
+            api_proxy_ports: vec![],
+            send_proxy_protocol: None,
+            allow_ports: vec![],
+            deny_ports: vec![],
+            deny_names: vec![],
+            allow_namespaces: vec![],
+            allow_node_access: false,
+            allow_cross_namespace_pod: false,
+            allow_undeclared_ports: false,
+            probe_named_target_ports: false,
+            admin_listen: None,
+            client_metrics_top: DEFAULT_CLIENT_METRICS_TOP,
+            http_connect_listen: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            tls_auto_detect: false,
+            reply_address: ReplyAddress::PodIp,
+            auth_methods: vec![AuthMethod::NotRequired],
+            disable_socks4: false,
+            users: BTreeMap::new(),
+            user_namespaces: BTreeMap::new(),
+            auth_secret: None,
+            watch_auth_secret: false,
+            watch_target_pods: false,
+            static_hosts: BTreeMap::new(),
+            error_replies: BTreeMap::new(),
+            audit_log: None,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the effective config: defaults, then the `--config` file, then the remaining flags.
+    pub fn load(cli: Cli) -> Result<Config, Errors> {
+        let mut config = match cli.config {
+            Some(ref path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        config.apply(cli);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, Errors> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| Errors::Read(path.to_path_buf(), e))?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => {
+                serde_yaml::from_str(&contents).map_err(|e| Errors::Yaml(path.to_path_buf(), e))
+            }
+            _ => toml::from_str(&contents).map_err(|e| Errors::Toml(path.to_path_buf(), e)),
+        }
+    }
+
+    fn apply(&mut self, cli: Cli) {
+        if !cli.listen.is_empty() {
+            self.listen = cli.listen;
+        }
+        if cli.listen_unix.is_some() {
+            self.listen_unix = cli.listen_unix;
+        }
+        if let Some(bind_retries) = cli.bind_retries {
+            self.bind_retries = bind_retries;
+        }
+        if let Some(cluster_domain) = cli.cluster_domain {
+            self.cluster_domain = cluster_domain;
+        }
+        if let Some(default_namespace) = cli.default_namespace {
+            self.default_namespace = default_namespace;
+        }
+        if !cli.search_domains.is_empty() {
+            self.search_domains = cli.search_domains;
+        }
+        if cli.namespace_label.is_some() {
+            self.namespace_label = cli.namespace_label;
+        }
+        if let Some(forward_rate) = cli.forward_rate {
+            self.forward_rate = forward_rate;
+        }
+        if let Some(forward_burst) = cli.forward_burst {
+            self.forward_burst = forward_burst;
+        }
+        if let Some(wait_for_ready) = cli.wait_for_ready {
+            self.wait_for_ready = wait_for_ready;
+        }
+        if let Some(list_page_size) = cli.list_page_size {
+            self.list_page_size = list_page_size;
+        }
+        if let Some(connect_timeout) = cli.connect_timeout {
+            self.connect_timeout = connect_timeout;
+        }
+        if let Some(forward_probe_ms) = cli.forward_probe_ms {
+            self.forward_probe_ms = forward_probe_ms;
+        }
+        if let Some(forward_retries) = cli.forward_retries {
+            self.forward_retries = forward_retries;
+        }
+        if let Some(circuit_breaker_failures) = cli.circuit_breaker_failures {
+            self.circuit_breaker_failures = circuit_breaker_failures;
+        }
+        if let Some(circuit_breaker_window) = cli.circuit_breaker_window {
+            self.circuit_breaker_window = circuit_breaker_window;
+        }
+        if let Some(circuit_breaker_cooldown) = cli.circuit_breaker_cooldown {
+            self.circuit_breaker_cooldown = circuit_breaker_cooldown;
+        }
+        if !cli.prewarm.is_empty() {
+            self.prewarm = cli.prewarm;
+        }
+        if let Some(prewarm_jitter_ms) = cli.prewarm_jitter_ms {
+            self.prewarm_jitter_ms = prewarm_jitter_ms;
+        }
+        if !cli.contexts.is_empty() {
+            self.contexts = cli.contexts;
+        }
+        if let Some(readiness_condition) = cli.readiness_condition {
+            self.readiness_condition = readiness_condition;
+        }
+        if cli.readiness_container.is_some() {
+            self.readiness_container = cli.readiness_container;
+        }
+        if cli.ignore_readiness {
+            self.ignore_readiness = true;
+        }
+        if let Some(rate_limit) = cli.rate_limit {
+            self.rate_limit = rate_limit;
+        }
+        if let Some(buffer_size) = cli.buffer_size {
+            self.buffer_size = buffer_size;
+        }
+        if let Some(max_connection_lifetime) = cli.max_connection_lifetime {
+            self.max_connection_lifetime = max_connection_lifetime;
+        }
+        if let Some(shutdown_grace) = cli.shutdown_grace {
+            self.shutdown_grace = shutdown_grace;
+        }
+        if let Some(max_connections) = cli.max_connections {
+            self.max_connections = max_connections;
+        }
+        if cli.reject_when_full {
+            self.reject_when_full = true;
+        }
+        if let Some(worker_threads) = cli.worker_threads {
+            self.worker_threads = worker_threads;
+        }
+        if let Some(forward_backend) = cli.forward_backend {
+            self.forward_backend = forward_backend;
+        }
+        if cli.websocket_url.is_some() {
+            self.websocket_url = cli.websocket_url;
+        }
+        if !cli.api_proxy_ports.is_empty() {
+            self.api_proxy_ports = cli.api_proxy_ports;
+        }
+        if cli.send_proxy_protocol.is_some() {
+            self.send_proxy_protocol = cli.send_proxy_protocol;
+        }
+        if !cli.allow_ports.is_empty() {
+            self.allow_ports = cli.allow_ports;
+        }
+        if !cli.deny_ports.is_empty() {
+            self.deny_ports = cli.deny_ports;
+        }
+        if !cli.deny_names.is_empty() {
+            self.deny_names = cli.deny_names;
+        }
+        if !cli.allow_namespaces.is_empty() {
+            self.allow_namespaces = cli.allow_namespaces;
+        }
+        if cli.allow_node_access {
+            self.allow_node_access = true;
+        }
+        if cli.allow_cross_namespace_pod {
+            self.allow_cross_namespace_pod = true;
+        }
+        if cli.allow_undeclared_ports {
+            self.allow_undeclared_ports = true;
+        }
+        if cli.probe_named_target_ports {
+            self.probe_named_target_ports = true;
+        }
+        if cli.admin_listen.is_some() {
+            self.admin_listen = cli.admin_listen;
+        }
+        if let Some(client_metrics_top) = cli.client_metrics_top {
+            self.client_metrics_top = client_metrics_top;
+        }
+        if cli.http_connect_listen.is_some() {
+            self.http_connect_listen = cli.http_connect_listen;
+        }
+        if cli.tls_cert.is_some() {
+            self.tls_cert = cli.tls_cert;
+        }
+        if cli.tls_key.is_some() {
+            self.tls_key = cli.tls_key;
+        }
+        if cli.tls_client_ca.is_some() {
+            self.tls_client_ca = cli.tls_client_ca;
+        }
+        if cli.tls_auto_detect {
+            self.tls_auto_detect = true;
+        }
+        if let Some(reply_address) = cli.reply_address {
+            self.reply_address = reply_address;
+        }
+        if !cli.auth_methods.is_empty() {
+            self.auth_methods = cli.auth_methods;
+        }
+        if cli.disable_socks4 {
+            self.disable_socks4 = true;
+        }
+        if cli.auth_secret.is_some() {
+            self.auth_secret = cli.auth_secret;
+        }
+        if cli.watch_auth_secret {
+            self.watch_auth_secret = true;
+        }
+        if cli.watch_target_pods {
+            self.watch_target_pods = true;
+        }
+        // Each flag overrides the reply for just its kind, leaving the file's others in place
+        self.error_replies.extend(cli.error_replies);
+        if !cli.static_hosts.is_empty() {
+            self.static_hosts = cli.static_hosts.into_iter().collect();
+        }
+        if cli.audit_log.is_some() {
+            self.audit_log = cli.audit_log;
+        }
+    }
+
+    /// The configured search domains, or the ones derived from the default namespace.
+    pub fn search_domains(&self) -> Vec<String> {
+        if !self.search_domains.is_empty() {
+            return self.search_domains.clone();
+        }
+
+        vec![
+            format!("{}.svc.{}", self.default_namespace, self.cluster_domain),
+            format!("svc.{}", self.cluster_domain),
+        ]
+    }
+
+    /// The SOCKS5 reply for a request that failed with `kind`.
+    pub fn error_reply(&self, kind: ErrorKind) -> ErrorReply {
+        self.error_replies
+            .get(&kind)
+            .copied()
+            .unwrap_or_else(|| kind.default_reply())
+    }
+
+    /// Whether clients may connect to `port`, as requested before any default is looked up.
+    pub fn port_allowed(&self, port: u16) -> bool {
+        let allowed =
+            self.allow_ports.is_empty() || self.allow_ports.iter().any(|r| r.contains(port));
+
+        allowed && !self.deny_ports.iter().any(|r| r.contains(port))
+    }
+
+    /// Whether a service or pod named `name` is blocked by `deny-name`.
+    pub fn name_denied(&self, name: &str) -> bool {
+        self.deny_names.iter().any(|p| p.matches(name))
+    }
+
+    /// Whether a client authenticated as `username`, or not at all, may forward into
+    /// `namespace`. Users listed in `user-namespaces` get theirs, none if the list is empty, and
+    /// everyone else `allow-namespaces`, every one if that's empty.
+    pub fn namespace_allowed(&self, username: Option<&str>, namespace: &str) -> bool {
+        match username.and_then(|u| self.user_namespaces.get(u)) {
+            Some(allowed) => allowed.iter().any(|n| n == namespace),
+            None => {
+                self.allow_namespaces.is_empty()
+                    || self.allow_namespaces.iter().any(|n| n == namespace)
+            }
+        }
+    }
+
+    /// This config with its secrets, the passwords in `users` and any credentials in
+    /// `websocket-url`, replaced by `<redacted>`, for logging.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        for password in config.users.values_mut() {
+            *password = REDACTED.into();
+        }
+        config.websocket_url = config.websocket_url.as_deref().map(redact_userinfo);
+        config
+    }
+
+    /// The `(namespace, name)` of `auth-secret`.
+    pub fn auth_secret_ref(&self) -> Option<(&str, &str)> {
+        self.auth_secret
+            .as_deref()?
+            .split_once('/')
+            .filter(|(ns, name)| !ns.is_empty() && !name.is_empty() && !name.contains('/'))
+    }
+
+    pub fn validate(&self) -> Result<(), Errors> {
+        if self.listen.is_empty() && self.listen_unix.is_none() {
+            return Err(Errors::Invalid(
+                "at least one listen address or listen-unix is required".into(),
+            ));
+        }
+
+        if cfg!(not(unix)) && self.listen_unix.is_some() {
+            return Err(Errors::Invalid(
+                "listen-unix is only supported on unix platforms".into(),
+            ));
+        }
+
+        if self.cluster_domain.is_empty()
+            || self.cluster_domain.starts_with('.')
+            || self.cluster_domain.ends_with('.')
+        {
+            return Err(Errors::Invalid(format!(
+                "cluster-domain {:?} must be non-empty and not start or end with '.'",
+                self.cluster_domain
+            )));
+        }
+
+        if self.default_namespace.is_empty() || self.default_namespace.contains('.') {
+            return Err(Errors::Invalid(format!(
+                "default-namespace {:?} must be a non-empty namespace name",
+                self.default_namespace
+            )));
+        }
+
+        if let Some(domain) = self
+            .search_domains
+            .iter()
+            .find(|d| d.is_empty() || d.starts_with('.') || d.ends_with('.'))
+        {
+            return Err(Errors::Invalid(format!(
+                "search-domain {domain:?} must be non-empty and not start or end with '.'"
+            )));
+        }
+
+        if self
+            .namespace_label
+            .as_ref()
+            .is_some_and(|l| l.is_empty() || l.contains(['=', ',']))
+        {
+            return Err(Errors::Invalid(
+                "namespace-label must be a label key".into(),
+            ));
+        }
+
+        if !self.forward_rate.is_finite() || self.forward_rate <= 0.0 {
+            return Err(Errors::Invalid(format!(
+                "forward-rate {} must be a positive number",
+                self.forward_rate
+            )));
+        }
+
+        if self.forward_burst == 0 {
+            return Err(Errors::Invalid("forward-burst must be at least 1".into()));
+        }
+
+        if self.connect_timeout == 0 {
+            return Err(Errors::Invalid("connect-timeout must be at least 1".into()));
+        }
+
+        if self.buffer_size == 0 {
+            return Err(Errors::Invalid("buffer-size must be at least 1".into()));
+        }
+
+        if self.buffer_size > MAX_BUFFER_SIZE {
+            return Err(Errors::Invalid("buffer-size must be at most 16 MiB".into()));
+        }
+
+        if self.client_metrics_top > MAX_CLIENT_METRICS_TOP {
+            return Err(Errors::Invalid(format!(
+                "client-metrics-top must be at most {MAX_CLIENT_METRICS_TOP}"
+            )));
+        }
+
+        if self.readiness_condition.is_empty() {
+            return Err(Errors::Invalid(
+                "readiness-condition must be non-empty".into(),
+            ));
+        }
+
+        if self.ignore_readiness && self.readiness_container.is_some() {
+            return Err(Errors::Invalid(
+                "ignore-readiness and readiness-container can't be used together".into(),
+            ));
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(Errors::Invalid(
+                "tls-cert and tls-key must be set together".into(),
+            ));
+        }
+
+        if self.tls_client_ca.is_some() && self.tls_cert.is_none() {
+            return Err(Errors::Invalid(
+                "tls-client-ca requires tls-cert and tls-key".into(),
+            ));
+        }
+
+        if self.tls_auto_detect && self.tls_cert.is_none() {
+            return Err(Errors::Invalid(
+                "tls-auto-detect requires tls-cert and tls-key".into(),
+            ));
+        }
+
+        // Required client certificates mean nothing if clients can leave TLS out altogether
+        if self.tls_auto_detect && self.tls_client_ca.is_some() {
+            return Err(Errors::Invalid(
+                "tls-auto-detect can't be used with tls-client-ca".into(),
+            ));
+        }
+
+        // No cluster at all is used with static hosts
+        if !self.contexts.is_empty() && !self.static_hosts.is_empty() {
+            return Err(Errors::Invalid(
+                "context can't be used with static-host".into(),
+            ));
+        }
+
+        let mut names = BTreeSet::new();
+        if let Some(c) = self.contexts.iter().find(|c| !names.insert(&c.name)) {
+            return Err(Errors::Invalid(format!(
+                "more than one context is named {}",
+                c.name
+            )));
+        }
+
+        if self.reject_when_full && self.max_connections == 0 {
+            return Err(Errors::Invalid(
+                "reject-when-full requires max-connections".into(),
+            ));
+        }
+
+        if self.auth_methods.is_empty() {
+            return Err(Errors::Invalid(
+                "at least one auth-method is required".into(),
+            ));
+        }
+
+        if self.auth_methods.contains(&AuthMethod::UserPass)
+            && self.users.is_empty()
+            && self.auth_secret.is_none()
+        {
+            return Err(Errors::Invalid(
+                "auth-method user-pass requires users or auth-secret".into(),
+            ));
+        }
+
+        if let Some(ref secret) = self.auth_secret {
+            if self.auth_secret_ref().is_none() {
+                return Err(Errors::Invalid(format!(
+                    "auth-secret {secret:?} must be <namespace>/<name>"
+                )));
+            }
+        }
+
+        if self.watch_auth_secret && self.auth_secret.is_none() {
+            return Err(Errors::Invalid(
+                "watch-auth-secret requires auth-secret".into(),
+            ));
+        }
+
+        match (self.forward_backend, &self.websocket_url) {
+            (ForwardBackend::Websocket, None) => {
+                return Err(Errors::Invalid(
+                    "forward-backend websocket requires websocket-url".into(),
+                ));
+            }
+            (_, Some(url)) if !url.starts_with("ws://") && !url.starts_with("wss://") => {
+                return Err(Errors::Invalid(format!(
+                    "websocket-url {url:?} must be a ws:// or wss:// URL"
+                )));
+            }
+            _ => {}
+        }
+
+        if !self.static_hosts.is_empty() && self.auth_secret.is_some() {
+            return Err(Errors::Invalid(
+                "auth-secret can't be used with static-hosts, no cluster is contacted".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a `--static-host` mapping, `<name>=<host>:<port>`.
+pub fn parse_static_host(mapping: &str) -> Result<(String, String), String> {
+    match mapping.split_once('=') {
+        Some((name, target)) if !name.is_empty() && !target.is_empty() => {
+            Ok((name.into(), target.into()))
+        }
+        _ => Err(format!("{mapping:?} must be <name>=<host>:<port>")),
+    }
+}
+
+/// Parses an `--error-reply` override, `<kind>=<reply>`.
+pub fn parse_error_reply(mapping: &str) -> Result<(ErrorKind, ErrorReply), String> {
+    use clap::ValueEnum;
+
+    let (kind, reply) = mapping
+        .split_once('=')
+        .ok_or_else(|| format!("{mapping:?} must be <kind>=<reply>"))?;
+
+    Ok((
+        ErrorKind::from_str(kind, false).map_err(|_| format!("unknown error kind {kind:?}"))?,
+        ErrorReply::from_str(reply, false).map_err(|_| format!("unknown reply {reply:?}"))?,
+    ))
+}
+
+/// `url` without the `user:password@` part of its authority, if it has one.
+pub(crate) fn redact_userinfo(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.into();
+    };
+    let authority_len = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+
+    match rest[..authority_len].rsplit_once('@') {
+        Some((_, host)) => format!("{scheme}://{REDACTED}@{host}{}", &rest[authority_len..]),
+        None => url.into(),
+    }
+}
+
+/// Parses a listen address. Unlike `SocketAddr`'s parser this accepts an IPv6 zone given as an
+/// interface name, `[fe80::1%eth0]:1080`, and defaults the port when it's left off.
+pub fn parse_listen_addr(addr: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, DEFAULT_PORT));
+    }
+
+    let (host, port) = addr
+        .strip_prefix('[')
+        .and_then(|a| a.split_once(']'))
+        .ok_or_else(|| format!("{addr:?} is not an address"))?;
+
+    let port = match port {
+        "" => DEFAULT_PORT,
+        p => p
+            .strip_prefix(':')
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(|| format!("{addr:?} has an invalid port"))?,
+    };
+
+    let (ip, zone) = host.split_once('%').unwrap_or((host, ""));
+    let ip: Ipv6Addr = ip
+        .parse()
+        .map_err(|_| format!("{addr:?} is not an IPv6 address"))?;
+    let scope_id = match zone {
+        "" => 0,
+        zone => zone
+            .parse()
+            .ok()
+            .or_else(|| interface_index(zone))
+            .ok_or_else(|| format!("{addr:?} has an unknown zone {zone:?}"))?,
+    };
+
+    Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: `name` is a valid NUL terminated string for the duration of the call
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+fn deserialize_listen_addrs<'de, D>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|a| parse_listen_addr(a).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests;
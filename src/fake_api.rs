@@ -0,0 +1,85 @@
+//! A stand-in for the API server in tests: answers requests accepted on a local listener with
+//! canned responses, recording what each asked for.
+
+use futures::SinkExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// The head of a request answered by [`respond`].
+pub(crate) struct Served {
+    head: String,
+}
+
+impl Served {
+    /// The request's target, its path and query.
+    pub(crate) fn path(&self) -> &str {
+        self.head.split(' ').nth(1).unwrap_or_default()
+    }
+
+    /// The value of the header `name`, which must be lowercase as the client sends it.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.head
+            .lines()
+            .find_map(|l| l.strip_prefix(name)?.strip_prefix(": "))
+    }
+}
+
+/// Answers one request on `listener` with `status`, eg. `200 OK`, and `body` as JSON.
+pub(crate) async fn respond(listener: &TcpListener, status: &str, body: &str) -> Served {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await.unwrap();
+
+    Served {
+        head: String::from_utf8(head).unwrap(),
+    }
+}
+
+/// Answers one request on `listener` with `body` as JSON, returning the request's target.
+pub(crate) async fn serve_json(listener: &TcpListener, body: &str) -> String {
+    respond(listener, "200 OK", body).await.path().to_string()
+}
+
+/// Accepts a port-forward to `ports` on `listener`, and sends the port number each of their data
+/// and error channels starts with. Returns the forward's websocket and the request's query.
+// The handshake callback's error type is tungstenite's, not ours to shrink
+#[allow(clippy::result_large_err)]
+pub(crate) async fn accept_portforward(
+    listener: &TcpListener,
+    ports: &[u16],
+) -> (WebSocketStream<TcpStream>, Option<String>) {
+    let (tcp, _) = listener.accept().await.unwrap();
+    let mut query = None;
+    let mut ws = tokio_tungstenite::accept_hdr_async(tcp, |req: &Request, mut res: Response| {
+        query = req.uri().query().map(str::to_string);
+        res.headers_mut().insert(
+            "sec-websocket-protocol",
+            HeaderValue::from_static("v4.channel.k8s.io"),
+        );
+        Ok(res)
+    })
+    .await
+    .unwrap();
+
+    for (i, port) in ports.iter().enumerate() {
+        for channel in [2 * i as u8, 2 * i as u8 + 1] {
+            let mut frame = vec![channel];
+            frame.extend_from_slice(&port.to_le_bytes());
+            ws.send(Message::binary(frame)).await.unwrap();
+        }
+    }
+
+    (ws, query)
+}
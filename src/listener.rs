@@ -0,0 +1,290 @@
+//! Client connections accepted from the TCP and UNIX socket listeners, merged into one stream.
+
+use std::fmt::Display;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::wrappers::TcpListenerStream;
+use tracing::{error, warn};
+#[cfg(unix)]
+use {
+    std::path::Path,
+    tokio::net::{UnixListener, UnixStream},
+    tokio_stream::wrappers::UnixListenerStream,
+};
+
+/// Where a client connected from, UNIX socket clients are usually unnamed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix(Option<PathBuf>),
+}
+
+impl PeerAddr {
+    /// The client's IP, UNIX socket clients have none.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) => Some(addr.ip()),
+            PeerAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{addr}"),
+            PeerAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            PeerAddr::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+impl Serialize for PeerAddr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(value: SocketAddr) -> Self {
+        PeerAddr::Tcp(value)
+    }
+}
+
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+pub type Accepted = io::Result<(ClientStream, PeerAddr)>;
+
+pub fn tcp(listener: TcpListener) -> impl Stream<Item = Accepted> {
+    TcpListenerStream::new(listener).map(|conn| {
+        let conn = conn?;
+        let peer_addr = conn.peer_addr()?;
+        Ok((ClientStream::Tcp(conn), peer_addr.into()))
+    })
+}
+
+#[cfg(unix)]
+pub fn unix(listener: UnixListener) -> impl Stream<Item = Accepted> {
+    UnixListenerStream::new(listener).map(|conn| {
+        let conn = conn?;
+        let peer_addr = conn.peer_addr()?.as_pathname().map(Path::to_path_buf);
+        Ok((ClientStream::Unix(conn), PeerAddr::Unix(peer_addr)))
+    })
+}
+
+/// Wait before the first retry of a failed bind, doubled for each one after.
+const BIND_BACKOFF: Duration = Duration::from_millis(250);
+/// Longest wait between retries of a failed bind.
+const MAX_BIND_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Binds each of `addrs`, retrying those that fail up to `retries` times with backoff, eg. for
+/// IPv6 that's not up yet at boot. Without retries any failure is returned. With them, addresses
+/// still failing once they've run out are skipped, only failing when none could be bound.
+pub async fn bind_all(addrs: &[SocketAddr], retries: u32) -> anyhow::Result<Vec<TcpListener>> {
+    let binds = addrs.iter().map(|&addr| async move {
+        bind_with_retries(addr, retries)
+            .await
+            .with_context(|| format!("failed to bind {addr}"))
+    });
+
+    let mut listeners = Vec::new();
+    let mut first_error = None;
+    for res in futures::future::join_all(binds).await {
+        match res {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if retries == 0 => return Err(e),
+            Err(e) => {
+                error!(error = format!("{e:#}"), "giving up on listen address");
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    match first_error {
+        Some(e) if listeners.is_empty() => Err(e),
+        _ => Ok(listeners),
+    }
+}
+
+async fn bind_with_retries(addr: SocketAddr, retries: u32) -> io::Result<TcpListener> {
+    let mut backoff = BIND_BACKOFF;
+    let mut retry = 0;
+    loop {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if retry < retries => {
+                retry += 1;
+                warn!(%addr, error = %e, retry, ?backoff, "failed to bind, retrying");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BIND_BACKOFF);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// First file descriptor passed by systemd socket activation, see `sd_listen_fds(3)`.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// The TCP sockets systemd passed in when socket activated, none when it wasn't.
+#[cfg(unix)]
+pub fn systemd_sockets() -> io::Result<Vec<TcpListener>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+
+    listen_fds(pid.as_deref(), fds.as_deref(), std::process::id())?
+        .map(adopt)
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn systemd_sockets() -> io::Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
+
+/// The file descriptors passed in, given `LISTEN_PID` and `LISTEN_FDS`. They're only meant for
+/// this process if `LISTEN_PID` is our pid, otherwise they were inherited from a parent.
+#[cfg(unix)]
+fn listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    own_pid: u32,
+) -> io::Result<std::ops::Range<i32>> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(0..0);
+    };
+    if pid.parse() != Ok(own_pid) {
+        return Ok(0..0);
+    }
+
+    let count: i32 = fds.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("LISTEN_FDS must be a number, got {fds:?}"),
+        )
+    })?;
+    Ok(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+}
+
+/// Takes ownership of a listening socket passed in as `fd`.
+#[cfg(unix)]
+fn adopt(fd: i32) -> io::Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: systemd passes each fd in the range exactly once, and nothing else claims them
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    // Fails for UNIX sockets, which have no inet address
+    listener.local_addr().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("fd {fd} passed by systemd isn't a TCP socket: {e}"),
+        )
+    })?;
+    // Not inherited by anything spawned, as `sd_listen_fds` also ensures
+    // SAFETY: fcntl is called on an fd owned by `listener`
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    listener.set_nonblocking(true)?;
+
+    TcpListener::from_std(listener)
+}
+
+/// Removes the socket file when dropped, so the next run can bind the same path.
+#[cfg(unix)]
+pub struct UnixSocket {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    /// Binds `path`, replacing a socket file left behind by a previous run that didn't clean up.
+    pub fn bind(path: &Path) -> io::Result<(UnixListener, UnixSocket)> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+
+        Ok((
+            listener,
+            UnixSocket {
+                path: path.to_path_buf(),
+            },
+        ))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), error = ?e, "failed to remove unix socket");
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,191 @@
+mod peer_addr {
+    use std::net::Ipv4Addr;
+
+    use super::super::*;
+
+    #[test]
+    fn serializes_as_string() {
+        let tcp = PeerAddr::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 50000)));
+        let named = PeerAddr::Unix(Some("/run/client.sock".into()));
+
+        assert_eq!(serde_json::to_string(&tcp).unwrap(), r#""127.0.0.1:50000""#);
+        assert_eq!(
+            serde_json::to_string(&named).unwrap(),
+            r#""unix:/run/client.sock""#
+        );
+        assert_eq!(PeerAddr::Unix(None).to_string(), "unix");
+    }
+}
+
+#[cfg(unix)]
+mod unix_socket {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("kube-fwd-socks-{name}-{}.sock", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn accepts_and_cleans_up() {
+        let path = socket_path("accept");
+        let (listener, socket) = UnixSocket::bind(&path).unwrap();
+        let mut accepted = Box::pin(unix(listener));
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut conn, peer_addr) = accepted.next().await.unwrap().unwrap();
+
+        client.write_all(b"hi").await.unwrap();
+        let mut buf = [0_u8; 2];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+        assert_eq!(peer_addr, PeerAddr::Unix(None));
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn replaces_stale_socket() {
+        let path = socket_path("stale");
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        assert!(path.exists());
+
+        let (_listener, _socket) = UnixSocket::bind(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_regular_files() {
+        let path = socket_path("regular");
+        std::fs::write(&path, b"not a socket").unwrap();
+
+        let res = UnixSocket::bind(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(res.is_err());
+    }
+}
+
+#[cfg(unix)]
+mod systemd_sockets {
+    use std::os::fd::IntoRawFd;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::super::*;
+
+    #[test]
+    fn not_activated() {
+        assert_eq!(listen_fds(None, None, 42).unwrap(), 0..0);
+        assert_eq!(listen_fds(None, Some("2"), 42).unwrap(), 0..0);
+    }
+
+    #[test]
+    fn activated() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42).unwrap(), 3..5);
+    }
+
+    #[test]
+    fn meant_for_another_process() {
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42).unwrap(), 0..0);
+    }
+
+    #[test]
+    fn invalid_count() {
+        assert!(listen_fds(Some("42"), Some("two"), 42).is_err());
+    }
+
+    #[tokio::test]
+    async fn adopts_a_passed_socket() {
+        let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = std_listener.local_addr().unwrap();
+
+        let listener = adopt(std_listener.into_raw_fd()).unwrap();
+        let mut accepted = Box::pin(tcp(listener));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"hi").await.unwrap();
+        let (_, peer_addr) = accepted.next().await.unwrap().unwrap();
+        assert_eq!(peer_addr, PeerAddr::Tcp(client.local_addr().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn rejects_unix_sockets() {
+        let path = std::env::temp_dir().join(format!(
+            "kube-fwd-socks-activated-{}.sock",
+            std::process::id()
+        ));
+        let socket = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(adopt(socket.into_raw_fd()).is_err());
+    }
+}
+
+mod bind_all {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+
+    /// A listener holding a port, so binding its address fails.
+    async fn taken() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    fn any_port() -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 0))
+    }
+
+    #[tokio::test]
+    async fn binds_every_address() {
+        let listeners = bind_all(&[any_port(), any_port()], 0).await.unwrap();
+
+        assert_eq!(listeners.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fails_straight_away_without_retries() {
+        let (_taken, addr) = taken().await;
+
+        let e = bind_all(&[any_port(), addr], 0).await.unwrap_err();
+
+        assert_eq!(e.to_string(), format!("failed to bind {addr}"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_until_the_address_is_free() {
+        let (taken, addr) = taken().await;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            drop(taken);
+        });
+
+        let listeners = bind_all(&[addr], 3).await.unwrap();
+
+        assert_eq!(listeners[0].local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skips_addresses_that_keep_failing() {
+        let (_taken, addr) = taken().await;
+
+        let listeners = bind_all(&[any_port(), addr], 2).await.unwrap();
+
+        assert_eq!(listeners.len(), 1);
+        assert_ne!(listeners[0].local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_when_nothing_could_be_bound() {
+        let (_taken, addr) = taken().await;
+
+        assert!(bind_all(&[addr], 2).await.is_err());
+    }
+}
@@ -1,16 +1,32 @@
+pub(crate) mod admin;
+pub(crate) mod build_info;
+pub(crate) mod config;
+#[cfg(test)]
+mod fake_api;
+pub(crate) mod listener;
+pub(crate) mod shutdown;
 pub(crate) mod socks;
+pub(crate) mod tls;
 
-use futures::{StreamExt as FuturesStreamExt, TryStreamExt};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use clap::Parser;
+use futures::stream::BoxStream;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
 use tokio::net::TcpListener;
-use tokio_stream::{wrappers::TcpListenerStream, StreamExt};
 
-use kube::Client;
+use k8s_openapi::api::core::v1::Secret;
+use kube::Api;
+
+use tracing::{error, field::Empty, info, info_span, trace, Instrument};
 
-use tracing::{error, info, info_span, trace, Instrument};
+use crate::config::{Cli, Config};
+use crate::socks::kube_client::KubeClient;
+use crate::socks::Frontend;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     let format = tracing_subscriber::fmt::format()
         .without_time()
         .with_level(false)
@@ -22,29 +38,155 @@ async fn main() -> anyhow::Result<()> {
         .with_max_level(tracing::Level::INFO)
         .init();
 
-    let client = Client::try_default().await?;
+    let cli = Cli::parse();
+    info!(
+        version = build_info::VERSION,
+        commit = build_info::GIT_HASH,
+        built = build_info::BUILD_TIMESTAMP,
+        "Starting kube-fwd-socks"
+    );
+
+    let config = Arc::new(Config::load(cli)?);
+    info!(
+        config = %serde_json::to_string(&config.redacted())?,
+        "Effective configuration"
+    );
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    // Otherwise tokio's default, which honours `TOKIO_WORKER_THREADS`
+    if config.worker_threads > 0 {
+        runtime.worker_threads(config.worker_threads);
+    }
+    runtime
+        .build()
+        .context("failed to start the runtime")?
+        .block_on(run(config))
+}
+
+async fn run(config: Arc<Config>) -> anyhow::Result<()> {
+    let kube_client = match config.static_hosts.is_empty() {
+        true => Some(socks::kube_client::connect().await?),
+        false => {
+            info!(hosts = ?config.static_hosts, "Serving static hosts, not using a cluster");
+            None
+        }
+    };
+    let mut clusters = BTreeMap::new();
+    for c in &config.contexts {
+        let client = socks::kube_client::connect_context(&c.context).await?;
+        info!(name = c.name, context = c.context, "Serving cluster");
+        clusters.insert(c.name.clone(), KubeClient::for_context(client, &c.context));
+    }
+    let ctx = socks::Context::new(kube_client, config.clone())?.with_clusters(clusters);
+
+    if let Some((namespace, name)) = config.auth_secret_ref() {
+        let kube_client = ctx
+            .kube_client
+            .as_ref()
+            .context("auth-secret requires a cluster")?;
+        let secrets: Api<Secret> = Api::namespaced(kube_client.get(), namespace);
+        let resource_version = ctx.credentials.load_secret(&secrets, name).await?;
+
+        if config.watch_auth_secret {
+            let credentials = ctx.credentials.clone();
+            let name = name.to_string();
+            tokio::spawn(async move {
+                credentials
+                    .watch_secret(secrets, name, resource_version)
+                    .await
+            });
+        }
+    }
+
+    if let Some(admin_addr) = config.admin_listen {
+        let admin_listener = TcpListener::bind(admin_addr).await?;
+        info!(address = ?admin_listener.local_addr()?, "Admin endpoint bound");
+
+        let registry = ctx.registry.clone();
+        let metrics = ctx.metrics.clone();
+        let kube_client = ctx.kube_client.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                admin::serve(admin_listener, registry, metrics, kube_client, config).await
+            {
+                error!(
+                    error = e.as_ref() as &dyn std::error::Error,
+                    "admin endpoint failed"
+                );
+            }
+        });
+    }
 
-    let socket_v4 = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 1080))).await?;
-    let socket_v6 = TcpListener::bind(SocketAddr::from((Ipv6Addr::LOCALHOST, 1080))).await?;
+    socks::prewarm(&ctx);
 
-    info!(address = ?[socket_v4.local_addr()?, socket_v6.local_addr()? ], "Bound, Ctrl+C to stop");
+    let mut sockets = listener::systemd_sockets().context("failed to adopt systemd sockets")?;
+    if sockets.is_empty() {
+        sockets = listener::bind_all(&config.listen, config.bind_retries).await?;
+    } else {
+        info!(
+            count = sockets.len(),
+            "Socket activated, using the sockets passed by systemd instead of listen"
+        );
+    }
 
-    TcpListenerStream::new(socket_v4)
-        .merge(TcpListenerStream::new(socket_v6))
-        .take_until(tokio::signal::ctrl_c())
-        .try_for_each(|client_conn| async {
+    let addresses = sockets
+        .iter()
+        .map(|s| s.local_addr())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut accepted: Vec<_> = sockets
+        .into_iter()
+        .map(|s| for_frontend(listener::tcp(s), Frontend::Socks))
+        .collect();
+
+    if let Some(addr) = config.http_connect_listen {
+        let http_listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to bind {addr}"))?;
+        info!(address = ?http_listener.local_addr()?, "HTTP CONNECT listener bound");
+        accepted.push(for_frontend(
+            listener::tcp(http_listener),
+            Frontend::HttpConnect,
+        ));
+    }
+
+    // Held until the accept loop ends so the socket file is removed on shutdown
+    #[cfg(unix)]
+    let _unix_socket = match config.listen_unix {
+        Some(ref path) => {
+            let (unix_listener, socket) = listener::UnixSocket::bind(path)
+                .with_context(|| format!("failed to bind {}", path.display()))?;
+            info!(path = %path.display(), "Bound unix socket");
+            accepted.push(for_frontend(listener::unix(unix_listener), Frontend::Socks));
+            Some(socket)
+        }
+        None => None,
+    };
+
+    let shutdown = shutdown::Signals::new().context("failed to register signal handlers")?;
+
+    info!(address = ?addresses, "Bound, Ctrl+C or SIGTERM to stop");
+
+    let ctx = &ctx;
+    stream::select_all(accepted)
+        .take_until(shutdown.recv())
+        .try_for_each(|(client_conn, peer_addr, frontend)| async move {
             let _connection_span = info_span!(
                 "connection",
-                peer_addr = client_conn.peer_addr()?.to_string()
+                peer_addr = peer_addr.to_string(),
+                correlation_id = Empty,
+                identity = Empty
             )
             .entered();
             trace!("accepted new connection");
 
-            let c = client.clone();
+            let c = ctx.clone();
 
             tokio::spawn(
                 async move {
-                    if let Err(e) = socks::handle(client_conn, c).await {
+                    if let Err(e) = socks::serve(frontend, client_conn, peer_addr, c).await {
                         error!(
                             error = e.as_ref() as &dyn std::error::Error,
                             "failed to forward connection"
@@ -58,5 +200,17 @@ async fn main() -> anyhow::Result<()> {
         })
         .await?;
 
+    ctx.drain().await;
+
     Ok(())
 }
+
+/// Tags connections accepted by a listener with the protocol they're handled as.
+fn for_frontend(
+    accepted: impl Stream<Item = listener::Accepted> + Send + 'static,
+    frontend: Frontend,
+) -> BoxStream<'static, std::io::Result<(listener::ClientStream, listener::PeerAddr, Frontend)>> {
+    accepted
+        .map(move |a| a.map(|(conn, peer_addr)| (conn, peer_addr, frontend)))
+        .boxed()
+}
@@ -0,0 +1,35 @@
+use tracing::info;
+
+/// The signals asking the process to stop: Ctrl+C (SIGINT) and, on unix, SIGTERM which is what
+/// Kubernetes sends when deleting a pod.
+pub struct Signals {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    /// Registers the handlers up front, so failing to is an error at startup rather than an
+    /// immediate shutdown.
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Signals {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?,
+        })
+    }
+
+    /// Resolves once any of the signals is received.
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    pub async fn recv(mut self) {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("Received Ctrl+C, shutting down"),
+            _ = self.terminate.recv() => info!("Received SIGTERM, shutting down"),
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+            info!("Received Ctrl+C, shutting down");
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Relays plain HTTP/1.1 requests to a pod through the API server's `pods/proxy` subresource,
+//! for clusters where port-forward streams are unreliable or blocked. Only HTTP can be relayed
+//! this way, each request is re-sent by the API server rather than tunnelled as raw TCP.
+
+use futures::TryStreamExt;
+use http::header::{self, HeaderName};
+use http::{HeaderMap, Method, Request, Response, StatusCode, Version};
+use http_body_util::BodyExt;
+use kube::client::Body;
+use kube::Client;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tracing::{debug, warn};
+
+use crate::socks::resolver::Target;
+
+/// Largest request head accepted from the client.
+const MAX_HEAD_LEN: usize = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+
+/// Largest request body relayed, it's buffered before being sent on.
+const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Errors {
+    #[error("Malformed request - {0}")]
+    Malformed(String),
+    #[error("Unsupported request - {0}")]
+    Unsupported(&'static str),
+    #[error("IO Error {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Serves HTTP requests read from `stream` until the client closes it or asks to.
+pub async fn relay(client: Client, target: Target, mut stream: DuplexStream) {
+    let mut buf = Vec::new();
+
+    loop {
+        let req = match read_request(&mut stream, &mut buf, &target).await {
+            Ok(Some(req)) => req,
+            Ok(None) => return,
+            Err(e) => {
+                warn!(error = ?e, "rejecting request to api proxy");
+                let status = match e {
+                    Errors::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+                    _ => StatusCode::BAD_REQUEST,
+                };
+                let _ = write_error(&mut stream, status, &e.to_string()).await;
+                return;
+            }
+        };
+
+        let ClientRequest {
+            request,
+            close,
+            version,
+        } = req;
+        let head_only = request.method() == Method::HEAD;
+        debug!(method = %request.method(), uri = %request.uri(), "relaying request via api proxy");
+
+        let res = match client.send(request.map(Body::from)).await {
+            Ok(res) => write_response(&mut stream, res, head_only, version).await,
+            Err(e) => {
+                warn!(error = ?e, "api proxy request failed");
+                write_error(&mut stream, StatusCode::BAD_GATEWAY, &e.to_string())
+                    .await
+                    .map(|()| false)
+            }
+        };
+
+        match res {
+            Ok(ended_by_close) if !close && !ended_by_close => {}
+            Ok(_) => return,
+            Err(e) => {
+                debug!(error = ?e, "failed to write api proxy response");
+                return;
+            }
+        }
+    }
+}
+
+/// A request read from the client.
+#[derive(Debug)]
+struct ClientRequest {
+    /// Rewritten to go to the target's proxy subresource
+    request: Request<Vec<u8>>,
+    /// Whether the connection should be closed after it
+    close: bool,
+    /// The client's HTTP version, which its response is sent as
+    version: Version,
+}
+
+/// Reads the next request, `None` when the client closed between requests.
+async fn read_request(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+    target: &Target,
+) -> Result<Option<ClientRequest>, Errors> {
+    let head_len = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(Errors::Malformed("request head too large".into()));
+        }
+
+        let mut chunk = [0_u8; 4096];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return match buf.is_empty() {
+                true => Ok(None),
+                false => Err(Errors::Malformed("connection closed mid request".into())),
+            };
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    };
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    parsed
+        .parse(&buf[..head_len])
+        .map_err(|e| Errors::Malformed(e.to_string()))?;
+
+    let method = Method::from_bytes(parsed.method.unwrap_or_default().as_bytes())
+        .map_err(|e| Errors::Malformed(e.to_string()))?;
+    let path = origin_form(parsed.path.unwrap_or_default())?;
+    let http_10 = parsed.version == Some(0);
+
+    let mut builder = Request::builder()
+        .method(method)
+        .uri(proxy_uri(target, path));
+    let mut content_length = 0;
+    let mut close = http_10;
+    for h in parsed.headers.iter() {
+        let name = HeaderName::from_bytes(h.name.as_bytes())
+            .map_err(|e| Errors::Malformed(e.to_string()))?;
+
+        if name == header::TRANSFER_ENCODING {
+            return Err(Errors::Unsupported("chunked request bodies"));
+        }
+        if name == header::CONTENT_LENGTH {
+            content_length = std::str::from_utf8(h.value)
+                .ok()
+                .and_then(|v| v.trim().parse::<usize>().ok())
+                .ok_or_else(|| Errors::Malformed("invalid content-length".into()))?;
+        }
+        if name == header::CONNECTION {
+            let value = String::from_utf8_lossy(h.value).to_ascii_lowercase();
+            close = value.contains("close") || (http_10 && !value.contains("keep-alive"));
+        }
+        if !is_hop_by_hop(&name) && !is_credential(&name) && name != header::HOST {
+            builder = builder.header(name, h.value);
+        }
+    }
+
+    if content_length > MAX_BODY_LEN {
+        return Err(Errors::Unsupported("request bodies over 16MiB"));
+    }
+
+    buf.drain(..head_len);
+    while buf.len() < content_length {
+        let mut chunk = [0_u8; 4096];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(Errors::Malformed("connection closed mid body".into()));
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+    let body: Vec<u8> = buf.drain(..content_length).collect();
+
+    let req = builder
+        .body(body)
+        .map_err(|e| Errors::Malformed(e.to_string()))?;
+
+    Ok(Some(ClientRequest {
+        request: req,
+        close,
+        version: match http_10 {
+            true => Version::HTTP_10,
+            false => Version::HTTP_11,
+        },
+    }))
+}
+
+/// The path and query of a request target, which proxy clients may send in absolute form.
+fn origin_form(target: &str) -> Result<&str, Errors> {
+    if target.starts_with('/') {
+        return Ok(target);
+    }
+
+    let rest = target
+        .strip_prefix("http://")
+        .ok_or(Errors::Unsupported("request targets other than http paths"))?;
+
+    Ok(rest.find('/').map(|i| &rest[i..]).unwrap_or("/"))
+}
+
+fn proxy_uri(target: &Target, path: &str) -> String {
+    format!(
+        "/api/v1/namespaces/{}/pods/{}:{}/proxy{path}",
+        target.namespace, target.pod, target.port
+    )
+}
+
+/// Headers the API server acts on itself. Requests are sent with the proxy's credentials, so a
+/// client's `Authorization` would be taken as the proxy's own, and impersonation headers would
+/// let any client act as whoever the proxy may impersonate.
+fn is_credential(name: &HeaderName) -> bool {
+    name == header::AUTHORIZATION || name.as_str().starts_with("impersonate-")
+}
+
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    [
+        header::CONNECTION,
+        header::PROXY_AUTHENTICATE,
+        header::PROXY_AUTHORIZATION,
+        header::TE,
+        header::TRAILER,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+    ]
+    .contains(name)
+        || name.as_str() == "keep-alive"
+}
+
+/// Writes the response, streaming the body as it arrives: chunked for HTTP/1.1 clients, and
+/// as is for HTTP/1.0 ones, which can't decode chunks. Returns whether the connection has to be
+/// closed to end the body, for HTTP/1.0 responses the API server gave no length for.
+async fn write_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    res: Response<Body>,
+    head_only: bool,
+    version: Version,
+) -> Result<bool, Errors> {
+    let (parts, body) = res.into_parts();
+    let has_body = !head_only
+        && !parts.status.is_informational()
+        && parts.status != StatusCode::NO_CONTENT
+        && parts.status != StatusCode::NOT_MODIFIED;
+    let chunked = has_body && version != Version::HTTP_10;
+
+    stream
+        .write_all(&response_head(
+            version,
+            parts.status,
+            &parts.headers,
+            chunked,
+        ))
+        .await?;
+
+    if has_body {
+        let mut data = body.into_data_stream();
+        while let Some(chunk) = data.try_next().await.map_err(std::io::Error::other)? {
+            if chunk.is_empty() {
+                continue;
+            }
+            if !chunked {
+                stream.write_all(&chunk).await?;
+                continue;
+            }
+            stream
+                .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                .await?;
+            stream.write_all(&chunk).await?;
+            stream.write_all(b"\r\n").await?;
+        }
+        if chunked {
+            stream.write_all(b"0\r\n\r\n").await?;
+        }
+    }
+
+    stream.flush().await?;
+    Ok(has_body && !chunked && !parts.headers.contains_key(header::CONTENT_LENGTH))
+}
+
+fn response_head(
+    version: Version,
+    status: StatusCode,
+    headers: &HeaderMap,
+    chunked: bool,
+) -> Vec<u8> {
+    let mut head = format!(
+        "{version:?} {} {}\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    )
+    .into_bytes();
+
+    for (name, value) in headers {
+        if is_hop_by_hop(name) || (chunked && name == header::CONTENT_LENGTH) {
+            continue;
+        }
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+
+    if chunked {
+        head.extend_from_slice(b"transfer-encoding: chunked\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+
+    head
+}
+
+async fn write_error(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: StatusCode,
+    message: &str,
+) -> Result<(), Errors> {
+    let body = format!("{message}\r\n");
+    let res = format!(
+        "HTTP/1.1 {} {}\r\ncontent-type: text/plain\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default(),
+        body.len(),
+    );
+
+    stream.write_all(res.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
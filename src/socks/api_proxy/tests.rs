@@ -0,0 +1,251 @@
+use super::*;
+
+fn target() -> Target {
+    Target {
+        namespace: "apps".into(),
+        pod: "web-0".into(),
+        port: 8080,
+        pod_ip: None,
+        app_protocol: None,
+        cluster: None,
+        connect_timeout: None,
+        named_target_port: None,
+    }
+}
+
+mod read_request {
+    use tokio::io::AsyncWriteExt;
+
+    use super::super::*;
+    use super::target;
+
+    async fn read(raw: &[u8]) -> (Result<Option<ClientRequest>, Errors>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(raw.len().max(1));
+        client.write_all(raw).await.unwrap();
+        drop(client);
+
+        let mut buf = Vec::new();
+        let res = read_request(&mut server, &mut buf, &target()).await;
+        (res, buf)
+    }
+
+    #[tokio::test]
+    async fn rewrites_to_proxy_subresource() {
+        let (res, _) =
+            read(b"GET /healthz?verbose HTTP/1.1\r\nHost: web\r\nAccept: */*\r\n\r\n").await;
+        let ClientRequest {
+            request: req,
+            close,
+            version,
+        } = res.unwrap().unwrap();
+
+        assert_eq!(req.method(), Method::GET);
+        assert_eq!(
+            req.uri(),
+            "/api/v1/namespaces/apps/pods/web-0:8080/proxy/healthz?verbose"
+        );
+        assert_eq!(req.headers()[header::ACCEPT], "*/*");
+        assert!(!req.headers().contains_key(header::HOST));
+        assert!(!close);
+        assert_eq!(version, Version::HTTP_11);
+    }
+
+    #[tokio::test]
+    async fn drops_credentials_and_impersonation() {
+        let (res, _) = read(
+            b"GET / HTTP/1.1\r\nAuthorization: Bearer client\r\nImpersonate-User: admin\r\n\
+              Impersonate-Group: system:masters\r\nImpersonate-Extra-Scopes: all\r\n\
+              Impersonate-Uid: 1\r\nAccept: */*\r\n\r\n",
+        )
+        .await;
+        let req = res.unwrap().unwrap().request;
+
+        let names: Vec<&str> = req.headers().keys().map(|n| n.as_str()).collect();
+        assert_eq!(names, ["accept"]);
+    }
+
+    #[tokio::test]
+    async fn reads_body_and_keeps_pipelined_bytes() {
+        let (res, buf) =
+            read(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhelloGET")
+                .await;
+        let ClientRequest {
+            request: req,
+            close,
+            ..
+        } = res.unwrap().unwrap();
+
+        assert_eq!(req.body(), b"hello");
+        assert!(!req.headers().contains_key(header::CONNECTION));
+        assert!(close);
+        assert_eq!(buf, b"GET");
+    }
+
+    #[tokio::test]
+    async fn http_10_closes_by_default() {
+        let (res, _) = read(b"GET / HTTP/1.0\r\n\r\n").await;
+        let req = res.unwrap().unwrap();
+
+        assert!(req.close);
+        assert_eq!(req.version, Version::HTTP_10);
+    }
+
+    #[tokio::test]
+    async fn none_when_closed_between_requests() {
+        let (res, _) = read(b"").await;
+
+        assert!(res.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_chunked_bodies() {
+        let (res, _) = read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").await;
+
+        assert!(matches!(res, Err(Errors::Unsupported(_))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_requests() {
+        let (res, _) = read(b"GET / HTTP/1.1\r\nHost: web\r\n").await;
+
+        assert!(matches!(res, Err(Errors::Malformed(_))), "{res:?}");
+    }
+}
+
+mod origin_form {
+    use super::super::*;
+
+    #[test]
+    fn passes_paths_through() {
+        assert_eq!(origin_form("/a/b?c").unwrap(), "/a/b?c");
+    }
+
+    #[test]
+    fn strips_absolute_form() {
+        assert_eq!(origin_form("http://web:8080/a?b").unwrap(), "/a?b");
+        assert_eq!(origin_form("http://web:8080").unwrap(), "/");
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        assert!(origin_form("https://web/").is_err());
+        assert!(origin_form("*").is_err());
+    }
+}
+
+mod response_head {
+    use super::super::*;
+
+    #[test]
+    fn drops_hop_by_hop_and_length_when_chunked() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "text/html".parse().unwrap());
+        headers.insert(header::CONTENT_LENGTH, 12.into());
+        headers.insert(header::CONNECTION, "keep-alive".parse().unwrap());
+
+        let head = response_head(Version::HTTP_11, StatusCode::OK, &headers, true);
+
+        assert_eq!(
+            String::from_utf8(head).unwrap(),
+            "HTTP/1.1 200 OK\r\ncontent-type: text/html\r\ntransfer-encoding: chunked\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn keeps_length_without_body() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, 12.into());
+
+        let head = response_head(Version::HTTP_11, StatusCode::NOT_MODIFIED, &headers, false);
+
+        assert_eq!(
+            String::from_utf8(head).unwrap(),
+            "HTTP/1.1 304 Not Modified\r\ncontent-length: 12\r\n\r\n"
+        );
+    }
+}
+
+mod write_response {
+    use tokio::io::AsyncReadExt;
+
+    use super::super::*;
+
+    #[tokio::test]
+    async fn streams_body_chunked() {
+        let res = Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(b"hello".to_vec()))
+            .unwrap();
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let close = write_response(&mut server, res, false, Version::HTTP_11)
+            .await
+            .unwrap();
+        drop(server);
+
+        let mut out = String::new();
+        client.read_to_string(&mut out).await.unwrap();
+        assert_eq!(
+            out,
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n"
+        );
+        assert!(!close);
+    }
+
+    #[tokio::test]
+    async fn http_10_keeps_the_length() {
+        let res = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, 5)
+            .body(Body::from(b"hello".to_vec()))
+            .unwrap();
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let close = write_response(&mut server, res, false, Version::HTTP_10)
+            .await
+            .unwrap();
+        drop(server);
+
+        let mut out = String::new();
+        client.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out, "HTTP/1.0 200 OK\r\ncontent-length: 5\r\n\r\nhello");
+        assert!(!close);
+    }
+
+    #[tokio::test]
+    async fn http_10_without_a_length_is_ended_by_closing() {
+        let res = Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(b"hello".to_vec()))
+            .unwrap();
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let close = write_response(&mut server, res, false, Version::HTTP_10)
+            .await
+            .unwrap();
+        drop(server);
+
+        let mut out = String::new();
+        client.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out, "HTTP/1.0 200 OK\r\n\r\nhello");
+        assert!(close);
+    }
+
+    #[tokio::test]
+    async fn omits_body_for_head() {
+        let res = Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(b"hello".to_vec()))
+            .unwrap();
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        write_response(&mut server, res, true, Version::HTTP_11)
+            .await
+            .unwrap();
+        drop(server);
+
+        let mut out = String::new();
+        client.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out, "HTTP/1.1 200 OK\r\n\r\n");
+    }
+}
@@ -0,0 +1,145 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::listener::PeerAddr;
+use crate::socks::resolver::Target;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Errors {
+    #[error("Failed to open audit log {0}: {1}")]
+    Open(PathBuf, #[source] std::io::Error),
+}
+
+/// Append only JSON lines file with one record per connection attempt, kept apart from the
+/// diagnostic tracing output.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self, Errors> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Errors::Open(path.to_path_buf(), e))?;
+
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Writes the record as a single unbuffered write, so it reaches the OS immediately and
+    /// concurrent records never interleave.
+    pub fn record(&self, attempt: &Attempt) {
+        let mut line = match serde_json::to_vec(attempt) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(error = ?e, "failed to serialize audit record");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            warn!(error = ?e, "failed to write audit record");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    /// The forward was established and the client told so
+    Forwarded,
+    /// Refused by the proxy, eg. failed authentication or an unsupported command
+    Rejected,
+    /// The destination couldn't be resolved or forwarded to
+    Failed,
+    /// The client went away before the forward was established
+    Disconnected,
+    /// The client broke the protocol or the connection failed
+    Error,
+}
+
+/// What's known about a connection attempt, filled in as it's handled and written to the audit
+/// log as soon as its outcome is known.
+#[derive(Serialize)]
+pub struct Attempt {
+    #[serde(skip)]
+    log: Option<Arc<AuditLog>>,
+    pub timestamp: String,
+    pub connection_id: u64,
+    pub peer_addr: PeerAddr,
+    pub protocol: Option<&'static str>,
+    pub username: Option<String>,
+    pub correlation_id: Option<String>,
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub target: Option<Target>,
+    pub outcome: Option<Outcome>,
+    pub reason: Option<String>,
+    /// Identifies what went wrong for failures, unlike `reason` it never changes wording
+    pub error_code: Option<&'static str>,
+}
+
+impl Attempt {
+    pub fn new(log: Option<Arc<AuditLog>>, connection_id: u64, peer_addr: PeerAddr) -> Self {
+        Attempt {
+            log,
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            connection_id,
+            peer_addr,
+            protocol: None,
+            username: None,
+            correlation_id: None,
+            address: None,
+            port: None,
+            target: None,
+            outcome: None,
+            reason: None,
+            error_code: None,
+        }
+    }
+
+    pub fn requested(&mut self, address: impl Into<String>, port: u16) {
+        self.address = Some(address.into());
+        self.port = Some(port);
+    }
+
+    /// Records the outcome and writes the attempt out, unless an earlier, more specific, outcome
+    /// was already recorded.
+    pub fn outcome(&mut self, outcome: Outcome, reason: impl ToString) {
+        if self.outcome.is_some() {
+            return;
+        }
+
+        self.outcome = Some(outcome);
+        self.reason = Some(reason.to_string()).filter(|r| !r.is_empty());
+
+        if let Some(ref log) = self.log {
+            log.record(self);
+        }
+    }
+
+    /// Like [`Attempt::outcome`] for a failure with error `code`, returning whether it was
+    /// recorded rather than an earlier outcome kept.
+    pub fn failed(&mut self, outcome: Outcome, code: &'static str, reason: impl ToString) -> bool {
+        if self.outcome.is_some() {
+            return false;
+        }
+
+        self.error_code = Some(code);
+        self.outcome(outcome, reason);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests;
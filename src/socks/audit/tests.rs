@@ -0,0 +1,89 @@
+mod audit_log {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::super::*;
+
+    fn attempt(log: Option<Arc<AuditLog>>) -> Attempt {
+        Attempt::new(
+            log,
+            7,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 50000)).into(),
+        )
+    }
+
+    #[test]
+    fn first_outcome_wins() {
+        let mut attempt = attempt(None);
+
+        attempt.outcome(Outcome::Rejected, "bind is not supported");
+        attempt.outcome(Outcome::Error, "connection reset");
+
+        assert_eq!(attempt.outcome, Some(Outcome::Rejected));
+        assert_eq!(attempt.reason.as_deref(), Some("bind is not supported"));
+    }
+
+    #[test]
+    fn failures_record_their_code() {
+        let mut attempt = attempt(None);
+
+        assert!(attempt.failed(Outcome::Failed, "pod_not_found", "Pod Not Found apps/web-0"));
+        assert!(!attempt.failed(Outcome::Error, "client_connection", "connection reset"));
+
+        assert_eq!(attempt.outcome, Some(Outcome::Failed));
+        assert_eq!(attempt.error_code, Some("pod_not_found"));
+    }
+
+    #[test]
+    fn code_is_not_recorded_over_an_earlier_outcome() {
+        let mut attempt = attempt(None);
+
+        attempt.outcome(Outcome::Forwarded, "");
+        assert!(!attempt.failed(Outcome::Error, "pod_connection", "connection reset"));
+
+        assert_eq!(attempt.error_code, None);
+    }
+
+    #[test]
+    fn appends_json_lines() {
+        let path =
+            std::env::temp_dir().join(format!("kube-fwd-socks-audit-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = Arc::new(AuditLog::open(&path).unwrap());
+
+        let mut forwarded = attempt(Some(log.clone()));
+        forwarded.protocol = Some("socks5");
+        forwarded.username = Some("alice".into());
+        forwarded.requested("web.default.svc", 80);
+        forwarded.target = Some(Target {
+            namespace: "default".into(),
+            pod: "web-0".into(),
+            port: 8080,
+            pod_ip: None,
+            app_protocol: None,
+            cluster: None,
+            connect_timeout: None,
+            named_target_port: None,
+        });
+        forwarded.outcome(Outcome::Forwarded, "");
+        forwarded.outcome(Outcome::Error, "connection reset");
+        attempt(Some(log)).outcome(Outcome::Rejected, "authentication failed");
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["peer_addr"], "127.0.0.1:50000");
+        assert_eq!(lines[0]["username"], "alice");
+        assert_eq!(lines[0]["address"], "web.default.svc");
+        assert_eq!(lines[0]["target"]["pod"], "web-0");
+        assert_eq!(lines[0]["outcome"], "forwarded");
+        assert_eq!(lines[0]["reason"], serde_json::Value::Null);
+        assert_eq!(lines[0]["error_code"], serde_json::Value::Null);
+        assert_eq!(lines[1]["outcome"], "rejected");
+        assert_eq!(lines[1]["reason"], "authentication failed");
+    }
+}
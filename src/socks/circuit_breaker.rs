@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::socks::metrics::Metrics;
+
+/// The address as requested, which names the namespace and target, and the port.
+pub type Key = (String, u16);
+
+/// Circuit breakers for requests that keep failing to reach a pod, keyed by what was requested.
+///
+/// After `circuit-breaker-failures` failures in a row within `circuit-breaker-window`, requests
+/// for the same address and port fail straight away for `circuit-breaker-cooldown`, without
+/// asking the API server anything. Then one is let through as a trial, which closes the breaker
+/// if it succeeds and opens it for another cooldown if it fails.
+pub struct CircuitBreakers {
+    /// 0 when there are no breakers
+    failures: u32,
+    window: Duration,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<Key, Circuit>>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Circuit {
+    /// Failed `failures` times in a row, the first at `since`, but not enough to trip
+    Closed { failures: u32, since: Instant },
+    /// Failing requests straight away until `until`
+    Open { until: Instant },
+    /// A trial request was let through at `since`
+    HalfOpen { since: Instant },
+}
+
+impl CircuitBreakers {
+    pub fn new(config: &Config, metrics: Arc<Metrics>) -> Self {
+        CircuitBreakers {
+            failures: config.circuit_breaker_failures,
+            window: Duration::from_secs(config.circuit_breaker_window),
+            cooldown: Duration::from_secs(config.circuit_breaker_cooldown),
+            circuits: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    /// Whether a request for `key` may go ahead, or how long until its breaker lets one through.
+    pub fn check(&self, key: &Key) -> Result<(), Duration> {
+        let res = self.check_at(key, Instant::now());
+        if res.is_err() {
+            self.metrics.record_circuit_rejection();
+        }
+        res
+    }
+
+    fn check_at(&self, key: &Key, now: Instant) -> Result<(), Duration> {
+        if self.failures == 0 {
+            return Ok(());
+        }
+
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(key) else {
+            return Ok(());
+        };
+
+        match *circuit {
+            Circuit::Closed { .. } => Ok(()),
+            Circuit::Open { until } if now < until => Err(until - now),
+            // A trial that never reported back was abandoned, eg. by the client going away
+            Circuit::HalfOpen { since } if now < since + self.cooldown => {
+                Err(since + self.cooldown - now)
+            }
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => {
+                *circuit = Circuit::HalfOpen { since: now };
+                Ok(())
+            }
+        }
+    }
+
+    /// Records how a request for `key` that [`check`](Self::check) let through ended.
+    pub fn record(&self, key: Key, failed: bool) {
+        self.record_at(key, failed, Instant::now());
+    }
+
+    fn record_at(&self, key: Key, failed: bool, now: Instant) {
+        if self.failures == 0 {
+            return;
+        }
+
+        let mut circuits = self.circuits.lock().unwrap();
+
+        if !failed {
+            if let Some(Circuit::Open { .. } | Circuit::HalfOpen { .. }) = circuits.remove(&key) {
+                self.metrics.record_circuit_closed();
+            }
+            return;
+        }
+
+        if !circuits.contains_key(&key) {
+            self.forget_stale(&mut circuits, now);
+        }
+
+        let circuit = circuits.entry(key).or_insert(Circuit::Closed {
+            failures: 0,
+            since: now,
+        });
+        let (failures, since) = match *circuit {
+            Circuit::Closed { failures, since } if now < since + self.window => {
+                (failures + 1, since)
+            }
+            Circuit::Closed { .. } => (1, now),
+            Circuit::HalfOpen { .. } => {
+                *circuit = Circuit::Open {
+                    until: now + self.cooldown,
+                };
+                self.metrics.record_circuit_trip(true);
+                return;
+            }
+            // Let through before it opened, so it says nothing new
+            Circuit::Open { .. } => return,
+        };
+
+        *circuit = match failures >= self.failures {
+            true => {
+                self.metrics.record_circuit_trip(false);
+                Circuit::Open {
+                    until: now + self.cooldown,
+                }
+            }
+            false => Circuit::Closed { failures, since },
+        };
+    }
+
+    /// Drops circuits nothing has been requested through for a whole window, so addresses
+    /// that are never asked for again don't pile up.
+    fn forget_stale(&self, circuits: &mut HashMap<Key, Circuit>, now: Instant) {
+        circuits.retain(|_, circuit| {
+            let (last, open) = match *circuit {
+                Circuit::Closed { since, .. } => (since, false),
+                Circuit::Open { until } => (until, true),
+                Circuit::HalfOpen { since } => (since + self.cooldown, true),
+            };
+
+            let keep = now < last + self.window;
+            if !keep && open {
+                self.metrics.record_circuit_closed();
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,202 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::socks::metrics::Metrics;
+
+use super::{CircuitBreakers, Key};
+
+fn breakers(failures: u32) -> (CircuitBreakers, Arc<Metrics>) {
+    let config = Config {
+        circuit_breaker_failures: failures,
+        circuit_breaker_window: 60,
+        circuit_breaker_cooldown: 30,
+        ..Default::default()
+    };
+    let metrics = Arc::new(Metrics::default());
+    (CircuitBreakers::new(&config, metrics.clone()), metrics)
+}
+
+fn key() -> Key {
+    ("web.apps.svc.cluster.local".into(), 80)
+}
+
+fn secs(n: u64) -> Duration {
+    Duration::from_secs(n)
+}
+
+mod tripping {
+    use super::super::*;
+    use super::{breakers, key, secs};
+
+    #[test]
+    fn opens_after_enough_failures_in_a_row() {
+        let (breakers, _) = breakers(3);
+        let now = Instant::now();
+
+        breakers.record_at(key(), true, now);
+        breakers.record_at(key(), true, now + secs(1));
+        assert_eq!(breakers.check_at(&key(), now + secs(2)), Ok(()));
+
+        breakers.record_at(key(), true, now + secs(2));
+        assert_eq!(breakers.check_at(&key(), now + secs(2)), Err(secs(30)));
+        assert_eq!(breakers.check_at(&key(), now + secs(12)), Err(secs(20)));
+    }
+
+    #[test]
+    fn a_success_resets_the_count() {
+        let (breakers, _) = breakers(2);
+        let now = Instant::now();
+
+        breakers.record_at(key(), true, now);
+        breakers.record_at(key(), false, now + secs(1));
+        breakers.record_at(key(), true, now + secs(2));
+
+        assert_eq!(breakers.check_at(&key(), now + secs(3)), Ok(()));
+    }
+
+    #[test]
+    fn failures_outside_the_window_start_again() {
+        let (breakers, _) = breakers(2);
+        let now = Instant::now();
+
+        breakers.record_at(key(), true, now);
+        breakers.record_at(key(), true, now + secs(61));
+
+        assert_eq!(breakers.check_at(&key(), now + secs(61)), Ok(()));
+    }
+
+    #[test]
+    fn other_addresses_are_unaffected() {
+        let (breakers, _) = breakers(1);
+        let now = Instant::now();
+
+        breakers.record_at(key(), true, now);
+
+        let other = ("web.apps.svc.cluster.local".to_string(), 443);
+        assert_eq!(breakers.check_at(&other, now), Ok(()));
+    }
+
+    #[test]
+    fn disabled_without_a_failure_count() {
+        let (breakers, _) = breakers(0);
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            breakers.record_at(key(), true, now);
+        }
+
+        assert_eq!(breakers.check_at(&key(), now), Ok(()));
+    }
+}
+
+mod half_open {
+    use super::super::*;
+    use super::{breakers, key, secs};
+
+    fn tripped() -> (CircuitBreakers, Instant) {
+        let (breakers, _) = breakers(1);
+        let now = Instant::now();
+        breakers.record_at(key(), true, now);
+        (breakers, now)
+    }
+
+    #[test]
+    fn lets_one_trial_through_after_the_cooldown() {
+        let (breakers, now) = tripped();
+
+        assert_eq!(breakers.check_at(&key(), now + secs(30)), Ok(()));
+        assert_eq!(breakers.check_at(&key(), now + secs(31)), Err(secs(29)));
+    }
+
+    #[test]
+    fn a_successful_trial_closes_it() {
+        let (breakers, now) = tripped();
+
+        breakers.check_at(&key(), now + secs(30)).unwrap();
+        breakers.record_at(key(), false, now + secs(31));
+
+        assert_eq!(breakers.check_at(&key(), now + secs(31)), Ok(()));
+        assert_eq!(breakers.check_at(&key(), now + secs(32)), Ok(()));
+    }
+
+    #[test]
+    fn a_failed_trial_opens_it_again() {
+        let (breakers, now) = tripped();
+
+        breakers.check_at(&key(), now + secs(30)).unwrap();
+        breakers.record_at(key(), true, now + secs(31));
+
+        assert_eq!(breakers.check_at(&key(), now + secs(31)), Err(secs(30)));
+    }
+
+    #[test]
+    fn an_abandoned_trial_is_retried_after_another_cooldown() {
+        let (breakers, now) = tripped();
+
+        breakers.check_at(&key(), now + secs(30)).unwrap();
+
+        assert!(breakers.check_at(&key(), now + secs(59)).is_err());
+        assert_eq!(breakers.check_at(&key(), now + secs(60)), Ok(()));
+    }
+}
+
+mod metrics {
+    use super::super::*;
+    use super::{breakers, key, secs};
+
+    fn gauge(metrics: &Metrics) -> String {
+        metrics
+            .render()
+            .lines()
+            .find(|l| l.starts_with("socks_circuit_breakers_open "))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn counts_open_breakers() {
+        let (breakers, metrics) = breakers(1);
+        let now = Instant::now();
+
+        breakers.record_at(key(), true, now);
+        assert_eq!(gauge(&metrics), "socks_circuit_breakers_open 1");
+
+        breakers.check_at(&key(), now + secs(30)).unwrap();
+        breakers.record_at(key(), true, now + secs(30));
+        assert_eq!(gauge(&metrics), "socks_circuit_breakers_open 1");
+
+        breakers.check_at(&key(), now + secs(60)).unwrap();
+        breakers.record_at(key(), false, now + secs(60));
+        assert_eq!(gauge(&metrics), "socks_circuit_breakers_open 0");
+
+        let render = metrics.render();
+        assert!(render.contains("socks_circuit_breaker_trips_total 2\n"));
+    }
+
+    #[test]
+    fn counts_rejections() {
+        let (breakers, metrics) = breakers(1);
+
+        breakers.record(key(), true);
+        assert!(breakers.check(&key()).is_err());
+        assert!(breakers.check(&key()).is_err());
+
+        assert!(metrics
+            .render()
+            .contains("socks_circuit_breaker_rejections_total 2\n"));
+    }
+
+    #[test]
+    fn forgetting_an_open_breaker_closes_it() {
+        let (breakers, metrics) = breakers(1);
+        let now = Instant::now();
+
+        breakers.record_at(key(), true, now);
+        let other = ("db.apps.svc.cluster.local".to_string(), 5432);
+        breakers.record_at(other, true, now + secs(120));
+
+        assert_eq!(gauge(&metrics), "socks_circuit_breakers_open 1");
+        assert_eq!(breakers.check_at(&key(), now + secs(120)), Ok(()));
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::core::v1::Secret;
+use kube::api::{WatchEvent, WatchParams};
+use kube::Api;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+/// Prefix marking a password as an already hashed hex SHA-256 digest.
+pub const SHA256_PREFIX: &str = "sha256:";
+
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+type PasswordHash = [u8; 32];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Errors {
+    #[error("Password for user {0} is not valid hex after {SHA256_PREFIX}")]
+    InvalidHash(String),
+    #[error("Secret {0} Not Found")]
+    SecretNotFound(String),
+    #[error("Lookup Failed {0:?}")]
+    LookupFailed(#[source] kube::Error),
+}
+
+/// Usernames to password hashes for SOCKS5 user-pass auth, plaintext passwords are never kept.
+#[derive(Default)]
+pub struct Credentials {
+    /// From the config file
+    configured: HashMap<String, PasswordHash>,
+    /// From `auth-secret`, replaced whenever the secret is reloaded
+    secret: RwLock<HashMap<String, PasswordHash>>,
+}
+
+impl Credentials {
+    pub fn new(users: &BTreeMap<String, String>) -> Result<Self, Errors> {
+        Ok(Credentials {
+            configured: hash_all(users.iter().map(|(u, p)| (u.as_str(), p.as_bytes())))?,
+            secret: RwLock::default(),
+        })
+    }
+
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        let given = hash(password.as_bytes());
+
+        let expected = self
+            .secret
+            .read()
+            .unwrap()
+            .get(username)
+            .or_else(|| self.configured.get(username))
+            .copied();
+
+        expected.is_some_and(|e| constant_time_eq(&e, &given))
+    }
+
+    /// Replaces the secret sourced users, each data key is a username and value its password.
+    pub fn set_secret(&self, secret: &Secret) -> Result<usize, Errors> {
+        let users = hash_all(
+            secret
+                .data
+                .iter()
+                .flatten()
+                .map(|(u, p)| (u.as_str(), p.0.as_slice())),
+        )?;
+        let count = users.len();
+
+        *self.secret.write().unwrap() = users;
+
+        Ok(count)
+    }
+
+    /// Loads the named secret, returning its resource version to watch from.
+    pub async fn load_secret(&self, api: &Api<Secret>, name: &str) -> Result<String, Errors> {
+        let secret = api
+            .get_opt(name)
+            .await
+            .map_err(Errors::LookupFailed)?
+            .ok_or_else(|| Errors::SecretNotFound(name.into()))?;
+
+        let count = self.set_secret(&secret)?;
+        info!(secret = name, users = count, "loaded auth secret");
+
+        Ok(secret.metadata.resource_version.unwrap_or_default())
+    }
+
+    /// Keeps the secret sourced users up to date with the named secret, never returns.
+    pub async fn watch_secret(&self, api: Api<Secret>, name: String, mut resource_version: String) {
+        let watch_params = WatchParams::default().fields(&format!("metadata.name={name}"));
+
+        loop {
+            let res: Result<(), Errors> = async {
+                let mut events = api
+                    .watch(&watch_params, &resource_version)
+                    .await
+                    .map_err(Errors::LookupFailed)?
+                    .boxed();
+
+                while let Some(event) = events.try_next().await.map_err(Errors::LookupFailed)? {
+                    match event {
+                        WatchEvent::Added(secret) | WatchEvent::Modified(secret) => {
+                            resource_version =
+                                secret.metadata.resource_version.clone().unwrap_or_default();
+                            let count = self.set_secret(&secret)?;
+                            info!(secret = name, users = count, "reloaded auth secret");
+                        }
+                        WatchEvent::Deleted(_) => {
+                            warn!(
+                                secret = name,
+                                "auth secret deleted, keeping last known users"
+                            );
+                        }
+                        WatchEvent::Bookmark(b) => resource_version = b.metadata.resource_version,
+                        WatchEvent::Error(e) => {
+                            return Err(Errors::LookupFailed(kube::Error::Api(e)))
+                        }
+                    }
+                }
+
+                Ok(())
+            }
+            .await;
+
+            if let Err(e) = res {
+                warn!(secret = name, error = ?e, "auth secret watch failed, reloading");
+                tokio::time::sleep(WATCH_RETRY_DELAY).await;
+
+                match self.load_secret(&api, &name).await {
+                    Ok(rv) => resource_version = rv,
+                    Err(e) => warn!(secret = name, error = ?e, "failed to reload auth secret"),
+                }
+            }
+        }
+    }
+}
+
+fn hash_all<'a>(
+    users: impl Iterator<Item = (&'a str, &'a [u8])>,
+) -> Result<HashMap<String, PasswordHash>, Errors> {
+    users
+        .map(|(username, password)| {
+            let hash = match password.strip_prefix(SHA256_PREFIX.as_bytes()) {
+                Some(hex) => decode_hex(hex).ok_or_else(|| Errors::InvalidHash(username.into()))?,
+                None => hash(password),
+            };
+            Ok((username.to_string(), hash))
+        })
+        .collect()
+}
+
+fn hash(password: &[u8]) -> PasswordHash {
+    Sha256::digest(password).into()
+}
+
+fn decode_hex(hex: &[u8]) -> Option<PasswordHash> {
+    let hex = std::str::from_utf8(hex).ok()?.trim();
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut out = [0_u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+/// Compares without exiting early, so timing doesn't reveal how much of a hash matched.
+fn constant_time_eq(a: &PasswordHash, b: &PasswordHash) -> bool {
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,56 @@
+mod verify {
+    use k8s_openapi::ByteString;
+
+    use super::super::*;
+
+    // echo -n hunter2 | sha256sum
+    const HUNTER2_SHA256: &str =
+        "sha256:f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7";
+
+    fn users(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(u, p)| (u.to_string(), p.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn plaintext_password() {
+        let creds = Credentials::new(&users(&[("alice", "hunter2")])).unwrap();
+
+        assert!(creds.verify("alice", "hunter2"));
+        assert!(!creds.verify("alice", "hunter3"));
+        assert!(!creds.verify("bob", "hunter2"));
+    }
+
+    #[test]
+    fn hashed_password() {
+        let creds = Credentials::new(&users(&[("alice", HUNTER2_SHA256)])).unwrap();
+
+        assert!(creds.verify("alice", "hunter2"));
+        assert!(!creds.verify("alice", HUNTER2_SHA256));
+    }
+
+    #[test]
+    fn invalid_hash_is_rejected() {
+        assert!(Credentials::new(&users(&[("alice", "sha256:zz")])).is_err());
+    }
+
+    #[test]
+    fn secret_users_override_configured() {
+        let creds = Credentials::new(&users(&[("alice", "old"), ("bob", "bobs")])).unwrap();
+
+        let secret = Secret {
+            data: Some(BTreeMap::from([(
+                "alice".to_string(),
+                ByteString(HUNTER2_SHA256.as_bytes().to_vec()),
+            )])),
+            ..Default::default()
+        };
+        assert_eq!(creds.set_secret(&secret).unwrap(), 1);
+
+        assert!(creds.verify("alice", "hunter2"));
+        assert!(!creds.verify("alice", "old"));
+        assert!(creds.verify("bob", "bobs"));
+    }
+}
@@ -0,0 +1,127 @@
+//! A local TCP echo server standing in for a pod, and a resolver forwarding every address to it,
+//! so the whole path from a client's request to bytes reaching the pod can be tested without a
+//! cluster. Also a resolver that never resolves, for tests that mustn't get as far as a pod, and
+//! the SOCKS5 request clients in these tests send.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::socks::resolver::{self, Destination, PodStream, Resolver, Target};
+
+/// A SOCKS5 CONNECT request for `address`, as a domain name, and `port`.
+pub(crate) fn connect_request(address: &str, port: u16) -> Vec<u8> {
+    let mut req = vec![5, 1, 0, 3, address.len() as u8];
+    req.extend_from_slice(address.as_bytes());
+    req.extend_from_slice(&port.to_be_bytes());
+    req
+}
+
+/// Echoes back whatever each connection sends, until it's dropped.
+pub(crate) struct EchoServer {
+    addr: SocketAddr,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl EchoServer {
+    pub(crate) async fn start() -> Self {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let task = tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+
+        Self { addr, task }
+    }
+
+    /// A resolver connecting to this server, whatever the client asked for.
+    pub(crate) fn resolver(&self) -> EchoResolver {
+        EchoResolver {
+            addr: self.addr,
+            requested: None,
+        }
+    }
+}
+
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Forwards every destination to an [`EchoServer`], recording what was asked for.
+pub(crate) struct EchoResolver {
+    pub addr: SocketAddr,
+    pub requested: Option<(String, u16)>,
+}
+
+impl Resolver for EchoResolver {
+    async fn forwarder(
+        &mut self,
+        destination: Destination<'_>,
+        port: u16,
+    ) -> Result<(Target, Box<dyn PodStream>), resolver::Errors> {
+        let requested = match destination {
+            Destination::Dns(address) => address.to_string(),
+            Destination::Ip(ip) => ip.to_string(),
+        };
+        self.requested = Some((requested, port));
+
+        let stream = TcpStream::connect(self.addr)
+            .await
+            .map_err(|e| resolver::Errors::ForwardFailed(e.into()))?;
+        let target = Target {
+            namespace: "apps".into(),
+            pod: "echo-0".into(),
+            port: self.addr.port(),
+            pod_ip: Some(self.addr.ip()),
+            app_protocol: None,
+            cluster: None,
+            connect_timeout: None,
+            named_target_port: None,
+        };
+        Ok((target, Box::new(stream)))
+    }
+
+    async fn forward_closed(&mut self) -> Option<String> {
+        futures::future::pending().await
+    }
+
+    async fn join(self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Never finishes resolving, dropping `resolving` once a resolution is abandoned.
+#[derive(Default)]
+pub(crate) struct Unresolved {
+    pub resolving: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl Resolver for Unresolved {
+    async fn forwarder(
+        &mut self,
+        _destination: Destination<'_>,
+        _port: u16,
+    ) -> Result<(Target, Box<dyn PodStream>), resolver::Errors> {
+        let _resolving = self.resolving.take();
+        futures::future::pending().await
+    }
+
+    async fn forward_closed(&mut self) -> Option<String> {
+        futures::future::pending().await
+    }
+
+    async fn join(self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,341 @@
+//! An HTTP CONNECT front-end for tooling that would rather use an HTTP proxy than SOCKS. Targets
+//! are resolved and forwarded to exactly as for SOCKS clients, only the handshake differs.
+
+use std::net::IpAddr;
+
+use base64::Engine;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tracing::{debug, info, warn};
+
+use crate::config::{AuthMethod, ErrorKind, ErrorReply};
+use crate::listener::PeerAddr;
+use crate::socks::audit::{Attempt, Outcome};
+use crate::socks::resolver::{Destination, PodResolver, Resolver, StaticResolver};
+use crate::socks::{
+    confirm_forward, correlate, finish, pipe, record_failure, until_disconnect, Context,
+    FullResolver, LocalAsyncReadWriteExt, PORT_NOT_ALLOWED,
+};
+
+/// Longest request head read, up to and including the blank line ending it.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+const CONNECTION_ESTABLISHED: &str = "HTTP/1.1 200 Connection Established\r\n\r\n";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Errors {
+    #[error("Invalid HTTP request: {0}")]
+    InvalidRequest(String),
+    #[error("Request head is longer than {MAX_HEAD_LEN} bytes")]
+    HeadTooLong,
+    #[error("Client sent an HTTP {0} request, only CONNECT is supported")]
+    NotConnect(String),
+    #[error("CONNECT target {0:?} isn't a <host>:<port>")]
+    InvalidTarget(String),
+}
+
+impl Errors {
+    /// A short identifier for metric labels and the audit log, which stays the same however the
+    /// message is worded.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Errors::InvalidRequest(_) => "invalid_http_request",
+            Errors::HeadTooLong => "http_head_too_long",
+            Errors::NotConnect(_) => "http_not_connect",
+            Errors::InvalidTarget(_) => "invalid_connect_target",
+        }
+    }
+
+    fn response(&self) -> String {
+        match self {
+            Errors::InvalidRequest(_) | Errors::InvalidTarget(_) => status(400, "Bad Request"),
+            Errors::HeadTooLong => status(431, "Request Header Fields Too Large"),
+            Errors::NotConnect(_) => status(405, "Method Not Allowed"),
+        }
+    }
+}
+
+/// A parsed CONNECT request.
+#[derive(Debug, PartialEq, Eq)]
+struct ConnectRequest {
+    host: String,
+    port: u16,
+    /// From a `Proxy-Authorization: Basic` header
+    credentials: Option<(String, String)>,
+}
+
+/// Handles a connection accepted on the HTTP CONNECT listener.
+pub(crate) async fn handle(
+    client_conn: impl AsyncRead + AsyncWrite + Unpin,
+    peer_addr: PeerAddr,
+    ctx: Context,
+) -> anyhow::Result<()> {
+    let _slot = match ctx.connection_slot().await {
+        Ok(slot) => slot,
+        Err(e) => {
+            let resolver = FullResolver(Some(e));
+            return handle_with(client_conn, peer_addr, ctx, resolver).await;
+        }
+    };
+
+    match ctx.kube_client {
+        Some(ref kube_client) => {
+            let resolver =
+                PodResolver::new(ctx.clone(), kube_client.clone()).for_client(&peer_addr);
+            handle_with(client_conn, peer_addr, ctx, resolver).await
+        }
+        None => {
+            let resolver = StaticResolver::new(ctx.config.clone());
+            handle_with(client_conn, peer_addr, ctx, resolver).await
+        }
+    }
+}
+
+/// Handles an HTTP CONNECT client, opening the stream it asks for with `resolver`.
+pub(crate) async fn handle_with(
+    client_conn: impl AsyncRead + AsyncWrite + Unpin,
+    peer_addr: PeerAddr,
+    ctx: Context,
+    mut resolver: impl Resolver,
+) -> anyhow::Result<()> {
+    let conn = ctx.registry.register(peer_addr.clone());
+    ctx.metrics.record_connection(peer_addr.ip());
+    let mut attempt = Attempt::new(ctx.audit.clone(), conn.id(), peer_addr);
+    attempt.protocol = Some("http-connect");
+
+    // Anything the client sends after the request head stays buffered for the pod
+    let mut client = BufReader::new(client_conn);
+    let res = connect(&mut client, &ctx, &conn, &mut attempt, &mut resolver).await;
+    let res = finish(&ctx, &mut attempt, res);
+
+    resolver.join().await?;
+    res
+}
+
+async fn connect(
+    client: &mut (impl AsyncBufRead + AsyncWrite + Unpin),
+    ctx: &Context,
+    conn: &crate::socks::registry::Connection,
+    attempt: &mut Attempt,
+    resolver: &mut impl Resolver,
+) -> anyhow::Result<()> {
+    let req = match read_head(client).await.and_then(|h| parse_connect(&h)) {
+        Ok(req) => req,
+        Err(e) => {
+            client.send(e.response()).await?;
+            return Err(e.into());
+        }
+    };
+    info!(
+        host = req.host,
+        port = req.port,
+        "valid HTTP CONNECT request"
+    );
+    attempt.requested(req.host.clone(), req.port);
+
+    if let Some((ref username, _)) = req.credentials {
+        correlate(attempt, username);
+    }
+    let Some(username) = authenticated(ctx, &req) else {
+        warn!("HTTP CONNECT client didn't authenticate");
+        attempt.outcome(Outcome::Rejected, "authentication failed");
+        client
+            .send(
+                "HTTP/1.1 407 Proxy Authentication Required\r\n\
+                 Proxy-Authenticate: Basic realm=\"kube-fwd-socks\"\r\n\
+                 Content-Length: 0\r\n\r\n",
+            )
+            .await?;
+        return Ok(());
+    };
+    // Only a verified user is audited, a client whose credentials were wrong but optional isn't
+    // anyone in particular
+    attempt.username = username.map(Into::into);
+    resolver.authenticated(username);
+
+    if !ctx.config.port_allowed(req.port) {
+        warn!(port = req.port, "port not allowed, rejecting");
+        attempt.outcome(Outcome::Rejected, PORT_NOT_ALLOWED);
+        client.send(status(403, "Forbidden")).await?;
+        return Ok(());
+    }
+
+    let destination = match req.host.parse::<IpAddr>() {
+        Ok(ip) => Destination::Ip(ip),
+        Err(_) => Destination::Dns(req.host.as_str()),
+    };
+
+    let mut early = Vec::new();
+    let forwarder = until_disconnect(
+        client,
+        &mut early,
+        ctx.unless_drained(resolver.forwarder(destination, req.port)),
+    );
+    let forwarded = match forwarder.await {
+        None => {
+            debug!("client disconnected before forward was established");
+            attempt.outcome(Outcome::Disconnected, "");
+            return Ok(());
+        }
+        Some(Ok((target, s))) => {
+            conn.set_target(&target);
+            let mut s = conn.count(s);
+            attempt.target = Some(target.clone());
+            confirm_forward(
+                &ctx.config,
+                &attempt.peer_addr,
+                &target,
+                &mut s,
+                &early,
+                resolver,
+            )
+            .await
+            .map(|()| (target, s))
+        }
+        Some(Err(e)) => Err(e),
+    };
+    let (target, mut pod_stream) = match forwarded {
+        Ok(f) => f,
+        Err(e) => {
+            warn!(error = ?e, "failed to resolve and open forward stream");
+            record_failure(ctx, attempt, Outcome::Failed, e.code(), &e);
+            let response = match ctx.config.error_reply(e.kind()) {
+                _ if matches!(
+                    e.kind(),
+                    ErrorKind::TooManyConnections | ErrorKind::ShuttingDown
+                ) =>
+                {
+                    status(503, "Service Unavailable")
+                }
+                ErrorReply::NotAllowed => status(403, "Forbidden"),
+                ErrorReply::TtlExpired => status(504, "Gateway Timeout"),
+                _ => status(502, "Bad Gateway"),
+            };
+            client.send(response).await?;
+            return Ok(());
+        }
+    };
+
+    info!(?target, "forwarding");
+    let _watching = ctx.watch(&target);
+    attempt.outcome(Outcome::Forwarded, "");
+
+    client.send(CONNECTION_ESTABLISHED).await?;
+
+    pipe(
+        client,
+        &mut pod_stream,
+        resolver,
+        &ctx.config,
+        ctx.drained(),
+    )
+    .await?;
+    drop(pod_stream);
+
+    Ok(())
+}
+
+/// Who the client authenticated as, `Some(None)` when it needn't and didn't, or `None` if it's
+/// refused. Credentials sent when not required still scope the client to their user's namespaces.
+fn authenticated<'r>(ctx: &Context, req: &'r ConnectRequest) -> Option<Option<&'r str>> {
+    let verified = match req.credentials {
+        Some((ref username, ref password)) if ctx.credentials.verify(username, password) => {
+            Some(username.as_str())
+        }
+        _ => None,
+    };
+
+    match verified {
+        Some(_) => Some(verified),
+        None if ctx.config.auth_methods.contains(&AuthMethod::NotRequired) => Some(None),
+        None => None,
+    }
+}
+
+/// Reads the request line and headers, up to and including the blank line ending them.
+async fn read_head(client: &mut (impl AsyncBufRead + Unpin)) -> Result<Vec<u8>, Errors> {
+    let mut head = Vec::new();
+    loop {
+        let read = client
+            .read_until(b'\n', &mut head)
+            .await
+            .map_err(|e| Errors::InvalidRequest(e.to_string()))?;
+        if read == 0 {
+            return Err(Errors::InvalidRequest(
+                "connection closed before the request was complete".into(),
+            ));
+        }
+        if head.len() > MAX_HEAD_LEN {
+            return Err(Errors::HeadTooLong);
+        }
+        if head.ends_with(b"\n\r\n") || head.ends_with(b"\n\n") {
+            return Ok(head);
+        }
+    }
+}
+
+fn parse_connect(head: &[u8]) -> Result<ConnectRequest, Errors> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => {
+            return Err(Errors::InvalidRequest("incomplete request head".into()))
+        }
+        Err(e) => return Err(Errors::InvalidRequest(e.to_string())),
+    }
+
+    let method = req.method.unwrap_or_default();
+    if method != "CONNECT" {
+        return Err(Errors::NotConnect(method.into()));
+    }
+
+    let target = req.path.unwrap_or_default();
+    let (host, port) = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+        .ok_or_else(|| Errors::InvalidTarget(target.into()))?;
+    // IPv6 addresses are bracketed, like in a URL
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return Err(Errors::InvalidTarget(target.into()));
+    }
+
+    let credentials = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("proxy-authorization"))
+        .and_then(|h| basic_credentials(h.value));
+
+    Ok(ConnectRequest {
+        host: host.into(),
+        port,
+        credentials,
+    })
+}
+
+/// The username and password in a `Basic` authorization header's value.
+fn basic_credentials(value: &[u8]) -> Option<(String, String)> {
+    let value = std::str::from_utf8(value).ok()?.trim();
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.into(), password.into()))
+}
+
+/// A response with no body.
+fn status(code: u16, reason: &str) -> String {
+    format!("HTTP/1.1 {code} {reason}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+}
+
+#[cfg(test)]
+mod tests;
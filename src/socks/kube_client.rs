@@ -0,0 +1,192 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use kube::Client;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+/// Consecutive connection failures after which the client is rebuilt.
+const REBUILD_AFTER_FAILURES: u32 = 3;
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// The shared kube client, rebuilt from scratch when the API server stops answering it.
+///
+/// A rebuild re-reads the kubeconfig or service account credentials, so it also recovers from
+/// credentials that were rotated under a long running process.
+pub struct KubeClient {
+    client: RwLock<Client>,
+    health: Health,
+    /// Kubeconfig context the client was built for, `None` when it was inferred
+    context: Option<String>,
+}
+
+/// Whether the API server can currently be reached, for the readiness endpoint.
+#[derive(Debug, Default)]
+pub struct Health {
+    consecutive_failures: AtomicU32,
+    unhealthy: AtomicBool,
+    rebuilding: AtomicBool,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct HealthInfo {
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+}
+
+impl KubeClient {
+    pub fn new(client: Client) -> Self {
+        KubeClient {
+            client: RwLock::new(client),
+            health: Health::default(),
+            context: None,
+        }
+    }
+
+    /// A client built with [`connect_context`], rebuilt for the same context.
+    pub fn for_context(client: Client, context: &str) -> Self {
+        KubeClient {
+            context: Some(context.into()),
+            ..KubeClient::new(client)
+        }
+    }
+
+    /// The current client, clone it for each use so a rebuild takes effect for new requests.
+    pub fn get(&self) -> Client {
+        self.client.read().unwrap().clone()
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /// Records the outcome of an API call, `Err` with the error when it failed. Once enough
+    /// calls in a row have failed to reach the API server a rebuild is started in the background.
+    pub fn observe(self: &Arc<Self>, res: Result<(), &kube::Error>) {
+        let failed = res.is_err_and(is_connection_error);
+        if self.health.record(failed) {
+            tokio::spawn(self.clone().rebuild());
+        }
+    }
+
+    async fn rebuild(self: Arc<Self>) {
+        warn!(
+            context = self.context,
+            "lost the API server, rebuilding client"
+        );
+
+        for attempt in 0.. {
+            match reconnect(self.context.as_deref()).await {
+                Ok(client) => {
+                    *self.client.write().unwrap() = client;
+                    self.health.recovered();
+                    info!(attempt, "rebuilt client");
+                    return;
+                }
+                Err(e) => {
+                    let delay = backoff(attempt, rand::random());
+                    warn!(attempt, error = ?e, ?delay, "failed to rebuild client");
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
+
+/// Builds a client from the kubeconfig, or the in-cluster service account when there isn't one.
+pub async fn connect() -> Result<Client, kube::Error> {
+    let config = kube::Config::infer()
+        .await
+        .map_err(kube::Error::InferConfig)?;
+    client_from(config)
+}
+
+/// Builds a client for the kubeconfig context `context`.
+pub async fn connect_context(context: &str) -> anyhow::Result<Client> {
+    let options = kube::config::KubeConfigOptions {
+        context: Some(context.into()),
+        ..Default::default()
+    };
+    let config = kube::Config::from_kubeconfig(&options)
+        .await
+        .with_context(|| format!("failed to load kubeconfig context {context}"))?;
+    Ok(client_from(config)?)
+}
+
+/// The in-cluster config refers to the projected service account token by path, and kube
+/// re-reads it once the cached copy is a minute old, which keeps up with the kubelet rotating
+/// it. So the config is used as inferred, a `token_file` must never be swapped for the `token`
+/// read from it.
+fn client_from(config: kube::Config) -> Result<Client, kube::Error> {
+    if let Some(ref path) = config.auth_info.token_file {
+        debug!(path, "authenticating with token file");
+    }
+
+    Client::try_from(config)
+}
+
+/// A fresh client, checking it can actually reach the API server before it replaces the old one.
+async fn reconnect(context: Option<&str>) -> anyhow::Result<Client> {
+    let client = match context {
+        Some(context) => connect_context(context).await?,
+        None => connect().await?,
+    };
+    client.apiserver_version().await?;
+    Ok(client)
+}
+
+impl Health {
+    /// Counts a call, returns true when this failure should start a rebuild.
+    fn record(&self, failed: bool) -> bool {
+        if !failed {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            return false;
+        }
+
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < REBUILD_AFTER_FAILURES {
+            return false;
+        }
+
+        self.unhealthy.store(true, Ordering::Relaxed);
+        !self.rebuilding.swap(true, Ordering::AcqRel)
+    }
+
+    fn recovered(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        self.unhealthy.store(false, Ordering::Relaxed);
+        self.rebuilding.store(false, Ordering::Release);
+    }
+
+    pub fn info(&self) -> HealthInfo {
+        HealthInfo {
+            healthy: !self.unhealthy.load(Ordering::Relaxed),
+            consecutive_failures: self.consecutive_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Failures to reach or authenticate with the API server, rather than it answering with an error.
+fn is_connection_error(e: &kube::Error) -> bool {
+    match e {
+        kube::Error::HyperError(_) | kube::Error::Service(_) | kube::Error::Auth(_) => true,
+        kube::Error::Api(response) => response.code == 401,
+        _ => false,
+    }
+}
+
+/// Exponential backoff with "equal jitter", somewhere between half and all of the doubled delay,
+/// so many proxies losing the same API server don't all retry in lock step. `jitter` is in 0..1.
+fn backoff(attempt: u32, jitter: f64) -> Duration {
+    let delay = BACKOFF_BASE
+        .saturating_mul(2_u32.saturating_pow(attempt))
+        .min(BACKOFF_MAX);
+
+    delay / 2 + delay.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,148 @@
+mod health {
+    use super::super::*;
+
+    #[test]
+    fn rebuilds_once_after_consecutive_failures() {
+        let health = Health::default();
+
+        assert!(!health.record(true));
+        assert!(!health.record(true));
+        assert!(health.record(true));
+        assert!(!health.record(true), "already rebuilding");
+
+        assert_eq!(
+            health.info(),
+            HealthInfo {
+                healthy: false,
+                consecutive_failures: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let health = Health::default();
+
+        health.record(true);
+        health.record(true);
+        health.record(false);
+
+        assert!(!health.record(true));
+        assert!(health.info().healthy);
+    }
+
+    #[test]
+    fn recovering_allows_another_rebuild() {
+        let health = Health::default();
+        for _ in 0..REBUILD_AFTER_FAILURES {
+            health.record(true);
+        }
+
+        health.recovered();
+
+        assert!(health.info().healthy);
+        for _ in 1..REBUILD_AFTER_FAILURES {
+            assert!(!health.record(true));
+        }
+        assert!(health.record(true));
+    }
+}
+
+mod is_connection_error {
+    use kube::core::ErrorResponse;
+
+    use super::super::*;
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(ErrorResponse {
+            status: "Failure".into(),
+            message: String::new(),
+            reason: String::new(),
+            code,
+        })
+    }
+
+    #[test]
+    fn unauthorized_is_a_connection_error() {
+        assert!(is_connection_error(&api_error(401)));
+    }
+
+    #[test]
+    fn api_responses_are_not() {
+        assert!(!is_connection_error(&api_error(403)));
+        assert!(!is_connection_error(&api_error(404)));
+    }
+
+    #[test]
+    fn service_errors_are() {
+        assert!(is_connection_error(&kube::Error::Service(
+            "connect refused".into()
+        )));
+    }
+}
+
+mod backoff {
+    use super::super::*;
+
+    #[test]
+    fn doubles_between_half_and_full() {
+        assert_eq!(backoff(0, 0.0), Duration::from_millis(500));
+        assert_eq!(backoff(0, 1.0), Duration::from_secs(1));
+        assert_eq!(backoff(3, 0.0), Duration::from_secs(4));
+        assert_eq!(backoff(3, 1.0), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn is_capped() {
+        assert_eq!(backoff(30, 1.0), BACKOFF_MAX);
+        assert_eq!(backoff(u32::MAX, 0.0), BACKOFF_MAX / 2);
+    }
+}
+
+mod client_from {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use crate::fake_api;
+
+    const VERSION: &str = r#"{"major":"1","minor":"31","gitVersion":"v1.31.0","gitCommit":"","gitTreeState":"","buildDate":"","goVersion":"","compiler":"","platform":""}"#;
+
+    /// Answers one request to `/version`, returning its `Authorization` header.
+    async fn authorization(listener: &TcpListener) -> String {
+        let served = fake_api::respond(listener, "200 OK", VERSION).await;
+        served
+            .header("authorization")
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn rebuilding_reads_the_rotated_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let token_file = std::env::temp_dir().join(format!(
+            "kube-fwd-socks-token-{}",
+            listener.local_addr().unwrap().port()
+        ));
+
+        let mut config = kube::Config::new(
+            format!("http://{}", listener.local_addr().unwrap())
+                .parse()
+                .unwrap(),
+        );
+        config.auth_info.token_file = Some(token_file.to_string_lossy().into());
+
+        std::fs::write(&token_file, "first").unwrap();
+        let client = client_from(config.clone()).unwrap();
+        let (res, auth) = tokio::join!(client.apiserver_version(), authorization(&listener));
+        res.unwrap();
+        assert_eq!(auth, "Bearer first");
+
+        std::fs::write(&token_file, "second").unwrap();
+        let client = client_from(config).unwrap();
+        let (res, auth) = tokio::join!(client.apiserver_version(), authorization(&listener));
+        res.unwrap();
+        assert_eq!(auth, "Bearer second");
+
+        std::fs::remove_file(token_file).unwrap();
+    }
+}
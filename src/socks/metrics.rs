@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the `socks_ready_wait_seconds` buckets, fine below a second to tell quick
+/// readiness flips from pods that are still starting.
+const READY_WAIT_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Client IPs counted for each one reported in `socks_connections_by_client`, so a client
+/// connecting often isn't pushed out of the top by a crowd connecting once each.
+const CLIENTS_TRACKED_PER_REPORTED: usize = 8;
+
+/// Metrics served on the admin endpoint's `/metrics` in the Prometheus text format.
+pub struct Metrics {
+    /// How long connections waited for a pod to become ready with `wait-for-ready`, whether or
+    /// not one did.
+    pub ready_wait: Histogram,
+    /// Failed connection attempts by error code
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    /// Connections accepted, from any client
+    connections: AtomicU64,
+    /// Circuit breakers open or half-open right now
+    circuits_open: AtomicU64,
+    circuit_trips: AtomicU64,
+    circuit_rejections: AtomicU64,
+    clients: Mutex<TopClients>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new(crate::config::DEFAULT_CLIENT_METRICS_TOP)
+    }
+}
+
+impl Metrics {
+    /// Reports connections of the `top_clients` client IPs connecting most, none when 0.
+    pub fn new(top_clients: usize) -> Self {
+        Metrics {
+            ready_wait: Histogram::new(READY_WAIT_BUCKETS),
+            errors: Mutex::default(),
+            connections: AtomicU64::new(0),
+            circuits_open: AtomicU64::new(0),
+            circuit_trips: AtomicU64::new(0),
+            circuit_rejections: AtomicU64::new(0),
+            clients: Mutex::new(TopClients::new(top_clients)),
+        }
+    }
+
+    /// Counts a connection accepted from `client`, `None` for clients without an IP such as
+    /// those on the UNIX socket.
+    pub fn record_connection(&self, client: Option<IpAddr>) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        if let Some(ip) = client {
+            self.clients.lock().unwrap().record(ip);
+        }
+    }
+
+    /// Counts a failed connection attempt under its error `code`.
+    pub fn record_error(&self, code: &'static str) {
+        *self.errors.lock().unwrap().entry(code).or_default() += 1;
+    }
+
+    /// Counts a circuit breaker tripping, `reopened` if it was already open and its trial
+    /// request failed.
+    pub fn record_circuit_trip(&self, reopened: bool) {
+        self.circuit_trips.fetch_add(1, Ordering::Relaxed);
+        if !reopened {
+            self.circuits_open.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a circuit breaker closing again, or being forgotten while open.
+    pub fn record_circuit_closed(&self) {
+        self.circuits_open.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request failed straight away by an open circuit breaker.
+    pub fn record_circuit_rejection(&self) {
+        self.circuit_rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.ready_wait.render(
+            &mut out,
+            "socks_ready_wait_seconds",
+            "Time spent waiting for a pod to become ready",
+        );
+
+        let name = "socks_connection_errors_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Failed connection attempts by error code"
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (code, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "{name}{{code=\"{code}\"}} {count}");
+        }
+
+        let name = "socks_connections_total";
+        let _ = writeln!(out, "# HELP {name} Connections accepted from any client");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {}", self.connections.load(Ordering::Relaxed));
+
+        let name = "socks_connections_by_client";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Connections accepted from the client IPs connecting most, estimated \
+             high by at most the count of the least connecting IP tracked"
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (ip, count) in self.clients.lock().unwrap().top() {
+            let _ = writeln!(out, "{name}{{peer=\"{ip}\"}} {count}");
+        }
+
+        let name = "socks_circuit_breakers_open";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Addresses whose circuit breaker is open or letting a trial through"
+        );
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {}", self.circuits_open.load(Ordering::Relaxed));
+
+        let name = "socks_circuit_breaker_trips_total";
+        let _ = writeln!(out, "# HELP {name} Times a circuit breaker opened");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {}", self.circuit_trips.load(Ordering::Relaxed));
+
+        let name = "socks_circuit_breaker_rejections_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Requests failed straight away by an open circuit breaker"
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(
+            out,
+            "{name} {}",
+            self.circuit_rejections.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+/// Connection counts of the client IPs connecting most, in memory bounded however many distinct
+/// IPs connect, eg. with spoofed sources. Once full, a new IP takes the place of the one with
+/// the fewest connections and carries on from its count, so counts are never too low and a
+/// client connecting more than its share can't be pushed out (the space-saving algorithm).
+struct TopClients {
+    reported: usize,
+    counts: HashMap<IpAddr, u64>,
+}
+
+impl TopClients {
+    fn new(reported: usize) -> Self {
+        TopClients {
+            reported,
+            counts: HashMap::new(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.reported.saturating_mul(CLIENTS_TRACKED_PER_REPORTED)
+    }
+
+    fn record(&mut self, ip: IpAddr) {
+        if let Some(count) = self.counts.get_mut(&ip) {
+            *count += 1;
+            return;
+        }
+        if self.capacity() == 0 {
+            return;
+        }
+
+        let mut count = 1;
+        if self.counts.len() >= self.capacity() {
+            let fewest = self
+                .counts
+                .iter()
+                .min_by_key(|(_, c)| **c)
+                .map(|(ip, c)| (*ip, *c));
+            if let Some((evicted, evicted_count)) = fewest {
+                self.counts.remove(&evicted);
+                count += evicted_count;
+            }
+        }
+        self.counts.insert(ip, count);
+    }
+
+    /// The reported IPs with their counts, most connections first.
+    fn top(&self) -> Vec<(IpAddr, u64)> {
+        let mut top: Vec<_> = self.counts.iter().map(|(ip, c)| (*ip, *c)).collect();
+        top.sort_by(|(a_ip, a), (b_ip, b)| b.cmp(a).then(a_ip.cmp(b_ip)));
+        top.truncate(self.reported);
+        top
+    }
+}
+
+/// A histogram of durations in seconds.
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative, with one more for those above every bound
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(self.bounds.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(
+            duration.as_micros().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {count}");
+        }
+
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,169 @@
+mod histogram {
+    use super::super::*;
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.ready_wait.observe(Duration::from_millis(40));
+        metrics.ready_wait.observe(Duration::from_millis(300));
+        metrics.ready_wait.observe(Duration::from_secs(3));
+
+        let out = metrics.render();
+
+        assert!(
+            out.contains("# TYPE socks_ready_wait_seconds histogram\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("socks_ready_wait_seconds_bucket{le=\"0.05\"} 1\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("socks_ready_wait_seconds_bucket{le=\"0.25\"} 1\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("socks_ready_wait_seconds_bucket{le=\"0.5\"} 2\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("socks_ready_wait_seconds_bucket{le=\"5\"} 3\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("socks_ready_wait_seconds_bucket{le=\"+Inf\"} 3\n"),
+            "{out}"
+        );
+        assert!(out.contains("socks_ready_wait_seconds_sum 3.34\n"), "{out}");
+        assert!(out.contains("socks_ready_wait_seconds_count 3\n"), "{out}");
+    }
+
+    #[test]
+    fn bound_is_inclusive() {
+        let metrics = Metrics::default();
+        metrics.ready_wait.observe(Duration::from_secs(1));
+
+        let out = metrics.render();
+
+        assert!(
+            out.contains("socks_ready_wait_seconds_bucket{le=\"0.5\"} 0\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("socks_ready_wait_seconds_bucket{le=\"1\"} 1\n"),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn beyond_every_bound() {
+        let metrics = Metrics::default();
+        metrics.ready_wait.observe(Duration::from_secs(600));
+
+        let out = metrics.render();
+
+        assert!(
+            out.contains("socks_ready_wait_seconds_bucket{le=\"120\"} 0\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("socks_ready_wait_seconds_bucket{le=\"+Inf\"} 1\n"),
+            "{out}"
+        );
+    }
+}
+
+mod errors {
+    use super::super::*;
+
+    #[test]
+    fn counted_by_code() {
+        let metrics = Metrics::default();
+        metrics.record_error("pod_not_found");
+        metrics.record_error("rate_limited");
+        metrics.record_error("pod_not_found");
+
+        let out = metrics.render();
+
+        assert!(
+            out.contains("# TYPE socks_connection_errors_total counter\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("socks_connection_errors_total{code=\"pod_not_found\"} 2\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("socks_connection_errors_total{code=\"rate_limited\"} 1\n"),
+            "{out}"
+        );
+    }
+}
+
+mod connections_by_client {
+    use super::super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn counted_by_peer_ip() {
+        let metrics = Metrics::default();
+        metrics.record_connection(Some(ip(1)));
+        metrics.record_connection(Some(ip(2)));
+        metrics.record_connection(Some(ip(1)));
+        metrics.record_connection(None);
+
+        let out = metrics.render();
+
+        assert!(out.contains("socks_connections_total 4\n"), "{out}");
+        assert!(
+            out.contains("socks_connections_by_client{peer=\"192.0.2.1\"} 2\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("socks_connections_by_client{peer=\"192.0.2.2\"} 1\n"),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn only_the_top_are_reported() {
+        let mut clients = TopClients::new(2);
+        for (last, connections) in [(1, 5), (2, 1), (3, 3)] {
+            for _ in 0..connections {
+                clients.record(ip(last));
+            }
+        }
+
+        assert_eq!(clients.top(), vec![(ip(1), 5), (ip(3), 3)]);
+    }
+
+    #[test]
+    fn a_flood_of_new_ips_stays_bounded() {
+        let mut clients = TopClients::new(2);
+        for _ in 0..100 {
+            clients.record(ip(1));
+        }
+
+        for i in 0..1000u16 {
+            clients.record(IpAddr::from([10, 0, (i >> 8) as u8, i as u8]));
+        }
+
+        assert_eq!(clients.counts.len(), clients.capacity());
+        // More than its share of every connection, so never the least connecting IP
+        assert_eq!(clients.top()[0], (ip(1), 100));
+    }
+
+    #[test]
+    fn none_reported_when_0() {
+        let metrics = Metrics::new(0);
+        metrics.record_connection(Some(ip(1)));
+
+        let out = metrics.render();
+
+        assert!(out.contains("socks_connections_total 1\n"), "{out}");
+        assert!(!out.contains("socks_connections_by_client{"), "{out}");
+    }
+}
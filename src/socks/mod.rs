@@ -1,104 +1,670 @@
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use futures::FutureExt;
 use kube::Client;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tracing::{debug, error, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn, Span};
 
-use crate::socks::resolver::PodResolver;
+use crate::config::{AuthMethod, Config, ErrorReply, ReplyAddress};
+use crate::listener::PeerAddr;
+use crate::socks::audit::{Attempt, AuditLog, Outcome};
+use crate::socks::circuit_breaker::CircuitBreakers;
+use crate::socks::credentials::Credentials;
+use crate::socks::kube_client::KubeClient;
+use crate::socks::metrics::Metrics;
+use crate::socks::pod_watch::{PodWatch, Watching};
+use crate::socks::prewarm::Prewarmed;
+use crate::socks::rate_limit::RateLimiter;
+use crate::socks::registry::{Connection, Registry};
+use crate::socks::resolver::{
+    Destination, PodResolver, PodStream, Resolver, RoundRobin, StaticResolver, Target,
+};
+use crate::socks::throttle::Throttled;
+use crate::tls;
 
+mod api_proxy;
+mod audit;
+mod circuit_breaker;
+pub(crate) mod credentials;
+#[cfg(test)]
+mod echo;
+pub(crate) mod http_connect;
+pub(crate) mod kube_client;
+pub(crate) mod metrics;
+mod pod_watch;
+mod prewarm;
+mod proxy_protocol;
+mod rate_limit;
+pub(crate) mod registry;
 mod resolver;
+mod throttle;
+#[cfg(feature = "socks4")]
 mod v4;
 mod v5;
+mod websocket;
+
+/// State shared by every connection, cheap to clone.
+#[derive(Clone)]
+pub(crate) struct Context {
+    /// Not set when serving `static-hosts`, no cluster is used then
+    pub kube_client: Option<Arc<KubeClient>>,
+    /// Clients for the `contexts` clusters by name
+    pub clusters: Arc<BTreeMap<String, Arc<KubeClient>>>,
+    pub config: Arc<Config>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub circuit_breakers: Arc<CircuitBreakers>,
+    pub registry: Arc<Registry>,
+    pub credentials: Arc<Credentials>,
+    pub audit: Option<Arc<AuditLog>>,
+    pub prewarmed: Arc<Prewarmed>,
+    pub metrics: Arc<Metrics>,
+    /// Set when the listeners speak TLS
+    pub tls: Option<TlsAcceptor>,
+    pub round_robin: Arc<RoundRobin>,
+    /// Set with `watch-target-pods`
+    pub pod_watch: Option<Arc<PodWatch>>,
+    /// One permit per connection allowed by `max-connections`, unlimited when not set
+    pub connection_slots: Option<Arc<Semaphore>>,
+    /// Set once the connections still open after `shutdown-grace` are to be closed. Every
+    /// connection being served holds a receiver, so shutdown can wait for them all to finish.
+    pub draining: Arc<watch::Sender<bool>>,
+}
+
+impl Context {
+    pub fn new(kube_client: Option<Client>, config: Arc<Config>) -> anyhow::Result<Self> {
+        let rate_limiter = Arc::new(RateLimiter::new(config.forward_rate, config.forward_burst));
+
+        let credentials = Arc::new(Credentials::new(&config.users)?);
+
+        let audit = match config.audit_log {
+            Some(ref path) => Some(Arc::new(AuditLog::open(path)?)),
+            None => None,
+        };
+
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => {
+                Some(tls::acceptor(cert, key, config.tls_client_ca.as_deref())?)
+            }
+            _ => None,
+        };
+
+        let kube_client = kube_client.map(|c| Arc::new(KubeClient::new(c)));
+        let pod_watch = match (config.watch_target_pods, &kube_client) {
+            (true, Some(_)) => Some(Arc::new(PodWatch::default())),
+            _ => None,
+        };
+
+        let connection_slots = match config.max_connections {
+            0 => None,
+            max => Some(Arc::new(Semaphore::new(max))),
+        };
+
+        let metrics = Arc::new(Metrics::new(config.client_metrics_top));
+        let circuit_breakers = Arc::new(CircuitBreakers::new(&config, metrics.clone()));
+
+        Ok(Context {
+            kube_client,
+            clusters: Arc::default(),
+            config,
+            rate_limiter,
+            circuit_breakers,
+            registry: Arc::new(Registry::default()),
+            credentials,
+            audit,
+            prewarmed: Arc::new(Prewarmed::default()),
+            metrics,
+            tls,
+            round_robin: Arc::new(RoundRobin::default()),
+            pod_watch,
+            connection_slots,
+            draining: Arc::new(watch::Sender::new(false)),
+        })
+    }
+
+    /// Serves the clusters of `contexts` too, with their clients by name.
+    pub fn with_clusters(self, clusters: BTreeMap<String, KubeClient>) -> Self {
+        let clusters = clusters
+            .into_iter()
+            .map(|(n, c)| (n, Arc::new(c)))
+            .collect();
+
+        Context {
+            clusters: Arc::new(clusters),
+            ..self
+        }
+    }
+
+    /// The client for the cluster `target` is in.
+    fn kube_client_for(&self, target: &Target) -> Option<&Arc<KubeClient>> {
+        match target.cluster {
+            Some(ref name) => self.clusters.get(name),
+            None => self.kube_client.as_ref(),
+        }
+    }
+
+    /// Takes one of the `max-connections` slots, to be held for as long as the connection is
+    /// handled. Waits for a connection to finish when they're all taken, or fails straight away
+    /// with `reject-when-full`.
+    async fn connection_slot(&self) -> Result<Option<OwnedSemaphorePermit>, resolver::Errors> {
+        let Some(ref slots) = self.connection_slots else {
+            return Ok(None);
+        };
+
+        if self.config.reject_when_full {
+            return match slots.clone().try_acquire_owned() {
+                Ok(slot) => Ok(Some(slot)),
+                Err(_) => Err(resolver::Errors::TooManyConnections(
+                    self.config.max_connections,
+                )),
+            };
+        }
+
+        // The semaphore is never closed
+        Ok(slots.clone().acquire_owned().await.ok())
+    }
+
+    /// Waits up to `shutdown-grace` for the connections being served to finish, then drains the
+    /// rest: those still waiting for their forward are sent a failure reply, forwarding ones
+    /// have both sides shut down, and any others are closed.
+    pub async fn drain(&self) {
+        let grace = Duration::from_secs(self.config.shutdown_grace);
+        if tokio::time::timeout(grace, self.draining.closed())
+            .await
+            .is_ok()
+        {
+            return;
+        }
+
+        warn!(
+            connections = self.draining.receiver_count(),
+            ?grace,
+            "connections still open after shutdown-grace, draining them"
+        );
+        for conn in self.registry.snapshot() {
+            let target = conn
+                .target
+                .map(|t| format!("{}/{}:{}", t.namespace, t.pod, t.port));
+            warn!(
+                id = conn.id,
+                peer_addr = %conn.peer_addr,
+                target,
+                uptime_secs = conn.uptime_secs,
+                "draining connection"
+            );
+        }
+
+        self.draining.send_replace(true);
+        let _ = tokio::time::timeout(2 * DRAIN_TIMEOUT, self.draining.closed()).await;
+    }
+
+    /// Completes once connections are being drained.
+    async fn drained(&self) {
+        let _ = self
+            .draining
+            .subscribe()
+            .wait_for(|draining| *draining)
+            .await;
+    }
+
+    /// Runs `fut`, unless connections are drained first, when it's dropped and the client is
+    /// failed as the proxy is shutting down.
+    async fn unless_drained<T>(
+        &self,
+        fut: impl std::future::Future<Output = Result<T, resolver::Errors>>,
+    ) -> Result<T, resolver::Errors> {
+        tokio::select! {
+            res = fut => res,
+            () = self.drained() => Err(resolver::Errors::ShuttingDown),
+        }
+    }
+
+    /// Watches the target's pod while the result is held, if `watch-target-pods` is set.
+    fn watch(&self, target: &Target) -> Option<Watching> {
+        let kube_client = self.kube_client_for(target)?;
+        self.pod_watch
+            .as_ref()
+            .map(|w| w.watch(target, kube_client))
+    }
+}
+
+/// Starts opening the `--prewarm` forwards in the background.
+pub(crate) fn prewarm(ctx: &Context) {
+    if let Some(ref kube_client) = ctx.kube_client {
+        prewarm::start(ctx, kube_client);
+    }
+}
+
+/// The protocol a listener's clients speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Frontend {
+    Socks,
+    HttpConnect,
+}
 
+/// Handles a connection accepted from a listener for `frontend`.
+pub(crate) async fn serve(
+    frontend: Frontend,
+    client_conn: impl AsyncRead + AsyncWrite + Unpin,
+    peer_addr: PeerAddr,
+    ctx: Context,
+) -> anyhow::Result<()> {
+    // Held until the connection is finished with, which shutdown waits for
+    let _draining = ctx.draining.subscribe();
+
+    let drained = ctx.clone();
+    let handled = async move {
+        match frontend {
+            Frontend::Socks => accept(client_conn, peer_addr, ctx).await,
+            Frontend::HttpConnect => http_connect::handle(client_conn, peer_addr, ctx).await,
+        }
+    };
+
+    // Connections waiting for a reply or forwarding close themselves when drained, the rest,
+    // eg. still authenticating, are dropped once those have had the chance to
+    tokio::select! {
+        res = handled => res,
+        () = async {
+            drained.drained().await;
+            tokio::time::sleep(DRAIN_TIMEOUT).await;
+        } => {
+            debug!("connection closed by shutdown");
+            Ok(())
+        }
+    }
+}
+
+/// How long drained connections are given to send their reply and close.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// First byte of a TLS handshake record, a client's ClientHello.
+const TLS_HANDSHAKE: u8 = 0x16;
+/// Major version byte of every TLS record's protocol version.
+const TLS_MAJOR_VERSION: u8 = 0x03;
+
+/// Handles a connection accepted from a listener, completing the TLS handshake first when TLS
+/// is configured. With `tls-auto-detect` only connections starting with a TLS record are
+/// treated as TLS and any others as plaintext SOCKS, otherwise every client must use TLS.
+pub(crate) async fn accept(
+    client_conn: impl AsyncRead + AsyncWrite + Unpin,
+    peer_addr: PeerAddr,
+    ctx: Context,
+) -> anyhow::Result<()> {
+    let Some(tls) = ctx.tls.clone() else {
+        return handle(client_conn, peer_addr, None, ctx).await;
+    };
+
+    let mut client_conn = BufReader::new(client_conn);
+    if ctx.config.tls_auto_detect && !is_tls_record(client_conn.fill_buf().await?) {
+        debug!("connection doesn't start with a TLS record, handling as plaintext");
+        return handle(client_conn, peer_addr, None, ctx).await;
+    }
+
+    let tls_conn = tls
+        .accept(client_conn)
+        .await
+        .context("TLS handshake failed")?;
+    let identity = tls::client_identity(tls_conn.get_ref().1);
+    handle(tls_conn, peer_addr, identity, ctx).await
+}
+
+/// Whether `buf`, the start of a connection, is a TLS handshake record. Neither SOCKS version
+/// starts with the handshake byte, so that alone is enough when it's all that's arrived yet.
+fn is_tls_record(buf: &[u8]) -> bool {
+    matches!(
+        buf,
+        [TLS_HANDSHAKE] | [TLS_HANDSHAKE, TLS_MAJOR_VERSION, ..]
+    )
+}
+
+/// Handles a single client connection, `client_conn` may be plain TCP or already decrypted TLS.
+/// `identity` is who the client authenticated as while connecting, eg. with a TLS client
+/// certificate, and takes the place of a SOCKS5 username.
 pub(crate) async fn handle(
-    client_conn: tokio::net::TcpStream,
-    kube_client: Client,
+    client_conn: impl AsyncRead + AsyncWrite + Unpin,
+    peer_addr: PeerAddr,
+    identity: Option<String>,
+    ctx: Context,
 ) -> anyhow::Result<()> {
-    let mut buf = [0x0_u8; 1];
-    client_conn.peek(&mut buf).await?;
+    let _slot = match ctx.connection_slot().await {
+        Ok(slot) => slot,
+        Err(e) => {
+            let resolver = FullResolver(Some(e));
+            return handle_with(client_conn, peer_addr, identity, ctx, resolver).await;
+        }
+    };
 
-    let ver = buf[0];
+    match ctx.kube_client {
+        Some(ref kube_client) => {
+            let resolver =
+                PodResolver::new(ctx.clone(), kube_client.clone()).for_client(&peer_addr);
+            handle_with(client_conn, peer_addr, identity, ctx, resolver).await
+        }
+        None => {
+            let resolver = StaticResolver::new(ctx.config.clone());
+            handle_with(client_conn, peer_addr, identity, ctx, resolver).await
+        }
+    }
+}
+
+/// Handles a client connection, opening the stream it asks for with `resolver`.
+pub(crate) async fn handle_with(
+    client_conn: impl AsyncRead + AsyncWrite + Unpin,
+    peer_addr: PeerAddr,
+    identity: Option<String>,
+    ctx: Context,
+    mut resolver: impl Resolver,
+) -> anyhow::Result<()> {
+    // Buffered so the first bytes can be inspected without consuming them, which works for any
+    // stream unlike `TcpStream::peek`
+    let mut client_conn = BufReader::new(client_conn);
+    let buf: Vec<u8> = client_conn
+        .fill_buf()
+        .await?
+        .iter()
+        .take(8)
+        .copied()
+        .collect();
+
+    let ver = buf.first().copied().unwrap_or_default();
 
     debug!("handling connection with version {}", ver);
 
-    let mut resolver = PodResolver::new(kube_client);
+    let conn = ctx.registry.register(peer_addr.clone());
+    ctx.metrics.record_connection(peer_addr.ip());
+    let mut attempt = Attempt::new(ctx.audit.clone(), conn.id(), peer_addr);
+
+    if let Some(ref identity) = identity {
+        Span::current().record("identity", identity.as_str());
+        attempt.username = Some(identity.clone());
+    }
+    resolver.authenticated(identity.as_deref());
 
     let res = match ver {
-        v4::VERSION => handle_v4(client_conn, &mut resolver).await,
-        v5::VERSION => handle_v5(client_conn, &mut resolver).await,
-        _ => Err(Errors::UnsupportedVersion(ver).into()),
+        // SOCKS4 has no authentication, so it's closed without reading the request
+        SOCKS4_VERSION if !cfg!(feature = "socks4") || ctx.config.disable_socks4 => {
+            warn!("socks4 client connected, but it is disabled, closing");
+            attempt.protocol = Some("socks4");
+            attempt.outcome(Outcome::Rejected, SOCKS4_DISABLED);
+            Ok(())
+        }
+        #[cfg(feature = "socks4")]
+        SOCKS4_VERSION => handle_v4(client_conn, &ctx, &conn, &mut attempt, &mut resolver).await,
+        v5::VERSION => {
+            let identity = identity.as_deref();
+            handle_v5(
+                client_conn,
+                &ctx,
+                &conn,
+                &mut attempt,
+                identity,
+                &mut resolver,
+            )
+            .await
+        }
+        _ => match detect_http(&buf) {
+            Some(method) => handle_http(client_conn, method).await,
+            None => Err(Errors::UnsupportedVersion(ver).into()),
+        },
     };
 
+    let res = finish(&ctx, &mut attempt, res);
+
     resolver.join().await?;
     res?;
 
     Ok(())
 }
 
+/// Fails the client's request with why there's no room for its connection, so it gets the
+/// usual failure reply of its protocol before being closed.
+struct FullResolver(Option<resolver::Errors>);
+
+impl Resolver for FullResolver {
+    async fn forwarder(
+        &mut self,
+        _: Destination<'_>,
+        _: u16,
+    ) -> Result<(Target, Box<dyn PodStream>), resolver::Errors> {
+        Err(self.0.take().expect("one request per connection"))
+    }
+
+    async fn forward_closed(&mut self) -> Option<String> {
+        futures::future::pending().await
+    }
+
+    async fn join(self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records how handling a connection ended, for outcomes the handler didn't record itself.
+fn finish(ctx: &Context, attempt: &mut Attempt, res: anyhow::Result<()>) -> anyhow::Result<()> {
+    // A client hanging up before reading a reply is routine, not worth an error in the logs
+    let res = match res {
+        Err(e) if matches!(e.downcast_ref(), Some(Errors::ClientGone(_))) => {
+            debug!(error = ?e, "client disconnected before reading the reply");
+            attempt.outcome(Outcome::Disconnected, &e);
+            Ok(())
+        }
+        res => res,
+    };
+
+    // Covers protocol and IO errors, the handlers record every other outcome themselves
+    match res {
+        Ok(()) => attempt.outcome(Outcome::Error, "ended without an outcome"),
+        Err(ref e) => match error_code(e) {
+            Some(code) => record_failure(ctx, attempt, Outcome::Error, code, e),
+            None => attempt.outcome(Outcome::Error, e),
+        },
+    }
+
+    res
+}
+
+/// Longest client supplied correlation id kept, longer ones are truncated.
+const MAX_CORRELATION_ID_LEN: usize = 128;
+
+/// Attaches the correlation id a client sent in the SOCKS4 user id or SOCKS5 username to the
+/// connection's span, so every log line for the connection carries it, and to the audit record.
+fn correlate(attempt: &mut Attempt, raw: &str) {
+    if let Some(id) = correlation_id(raw) {
+        Span::current().record("correlation_id", &id);
+        attempt.correlation_id = Some(id);
+    }
+}
+
+/// `raw` without control characters, which could forge log lines, if anything is left.
+fn correlation_id(raw: &str) -> Option<String> {
+    let id: String = raw
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_CORRELATION_ID_LEN)
+        .collect();
+
+    (!id.is_empty()).then_some(id)
+}
+
+/// BIND asks the proxy to accept a connection the destination makes back to it, eg. for active
+/// FTP. Port-forwards only carry connections into a pod, the pod has no route to a listener on
+/// the proxy, so there is never a second connection to relay and BIND is rejected outright
+/// rather than replying with a bound address nothing can reach.
+pub(crate) const BIND_UNSUPPORTED: &str =
+    "bind is not supported, pods can't connect back through a port-forward";
+
+const PORT_NOT_ALLOWED: &str = "port not allowed by allow-port/deny-port";
+
+const NO_AUTH_METHODS_OFFERED: &str = "auth failed, client offered no auth methods";
+
+/// The first byte of a SOCKS4 request, known even when built without the `socks4` feature so
+/// those clients can still be turned away.
+const SOCKS4_VERSION: u8 = 4;
+
+#[cfg(feature = "socks4")]
+const SOCKS4_DISABLED: &str = "socks4 is disabled by disable-socks4";
+#[cfg(not(feature = "socks4"))]
+const SOCKS4_DISABLED: &str = "socks4 support isn't built in";
+
+const HTTP_METHODS: &[&str] = &[
+    "CONNECT", "DELETE", "GET", "HEAD", "OPTIONS", "PATCH", "POST", "PUT", "TRACE",
+];
+
+const HTTP_NOT_SOCKS_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\n\
+Content-Type: text/plain\r\n\
+Connection: close\r\n\
+\r\n\
+This is a SOCKS4a/SOCKS5 proxy, configure your client to use it as a SOCKS proxy.\r\n";
+
+/// Recognises the start of an HTTP request line, ie. a proxy client configured for HTTP
+fn detect_http(buf: &[u8]) -> Option<&'static str> {
+    HTTP_METHODS.iter().copied().find(|m| {
+        let prefix = &buf[..buf.len().min(m.len() + 1)];
+        prefix.len() > 1 && [m.as_bytes(), b" "].concat().starts_with(prefix)
+    })
+}
+
+async fn handle_http(
+    mut client_conn: impl AsyncRead + AsyncWrite + Unpin,
+    method: &'static str,
+) -> anyhow::Result<()> {
+    client_conn.write_all(HTTP_NOT_SOCKS_RESPONSE).await?;
+    client_conn.flush().await?;
+
+    Err(Errors::HttpRequest(method).into())
+}
+
+#[cfg(feature = "socks4")]
 async fn handle_v4(
     mut client_conn: impl AsyncRead + AsyncWrite + Unpin,
-    resolver: &mut PodResolver,
+    ctx: &Context,
+    conn: &Connection,
+    attempt: &mut Attempt,
+    resolver: &mut impl Resolver,
 ) -> anyhow::Result<()> {
-    let _ver = client_conn.read_u8().await?;
+    let req = client_conn.receive::<v4::Request>().await?;
+    let (dest_port, dest_addr) = (req.dest_port, req.dest_ip);
 
-    let method = client_conn.read_u8().await?;
-    let mut dest_addr: [u8; 4] = [0; 4];
-    let dest_port = client_conn.read_u16().await?;
-    client_conn.read_exact(&mut dest_addr).await?;
+    correlate(attempt, &req.user_id);
+    attempt.protocol = Some(if req.hostname.is_some() {
+        "socks4a"
+    } else {
+        "socks4"
+    });
+    attempt.requested(
+        req.hostname
+            .clone()
+            .unwrap_or_else(|| Ipv4Addr::from(dest_addr).to_string()),
+        dest_port,
+    );
 
-    if method == v4::METHOD_BIND {
-        warn!("client requested bind, rejecting");
+    if req.command == v4::METHOD_BIND {
+        warn!(
+            reason = BIND_UNSUPPORTED,
+            "client requested bind, rejecting"
+        );
+        attempt.outcome(Outcome::Rejected, BIND_UNSUPPORTED);
         client_conn
-            .write_all(&v4::Response::rejected_or_failed(dest_port, dest_addr).to_buf())
+            .send(v4::Response::rejected_or_failed(dest_port, dest_addr))
             .await?;
 
         return Ok(());
     }
-    if method != v4::METHOD_CONNECT {
+    if req.command != v4::METHOD_CONNECT {
         warn!("client requested unknown method, rejecting");
+        attempt.outcome(
+            Outcome::Rejected,
+            format!("unknown command {}", req.command),
+        );
         client_conn
-            .write_all(&v4::Response::rejected_or_failed(dest_port, dest_addr).to_buf())
+            .send(v4::Response::rejected_or_failed(dest_port, dest_addr))
             .await?;
 
         return Ok(());
     }
 
-    // Read unused userid block
-    discard_until_null(&mut client_conn).await?;
+    if !ctx.config.port_allowed(dest_port) {
+        warn!(port = dest_port, "port not allowed, rejecting");
+        attempt.outcome(Outcome::Rejected, PORT_NOT_ALLOWED);
+        client_conn
+            .send(v4::Response::rejected_or_failed(dest_port, dest_addr))
+            .await?;
 
-    if dest_addr == v4::SOCKS4A_ADDRESS {
-        let addr = read_until_null(&mut client_conn).await?;
-        info!(
-            port = dest_port,
-            addr, "client requested 4a - we should be able to handle this"
-        );
+        return Ok(());
+    }
 
-        let mut pod_stream = match resolver.forwarder(addr.as_str(), dest_port).await {
-            Ok(s) => s,
-            Err(e) => {
-                warn!(error = ?e, "failed to resolve and open forward stream");
-                client_conn
-                    .write_all(&v4::Response::rejected_or_failed(dest_port, dest_addr).to_buf())
-                    .await?;
-                return Ok(());
-            }
-        };
+    let destination = match req.hostname {
+        Some(ref addr) => {
+            info!(port = dest_port, addr, "client requested 4a");
+            Destination::Dns(addr.as_str())
+        }
+        None => {
+            let ip = Ipv4Addr::from(dest_addr);
+            info!(port = dest_port, %ip, "client requested 4");
+            Destination::Ip(ip.into())
+        }
+    };
 
-        client_conn
-            .write_all(&v4::Response::granted(dest_port, dest_addr).to_buf())
-            .await?;
+    let mut early = Vec::new();
+    let forwarder = until_disconnect(
+        &mut client_conn,
+        &mut early,
+        ctx.unless_drained(resolver.forwarder(destination, dest_port)),
+    );
+    let pod_stream = match forwarder.await {
+        None => {
+            debug!("client disconnected before forward was established");
+            attempt.outcome(Outcome::Disconnected, "");
+            return Ok(());
+        }
+        Some(Ok((target, s))) => {
+            conn.set_target(&target);
+            let mut s = conn.count(s);
+            let confirmed = confirm_forward(
+                &ctx.config,
+                &attempt.peer_addr,
+                &target,
+                &mut s,
+                &early,
+                resolver,
+            )
+            .await;
+            attempt.target = Some(target);
+            confirmed.map(|()| s)
+        }
+        Some(Err(e)) => Err(e),
+    };
+    let mut pod_stream = match pod_stream {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(error = ?e, "failed to resolve and open forward stream");
+            record_failure(ctx, attempt, Outcome::Failed, e.code(), &e);
+            client_conn
+                .send(v4::Response::rejected_or_failed(dest_port, dest_addr))
+                .await?;
+            return Ok(());
+        }
+    };
+    attempt.outcome(Outcome::Forwarded, "");
+    let _watching = attempt.target.as_ref().and_then(|t| ctx.watch(t));
 
-        tokio::io::copy_bidirectional(&mut client_conn, &mut pod_stream).await?;
-        drop(pod_stream);
-    } else {
-        warn!(
-            ?dest_port,
-            ?dest_addr,
-            "client requested version 4, rejecting"
-        );
+    client_conn
+        .send(v4::Response::granted(dest_port, dest_addr))
+        .await?;
 
-        client_conn
-            .write_all(&v4::Response::rejected_or_failed(dest_port, dest_addr).to_buf())
-            .await?;
-    }
+    pipe(
+        &mut client_conn,
+        &mut pod_stream,
+        resolver,
+        &ctx.config,
+        ctx.drained(),
+    )
+    .await?;
+    drop(pod_stream);
 
     client_conn.flush().await?;
     Ok(())
@@ -106,21 +672,32 @@ async fn handle_v4(
 
 async fn handle_v5(
     mut client: impl AsyncRead + AsyncWrite + Unpin,
-    resolver: &mut PodResolver,
+    ctx: &Context,
+    conn: &Connection,
+    attempt: &mut Attempt,
+    identity: Option<&str>,
+    resolver: &mut impl Resolver,
 ) -> anyhow::Result<()> {
-    let auth_request = client.receive::<v5::AuthRequest>().await?;
+    attempt.protocol = Some("socks5");
 
-    if !auth_request.contains(&v5::AuthMethods::NotRequired) {
-        client.send(v5::AuthResponse::none()).await?;
+    if !authenticate_v5(
+        &mut client,
+        &ctx.config,
+        &ctx.credentials,
+        attempt,
+        identity,
+    )
+    .await?
+    {
         return Ok(());
     }
-
-    client.send(v5::AuthResponse::not_required()).await?;
+    resolver.authenticated(attempt.username.as_deref());
 
     let req = match client.receive::<v5::CommandRequest>().await {
         Ok(c) => Ok(c),
         Err(v5::ParseError::ProtocolError(e)) => {
             error!(error = ?e, "command parse failed");
+            attempt.outcome(Outcome::Error, &e);
             let resp: v5::ConnectResponse = e.into();
             client.send(resp).await?;
             return Ok(());
@@ -130,108 +707,458 @@ async fn handle_v5(
 
     info!(request = ?req, "valid v5 command");
 
-    if req.command != v5::Command::Connect {
-        warn!(?req.command, "unsupported command");
+    attempt.requested(
+        match req.address {
+            v5::Address::IpAddr(ip) => ip.to_string(),
+            v5::Address::Dns(ref a) => a.clone(),
+        },
+        req.port,
+    );
+
+    if let Some(reason) = req.command.unsupported_reason() {
+        warn!(?req.command, reason, "unsupported command, rejecting");
+        attempt.outcome(Outcome::Rejected, reason);
         client
             .send(v5::ConnectResponse::unsupported_command())
             .await?;
         return Ok(());
     }
 
-    let address = match req.address {
-        v5::Address::IpAddr(_) => {
-            warn!(?req.address, "unsupported address");
-            client
-                .send(v5::ConnectResponse::unsupported_command())
-                .await?;
+    if !ctx.config.port_allowed(req.port) {
+        warn!(port = req.port, "port not allowed, rejecting");
+        attempt.outcome(Outcome::Rejected, PORT_NOT_ALLOWED);
+        client.send(v5::ConnectResponse::not_allowed()).await?;
+        return Ok(());
+    }
+
+    let destination = match req.address {
+        v5::Address::IpAddr(ip) => Destination::Ip(ip),
+        v5::Address::Dns(ref a) => Destination::Dns(a.as_str()),
+    };
+
+    let mut early = Vec::new();
+    let forwarder = until_disconnect(
+        &mut client,
+        &mut early,
+        ctx.unless_drained(resolver.forwarder(destination, req.port)),
+    );
+    let forwarded = match forwarder.await {
+        None => {
+            debug!("client disconnected before forward was established");
+            attempt.outcome(Outcome::Disconnected, "");
             return Ok(());
         }
-        v5::Address::Dns(ref a) => a.clone(),
+        Some(Ok((target, s))) => {
+            conn.set_target(&target);
+            let mut s = conn.count(s);
+            attempt.target = Some(target.clone());
+            confirm_forward(
+                &ctx.config,
+                &attempt.peer_addr,
+                &target,
+                &mut s,
+                &early,
+                resolver,
+            )
+            .await
+            .map(|()| (target, s))
+        }
+        Some(Err(e)) => Err(e),
     };
-
-    let mut pod_stream = match resolver.forwarder(address.as_str(), req.port).await {
-        Ok(s) => s,
+    let (target, mut pod_stream) = match forwarded {
+        Ok(f) => f,
         Err(e) => {
             warn!(error = ?e, "failed to resolve and open forward stream");
+            record_failure(ctx, attempt, Outcome::Failed, e.code(), &e);
+            let reply = ctx.config.error_reply(e.kind());
             client
-                .send(match e {
-                    resolver::Errors::PodNotFound {
-                        namespace: _,
-                        pod: _,
-                    } => v5::ConnectResponse::host_unreachable(req.address, req.port),
-                    resolver::Errors::ServiceNotFound {
-                        namespace: _,
-                        service: _,
-                    } => v5::ConnectResponse::host_unreachable(req.address, req.port),
-                    resolver::Errors::NamedServicePodsNotFound {
-                        namespace: _,
-                        service: _,
-                        pod: _,
-                    } => v5::ConnectResponse::host_unreachable(req.address, req.port),
-                    resolver::Errors::PortNotFound(_, _, _) => {
-                        v5::ConnectResponse::connection_refused(req.address, req.port)
-                    }
-                    resolver::Errors::UnsupportedAddress(_) => {
-                        v5::ConnectResponse::unsupported_address()
-                    }
-                    resolver::Errors::ForwardFailed(_) => v5::ConnectResponse::geneal_failure(),
-                    resolver::Errors::LookupFailed(_) => v5::ConnectResponse::geneal_failure(),
-                    resolver::Errors::ServiceInvalid {
-                        namespace: _,
-                        service: _,
-                        reason: _,
-                    } => v5::ConnectResponse::geneal_failure(),
-                    resolver::Errors::ServiceNoReadyPods {
-                        namespace: _,
-                        service: _,
-                    } => v5::ConnectResponse::connection_refused(req.address, req.port),
-                })
+                .send(error_response(reply, req.address, req.port))
                 .await?;
             return Ok(());
         }
     };
 
+    info!(?target, "forwarding");
+    let _watching = ctx.watch(&target);
+    attempt.outcome(Outcome::Forwarded, "");
+
     client
-        .send(v5::ConnectResponse::success(req.address, req.port))
+        .send(success_reply(
+            req.address,
+            req.port,
+            &target,
+            ctx.config.reply_address,
+        ))
         .await?;
 
-    tokio::io::copy_bidirectional(&mut client, &mut pod_stream).await?;
+    pipe(
+        &mut client,
+        &mut pod_stream,
+        resolver,
+        &ctx.config,
+        ctx.drained(),
+    )
+    .await?;
     drop(pod_stream);
 
     Ok(())
 }
 
-async fn discard_until_null(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<()> {
-    while stream.read_u8().await? != 0 {}
+/// The failure reply for a request for `address` and `port`.
+fn error_response(reply: ErrorReply, address: v5::Address, port: u16) -> v5::ConnectResponse {
+    match reply {
+        ErrorReply::GeneralFailure => v5::ConnectResponse::geneal_failure(),
+        ErrorReply::NotAllowed => v5::ConnectResponse::not_allowed(),
+        ErrorReply::NetworkUnreachable => v5::ConnectResponse::network_unreachable(address, port),
+        ErrorReply::HostUnreachable => v5::ConnectResponse::host_unreachable(address, port),
+        ErrorReply::ConnectionRefused => v5::ConnectResponse::connection_refused(address, port),
+        ErrorReply::TtlExpired => v5::ConnectResponse::ttl_expired(),
+        ErrorReply::AddressNotSupported => v5::ConnectResponse::unsupported_address(),
+    }
+}
+
+/// The success reply for `target`, requested as `address` and `port`.
+fn success_reply(
+    address: v5::Address,
+    port: u16,
+    target: &Target,
+    reply_address: ReplyAddress,
+) -> v5::ConnectResponse {
+    match (address, reply_address) {
+        (address @ v5::Address::Dns(_), ReplyAddress::Requested) => {
+            v5::ConnectResponse::success(address, port)
+        }
+        _ => v5::ConnectResponse::success(
+            target.pod_ip.unwrap_or(Ipv4Addr::UNSPECIFIED.into()).into(),
+            target.port,
+        ),
+    }
+}
+
+/// Copies between the client and pod until either side closes, or the forwarder stops so that
+/// clients aren't left idling on a tunnel that is already dead, or `max-connection-lifetime`
+/// runs out, or `drained` completes. Each direction is capped at `rate-limit` and copied through
+/// a `buffer-size` buffer.
+async fn pipe(
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    pod_stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    resolver: &mut impl Resolver,
+    config: &Config,
+    drained: impl std::future::Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let mut pod_stream = Throttled::new(pod_stream, config.rate_limit);
+    let max_lifetime = Duration::from_secs(config.max_connection_lifetime);
+    let mut close = false;
+
+    tokio::select! {
+        res = copy_bidirectional(client, &mut pod_stream, config.buffer_size) => {
+            let closed = res?;
+            info!(
+                closed_by = %closed.first,
+                bytes_to_pod = closed.to_pod,
+                bytes_to_client = closed.to_client,
+                "connection closed"
+            );
+        }
+        reason = resolver.forward_closed() => match reason {
+            Some(reason) => warn!(reason, "forward failed, closing client connection"),
+            None => debug!("forward closed, closing client connection"),
+        },
+        _ = lifetime_elapsed(max_lifetime) => {
+            info!(?max_lifetime, "connection reached max-connection-lifetime, closing");
+            close = true;
+        }
+        () = drained => {
+            warn!("proxy is shutting down, closing connection");
+            close = true;
+        }
+    }
+
+    // Close both sides cleanly rather than just dropping them mid-stream
+    if close {
+        let _ = client.shutdown().await;
+        let _ = pod_stream.shutdown().await;
+    }
+
     Ok(())
 }
 
-async fn read_until_null(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<String> {
-    let mut resp: String = String::new();
+/// Completes once `lifetime` has passed, never if it's zero.
+async fn lifetime_elapsed(lifetime: Duration) {
+    match lifetime.is_zero() {
+        true => futures::future::pending().await,
+        false => tokio::time::sleep(lifetime).await,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Client,
+    Pod,
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Side::Client => "client",
+            Side::Pod => "pod",
+        })
+    }
+}
+
+impl Side {
+    fn error(self, e: std::io::Error) -> Errors {
+        match self {
+            Side::Client => Errors::ClientConnection(e),
+            Side::Pod => Errors::PodConnection(e),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Closed {
+    /// Which side finished sending first
+    first: Side,
+    to_pod: u64,
+    to_client: u64,
+}
+
+/// Like `tokio::io::copy_bidirectional`, but keeps track of which side closed first and which
+/// side any error came from. Each direction reads up to `buffer_size` bytes at a time.
+async fn copy_bidirectional(
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    pod_stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    buffer_size: usize,
+) -> Result<Closed, Errors> {
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut pod_read, mut pod_write) = tokio::io::split(pod_stream);
+
+    let to_pod = copy_half(&mut client_read, &mut pod_write, Side::Client, buffer_size);
+    let to_client = copy_half(&mut pod_read, &mut client_write, Side::Pod, buffer_size);
+    tokio::pin!(to_pod, to_client);
+
+    let (mut first, mut to_pod_bytes, mut to_client_bytes) = (None, None, None);
+    while to_pod_bytes.is_none() || to_client_bytes.is_none() {
+        tokio::select! {
+            res = &mut to_pod, if to_pod_bytes.is_none() => {
+                to_pod_bytes = Some(res?);
+                first.get_or_insert(Side::Client);
+            }
+            res = &mut to_client, if to_client_bytes.is_none() => {
+                to_client_bytes = Some(res?);
+                first.get_or_insert(Side::Pod);
+            }
+        }
+    }
+
+    Ok(Closed {
+        first: first.unwrap_or(Side::Client),
+        to_pod: to_pod_bytes.unwrap_or_default(),
+        to_client: to_client_bytes.unwrap_or_default(),
+    })
+}
+
+/// Copies everything `from` sends until it finishes, then shuts down the other side's writer.
+/// Nothing more is read until what was read has been written, so a side that stops keeping up
+/// holds back the other through its own flow control, rather than `buffer_size` being exceeded.
+async fn copy_half(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    from: Side,
+    buffer_size: usize,
+) -> Result<u64, Errors> {
+    let to = match from {
+        Side::Client => Side::Pod,
+        Side::Pod => Side::Client,
+    };
+
+    let mut buf = vec![0_u8; buffer_size];
+    let mut total = 0;
+    loop {
+        let read = reader.read(&mut buf).await.map_err(|e| from.error(e))?;
+        if read == 0 {
+            break;
+        }
+
+        writer
+            .write_all(&buf[..read])
+            .await
+            .map_err(|e| to.error(e))?;
+        writer.flush().await.map_err(|e| to.error(e))?;
+        total += read as u64;
+    }
+
+    debug!(side = %from, bytes = total, "finished sending");
+    writer.shutdown().await.map_err(|e| to.error(e))?;
+
+    Ok(total)
+}
+
+/// Most bytes buffered from a client that starts sending before its forward is established.
+const MAX_EARLY_DATA: usize = 64 * 1024;
+
+/// Drives `fut` to completion unless `client` disconnects first, in which case `fut` is dropped
+/// and `None` returned. Anything the client sends in the meantime is appended to `early`.
+async fn until_disconnect<F: std::future::Future>(
+    client: &mut (impl AsyncRead + Unpin),
+    early: &mut Vec<u8>,
+    fut: F,
+) -> Option<F::Output> {
+    tokio::pin!(fut);
 
-    let mut next: u8 = stream.read_u8().await?;
-    while next != 0 {
-        resp.push(next.into());
-        next = stream.read_u8().await?;
+    let mut buf = [0_u8; 1024];
+    while early.len() < MAX_EARLY_DATA {
+        tokio::select! {
+            res = &mut fut => return Some(res),
+            read = client.read(&mut buf) => match read {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => early.extend_from_slice(&buf[..n]),
+            },
+        }
     }
 
-    Ok(resp)
+    Some(fut.await)
+}
+
+/// Checks a newly opened forward is still usable before the client is told it succeeded, also
+/// handing the pod whatever the client sent early, after a PROXY protocol header with
+/// `send-proxy-protocol`. A forward that died in the meantime then gets a failure reply, rather
+/// than the client seeing success followed straight away by a reset.
+async fn confirm_forward(
+    config: &Config,
+    peer_addr: &PeerAddr,
+    target: &Target,
+    pod_stream: &mut (impl AsyncWrite + Unpin),
+    early: &[u8],
+    resolver: &mut impl Resolver,
+) -> Result<(), resolver::Errors> {
+    if let Some(reason) = resolver.forward_closed().now_or_never() {
+        return Err(target.forward_closed(reason.as_deref().unwrap_or("closed by the pod")));
+    }
+
+    // HTTP relays re-send requests, a header would only corrupt the first
+    let mut preamble = match config.send_proxy_protocol {
+        Some(version) if !config.api_proxy_ports.contains(&target.port) => {
+            proxy_protocol::header(version, peer_addr, target)
+        }
+        _ => vec![],
+    };
+    preamble.extend_from_slice(early);
+
+    pod_stream
+        .write_all(&preamble)
+        .await
+        .map_err(|e| target.forward_closed(e))
+}
+
+/// Negotiates and performs SOCKS5 authentication, returns `false` if the client was rejected.
+/// A client that already has an `identity` needn't authenticate again.
+async fn authenticate_v5(
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    config: &Config,
+    credentials: &Credentials,
+    attempt: &mut Attempt,
+    identity: Option<&str>,
+) -> anyhow::Result<bool> {
+    let auth_request = client.receive::<v5::AuthRequest>().await?;
+    // Nothing to negotiate, but said apart from a client whose methods just aren't accepted
+    if auth_request.is_empty() {
+        info!("client offered no auth methods at all, closing");
+        attempt.outcome(Outcome::Rejected, NO_AUTH_METHODS_OFFERED);
+        client.send(v5::AuthResponse::none()).await?;
+        return Ok(false);
+    }
+
+    let mut preferred: Vec<v5::AuthMethods> = config
+        .auth_methods
+        .iter()
+        .map(|m| match m {
+            AuthMethod::NotRequired => v5::AuthMethods::NotRequired,
+            AuthMethod::UserPass => v5::AuthMethods::Basic,
+        })
+        .collect();
+    if let Some(identity) = identity {
+        debug!(identity, "authenticated by client certificate");
+        preferred.insert(0, v5::AuthMethods::NotRequired);
+    }
+
+    match auth_request.select(&preferred) {
+        Some(v5::AuthMethods::NotRequired) => {
+            client.send(v5::AuthResponse::not_required()).await?;
+            Ok(true)
+        }
+        Some(v5::AuthMethods::Basic) => {
+            client
+                .send(v5::AuthResponse::selected(v5::AuthMethods::Basic))
+                .await?;
+
+            let req = client.receive::<v5::UserPassRequest>().await?;
+            // The identity a client already authenticated as is the one that's audited
+            attempt.username.get_or_insert_with(|| req.username.clone());
+            correlate(attempt, &req.username);
+            if credentials.verify(&req.username, &req.password) {
+                debug!(username = req.username, "authenticated");
+                client.send(v5::UserPassResponse::success()).await?;
+                Ok(true)
+            } else {
+                warn!(username = req.username, "authentication failed");
+                attempt.outcome(Outcome::Rejected, "authentication failed");
+                client.send(v5::UserPassResponse::failure()).await?;
+                Ok(false)
+            }
+        }
+        _ => {
+            warn!("client offered no acceptable auth methods");
+            attempt.outcome(Outcome::Rejected, "no acceptable auth methods");
+            client.send(v5::AuthResponse::none()).await?;
+            Ok(false)
+        }
+    }
 }
 
 pub(crate) trait Request {
     type Error;
-    async fn parse(stream: &mut (impl AsyncReadExt + Unpin)) -> Result<Self, Self::Error>
+    async fn parse(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, Self::Error>
     where
         Self: std::marker::Sized;
 }
 
+/// The other half of [`Request`], something sent back to the client.
+pub(crate) trait Response {
+    async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()>;
+}
+
+impl<R: Response + ?Sized> Response for &R {
+    async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+        (**self).write(stream).await
+    }
+}
+
+/// HTTP CONNECT responses, which are plain text
+impl Response for str {
+    async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+        stream.write_all(self.as_bytes()).await
+    }
+}
+
+impl Response for String {
+    async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+        self.as_str().write(stream).await
+    }
+}
+
 trait LocalAsyncReadWriteExt {
     async fn receive<M: Request>(&mut self) -> Result<M, M::Error>;
-    async fn send<'a, I: Into<Vec<u8>>>(&mut self, v: I) -> std::io::Result<()>;
+    async fn send(&mut self, v: impl Response) -> Result<(), Errors>;
 }
 impl<T: AsyncRead + AsyncWrite + Unpin> LocalAsyncReadWriteExt for T {
-    async fn send<'a, I: Into<Vec<u8>>>(&mut self, v: I) -> std::io::Result<()> {
-        self.write_all(&v.into()).await
+    async fn send(&mut self, v: impl Response) -> Result<(), Errors> {
+        v.write(self).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset => {
+                Errors::ClientGone(e)
+            }
+            _ => Errors::ClientConnection(e),
+        })
     }
 
     async fn receive<M: Request>(&mut self) -> Result<M, M::Error> {
@@ -243,4 +1170,60 @@ impl<T: AsyncRead + AsyncWrite + Unpin> LocalAsyncReadWriteExt for T {
 pub enum Errors {
     #[error("Unsupported version {0} requested")]
     UnsupportedVersion(u8),
+    #[error("Client sent an HTTP {0} request, it is likely configured to use an HTTP proxy")]
+    HttpRequest(&'static str),
+    #[error("Client connection failed: {0}")]
+    ClientConnection(#[source] std::io::Error),
+    #[error("Client disconnected before reading the reply: {0}")]
+    ClientGone(#[source] std::io::Error),
+    #[error("Pod connection failed: {0}")]
+    PodConnection(#[source] std::io::Error),
+}
+
+impl Errors {
+    /// A short identifier for metric labels and the audit log, which stays the same however the
+    /// message is worded.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Errors::UnsupportedVersion(_) => "unsupported_version",
+            Errors::HttpRequest(_) => "http_request",
+            Errors::ClientConnection(_) => "client_connection",
+            Errors::ClientGone(_) => "client_gone",
+            Errors::PodConnection(_) => "pod_connection",
+        }
+    }
+}
+
+/// The code of the first error in `e`'s chain that has one.
+fn error_code(e: &anyhow::Error) -> Option<&'static str> {
+    e.chain().find_map(|cause| {
+        if let Some(e) = cause.downcast_ref::<Errors>() {
+            Some(e.code())
+        } else if let Some(e) = cause.downcast_ref::<resolver::Errors>() {
+            Some(e.code())
+        } else if let Some(e) = cause.downcast_ref::<http_connect::Errors>() {
+            Some(e.code())
+        } else if let Some(e) = cause.downcast_ref::<v5::ParseError>() {
+            Some(e.code())
+        } else {
+            cause.downcast_ref::<v5::Errors>().map(v5::Errors::code)
+        }
+    })
 }
+
+/// Records `outcome` for a failure with error `code`, counting it in the metrics unless an
+/// earlier outcome was already recorded.
+fn record_failure(
+    ctx: &Context,
+    attempt: &mut Attempt,
+    outcome: Outcome,
+    code: &'static str,
+    reason: impl ToString,
+) {
+    if attempt.failed(outcome, code, reason) {
+        ctx.metrics.record_error(code);
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod;
+use kube::runtime::watcher::{self, Event};
+use kube::runtime::WatchStreamExt;
+use kube::Api;
+use tokio::task::AbortHandle;
+use tracing::{debug, info, info_span, Instrument};
+
+use crate::socks::kube_client::KubeClient;
+use crate::socks::resolver::Target;
+
+/// `(cluster, namespace, pod)`
+type Key = (Option<String>, String, String);
+
+/// Watches the pods that connections and prewarmed forwards currently go to, logging when one
+/// changes, is deleted or is replaced by a new pod of the same name, so dropped connections can
+/// be matched up with the pod's lifecycle. Each pod has its own watch selecting it by name,
+/// stopped once nothing forwards to it any more.
+#[derive(Default)]
+pub struct PodWatch {
+    watched: Mutex<HashMap<Key, Watched>>,
+}
+
+struct Watched {
+    /// How many [`Watching`]s are held for the pod
+    users: usize,
+    task: AbortHandle,
+}
+
+/// Keeps the pod watched until dropped.
+pub struct Watching {
+    pod_watch: Arc<PodWatch>,
+    key: Key,
+}
+
+impl Drop for Watching {
+    fn drop(&mut self) {
+        let mut watched = self.pod_watch.watched.lock().unwrap();
+        if let Some(w) = watched.get_mut(&self.key) {
+            w.users -= 1;
+            if w.users == 0 {
+                w.task.abort();
+                watched.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl PodWatch {
+    /// Watches the target's pod, through `kube_client` for its cluster, for as long as the
+    /// returned guard is held, sharing the watch with any other connection to the same pod.
+    pub fn watch(self: &Arc<Self>, target: &Target, kube_client: &KubeClient) -> Watching {
+        let key = (
+            target.cluster.clone(),
+            target.namespace.clone(),
+            target.pod.clone(),
+        );
+
+        let mut watched = self.watched.lock().unwrap();
+        watched
+            .entry(key.clone())
+            .and_modify(|w| w.users += 1)
+            .or_insert_with(|| {
+                let (ref cluster, ref namespace, ref pod) = key;
+                let api = Api::namespaced(kube_client.get(), namespace);
+                let span = info_span!("pod_watch", cluster, namespace, pod);
+                let task = tokio::spawn(log_changes(api, pod.clone()).instrument(span));
+                Watched {
+                    users: 1,
+                    task: task.abort_handle(),
+                }
+            });
+
+        Watching {
+            pod_watch: self.clone(),
+            key,
+        }
+    }
+}
+
+/// Logs changes to the pod `name` until aborted, restarting the watch with backoff when it fails.
+async fn log_changes(api: Api<Pod>, name: String) {
+    let config = watcher::Config::default().fields(&format!("metadata.name={name}"));
+    let mut events = watcher::watcher(api, config).default_backoff().boxed();
+    let mut churn = Churn::default();
+
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(event) => {
+                churn.observe(event);
+            }
+            Err(e) => debug!(error = ?e, "pod watch failed, retrying"),
+        }
+    }
+}
+
+/// What's been seen of a watched pod, to tell a pod being recreated from it being modified.
+#[derive(Default)]
+struct Churn {
+    /// `metadata.uid` of the pod last seen
+    uid: Option<String>,
+    /// Whether the pod was listed since the watch last (re)started
+    listed: bool,
+}
+
+/// What a watch event meant for the watched pod.
+#[derive(Debug, PartialEq, Eq)]
+enum Change {
+    Seen,
+    Modified,
+    Deleted,
+    Recreated,
+}
+
+impl Churn {
+    /// Logs what `event` changed about the pod.
+    fn observe(&mut self, event: Event<Pod>) -> Change {
+        let (change, pod) = match event {
+            Event::Init => {
+                self.listed = false;
+                return Change::Seen;
+            }
+            Event::InitApply(pod) => {
+                self.listed = true;
+                (self.applied(&pod, Change::Seen), Some(pod))
+            }
+            Event::Apply(pod) => (self.applied(&pod, Change::Modified), Some(pod)),
+            Event::Delete(_) => (self.deleted(), None),
+            // Not listed after the watch restarted, so it was deleted while the watch was down
+            Event::InitDone if !self.listed => (self.deleted(), None),
+            Event::InitDone => return Change::Seen,
+        };
+
+        let phase = pod
+            .as_ref()
+            .and_then(|p| p.status.as_ref())
+            .and_then(|s| s.phase.as_deref());
+        let deleting = pod
+            .as_ref()
+            .is_some_and(|p| p.metadata.deletion_timestamp.is_some());
+        let resource_version = pod
+            .as_ref()
+            .and_then(|p| p.metadata.resource_version.as_deref());
+
+        match change {
+            Change::Seen => {}
+            Change::Modified => info!(phase, deleting, resource_version, "target pod modified"),
+            Change::Deleted => info!("target pod deleted"),
+            Change::Recreated => info!(phase, resource_version, "target pod recreated"),
+        }
+        change
+    }
+
+    /// A pod by the watched name with a different uid to the last one seen is a new pod.
+    fn applied(&mut self, pod: &Pod, change: Change) -> Change {
+        let uid = pod.metadata.uid.clone();
+        match std::mem::replace(&mut self.uid, uid.clone()) {
+            Some(previous) if Some(&previous) != uid.as_ref() => Change::Recreated,
+            // Created again after being deleted
+            None if change == Change::Modified => Change::Recreated,
+            _ => change,
+        }
+    }
+
+    fn deleted(&mut self) -> Change {
+        self.listed = false;
+        match self.uid.take() {
+            Some(_) => Change::Deleted,
+            // Already logged, eg. a delete event followed by a relist without the pod
+            None => Change::Seen,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,153 @@
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::ObjectMeta;
+
+fn pod(uid: &str) -> Pod {
+    Pod {
+        metadata: ObjectMeta {
+            name: Some("web-0".into()),
+            uid: Some(uid.into()),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+mod watch {
+    use std::net::SocketAddr;
+
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use crate::fake_api;
+
+    fn kube_client(api_server: SocketAddr) -> KubeClient {
+        let client = kube::Client::try_from(kube::Config::new(
+            format!("http://{api_server}").parse().unwrap(),
+        ))
+        .unwrap();
+        KubeClient::new(client)
+    }
+
+    fn target(pod: &str) -> Target {
+        Target {
+            namespace: "default".into(),
+            pod: pod.into(),
+            port: 80,
+            pod_ip: None,
+            app_protocol: None,
+            cluster: None,
+            connect_timeout: None,
+            named_target_port: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_until_the_last_guard_is_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let kube_client = kube_client(listener.local_addr().unwrap());
+        let pod_watch = Arc::new(PodWatch::default());
+
+        let first = pod_watch.watch(&target("web-0"), &kube_client);
+        let second = pod_watch.watch(&target("web-0"), &kube_client);
+        let other = pod_watch.watch(&target("web-1"), &kube_client);
+        let other_cluster = pod_watch.watch(
+            &Target {
+                cluster: Some("prod".into()),
+                ..target("web-0")
+            },
+            &kube_client,
+        );
+        assert_eq!(pod_watch.watched.lock().unwrap().len(), 3);
+        drop(other_cluster);
+
+        drop(first);
+        assert_eq!(pod_watch.watched.lock().unwrap().len(), 2);
+        drop(second);
+        assert_eq!(pod_watch.watched.lock().unwrap().len(), 1);
+        drop(other);
+        assert!(pod_watch.watched.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn selects_only_the_target_pod() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let kube_client = kube_client(listener.local_addr().unwrap());
+        let pod_watch = Arc::new(PodWatch::default());
+
+        let _watching = pod_watch.watch(&target("web-0"), &kube_client);
+
+        let served = fake_api::respond(&listener, "500 Internal Server Error", "").await;
+
+        let path = served.path();
+        assert!(
+            path.starts_with("/api/v1/namespaces/default/pods?"),
+            "{path}"
+        );
+        assert!(
+            path.contains("fieldSelector=metadata.name%3Dweb-0"),
+            "{path}"
+        );
+    }
+}
+
+mod churn {
+    use super::super::*;
+    use super::*;
+
+    /// A `Churn` that's seen the watch start with `pod` listed.
+    fn listed(pod: Pod) -> Churn {
+        let mut churn = Churn::default();
+        assert_eq!(churn.observe(Event::Init), Change::Seen);
+        assert_eq!(churn.observe(Event::InitApply(pod)), Change::Seen);
+        assert_eq!(churn.observe(Event::InitDone), Change::Seen);
+        churn
+    }
+
+    #[test]
+    fn modified() {
+        let mut churn = listed(pod("a"));
+
+        assert_eq!(churn.observe(Event::Apply(pod("a"))), Change::Modified);
+    }
+
+    #[test]
+    fn deleted() {
+        let mut churn = listed(pod("a"));
+
+        assert_eq!(churn.observe(Event::Delete(pod("a"))), Change::Deleted);
+    }
+
+    #[test]
+    fn recreated_after_a_delete() {
+        let mut churn = listed(pod("a"));
+        churn.observe(Event::Delete(pod("a")));
+
+        assert_eq!(churn.observe(Event::Apply(pod("b"))), Change::Recreated);
+    }
+
+    #[test]
+    fn recreated_without_seeing_the_delete() {
+        let mut churn = listed(pod("a"));
+
+        assert_eq!(churn.observe(Event::Apply(pod("b"))), Change::Recreated);
+    }
+
+    #[test]
+    fn relisted_as_a_new_pod() {
+        let mut churn = listed(pod("a"));
+        churn.observe(Event::Init);
+
+        assert_eq!(churn.observe(Event::InitApply(pod("b"))), Change::Recreated);
+    }
+
+    #[test]
+    fn missing_from_a_relist() {
+        let mut churn = listed(pod("a"));
+        churn.observe(Event::Init);
+
+        assert_eq!(churn.observe(Event::InitDone), Change::Deleted);
+        // Found missing again on the next relist, but that was already logged
+        churn.observe(Event::Init);
+        assert_eq!(churn.observe(Event::InitDone), Change::Seen);
+    }
+}
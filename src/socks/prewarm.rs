@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::{debug, info_span, Instrument};
+
+use crate::socks::kube_client::KubeClient;
+use crate::socks::rate_limit::Key;
+use crate::socks::resolver::{Forward, PodResolver};
+use crate::socks::Context;
+
+/// Forwards opened ahead of time for `--prewarm` targets, so the first client connecting to
+/// one doesn't wait for a forward to be established. Each is handed to a single client and
+/// replaced in the background.
+#[derive(Default)]
+pub struct Prewarmed {
+    forwards: Mutex<HashMap<Key, Forward>>,
+}
+
+impl Prewarmed {
+    pub fn insert(&self, key: Key, forward: Forward) {
+        if let Some(old) = self.forwards.lock().unwrap().insert(key, forward) {
+            old.abort();
+        }
+    }
+
+    /// Takes the forward for `key`, unless there's none or the pod has since closed it.
+    pub fn take(&self, key: &Key) -> Option<Forward> {
+        let mut forward = self.forwards.lock().unwrap().remove(key)?;
+
+        if forward.is_closed() {
+            debug!(?key, "discarding closed prewarmed forward");
+            forward.abort();
+            return None;
+        }
+
+        Some(forward)
+    }
+}
+
+/// Starts opening every `--prewarm` forward in the background, failures are only logged.
+pub fn start(ctx: &Context, kube_client: &Arc<KubeClient>) {
+    let window = Duration::from_millis(ctx.config.prewarm_jitter_ms);
+    let targets = &ctx.config.prewarm;
+    let delays = spread(targets.len(), window, rand::random);
+
+    for (target, delay) in targets.iter().zip(delays) {
+        let resolver = PodResolver::new(ctx.clone(), kube_client.clone());
+        let target = target.clone();
+        let span = info_span!("prewarm", %target);
+
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(delay).await;
+                resolver.prewarm(&target).await
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// How long each of `count` prewarms waits before starting so they're spread across `window`,
+/// one at a random point in each equal slice of it so no two land together. `jitter` gives
+/// where in its slice each goes, from 0 to 1.
+fn spread(count: usize, window: Duration, mut jitter: impl FnMut() -> f64) -> Vec<Duration> {
+    if window.is_zero() {
+        return vec![Duration::ZERO; count];
+    }
+
+    let slice = window / count.max(1) as u32;
+    (0..count as u32)
+        .map(|i| slice * i + slice.mul_f64(jitter().clamp(0.0, 1.0)))
+        .collect()
+}
+
+/// A random wait of up to `window` before replacing a prewarmed forward, so forwards handed out
+/// together aren't all replaced together.
+pub fn refill_delay(window: Duration) -> Duration {
+    window.mul_f64(rand::random())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,51 @@
+mod spread {
+    use super::super::*;
+
+    #[test]
+    fn straight_away_without_a_window() {
+        assert_eq!(spread(3, Duration::ZERO, || 0.5), vec![Duration::ZERO; 3]);
+    }
+
+    #[test]
+    fn distributed_across_the_window() {
+        let window = Duration::from_secs(10);
+
+        let delays = spread(10, window, rand::random);
+
+        assert!(delays.iter().all(|d| *d <= window), "{delays:?}");
+        // Each in its own slice, so none coincide however the jitter falls
+        for (i, delay) in delays.iter().enumerate() {
+            let slice = Duration::from_secs(i as u64)..=Duration::from_secs(i as u64 + 1);
+            assert!(slice.contains(delay), "{i}: {delay:?}");
+        }
+    }
+
+    #[test]
+    fn same_jitter_still_apart() {
+        let delays = spread(4, Duration::from_secs(2), || 0.0);
+
+        assert_eq!(
+            delays,
+            [0, 500, 1000, 1500].map(Duration::from_millis).to_vec()
+        );
+    }
+
+    #[test]
+    fn nothing_to_spread() {
+        assert!(spread(0, Duration::from_secs(1), || 0.5).is_empty());
+    }
+}
+
+mod refill_delay {
+    use super::super::*;
+
+    #[test]
+    fn within_the_window() {
+        let window = Duration::from_millis(100);
+
+        for _ in 0..100 {
+            assert!(refill_delay(window) <= window);
+        }
+        assert_eq!(refill_delay(Duration::ZERO), Duration::ZERO);
+    }
+}
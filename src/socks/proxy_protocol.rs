@@ -0,0 +1,108 @@
+//! PROXY protocol headers sent ahead of the client's bytes with `--send-proxy-protocol`, so pods
+//! learn the client's address, which a port-forward otherwise hides.
+// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config::ProxyProtocol;
+use crate::listener::PeerAddr;
+use crate::socks::resolver::Target;
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+/// Version 2, the connection is proxied for the addresses that follow
+const V2_PROXY: u8 = 0x21;
+/// Version 2, the connection was made by the proxy itself, no addresses follow
+const V2_LOCAL: u8 = 0x20;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+const V2_UNSPEC: u8 = 0x00;
+
+/// The header for a connection from `client` to `target`. When either address isn't known, as
+/// for UNIX socket clients or pods without an IP, it says so rather than making one up.
+pub fn header(version: ProxyProtocol, client: &PeerAddr, target: &Target) -> Vec<u8> {
+    let addrs = match (client, target.pod_ip) {
+        (PeerAddr::Tcp(client), Some(pod_ip)) => {
+            Some(same_family(*client, SocketAddr::new(pod_ip, target.port)))
+        }
+        _ => None,
+    };
+
+    match version {
+        ProxyProtocol::V1 => v1(addrs),
+        ProxyProtocol::V2 => v2(addrs),
+    }
+}
+
+/// Both addresses in one family as the header requires, IPv4 mapped into IPv6 if they differ.
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v6 = |a: SocketAddr| match a.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), a.port()),
+        IpAddr::V6(_) => a,
+    };
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(_), IpAddr::V6(_)) | (IpAddr::V6(_), IpAddr::V4(_)) => (to_v6(src), to_v6(dst)),
+        _ => (src, dst),
+    }
+}
+
+fn v1(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let Some((src, dst)) = addrs else {
+        return b"PROXY UNKNOWN\r\n".to_vec();
+    };
+
+    let family = match src {
+        SocketAddr::V4(_) => "TCP4",
+        SocketAddr::V6(_) => "TCP6",
+    };
+    format!(
+        "PROXY {family} {} {} {} {}\r\n",
+        src.ip(),
+        dst.ip(),
+        src.port(),
+        dst.port()
+    )
+    .into_bytes()
+}
+
+fn v2(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+
+    let Some((src, dst)) = addrs else {
+        header.extend_from_slice(&[V2_LOCAL, V2_UNSPEC, 0, 0]);
+        return header;
+    };
+
+    let mut addresses = Vec::with_capacity(36);
+    let family = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            addresses.extend_from_slice(&src.octets());
+            addresses.extend_from_slice(&dst.octets());
+            V2_TCP4
+        }
+        (src, dst) => {
+            addresses.extend_from_slice(&to_v6_octets(src));
+            addresses.extend_from_slice(&to_v6_octets(dst));
+            V2_TCP6
+        }
+    };
+    addresses.extend_from_slice(&src.port().to_be_bytes());
+    addresses.extend_from_slice(&dst.port().to_be_bytes());
+
+    header.extend_from_slice(&[V2_PROXY, family]);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.append(&mut addresses);
+    header
+}
+
+fn to_v6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,129 @@
+use std::net::IpAddr;
+
+use crate::socks::resolver::Target;
+
+fn target(pod_ip: Option<IpAddr>) -> Target {
+    Target {
+        namespace: "apps".into(),
+        pod: "web-0".into(),
+        port: 8080,
+        pod_ip,
+        app_protocol: None,
+        cluster: None,
+        connect_timeout: None,
+        named_target_port: None,
+    }
+}
+
+mod v1 {
+    use super::super::*;
+    use super::target;
+
+    #[test]
+    fn tcp4() {
+        let client = PeerAddr::Tcp("192.0.2.20:51234".parse().unwrap());
+
+        let res = header(
+            ProxyProtocol::V1,
+            &client,
+            &target(Some([10, 0, 0, 7].into())),
+        );
+
+        assert_eq!(res, b"PROXY TCP4 192.0.2.20 10.0.0.7 51234 8080\r\n");
+    }
+
+    #[test]
+    fn tcp6() {
+        let client = PeerAddr::Tcp("[2001:db8::1]:51234".parse().unwrap());
+
+        let res = header(
+            ProxyProtocol::V1,
+            &client,
+            &target(Some("fd00::7".parse().unwrap())),
+        );
+
+        assert_eq!(res, b"PROXY TCP6 2001:db8::1 fd00::7 51234 8080\r\n");
+    }
+
+    #[test]
+    fn mixed_families_are_mapped_to_ipv6() {
+        let client = PeerAddr::Tcp("192.0.2.20:51234".parse().unwrap());
+
+        let res = header(
+            ProxyProtocol::V1,
+            &client,
+            &target(Some("fd00::7".parse().unwrap())),
+        );
+
+        assert_eq!(res, b"PROXY TCP6 ::ffff:192.0.2.20 fd00::7 51234 8080\r\n");
+    }
+
+    #[test]
+    fn unknown_addresses() {
+        let unix = header(
+            ProxyProtocol::V1,
+            &PeerAddr::Unix(None),
+            &target(Some([10, 0, 0, 7].into())),
+        );
+        let no_pod_ip = header(
+            ProxyProtocol::V1,
+            &PeerAddr::Tcp("192.0.2.20:51234".parse().unwrap()),
+            &target(None),
+        );
+
+        assert_eq!(unix, b"PROXY UNKNOWN\r\n");
+        assert_eq!(no_pod_ip, b"PROXY UNKNOWN\r\n");
+    }
+}
+
+mod v2 {
+    use super::super::*;
+    use super::target;
+
+    #[test]
+    fn tcp4() {
+        let client = PeerAddr::Tcp("192.0.2.20:51234".parse().unwrap());
+
+        let res = header(
+            ProxyProtocol::V2,
+            &client,
+            &target(Some([10, 0, 0, 7].into())),
+        );
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        expected.extend_from_slice(&[192, 0, 2, 20, 10, 0, 0, 7, 0xc8, 0x22, 0x1f, 0x90]);
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn tcp6() {
+        let client = PeerAddr::Tcp("[2001:db8::1]:51234".parse().unwrap());
+
+        let res = header(
+            ProxyProtocol::V2,
+            &client,
+            &target(Some("fd00::7".parse().unwrap())),
+        );
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        expected.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7]);
+        expected.extend_from_slice(&[0xc8, 0x22, 0x1f, 0x90]);
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn unknown_addresses_are_local() {
+        let res = header(
+            ProxyProtocol::V2,
+            &PeerAddr::Unix(None),
+            &target(Some([10, 0, 0, 7].into())),
+        );
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(res, expected);
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub type Key = (String, String, u16);
+
+/// Token bucket limiter for new port-forwards, keyed by `(namespace, pod, port)`.
+///
+/// Each attempt takes a token, only attempts that fail [`Token::spend`] it, so forwards that
+/// establish successfully, and attempts abandoned because the client went away, don't drain the
+/// bucket.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst: burst.into(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for an attempt to forward to `key`, or `None` if its bucket is empty.
+    pub fn acquire(&self, key: Key) -> Option<Token<'_>> {
+        match self.try_acquire_at(&key, Instant::now()) {
+            true => Some(Token {
+                limiter: self,
+                key: Some(key),
+            }),
+            false => None,
+        }
+    }
+
+    fn try_acquire_at(&self, key: &Key, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Drop buckets that have refilled completely, they are indistinguishable from new ones
+        buckets.retain(|k, b| {
+            b.refill(self.rate, self.burst, now);
+            k == key || b.tokens < self.burst
+        });
+
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn release_at(&self, key: &Key, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if let Some(bucket) = buckets.get_mut(key) {
+            bucket.refill(self.rate, self.burst, now);
+            bucket.tokens = (bucket.tokens + 1.0).min(self.burst);
+        }
+    }
+}
+
+/// A token taken for one forward attempt, handed back when dropped unless it was spent.
+pub struct Token<'a> {
+    limiter: &'a RateLimiter,
+    /// `None` once spent
+    key: Option<Key>,
+}
+
+impl Token<'_> {
+    /// Keeps the token out of the bucket, for an attempt that failed.
+    pub fn spend(mut self) {
+        self.key = None;
+    }
+}
+
+impl Drop for Token<'_> {
+    fn drop(&mut self) {
+        if let Some(ref key) = self.key {
+            self.limiter.release_at(key, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,104 @@
+mod try_acquire {
+    use std::time::{Duration, Instant};
+
+    use super::super::*;
+
+    fn key(pod: &str) -> Key {
+        ("default".into(), pod.into(), 80)
+    }
+
+    #[test]
+    fn allows_up_to_burst() {
+        let limiter = RateLimiter::new(1.0, 3);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire_at(&key("a"), now));
+        assert!(limiter.try_acquire_at(&key("a"), now));
+        assert!(limiter.try_acquire_at(&key("a"), now));
+        assert!(!limiter.try_acquire_at(&key("a"), now));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::new(2.0, 1);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire_at(&key("a"), now));
+        assert!(!limiter.try_acquire_at(&key("a"), now));
+        assert!(!limiter.try_acquire_at(&key("a"), now + Duration::from_millis(250)));
+        assert!(limiter.try_acquire_at(&key("a"), now + Duration::from_millis(750)));
+    }
+
+    #[test]
+    fn keys_are_independent() {
+        let limiter = RateLimiter::new(1.0, 1);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire_at(&key("a"), now));
+        assert!(!limiter.try_acquire_at(&key("a"), now));
+        assert!(limiter.try_acquire_at(&key("b"), now));
+    }
+
+    #[test]
+    fn release_returns_token() {
+        let limiter = RateLimiter::new(1.0, 1);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire_at(&key("a"), now));
+        limiter.release_at(&key("a"), now);
+        assert!(limiter.try_acquire_at(&key("a"), now));
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let limiter = RateLimiter::new(1.0, 1);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire_at(&key("a"), now));
+        assert!(limiter.try_acquire_at(&key("b"), now + Duration::from_secs(2)));
+
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+}
+
+mod token {
+    use super::super::*;
+
+    fn key() -> Key {
+        ("default".into(), "web-0".into(), 80)
+    }
+
+    #[test]
+    fn returned_when_dropped() {
+        let limiter = RateLimiter::new(0.0, 1);
+
+        let token = limiter.acquire(key()).unwrap();
+        assert!(limiter.acquire(key()).is_none());
+        drop(token);
+
+        assert!(limiter.acquire(key()).is_some());
+    }
+
+    #[test]
+    fn spent_tokens_arent_returned() {
+        let limiter = RateLimiter::new(0.0, 1);
+
+        limiter.acquire(key()).unwrap().spend();
+
+        assert!(limiter.acquire(key()).is_none());
+    }
+
+    #[tokio::test]
+    async fn returned_when_the_attempt_is_cancelled() {
+        let limiter = RateLimiter::new(0.0, 1);
+
+        let attempt = async {
+            let _token = limiter.acquire(key()).unwrap();
+            std::future::pending::<()>().await;
+        };
+        let res = tokio::time::timeout(std::time::Duration::from_millis(1), attempt).await;
+        assert!(res.is_err());
+
+        assert!(limiter.acquire(key()).is_some());
+    }
+}
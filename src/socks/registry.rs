@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Poll};
+use std::time::Instant;
+
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::listener::PeerAddr;
+use crate::socks::resolver::Target;
+
+/// Tracks currently open client connections for the admin endpoint.
+#[derive(Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, Arc<Stats>>>,
+}
+
+struct Stats {
+    peer_addr: PeerAddr,
+    started: Instant,
+    target: Mutex<Option<Target>>,
+    bytes_to_pod: AtomicU64,
+    bytes_from_pod: AtomicU64,
+}
+
+/// A registered connection, removed from the registry when dropped.
+pub struct Connection {
+    registry: Arc<Registry>,
+    id: u64,
+    stats: Arc<Stats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer_addr: PeerAddr,
+    pub target: Option<Target>,
+    pub bytes_to_pod: u64,
+    pub bytes_from_pod: u64,
+    pub uptime_secs: f64,
+}
+
+impl Registry {
+    pub fn register(self: &Arc<Self>, peer_addr: PeerAddr) -> Connection {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(Stats {
+            peer_addr,
+            started: Instant::now(),
+            target: Mutex::new(None),
+            bytes_to_pod: AtomicU64::new(0),
+            bytes_from_pod: AtomicU64::new(0),
+        });
+
+        self.connections.lock().unwrap().insert(id, stats.clone());
+
+        Connection {
+            registry: self.clone(),
+            id,
+            stats,
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, stats)| ConnectionInfo {
+                id: *id,
+                peer_addr: stats.peer_addr.clone(),
+                target: stats.target.lock().unwrap().clone(),
+                bytes_to_pod: stats.bytes_to_pod.load(Ordering::Relaxed),
+                bytes_from_pod: stats.bytes_from_pod.load(Ordering::Relaxed),
+                uptime_secs: stats.started.elapsed().as_secs_f64(),
+            })
+            .collect()
+    }
+}
+
+impl Connection {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn set_target(&self, target: &Target) {
+        *self.stats.target.lock().unwrap() = Some(target.clone());
+    }
+
+    /// Wraps the pod side of the connection so bytes are counted as they flow.
+    pub fn count<S>(&self, stream: S) -> Counted<S> {
+        Counted {
+            inner: stream,
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.registry.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+pub struct Counted<S> {
+    inner: S,
+    stats: Arc<Stats>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = (buf.filled().len() - before) as u64;
+        self.stats.bytes_from_pod.fetch_add(read, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.stats
+            .bytes_to_pod
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,67 @@
+mod registry {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::super::*;
+
+    fn peer() -> PeerAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 50000)).into()
+    }
+
+    #[test]
+    fn deregisters_on_drop() {
+        let registry = Arc::new(Registry::default());
+
+        let a = registry.register(peer());
+        let b = registry.register(peer());
+        assert_eq!(registry.snapshot().len(), 2);
+
+        drop(a);
+        let remaining = registry.snapshot();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, b.id);
+    }
+
+    #[test]
+    fn records_target() {
+        let registry = Arc::new(Registry::default());
+        let conn = registry.register(peer());
+
+        conn.set_target(&Target {
+            namespace: "default".into(),
+            pod: "web-0".into(),
+            port: 80,
+            pod_ip: None,
+            app_protocol: None,
+            cluster: None,
+            connect_timeout: None,
+            named_target_port: None,
+        });
+
+        let snapshot = registry.snapshot();
+        assert_eq!(
+            snapshot[0].target.as_ref().map(|t| t.pod.as_str()),
+            Some("web-0")
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_bytes() {
+        let registry = Arc::new(Registry::default());
+        let conn = registry.register(peer());
+
+        let (pod, mut remote) = tokio::io::duplex(64);
+        let mut pod = conn.count(pod);
+
+        pod.write_all(b"hello").await.unwrap();
+        remote.write_all(b"hi").await.unwrap();
+        let mut buf = [0_u8; 2];
+        pod.read_exact(&mut buf).await.unwrap();
+
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot[0].bytes_to_pod, 5);
+        assert_eq!(snapshot[0].bytes_from_pod, 2);
+    }
+}
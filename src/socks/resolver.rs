@@ -1,20 +1,50 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::Context;
+use anyhow::Context as _;
+use futures::{FutureExt, StreamExt, TryStreamExt};
 use k8s_openapi::{
-    api::core::v1::{ContainerPort, Pod, Service},
-    apimachinery::pkg::util::intstr::IntOrString,
+    api::{
+        apps::v1::{DaemonSet, Deployment, ReplicaSet, StatefulSet},
+        core::v1::{ContainerPort, Namespace, Node, Pod, Service},
+        discovery::v1::EndpointSlice,
+    },
+    apimachinery::pkg::{
+        apis::meta::v1::{LabelSelector, LabelSelectorRequirement, ObjectMeta},
+        util::intstr::IntOrString,
+    },
 };
 use kube::{
-    api::{ListParams, Portforwarder},
-    Api, Client,
+    api::{ListParams, Portforwarder, WatchEvent, WatchParams},
+    core::NamespaceResourceScope,
+    Api, Client, Resource,
 };
 use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, error, field::Empty, info, instrument, warn, Instrument, Span};
+
+use crate::config::{Config, ErrorKind, ForwardBackend, PrewarmTarget, DEFAULT_FORWARD_PROBE_MS};
+use crate::listener::PeerAddr;
+use crate::socks::kube_client::KubeClient;
+use crate::socks::{api_proxy, prewarm, rate_limit, websocket, Context};
+
+pub use static_hosts::StaticResolver;
+
+mod static_hosts;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Errors {
     #[error("Pod Not Found {namespace}/{pod}")]
     PodNotFound { namespace: String, pod: String },
+    #[error("More than one Pod named {pod}, in namespaces {namespaces:?}")]
+    PodAmbiguous {
+        pod: String,
+        namespaces: Vec<String>,
+    },
     #[error("Service Not Found {namespace}/{service}")]
     ServiceNotFound { namespace: String, service: String },
     #[error("Service {namespace}/{service} Invalid - {reason}")]
@@ -23,7 +53,9 @@ pub enum Errors {
         service: String,
         reason: String,
     },
-    #[error("Service {namespace}/{service} has not matching ready pods")]
+    #[error("Service {namespace}/{service}'s selector doesn't match any pods")]
+    ServiceNoMatchingPods { namespace: String, service: String },
+    #[error("Service {namespace}/{service} has matching pods, but none are ready")]
     ServiceNoReadyPods { namespace: String, service: String },
     #[error("Pod {pod} for service {namespace}/{service} not found")]
     NamedServicePodsNotFound {
@@ -31,257 +63,2442 @@ pub enum Errors {
         service: String,
         pod: String,
     },
+    #[error("No endpoint {endpoint} in the EndpointSlices of Service {namespace}/{service}")]
+    EndpointNotFound {
+        namespace: String,
+        service: String,
+        endpoint: String,
+    },
+    #[error("{kind} Not Found {namespace}/{name}")]
+    WorkloadNotFound {
+        kind: &'static str,
+        namespace: String,
+        name: String,
+    },
+    #[error("{kind} {namespace}/{name} Invalid - {reason}")]
+    WorkloadInvalid {
+        kind: &'static str,
+        namespace: String,
+        name: String,
+        reason: String,
+    },
+    #[error("{kind} {namespace}/{name} has no ready pods")]
+    WorkloadNoReadyPods {
+        kind: &'static str,
+        namespace: String,
+        name: String,
+    },
+    #[error("No Namespace labelled {0}")]
+    NamespaceNotFound(String),
+    #[error("More than one Namespace labelled {selector}: {namespaces:?}")]
+    NamespaceAmbiguous {
+        selector: String,
+        namespaces: Vec<String>,
+    },
+    #[error("No Pod with IP {0}")]
+    PodIpNotFound(IpAddr),
+    #[error("No Pod with IP {ip} in Namespace {namespace}")]
+    PodIpNotFoundInNamespace { namespace: String, ip: IpAddr },
+    #[error("No Node with InternalIP {0}")]
+    NodeNotFound(IpAddr),
+    #[error("No ready host network pod to forward through on Node {0}")]
+    NodeNoHostNetworkPods(String),
     #[error("Port {2} Not Found on {0}/{1}")]
     PortNotFound(String, String, u16),
+    #[error("No Port named {port_name} on {namespace}/{service}")]
+    PortNameNotFound {
+        namespace: String,
+        service: String,
+        port_name: String,
+    },
+    #[error("Pod {namespace}/{pod} refused connection on port {port} - {reason}")]
+    ConnectionRefused {
+        namespace: String,
+        pod: String,
+        port: u16,
+        reason: String,
+    },
+    #[error(
+        "Pod {namespace}/{pod} doesn't listen on port {port}, targetPort {port_name} of Service \
+         {service}, it may only be declared for probes - {reason}"
+    )]
+    TargetPortNotServed {
+        namespace: String,
+        service: String,
+        port_name: String,
+        pod: String,
+        port: u16,
+        reason: String,
+    },
+    #[error("Too many new forwards to {namespace}/{pod}:{port}")]
+    RateLimited {
+        namespace: String,
+        pod: String,
+        port: u16,
+    },
     #[error("Unsupported Address {0}")]
     UnsupportedAddress(String),
     #[error("Forward Failed {0:?}")]
     ForwardFailed(#[source] anyhow::Error),
+    #[error("Forbidden to {verb} {resource} - {message}")]
+    Forbidden {
+        verb: &'static str,
+        resource: String,
+        message: String,
+    },
     #[error("Lookup Failed {0:?}")]
     LookupFailed(#[source] kube::Error),
+    #[error("No static host mapped for {0}")]
+    HostNotMapped(String),
+    #[error("Timed out after {timeout:?} {action} to {namespace}/{pod}:{port}")]
+    ConnectTimedOut {
+        action: &'static str,
+        namespace: String,
+        pod: String,
+        port: u16,
+        timeout: Duration,
+    },
+    #[error("No pod matching {selector} became ready within {waited:?}")]
+    ReadyWaitTimedOut { selector: String, waited: Duration },
+    #[error("Forward to {namespace}/{pod}:{port} closed before it could be used - {reason}")]
+    ForwardClosed {
+        namespace: String,
+        pod: String,
+        port: u16,
+        reason: String,
+    },
+    #[error("No cluster named {0}, add it with --context")]
+    ClusterNotFound(String),
+    #[error("Already handling {0} connections, the most allowed by max-connections")]
+    TooManyConnections(usize),
+    #[error("Forwards to {address}:{port} keep failing, not trying again for {retry_in:?}")]
+    CircuitOpen {
+        address: String,
+        port: u16,
+        retry_in: Duration,
+    },
+    #[error("Proxy is shutting down")]
+    ShuttingDown,
+    #[error("{kind} {namespace}/{name} is denied by deny-name")]
+    NameDenied {
+        kind: &'static str,
+        namespace: String,
+        name: String,
+    },
+    #[error(
+        "Namespace {namespace} isn't allowed for {}",
+        .username.as_deref().unwrap_or("unauthenticated clients")
+    )]
+    NamespaceDenied {
+        namespace: String,
+        username: Option<String>,
+    },
 }
 
-pub struct PodResolver {
-    client: Client,
-    forwarder: Option<Portforwarder>,
+impl Errors {
+    /// Which kind of failure this is, for picking the reply sent to the client.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Errors::PodNotFound { .. } => ErrorKind::PodNotFound,
+            Errors::PodAmbiguous { .. } => ErrorKind::PodAmbiguous,
+            Errors::ServiceNotFound { .. } => ErrorKind::ServiceNotFound,
+            Errors::ServiceInvalid { .. } => ErrorKind::ServiceInvalid,
+            Errors::ServiceNoMatchingPods { .. } => ErrorKind::ServiceNoMatchingPods,
+            Errors::ServiceNoReadyPods { .. } => ErrorKind::ServiceNoReadyPods,
+            Errors::NamedServicePodsNotFound { .. } => ErrorKind::NamedServicePodsNotFound,
+            Errors::EndpointNotFound { .. } => ErrorKind::EndpointNotFound,
+            Errors::WorkloadNotFound { .. } => ErrorKind::WorkloadNotFound,
+            Errors::WorkloadInvalid { .. } => ErrorKind::WorkloadInvalid,
+            Errors::WorkloadNoReadyPods { .. } => ErrorKind::WorkloadNoReadyPods,
+            Errors::NamespaceNotFound(_) => ErrorKind::NamespaceNotFound,
+            Errors::NamespaceAmbiguous { .. } => ErrorKind::NamespaceAmbiguous,
+            Errors::PodIpNotFound(_) | Errors::PodIpNotFoundInNamespace { .. } => {
+                ErrorKind::PodIpNotFound
+            }
+            Errors::NodeNotFound(_) => ErrorKind::NodeNotFound,
+            Errors::NodeNoHostNetworkPods(_) => ErrorKind::NodeNoHostNetworkPods,
+            Errors::PortNotFound(_, _, _) | Errors::PortNameNotFound { .. } => {
+                ErrorKind::PortNotFound
+            }
+            Errors::ConnectionRefused { .. } | Errors::TargetPortNotServed { .. } => {
+                ErrorKind::ConnectionRefused
+            }
+            Errors::RateLimited { .. } => ErrorKind::RateLimited,
+            Errors::UnsupportedAddress(_) => ErrorKind::UnsupportedAddress,
+            Errors::ForwardFailed(_) => ErrorKind::ForwardFailed,
+            Errors::Forbidden { .. } => ErrorKind::Forbidden,
+            Errors::LookupFailed(_) => ErrorKind::LookupFailed,
+            Errors::HostNotMapped(_) => ErrorKind::HostNotMapped,
+            Errors::NameDenied { .. } => ErrorKind::NameDenied,
+            Errors::NamespaceDenied { .. } => ErrorKind::NamespaceDenied,
+            Errors::ConnectTimedOut { .. } => ErrorKind::ConnectTimeout,
+            Errors::ReadyWaitTimedOut { .. } => ErrorKind::ReadyWaitTimeout,
+            Errors::ForwardClosed { .. } => ErrorKind::ForwardClosed,
+            Errors::ClusterNotFound(_) => ErrorKind::ClusterNotFound,
+            Errors::TooManyConnections(_) => ErrorKind::TooManyConnections,
+            Errors::CircuitOpen { .. } => ErrorKind::CircuitOpen,
+            Errors::ShuttingDown => ErrorKind::ShuttingDown,
+        }
+    }
+
+    /// Whether this says the target is unwell, as opposed to the request being wrong or refused,
+    /// or the API server failing, so counts towards tripping its circuit breaker.
+    pub fn is_target_failure(&self) -> bool {
+        matches!(
+            self,
+            Errors::ServiceNoReadyPods { .. }
+                | Errors::WorkloadNoReadyPods { .. }
+                | Errors::NodeNoHostNetworkPods(_)
+                | Errors::ConnectionRefused { .. }
+                | Errors::TargetPortNotServed { .. }
+                | Errors::ForwardFailed(_)
+                | Errors::ConnectTimedOut { .. }
+                | Errors::ReadyWaitTimedOut { .. }
+                | Errors::ForwardClosed { .. }
+        )
+    }
+
+    /// A stable identifier for this kind of failure, see [`ErrorKind::code`].
+    pub fn code(&self) -> &'static str {
+        self.kind().code()
+    }
+
+    /// The API error behind a failed lookup or forward.
+    fn kube_error(&self) -> Option<&kube::Error> {
+        match self {
+            Errors::LookupFailed(e) => Some(e),
+            Errors::ForwardFailed(e) => e.downcast_ref(),
+            _ => None,
+        }
+    }
 }
 
-impl PodResolver {
-    pub fn new(client: Client) -> Self {
-        PodResolver {
-            client,
-            forwarder: None,
+/// A pod and port resolved from a client supplied address.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Target {
+    pub namespace: String,
+    pub pod: String,
+    pub port: u16,
+    /// `status.podIP`, when the pod has been assigned one
+    pub pod_ip: Option<IpAddr>,
+    /// `appProtocol` of the service port it was reached through, eg. `http` or `kubernetes.io/h2c`
+    pub app_protocol: Option<String>,
+    /// `--context` name of the cluster the pod is in, `None` for the primary cluster
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<String>,
+    /// From the pod's or service's `kube-fwd-socks/connect-timeout` annotation, overriding
+    /// `connect-timeout` for this target
+    #[serde(skip)]
+    pub connect_timeout: Option<Duration>,
+    /// Set when reached through a service port whose `targetPort` is a name, for
+    /// `probe-named-target-ports` to check the pod listens on
+    #[serde(skip)]
+    pub named_target_port: Option<NamedTargetPort>,
+}
+
+/// A service port's `targetPort` given by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamedTargetPort {
+    pub service: String,
+    pub port_name: String,
+}
+
+impl NamedTargetPort {
+    fn of(service: &Service, port: u16) -> Option<Self> {
+        named_target_port(service, port).map(|port_name| NamedTargetPort {
+            service: service.metadata.name.clone().unwrap_or_default(),
+            port_name: port_name.into(),
+        })
+    }
+}
+
+impl Target {
+    fn new(pod: &Pod, namespace: &str, port: u16) -> Self {
+        Target {
+            namespace: namespace.into(),
+            pod: pod.metadata.name.clone().unwrap_or_default(),
+            port,
+            pod_ip: pod
+                .status
+                .as_ref()
+                .and_then(|s| s.pod_ip.as_ref())
+                .and_then(|ip| ip.parse().ok()),
+            app_protocol: None,
+            cluster: None,
+            connect_timeout: annotated_connect_timeout(&pod.metadata),
+            named_target_port: None,
         }
     }
 
-    pub async fn forwarder(
-        &mut self,
-        address: &str,
-        port: u16,
-    ) -> Result<impl AsyncRead + AsyncWrite + Unpin, Errors> {
-        let (pod_name, namespace, port) = self.resolve(address, port).await?;
+    /// Falls back to the service's connect timeout annotation when the pod doesn't have one.
+    fn through_service(self, service: &Service) -> Self {
+        Target {
+            connect_timeout: self
+                .connect_timeout
+                .or_else(|| annotated_connect_timeout(&service.metadata)),
+            ..self
+        }
+    }
 
-        let pods: Api<Pod> = Api::namespaced(self.client.clone(), namespace.as_str());
+    /// How long to wait for a forward to this target to be established.
+    fn connect_timeout_or(&self, config: &Config) -> Duration {
+        self.connect_timeout
+            .unwrap_or(Duration::from_secs(config.connect_timeout))
+    }
 
-        let mut forwarder = pods
-            .portforward(pod_name.as_str(), &[port])
-            .await
-            .map_err(|e| Errors::ForwardFailed(e.into()))?;
+    /// Like `new`, but port 0 picks the pod's first declared container port.
+    fn with_default_port(pod: &Pod, namespace: &str, port: u16) -> Result<Self, Errors> {
+        if port != 0 {
+            return Ok(Target::new(pod, namespace, port));
+        }
 
-        let stream = forwarder
-            .take_stream(port)
-            .context("port not found in forwarder")
-            .map_err(Errors::ForwardFailed)?;
+        let port = first_container_port(pod).ok_or_else(|| {
+            Errors::PortNotFound(
+                namespace.into(),
+                pod.metadata.name.clone().unwrap_or_default(),
+                port,
+            )
+        })?;
 
-        self.forwarder = Some(forwarder);
+        Ok(Target::new(pod, namespace, port))
+    }
 
-        Ok(stream)
+    fn connect_timed_out(&self, action: &'static str, timeout: Duration) -> Errors {
+        Errors::ConnectTimedOut {
+            action,
+            namespace: self.namespace.clone(),
+            pod: self.pod.clone(),
+            port: self.port,
+            timeout,
+        }
     }
 
-    pub async fn join(self) -> anyhow::Result<()> {
-        if let Some(f) = self.forwarder {
-            f.join().await?
+    pub fn forward_closed(&self, reason: impl ToString) -> Errors {
+        Errors::ForwardClosed {
+            namespace: self.namespace.clone(),
+            pod: self.pod.clone(),
+            port: self.port,
+            reason: reason.to_string(),
         }
+    }
 
-        Ok(())
+    fn rate_limit_key(&self) -> rate_limit::Key {
+        (self.namespace.clone(), self.pod.clone(), self.port)
     }
+}
 
-    async fn resolve(&self, address: &str, port: u16) -> Result<(String, String, u16), Errors> {
-        let mut segments: Vec<&str> = address.split('.').collect();
+/// Where a client asked to connect to.
+#[derive(Debug, Clone, Copy)]
+pub enum Destination<'a> {
+    Dns(&'a str),
+    Ip(IpAddr),
+}
 
-        if let Some(mut segment) = segments.pop() {
-            if segment == "local" && segments.last() == Some(&"cluster") {
-                let _ = segments.pop(); // remove "cluster"
+/// A connection to the target pod, either a port-forward stream or an HTTP relay.
+pub trait PodStream: AsyncRead + AsyncWrite + Unpin + Send {}
 
-                // grab the now last dns segment for the sub-resolver matching below
-                segment = segments
-                    .pop()
-                    .ok_or_else(|| Errors::UnsupportedAddress(address.to_string()))?;
-            }
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> PodStream for S {}
 
-            return match segment {
-                "svc" => self.resolve_service(segments.as_slice(), port).await,
-                "pod" => self.resolve_pod(segments.as_slice(), port).await,
-                _ => Err(Errors::UnsupportedAddress(address.to_string())),
-            };
-        }
+/// Buffered between the client connection and an HTTP relay.
+const API_PROXY_BUF_LEN: usize = 64 * 1024;
 
-        Err(Errors::UnsupportedAddress(address.to_string()))
+/// The outcome of [`PodResolver::find_pod`].
+struct FoundPod {
+    pod: Option<Pod>,
+    /// Of the listed pods, for watching from
+    resource_version: Option<String>,
+    /// How many pods were listed, ready or not
+    matched: usize,
+}
+
+/// An established port-forward to one or more ports of a pod.
+pub struct Forward {
+    forwarder: Portforwarder,
+    /// One per forwarded port, in the order they were asked for
+    streams: Vec<Box<dyn PodStream>>,
+    /// Must be held for as long as the forwarder runs, it errors if this is dropped
+    error: ForwardError,
+}
+
+impl Forward {
+    /// Whether the pod has already closed the forward, or reported an error on it.
+    pub fn is_closed(&mut self) -> bool {
+        self.error.as_mut().now_or_never().is_some()
     }
 
-    async fn resolve_service(
-        &self,
-        segments: &[&str],
+    pub fn abort(self) {
+        self.forwarder.abort();
+    }
+}
+
+type ForwardError = Pin<Box<dyn Future<Output = Option<String>> + Send + Sync>>;
+
+/// Opens streams to the destinations clients ask for, one resolver per client connection.
+pub trait Resolver: Send {
+    /// Resolves `destination` and opens a stream to it.
+    fn forwarder(
+        &mut self,
+        destination: Destination<'_>,
         port: u16,
-    ) -> Result<(String, String, u16), Errors> {
-        let pod_hostname: Option<&str>;
-        let service_name: &str;
-        let namespace: &str;
+    ) -> impl Future<Output = Result<(Target, Box<dyn PodStream>), Errors>> + Send;
 
-        if segments.len() == 2 {
-            pod_hostname = None;
-            service_name = segments[0];
-            namespace = segments[1];
-        } else if segments.len() == 2 {
-            pod_hostname = Some(segments[0]);
-            service_name = segments[1];
-            namespace = segments[2];
-        } else {
-            return Err(Errors::UnsupportedAddress(
-                segments.join(".") + "svc.cluster.local",
-            ));
-        }
+    /// Resolves once the opened stream stops, with the reason if the backend gave one. Never
+    /// resolves if there is no stream, or the backend can't tell.
+    fn forward_closed(&mut self) -> impl Future<Output = Option<String>> + Send;
 
-        let service_api: Api<Service> = Api::namespaced(self.client.clone(), namespace);
-        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+    /// Waits for the backend to finish once the connection is done with it.
+    fn join(self) -> impl Future<Output = anyhow::Result<()>> + Send;
 
-        if let Some(service) = service_api
-            .get_opt(service_name)
-            .await
-            .map_err(Errors::LookupFailed)?
-        {
-            let selectors = service
-                .spec
-                .as_ref()
-                .ok_or_else(|| Errors::ServiceInvalid {
-                    namespace: namespace.into(),
-                    service: service_name.into(),
-                    reason: "spec is not set".into(),
-                })?
-                .selector
-                .as_ref()
-                .ok_or_else(|| Errors::ServiceInvalid {
-                    namespace: namespace.into(),
-                    service: service_name.into(),
-                    reason: "spec.selectors is not set".into(),
-                })?;
+    /// Told who the client authenticated as, `None` if it didn't, before its request is
+    /// resolved. Backends without namespaces to scope users to can ignore it.
+    fn authenticated(&mut self, _username: Option<&str>) {}
+}
+
+/// Resolves cluster addresses against the Kubernetes API and port-forwards to the chosen pod.
+pub struct PodResolver {
+    client: Client,
+    kube_client: Arc<KubeClient>,
+    ctx: Context,
+    forwarder: Option<Portforwarder>,
+    /// Must be held for as long as the forwarder runs, it errors if this is dropped
+    forward_error: Option<ForwardError>,
+    /// `(namespace, pod)` of pods that failed to forward, skipped when picking another
+    excluded: Vec<(String, String)>,
+    /// `--context` name of the cluster being resolved against, `None` for the primary cluster
+    cluster: Option<String>,
+    /// Where the client connected from, for services with `ClientIP` session affinity
+    client_ip: Option<IpAddr>,
+    /// Who the client authenticated as, which namespaces it may forward into depends on
+    username: Option<String>,
+}
 
-            let list_params = selector_into_list_params(selectors);
+impl Resolver for PodResolver {
+    async fn forwarder(
+        &mut self,
+        destination: Destination<'_>,
+        port: u16,
+    ) -> Result<(Target, Box<dyn PodStream>), Errors> {
+        let key = match destination {
+            Destination::Dns(a) => (normalize_address(a).0, port),
+            Destination::Ip(ip) => (ip.to_string(), port),
+        };
+        if let Err(retry_in) = self.ctx.circuit_breakers.check(&key) {
+            return Err(Errors::CircuitOpen {
+                address: key.0,
+                port,
+                retry_in,
+            });
+        }
 
-            let pods = pod_api
-                .list(&list_params)
-                .await
-                .map_err(Errors::LookupFailed)?;
+        let address;
+        let destination = match destination {
+            Destination::Dns(a) => match split_cluster(a, &self.ctx.config.cluster_domain) {
+                Some((cluster, within)) => {
+                    self.use_cluster(cluster)?;
+                    address = within;
+                    Destination::Dns(&address)
+                }
+                None => destination,
+            },
+            Destination::Ip(_) => destination,
+        };
 
-            if let Some(hostname) = pod_hostname {
-                if let Some(pod) = pods.items.iter().find(|p| {
-                    Some(&hostname.into())
-                        == p.spec
-                            .as_ref()
-                            .and_then(|s| s.hostname.as_ref())
-                            .or(p.metadata.name.as_ref())
-                }) {
-                    return Ok((pod.metadata.name.clone().unwrap(), namespace.into(), port));
-                } else {
-                    return Err(Errors::NamedServicePodsNotFound {
-                        namespace: namespace.into(),
-                        service: service_name.into(),
-                        pod: hostname.into(),
-                    });
+        let res = self.establish(destination, port).await;
+        let failed = matches!(res, Err(ref e) if e.is_target_failure());
+        self.ctx.circuit_breakers.record(key, failed);
+
+        // Only outcomes that involved the API server say anything about whether it's reachable
+        match res {
+            Ok(_) => self.kube_client.observe(Ok(())),
+            Err(ref e) => {
+                if let Some(e) = e.kube_error() {
+                    self.kube_client.observe(Err(e));
                 }
             }
+        }
 
-            let ready_pod = pods.items.iter().find(|p| {
-                p.status.as_ref().map_or(false, |s| {
-                    s.conditions.as_ref().map_or(false, |cs| {
-                        cs.iter().any(|c| c.type_ == "Ready" && c.status == "True")
-                    })
-                })
-            });
+        res
+    }
 
-            if let Some(pod) = ready_pod {
-                let service_port = service
-                    .spec
-                    .as_ref()
-                    .and_then(|s| s.ports.iter().flatten().find(|p| p.port == port as i32))
-                    .and_then(|p| p.target_port.clone());
-
-                let pod_port = match service_port {
-                    Some(IntOrString::String(port_name)) => pod
-                        .spec
-                        .as_ref()
-                        .and_then(|spec| {
-                            spec.containers
-                                .iter()
-                                .flat_map(|c| c.ports.as_ref().unwrap_or(EMPTY_CONTAINER_PORT_VEC))
-                                .find(|p| p.name.as_ref().is_some_and(|n| n == &port_name))
-                                .and_then(|p| u16::try_from(p.container_port).ok())
-                        })
-                        .ok_or_else(|| {
-                            Errors::PortNotFound(namespace.into(), service_name.into(), port)
-                        }),
-                    Some(IntOrString::Int(i)) => {
-                        u16::try_from(i).map_err(|_| Errors::ServiceInvalid {
-                            namespace: namespace.into(),
-                            service: service_name.into(),
-                            reason: "could not convert target port to u16".into(),
-                        })
-                    }
-                    None => Ok(port),
-                }?;
+    async fn forward_closed(&mut self) -> Option<String> {
+        let Some(forward_error) = self.forward_error.as_mut() else {
+            return futures::future::pending().await;
+        };
 
-                return Ok((
-                    pod.metadata.name.clone().unwrap(),
-                    namespace.into(),
-                    pod_port,
-                ));
-            } else {
-                return Err(Errors::ServiceNoReadyPods {
-                    namespace: namespace.into(),
-                    service: service_name.into(),
-                });
+        let reason = forward_error.await;
+        // Completed futures must not be polled again
+        self.forward_error = None;
+        reason
+    }
+
+    async fn join(self) -> anyhow::Result<()> {
+        let res = match self.forwarder {
+            Some(f) => f.join().await,
+            None => Ok(()),
+        };
+
+        if let Some(Some(reason)) = self.forward_error.and_then(|e| e.now_or_never()) {
+            warn!(reason, "pod reported forward error");
+        }
+
+        match res {
+            // The pod going away mid-connection is routine, eg. while it restarts
+            Err(ref e) if is_disconnect(e) => {
+                debug!(
+                    error = e as &dyn std::error::Error,
+                    "forward ended by disconnect"
+                );
+                Ok(())
             }
+            res => Ok(res?),
         }
+    }
 
-        Err(Errors::ServiceNotFound {
-            namespace: namespace.into(),
-            service: service_name.into(),
-        })
+    fn authenticated(&mut self, username: Option<&str>) {
+        self.username = username.map(Into::into);
     }
+}
 
-    async fn resolve_pod(
-        &self,
-        segments: &[&str],
+impl PodResolver {
+    pub fn new(ctx: Context, kube_client: Arc<KubeClient>) -> Self {
+        PodResolver {
+            client: kube_client.get(),
+            kube_client,
+            ctx,
+            forwarder: None,
+            forward_error: None,
+            excluded: vec![],
+            cluster: None,
+            client_ip: None,
+            username: None,
+        }
+    }
+
+    /// Resolves for a client at `peer_addr`, which only matters to services with `ClientIP`
+    /// session affinity. Clients on the UNIX socket have no IP, so get no affinity.
+    pub fn for_client(self, peer_addr: &PeerAddr) -> Self {
+        PodResolver {
+            client_ip: peer_addr.ip(),
+            ..self
+        }
+    }
+
+    /// Resolves against the `--context` cluster named `name` from now on.
+    fn use_cluster(&mut self, name: String) -> Result<(), Errors> {
+        let kube_client = self
+            .ctx
+            .clusters
+            .get(&name)
+            .ok_or_else(|| Errors::ClusterNotFound(name.clone()))?;
+
+        debug!(cluster = name, "resolving in cluster");
+        self.client = kube_client.get();
+        self.kube_client = kube_client.clone();
+        self.cluster = Some(name);
+        Ok(())
+    }
+
+    /// Resolves and forwards to `destination`, if the forward fails before it's handed out
+    /// another pod is picked and tried instead, up to `forward-retries` times.
+    async fn establish(
+        &mut self,
+        destination: Destination<'_>,
         port: u16,
-    ) -> Result<(String, String, u16), Errors> {
-        if segments.len() != 2 {
-            return Err(Errors::UnsupportedAddress(
-                segments.join(".") + "pod.cluster.local",
-            ));
+    ) -> Result<(Target, Box<dyn PodStream>), Errors> {
+        let mut target = self.resolve_destination(destination, port).await?;
+        let mut retry = 0;
+
+        loop {
+            let err = match self.open(&target).await {
+                Ok(stream) => return Ok((target, stream)),
+                Err(
+                    e @ (Errors::ForwardFailed(_)
+                    | Errors::ConnectionRefused { .. }
+                    | Errors::ConnectTimedOut { .. }),
+                ) if retry < self.ctx.config.forward_retries => e,
+                Err(e) => return Err(e),
+            };
+            retry += 1;
+
+            warn!(error = ?err, ?target, retry, "forward failed, picking another pod");
+            self.excluded
+                .push((target.namespace.clone(), target.pod.clone()));
+
+            // Addresses naming a single pod resolve to the same one again, so there's nothing
+            // else to try and the forward's error is more useful than the lookup's
+            target = match self.resolve_destination(destination, port).await {
+                Ok(next) if !self.is_excluded(&next.namespace, &next.pod) => next,
+                _ => return Err(err),
+            };
         }
+    }
 
-        let pod_name = segments[0];
-        let namespace = segments[1];
+    async fn resolve_destination(
+        &self,
+        destination: Destination<'_>,
+        port: u16,
+    ) -> Result<Target, Errors> {
+        let target = match destination {
+            Destination::Dns(address) => self.resolve(address, port).await,
+            Destination::Ip(ip) => self.resolve_ip(ip, port).await,
+        }?;
 
-        let pods: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+        // Checked once resolved, so it's the pod's real name however the client addressed it.
+        // Addresses naming their namespace had it checked before any lookup, so clients can't
+        // learn what exists where they may not go, this covers the ones that don't.
+        self.check_name_allowed("Pod", &target.namespace, &target.pod)?;
+        self.check_namespace_allowed(&target.namespace)?;
+        Ok(Target {
+            cluster: self.cluster.clone(),
+            ..target
+        })
+    }
 
-        if let Some(_pod) = pods.get_opt(pod_name).await.map_err(Errors::LookupFailed)? {
-            // todo try and find port on pod or error
-        } else {
-            return Err(Errors::PodNotFound {
+    fn check_name_allowed(
+        &self,
+        kind: &'static str,
+        namespace: &str,
+        name: &str,
+    ) -> Result<(), Errors> {
+        match self.ctx.config.name_denied(name) {
+            true => Err(Errors::NameDenied {
+                kind,
                 namespace: namespace.into(),
-                pod: pod_name.into(),
-            });
+                name: name.into(),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    fn check_namespace_allowed(&self, namespace: &str) -> Result<(), Errors> {
+        let username = self.username.as_deref();
+        match self.ctx.config.namespace_allowed(username, namespace) {
+            true => Ok(()),
+            false => Err(Errors::NamespaceDenied {
+                namespace: namespace.into(),
+                username: self.username.clone(),
+            }),
         }
+    }
 
-        Ok((pod_name.into(), namespace.into(), port))
+    fn is_excluded(&self, namespace: &str, pod: &str) -> bool {
+        self.excluded
+            .iter()
+            .any(|(n, p)| n == namespace && p == pod)
     }
-}
 
-const EMPTY_CONTAINER_PORT_VEC: &Vec<ContainerPort> = &Vec::new();
+    fn is_excluded_pod(&self, pod: &Pod) -> bool {
+        self.is_excluded(
+            pod.metadata.namespace.as_deref().unwrap_or_default(),
+            pod.metadata.name.as_deref().unwrap_or_default(),
+        )
+    }
 
-fn selector_into_list_params(selectors: &BTreeMap<String, String>) -> ListParams {
-    let labels = selectors
-        .iter()
-        .fold(String::new(), |mut res, (key, value)| {
-            if !res.is_empty() {
-                res.push(',');
+    /// Opens the forward to an already resolved target.
+    async fn open(&mut self, target: &Target) -> Result<Box<dyn PodStream>, Errors> {
+        let rate_limiter = self.ctx.rate_limiter.clone();
+        let Some(token) = rate_limiter.acquire(target.rate_limit_key()) else {
+            return Err(Errors::RateLimited {
+                namespace: target.namespace.clone(),
+                pod: target.pod.clone(),
+                port: target.port,
+            });
+        };
+
+        // Established forwards don't count against the limit, only failed attempts do. Nor do
+        // attempts dropped part way because the client disconnected, which hands the token back.
+        // The named target port probe is a forward too, so it's made under the same token.
+        let res = match self.check_target_port(target).await {
+            Ok(()) => self.open_unlimited(target).await,
+            Err(e) => Err(e),
+        };
+        if res.is_err() {
+            token.spend();
+        }
+        res
+    }
+
+    async fn open_unlimited(&mut self, target: &Target) -> Result<Box<dyn PodStream>, Errors> {
+        let key = target.rate_limit_key();
+
+        if self.ctx.config.api_proxy_ports.contains(&target.port) {
+            let (stream, relayed) = tokio::io::duplex(API_PROXY_BUF_LEN);
+            tokio::spawn(
+                api_proxy::relay(self.client.clone(), target.clone(), relayed).in_current_span(),
+            );
+            return Ok(Box::new(stream));
+        }
+
+        let connect_timeout = target.connect_timeout_or(&self.ctx.config);
+
+        if let (ForwardBackend::Websocket, Some(url)) = (
+            self.ctx.config.forward_backend,
+            &self.ctx.config.websocket_url,
+        ) {
+            let stream = tokio::time::timeout(connect_timeout, websocket::connect(url, target))
+                .await
+                .map_err(|_| target.connect_timed_out("connecting websocket", connect_timeout))?
+                .map_err(|e| Errors::ForwardFailed(e.into()))?;
+
+            return Ok(Box::new(stream));
+        }
+
+        // Prewarmed forwards are all to the primary cluster
+        let prewarmed = match target.cluster {
+            None => self.ctx.prewarmed.take(&key),
+            Some(_) => None,
+        };
+        // Also refilled when the prewarmed forward had closed, or a refill hasn't finished yet
+        if prewarmed.is_some() || self.is_prewarm_target(target) {
+            self.refill(target.clone());
+        }
+
+        let mut streams = match prewarmed {
+            Some(forward) => {
+                debug!("using prewarmed forward");
+                self.hold(forward)
             }
-            res.push_str(key);
-            res.push('=');
-            res.push_str(value);
-            res
-        });
+            None => self.forward_ports(target, &[target.port]).await?,
+        };
 
-    ListParams::default().labels(&labels)
-}
+        Ok(streams.remove(0))
+    }
+
+    /// Opens a port-forward to `ports` of the target's pod, checking the pod doesn't refuse any
+    /// of them straight away. The forward's streams are in the same order as `ports`.
+    async fn port_forward(&self, target: &Target, ports: &[u16]) -> Result<Forward, Errors> {
+        if ports.is_empty() {
+            return Err(Errors::ForwardFailed(anyhow::anyhow!(
+                "no ports to forward"
+            )));
+        }
+
+        let connect_timeout = target.connect_timeout_or(&self.ctx.config);
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), &target.namespace);
+
+        let mut forwarder =
+            tokio::time::timeout(connect_timeout, pods.portforward(&target.pod, ports))
+                .await
+                .map_err(|_| target.connect_timed_out("establishing forward", connect_timeout))?
+                .map_err(|e| {
+                    forbidden(&e, "create", "pods/portforward")
+                        .unwrap_or_else(|| Errors::ForwardFailed(e.into()))
+                })?;
+
+        let mut streams: Vec<Box<dyn PodStream>> = Vec::with_capacity(ports.len());
+        let mut errors = Vec::with_capacity(ports.len());
+        for &port in ports {
+            let stream = forwarder
+                .take_stream(port)
+                .with_context(|| format!("port {port} not found in forwarder"))
+                .map_err(Errors::ForwardFailed)?;
+            let error = forwarder
+                .take_error(port)
+                .with_context(|| format!("port {port} not found in forwarder"))
+                .map_err(Errors::ForwardFailed)?;
+
+            streams.push(Box::new(stream));
+            errors.push(Box::pin(error));
+        }
+
+        // The first error on any port ends the whole forward, so that's the one reported
+        let mut forward_error: ForwardError =
+            Box::pin(futures::future::select_all(errors).map(|(reason, _index, _rest)| reason));
+
+        // The kubelet only reports that nothing is listening on the pods port through the error
+        // channel, so give it a moment to do so before telling the client everything is fine
+        let probe = Duration::from_millis(self.ctx.config.forward_probe_ms);
+        if !probe.is_zero() {
+            match tokio::time::timeout(probe, &mut forward_error).await {
+                Ok(Some(reason)) => {
+                    forwarder.abort();
+                    return Err(refused_or_failed(target, reason));
+                }
+                Ok(None) => {
+                    forwarder.abort();
+                    return Err(Errors::ForwardFailed(anyhow::anyhow!(
+                        "forward closed before it could be used"
+                    )));
+                }
+                Err(_elapsed) => {}
+            }
+        }
+
+        Ok(Forward {
+            forwarder,
+            streams,
+            error: forward_error,
+        })
+    }
+
+    /// Forwards to several ports of the target's pod over a single port-forward, returning a
+    /// stream for each in the same order as `ports`. The forward is held like the one opened by
+    /// [`Resolver::forwarder`], so `forward_closed` and `join` cover every port.
+    pub async fn forward_ports(
+        &mut self,
+        target: &Target,
+        ports: &[u16],
+    ) -> Result<Vec<Box<dyn PodStream>>, Errors> {
+        let forward = self.port_forward(target, ports).await?;
+        Ok(self.hold(forward))
+    }
+
+    /// Keeps the forward running for this connection, handing out its streams.
+    fn hold(&mut self, forward: Forward) -> Vec<Box<dyn PodStream>> {
+        self.forwarder = Some(forward.forwarder);
+        self.forward_error = Some(forward.error);
+        forward.streams
+    }
+
+    /// Opens a forward to the `--prewarm` target and keeps it for the next client to ask for it.
+    pub async fn prewarm(&self, target: &PrewarmTarget) {
+        let res = async {
+            let resolved = self
+                .resolve_pod(&[&target.pod, &target.namespace], target.port)
+                .await?;
+            let forward = self.port_forward(&resolved, &[resolved.port]).await?;
+            self.ctx
+                .prewarmed
+                .insert(resolved.rate_limit_key(), forward);
+            Ok::<_, Errors>(resolved)
+        }
+        .await;
+
+        match res {
+            Ok(resolved) => debug!(?resolved, "prewarmed forward"),
+            Err(e) => warn!(error = ?e, %target, "failed to prewarm forward"),
+        }
+    }
+
+    fn is_prewarm_target(&self, target: &Target) -> bool {
+        target.cluster.is_none()
+            && self.ctx.config.prewarm.iter().any(|p| {
+                p.namespace == target.namespace && p.pod == target.pod && p.port == target.port
+            })
+    }
+
+    /// Replaces a prewarmed forward that was just handed out, in the background.
+    fn refill(&self, target: Target) {
+        let resolver = PodResolver::new(self.ctx.clone(), self.kube_client.clone());
+        let target = PrewarmTarget {
+            namespace: target.namespace,
+            pod: target.pod,
+            port: target.port,
+        };
+
+        let delay = prewarm::refill_delay(Duration::from_millis(self.ctx.config.prewarm_jitter_ms));
+
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(delay).await;
+                resolver.prewarm(&target).await
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Resolves `address` as given, falling back to trying it under each search domain in order.
+    #[instrument(skip(self), err(Debug, level = "debug"))]
+    async fn resolve(&self, address: &str, port: u16) -> Result<Target, Errors> {
+        let (address, fully_qualified) = normalize_address(address);
+        let address = address.as_str();
+
+        // Nothing under a search domain makes an empty label valid, so don't look any further
+        if has_empty_label(address) {
+            return Err(Errors::UnsupportedAddress(address.to_string()));
+        }
+
+        let mut err = match self.resolve_absolute(address, port).await {
+            // As in DNS, a trailing dot means the name is complete and isn't searched for
+            Err(e @ Errors::UnsupportedAddress(_)) if !fully_qualified => e,
+            res => return res,
+        };
+
+        for domain in self.ctx.config.search_domains() {
+            let candidate = format!("{address}.{domain}");
+            debug!(candidate, "trying search domain");
+
+            match self.resolve_absolute(&candidate, port).await {
+                Err(Errors::UnsupportedAddress(_)) => {}
+                Err(
+                    e @ (Errors::ServiceNotFound { .. }
+                    | Errors::PodNotFound { .. }
+                    | Errors::WorkloadNotFound { .. }
+                    | Errors::NamespaceNotFound(_)
+                    | Errors::NamespaceDenied { .. }),
+                ) => {
+                    // Keep the first "not found" as it is more useful than an unsupported address. A
+                    // namespace the client may not use is searched past the same way, whether or
+                    // not the name exists there.
+                    if matches!(err, Errors::UnsupportedAddress(_)) {
+                        err = e;
+                    }
+                }
+                res => return res,
+            }
+        }
+
+        Err(err)
+    }
+
+    /// Resolves a pod IP to its pod, or with node access a node's InternalIP to a host network
+    /// pod running on it, so forwarding to the pod reaches the node itself, eg. on a NodePort.
+    #[instrument(skip(self), fields(node = Empty, pod = Empty), err(Debug, level = "debug"))]
+    async fn resolve_ip(&self, ip: IpAddr, port: u16) -> Result<Target, Errors> {
+        let pod_api: Api<Pod> = Api::all(self.client.clone());
+        let pod = pod_api
+            .list(&ListParams::default().fields(&format!("status.podIP={ip}")))
+            .await
+            .map_err(lookup_failed("list", "pods"))?
+            .items
+            .into_iter()
+            .find(|p| !is_host_network(p) && is_ready(p, &self.ctx.config));
+
+        if let Some(pod) = pod {
+            let namespace = pod.metadata.namespace.clone().unwrap_or_default();
+            let target = Target::with_default_port(&pod, &namespace, port)?;
+            Span::current().record("pod", target.pod.as_str());
+            return Ok(target);
+        }
+
+        if !self.ctx.config.allow_node_access {
+            return Err(Errors::PodIpNotFound(ip));
+        }
+
+        let node_api: Api<Node> = Api::all(self.client.clone());
+        let node_name = node_api
+            .list(&ListParams::default())
+            .await
+            .map_err(lookup_failed("list", "nodes"))?
+            .items
+            .into_iter()
+            .find(|n| node_has_internal_ip(n, ip))
+            .and_then(|n| n.metadata.name)
+            .ok_or(Errors::NodeNotFound(ip))?;
+
+        let span = Span::current();
+        span.record("node", node_name.as_str());
+
+        let pod_api: Api<Pod> = Api::all(self.client.clone());
+        let pod = pod_api
+            .list(&ListParams::default().fields(&format!("spec.nodeName={node_name}")))
+            .await
+            .map_err(lookup_failed("list", "pods"))?
+            .items
+            .into_iter()
+            .find(|p| is_host_network(p) && is_ready(p, &self.ctx.config))
+            .ok_or_else(|| Errors::NodeNoHostNetworkPods(node_name.clone()))?;
+
+        let namespace = pod.metadata.namespace.clone().unwrap_or_default();
+        let target = Target::new(&pod, &namespace, port);
+        span.record("pod", target.pod.as_str());
+
+        Ok(target)
+    }
+
+    async fn resolve_absolute(&self, address: &str, port: u16) -> Result<Target, Errors> {
+        if has_empty_label(address) {
+            return Err(Errors::UnsupportedAddress(address.to_string()));
+        }
+
+        let cluster_suffix = format!(".{}", self.ctx.config.cluster_domain.to_ascii_lowercase());
+        let mut segments: Vec<&str> = address
+            .strip_suffix(cluster_suffix.as_str())
+            .unwrap_or(address)
+            .split('.')
+            .collect();
+
+        match segments.pop() {
+            Some("svc") => self.resolve_service(segments.as_slice(), port).await,
+            Some("pod") => self.resolve_pod(segments.as_slice(), port).await,
+            Some("ep") => self.resolve_endpoint(segments.as_slice(), port).await,
+            Some("deploy") => {
+                self.resolve_workload::<Deployment>("deploy", segments.as_slice(), port)
+                    .await
+            }
+            Some("rs") => {
+                self.resolve_workload::<ReplicaSet>("rs", segments.as_slice(), port)
+                    .await
+            }
+            Some("ds") => {
+                self.resolve_workload::<DaemonSet>("ds", segments.as_slice(), port)
+                    .await
+            }
+            Some("sts") => {
+                self.resolve_workload::<StatefulSet>("sts", segments.as_slice(), port)
+                    .await
+            }
+            Some("nsl") => {
+                self.resolve_namespace_label(segments.as_slice(), port)
+                    .await
+            }
+            _ => Err(Errors::UnsupportedAddress(address.to_string())),
+        }
+    }
+
+    /// Resolves `<endpoint>.<service>.<namespace>` to the pod behind exactly that endpoint of the
+    /// service's EndpointSlices, picked by its IP or its index, rather than any ready pod.
+    #[instrument(skip(self), fields(namespace = Empty, service = Empty, pod = Empty))]
+    async fn resolve_endpoint(&self, segments: &[&str], port: u16) -> Result<Target, Errors> {
+        let unsupported = || {
+            Errors::UnsupportedAddress(format!(
+                "{}.ep.{}",
+                segments.join("."),
+                self.ctx.config.cluster_domain
+            ))
+        };
+
+        let [endpoint, service_name, namespace] = segments else {
+            return Err(unsupported());
+        };
+        let wanted = EndpointRef::parse(endpoint).ok_or_else(unsupported)?;
+
+        self.check_namespace_allowed(namespace)?;
+
+        let span = Span::current();
+        span.record("namespace", namespace);
+        span.record("service", service_name);
+
+        let service_api: Api<Service> = Api::namespaced(self.client.clone(), namespace);
+        let service = service_api
+            .get_opt(service_name)
+            .await
+            .map_err(lookup_failed("get", "services"))?
+            .ok_or_else(|| Errors::ServiceNotFound {
+                namespace: namespace.to_string(),
+                service: service_name.to_string(),
+            })?;
+        self.check_name_allowed("Service", namespace, service_name)?;
+
+        let port = match port {
+            0 => first_service_port(&service).ok_or_else(|| Errors::ServiceInvalid {
+                namespace: namespace.to_string(),
+                service: service_name.to_string(),
+                reason: "no ports to default port 0 to".into(),
+            })?,
+            port => port,
+        };
+        let port_name = service
+            .spec
+            .iter()
+            .flat_map(|s| s.ports.iter().flatten())
+            .find(|p| p.port == i32::from(port))
+            .map(|p| p.name.clone().unwrap_or_default())
+            .ok_or_else(|| {
+                Errors::PortNotFound(namespace.to_string(), service_name.to_string(), port)
+            })?;
+
+        let slices_api: Api<EndpointSlice> = Api::namespaced(self.client.clone(), namespace);
+        let slices = slices_api
+            .list(
+                &ListParams::default()
+                    .labels(&format!("kubernetes.io/service-name={service_name}")),
+            )
+            .await
+            .map_err(lookup_failed("list", "endpointslices"))?
+            .items;
+
+        let endpoints = slice_endpoints(&slices, &port_name);
+        let found = wanted
+            .find(&endpoints)
+            .ok_or_else(|| Errors::EndpointNotFound {
+                namespace: namespace.to_string(),
+                service: service_name.to_string(),
+                endpoint: endpoint.to_string(),
+            })?;
+        let invalid = |reason: String| Errors::ServiceInvalid {
+            namespace: namespace.to_string(),
+            service: service_name.to_string(),
+            reason,
+        };
+        let pod_name = found
+            .pod
+            .as_deref()
+            .ok_or_else(|| invalid(format!("endpoint {} isn't a pod", found.ip)))?;
+        let pod_port = found
+            .port
+            .ok_or_else(|| invalid(format!("EndpointSlices have no port {port_name:?}")))?;
+        span.record("pod", pod_name);
+
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+        let pod = pod_api
+            .get_opt(pod_name)
+            .await
+            .map_err(lookup_failed("get", "pods"))?
+            .ok_or_else(|| Errors::PodNotFound {
+                namespace: namespace.to_string(),
+                pod: pod_name.into(),
+            })?;
+
+        debug!(endpoint = %found.ip, pod_port, "selected endpoint");
+        Ok(Target {
+            app_protocol: service_app_protocol(&service, port),
+            ..Target::new(&pod, namespace, pod_port).through_service(&service)
+        })
+    }
+
+    /// Resolves `[<hostname>.]<service>.<value>` as a service in the one namespace labelled
+    /// `<namespace-label>=<value>`, for namespaces whose names are generated.
+    #[instrument(skip(self), fields(namespace = Empty))]
+    async fn resolve_namespace_label(
+        &self,
+        segments: &[&str],
+        port: u16,
+    ) -> Result<Target, Errors> {
+        let unsupported = || {
+            Errors::UnsupportedAddress(format!(
+                "{}.nsl.{}",
+                segments.join("."),
+                self.ctx.config.cluster_domain
+            ))
+        };
+
+        let key = self
+            .ctx
+            .config
+            .namespace_label
+            .as_deref()
+            .ok_or_else(unsupported)?;
+        let (value, service_segments) = segments.split_last().ok_or_else(unsupported)?;
+        validate_label_value(value).map_err(|_| unsupported())?;
+
+        let selector = format!("{key}={value}");
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let namespaces = namespace_api
+            .list(&ListParams::default().labels(&selector))
+            .await
+            .map_err(lookup_failed("list", "namespaces"))?
+            .items;
+
+        let namespace = match namespaces.as_slice() {
+            [namespace] => namespace.metadata.name.clone().unwrap_or_default(),
+            [] => return Err(Errors::NamespaceNotFound(selector)),
+            many => {
+                return Err(Errors::NamespaceAmbiguous {
+                    selector,
+                    namespaces: many
+                        .iter()
+                        .filter_map(|n| n.metadata.name.clone())
+                        .collect(),
+                })
+            }
+        };
+        Span::current().record("namespace", namespace.as_str());
+
+        let segments: Vec<&str> = service_segments
+            .iter()
+            .copied()
+            .chain([namespace.as_str()])
+            .collect();
+        self.resolve_service(&segments, port).await
+    }
+
+    #[instrument(
+        skip(self),
+        fields(
+            namespace = Empty,
+            service = Empty,
+            hostname = Empty,
+            port_name = Empty,
+            selector = Empty,
+            candidates = Empty,
+            pod = Empty,
+            app_protocol = Empty,
+            headless = Empty,
+            affinity = Empty,
+        )
+    )]
+    async fn resolve_service(&self, segments: &[&str], port: u16) -> Result<Target, Errors> {
+        let unsupported = || {
+            Errors::UnsupportedAddress(format!(
+                "{}.svc.{}",
+                segments.join("."),
+                self.ctx.config.cluster_domain
+            ))
+        };
+
+        let pod_hostname: Option<&str>;
+        let service_name: &str;
+        let namespace: &str;
+
+        // `_<port>._<proto>.<service>.<namespace>`, the name of the service's SRV record for a
+        // named port, which takes the place of the requested port
+        let (port_name, segments) = match segments {
+            [port_name, proto, rest @ ..] if proto.starts_with('_') => {
+                let port_name = port_name.strip_prefix('_').ok_or_else(unsupported)?;
+                // Port-forwards only carry TCP
+                if *proto != "_tcp" || port_name.is_empty() {
+                    return Err(unsupported());
+                }
+                (Some(port_name), rest)
+            }
+            _ => (None, segments),
+        };
+
+        if segments.len() == 2 {
+            pod_hostname = None;
+            service_name = segments[0];
+            namespace = segments[1];
+        } else if segments.len() == 3 && port_name.is_none() {
+            pod_hostname = Some(segments[0]);
+            service_name = segments[1];
+            namespace = segments[2];
+        } else {
+            return Err(unsupported());
+        }
+        self.check_namespace_allowed(namespace)?;
+
+        let span = Span::current();
+        span.record("namespace", namespace);
+        span.record("service", service_name);
+        if let Some(hostname) = pod_hostname {
+            span.record("hostname", hostname);
+        }
+        if let Some(port_name) = port_name {
+            span.record("port_name", port_name);
+        }
+
+        let service_api: Api<Service> = Api::namespaced(self.client.clone(), namespace);
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+
+        if let Some(service) = service_api
+            .get_opt(service_name)
+            .await
+            .map_err(lookup_failed("get", "services"))?
+        {
+            self.check_name_allowed("Service", namespace, service_name)?;
+
+            let selectors = service
+                .spec
+                .as_ref()
+                .ok_or_else(|| Errors::ServiceInvalid {
+                    namespace: namespace.into(),
+                    service: service_name.into(),
+                    reason: "spec is not set".into(),
+                })?
+                .selector
+                .as_ref()
+                .ok_or_else(|| Errors::ServiceInvalid {
+                    namespace: namespace.into(),
+                    service: service_name.into(),
+                    reason: "spec.selectors is not set".into(),
+                })?;
+
+            span.record("selector", summarize_selector(selectors));
+
+            let port = match (port_name, port) {
+                (Some(port_name), _) => {
+                    named_service_port(&service, port_name).ok_or_else(|| {
+                        Errors::PortNameNotFound {
+                            namespace: namespace.into(),
+                            service: service_name.into(),
+                            port_name: port_name.into(),
+                        }
+                    })?
+                }
+                (None, 0) => {
+                    first_service_port(&service).ok_or_else(|| Errors::ServiceInvalid {
+                        namespace: namespace.into(),
+                        service: service_name.into(),
+                        reason: "no ports to default port 0 to".into(),
+                    })?
+                }
+                (None, port) => port,
+            };
+
+            let app_protocol = service_app_protocol(&service, port);
+            if let Some(ref app_protocol) = app_protocol {
+                span.record("app_protocol", app_protocol.as_str());
+            }
+            let named_target_port = NamedTargetPort::of(&service, port);
+
+            let labels =
+                selector_into_labels(selectors).map_err(|reason| Errors::ServiceInvalid {
+                    namespace: namespace.into(),
+                    service: service_name.into(),
+                    reason,
+                })?;
+
+            let port_error = |e| match e {
+                PortError::NotFound => {
+                    Errors::PortNotFound(namespace.into(), service_name.into(), port)
+                }
+                PortError::Invalid(reason) => Errors::ServiceInvalid {
+                    namespace: namespace.into(),
+                    service: service_name.into(),
+                    reason,
+                },
+            };
+
+            // Headless services have no virtual IP, their DNS records name the pods themselves
+            let headless = is_headless(&service);
+            if headless {
+                span.record("headless", true);
+            }
+
+            if let Some(hostname) = pod_hostname {
+                let publish_not_ready = service
+                    .spec
+                    .as_ref()
+                    .and_then(|s| s.publish_not_ready_addresses)
+                    .unwrap_or_default();
+
+                // Matched a page at a time, so a pod named after the hostname on an earlier page
+                // wins over one with that `spec.hostname` on a later one
+                let found = self
+                    .find_pod(&pod_api, &labels, |pods| match headless {
+                        // Only the pods cluster DNS has a record for
+                        true => pods.into_iter().find(|p| {
+                            has_dns_hostname(p, hostname, service_name)
+                                && (publish_not_ready || is_ready(p, &self.ctx.config))
+                        }),
+                        false => find_by_hostname(&pods, hostname).cloned(),
+                    })
+                    .await?;
+
+                if let Some(ref pod) = found.pod {
+                    let pod_port = service_pod_port(&service, pod, port).map_err(port_error)?;
+                    let target = Target {
+                        app_protocol,
+                        named_target_port,
+                        ..Target::new(pod, namespace, pod_port).through_service(&service)
+                    };
+                    span.record("pod", target.pod.as_str());
+                    debug!(
+                        pod_port,
+                        app_protocol = target.app_protocol,
+                        "selected pod by hostname"
+                    );
+                    return Ok(target);
+                } else {
+                    return Err(Errors::NamedServicePodsNotFound {
+                        namespace: namespace.into(),
+                        service: service_name.into(),
+                        pod: hostname.into(),
+                    });
+                }
+            }
+
+            let affinity = self.client_ip.filter(|_| has_client_ip_affinity(&service));
+            let found = match (affinity, headless) {
+                (Some(client_ip), _) => {
+                    span.record("affinity", "ClientIP");
+                    self.affine_ready_pod(&pod_api, &labels, client_ip).await?
+                }
+                (None, true) => {
+                    self.next_ready_pod(&pod_api, &labels, namespace, service_name)
+                        .await?
+                }
+                (None, false) => self.ready_pod(&pod_api, &labels).await?,
+            };
+
+            if let Some(pod) = found.pod {
+                let pod_port = service_pod_port(&service, &pod, port).map_err(port_error)?;
+
+                let target = Target {
+                    app_protocol,
+                    named_target_port,
+                    ..Target::new(&pod, namespace, pod_port).through_service(&service)
+                };
+                span.record("pod", target.pod.as_str());
+                debug!(
+                    pod_port,
+                    app_protocol = target.app_protocol,
+                    "selected ready pod"
+                );
+
+                return Ok(target);
+            } else if found.matched == 0 {
+                return Err(Errors::ServiceNoMatchingPods {
+                    namespace: namespace.into(),
+                    service: service_name.into(),
+                });
+            } else {
+                return Err(Errors::ServiceNoReadyPods {
+                    namespace: namespace.into(),
+                    service: service_name.into(),
+                });
+            }
+        }
+
+        Err(Errors::ServiceNotFound {
+            namespace: namespace.into(),
+            service: service_name.into(),
+        })
+    }
+
+    /// With `probe-named-target-ports`, checks the target's pod listens on the port the service
+    /// port's named `targetPort` mapped to, as a name may be declared only for a probe. Done with
+    /// a forward that's closed again straight away, and skipped if that can't be opened, leaving
+    /// the forward proper to report why.
+    async fn check_target_port(&self, target: &Target) -> Result<(), Errors> {
+        if !self.ctx.config.probe_named_target_ports {
+            return Ok(());
+        }
+        let Some(ref named) = target.named_target_port else {
+            return Ok(());
+        };
+
+        let connect_timeout = target.connect_timeout_or(&self.ctx.config);
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), &target.namespace);
+        let mut forwarder = match tokio::time::timeout(
+            connect_timeout,
+            pods.portforward(&target.pod, &[target.port]),
+        )
+        .await
+        {
+            Ok(Ok(forwarder)) => forwarder,
+            Ok(Err(e)) => {
+                debug!(
+                    error = &e as &dyn std::error::Error,
+                    "couldn't probe target port"
+                );
+                return Ok(());
+            }
+            Err(_elapsed) => {
+                debug!("timed out probing target port");
+                return Ok(());
+            }
+        };
+
+        // Refusals only come through the error channel, like for the forward proper
+        let probe = match self.ctx.config.forward_probe_ms {
+            0 => DEFAULT_FORWARD_PROBE_MS,
+            ms => ms,
+        };
+        let refused = match forwarder.take_error(target.port) {
+            Some(error) => tokio::time::timeout(Duration::from_millis(probe), error)
+                .await
+                .ok()
+                .flatten(),
+            None => None,
+        };
+        forwarder.abort();
+
+        match refused {
+            Some(reason) => Err(Errors::TargetPortNotServed {
+                namespace: target.namespace.clone(),
+                service: named.service.clone(),
+                port_name: named.port_name.clone(),
+                pod: target.pod.clone(),
+                port: target.port,
+                reason,
+            }),
+            None => {
+                debug!(
+                    port_name = named.port_name,
+                    "pod listens on named target port"
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Resolves `<name>.<namespace>` to a ready pod selected by the named workload.
+    #[instrument(
+        skip(self),
+        fields(
+            kind = K::KIND,
+            namespace = Empty,
+            workload = Empty,
+            selector = Empty,
+            candidates = Empty,
+            pod = Empty,
+        )
+    )]
+    async fn resolve_workload<K: Workload>(
+        &self,
+        suffix: &str,
+        segments: &[&str],
+        port: u16,
+    ) -> Result<Target, Errors> {
+        if segments.len() != 2 {
+            return Err(Errors::UnsupportedAddress(format!(
+                "{}.{suffix}.{}",
+                segments.join("."),
+                self.ctx.config.cluster_domain
+            )));
+        }
+
+        let name = segments[0];
+        let namespace = segments[1];
+        self.check_namespace_allowed(namespace)?;
+
+        let span = Span::current();
+        span.record("namespace", namespace);
+        span.record("workload", name);
+
+        let workload_api: Api<K> = Api::namespaced(self.client.clone(), namespace);
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+
+        let workload = workload_api
+            .get_opt(name)
+            .await
+            .map_err(lookup_failed("get", &K::plural(&())))?
+            .ok_or_else(|| Errors::WorkloadNotFound {
+                kind: K::KIND,
+                namespace: namespace.into(),
+                name: name.into(),
+            })?;
+
+        let invalid = |reason: String| Errors::WorkloadInvalid {
+            kind: K::KIND,
+            namespace: namespace.into(),
+            name: name.into(),
+            reason,
+        };
+
+        let selector = workload
+            .selector()
+            .ok_or_else(|| invalid("spec is not set".into()))?;
+        let labels = label_selector_into_labels(selector).map_err(invalid)?;
+
+        span.record("selector", labels.as_str());
+
+        let pod = self
+            .ready_pod(&pod_api, &labels)
+            .await?
+            .pod
+            .ok_or_else(|| Errors::WorkloadNoReadyPods {
+                kind: K::KIND,
+                namespace: namespace.into(),
+                name: name.into(),
+            })?;
+
+        let target = Target::with_default_port(&pod, namespace, port)?;
+        span.record("pod", target.pod.as_str());
+        debug!("selected ready pod");
+
+        Ok(target)
+    }
+
+    /// Lists the pods matching `labels` and picks a ready one, waiting for one to become ready
+    /// if configured to.
+    async fn ready_pod(&self, pod_api: &Api<Pod>, labels: &str) -> Result<FoundPod, Errors> {
+        let found = self
+            .find_pod(pod_api, labels, |pods| {
+                pods.into_iter()
+                    .find(|p| is_ready(p, &self.ctx.config) && !self.is_excluded_pod(p))
+            })
+            .await?;
+
+        self.or_wait_for_ready_pod(pod_api, labels, found).await
+    }
+
+    /// Picks each of the ready pods matching `labels` in turn, as clients of a headless service's
+    /// DNS records spread across them, waiting for one to become ready if configured to.
+    async fn next_ready_pod(
+        &self,
+        pod_api: &Api<Pod>,
+        labels: &str,
+        namespace: &str,
+        service: &str,
+    ) -> Result<FoundPod, Errors> {
+        // Every page is needed to take turns fairly
+        let (mut ready, mut found) = self.all_ready_pods(pod_api, labels).await?;
+
+        if !ready.is_empty() {
+            // Listed in whatever order the API server likes, so turns follow the names instead
+            ready.sort_by(|a, b| a.metadata.name.cmp(&b.metadata.name));
+            let turn = self.ctx.round_robin.next(namespace, service) % ready.len();
+            found.pod = Some(ready.swap_remove(turn));
+        }
+
+        self.or_wait_for_ready_pod(pod_api, labels, found).await
+    }
+
+    /// Picks the ready pod matching `labels` that `client_ip` keeps getting for as long as that
+    /// pod stays ready, like a service's `ClientIP` session affinity, waiting for one to become
+    /// ready if configured to.
+    async fn affine_ready_pod(
+        &self,
+        pod_api: &Api<Pod>,
+        labels: &str,
+        client_ip: IpAddr,
+    ) -> Result<FoundPod, Errors> {
+        let (ready, mut found) = self.all_ready_pods(pod_api, labels).await?;
+        found.pod = pick_affine(ready, client_ip);
+
+        self.or_wait_for_ready_pod(pod_api, labels, found).await
+    }
+
+    /// Waits for a pod matching `labels` to become ready if none was `found`.
+    async fn or_wait_for_ready_pod(
+        &self,
+        pod_api: &Api<Pod>,
+        labels: &str,
+        mut found: FoundPod,
+    ) -> Result<FoundPod, Errors> {
+        if found.pod.is_none() {
+            found.pod = self
+                .wait_for_ready_pod(pod_api, labels, found.resource_version.clone())
+                .await?;
+        }
+        Ok(found)
+    }
+
+    /// Lists every ready pod matching `labels`, other than excluded ones.
+    async fn all_ready_pods(
+        &self,
+        pod_api: &Api<Pod>,
+        labels: &str,
+    ) -> Result<(Vec<Pod>, FoundPod), Errors> {
+        let mut ready = Vec::new();
+        let found = self
+            .find_pod(pod_api, labels, |pods| {
+                ready.extend(
+                    pods.into_iter()
+                        .filter(|p| is_ready(p, &self.ctx.config) && !self.is_excluded_pod(p)),
+                );
+                None
+            })
+            .await?;
+
+        Ok((ready, found))
+    }
+
+    /// Lists the pods matching `labels` up to `list-page-size` at a time, until `pick` chooses
+    /// one from a page, so a selector matching huge numbers of pods needn't be fetched in full.
+    async fn find_pod(
+        &self,
+        pod_api: &Api<Pod>,
+        labels: &str,
+        mut pick: impl FnMut(Vec<Pod>) -> Option<Pod>,
+    ) -> Result<FoundPod, Errors> {
+        let mut params = ListParams::default().labels(labels);
+        if self.ctx.config.list_page_size > 0 {
+            params = params.limit(self.ctx.config.list_page_size);
+        }
+
+        let mut found = FoundPod {
+            pod: None,
+            resource_version: None,
+            matched: 0,
+        };
+        loop {
+            let page = pod_api
+                .list(&params)
+                .await
+                .map_err(lookup_failed("list", "pods"))?;
+
+            found.matched += page.items.len();
+            Span::current().record("candidates", found.matched);
+            // Every page is from the snapshot the first was, so that's where a watch picks up
+            found.resource_version = found.resource_version.or(page.metadata.resource_version);
+
+            found.pod = pick(page.items);
+            match page.metadata.continue_ {
+                Some(token) if found.pod.is_none() && !token.is_empty() => {
+                    params = params.continue_token(&token);
+                }
+                _ => return Ok(found),
+            }
+        }
+    }
+
+    /// Watches for a pod matching `labels` to become ready, for up to the configured
+    /// `wait-for-ready`, failing with `ReadyWaitTimedOut` if none does. Dropping the returned
+    /// future cancels the watch.
+    async fn wait_for_ready_pod(
+        &self,
+        pod_api: &Api<Pod>,
+        labels: &str,
+        resource_version: Option<String>,
+    ) -> Result<Option<Pod>, Errors> {
+        let wait = Duration::from_secs(self.ctx.config.wait_for_ready);
+        if wait.is_zero() {
+            return Ok(None);
+        }
+
+        debug!(?wait, "no ready pods, watching for one");
+        let started = Instant::now();
+
+        let watch_params = WatchParams::default()
+            .labels(labels)
+            .timeout(wait.as_secs().min(MAX_WATCH_TIMEOUT_SECS) as u32);
+
+        let mut events = pod_api
+            .watch(&watch_params, resource_version.as_deref().unwrap_or("0"))
+            .await
+            .map_err(lookup_failed("watch", "pods"))?
+            .boxed();
+
+        let ready = tokio::time::timeout(wait, async {
+            while let Some(event) = events.try_next().await? {
+                match event {
+                    WatchEvent::Added(pod) | WatchEvent::Modified(pod)
+                        if is_ready(&pod, &self.ctx.config) && !self.is_excluded_pod(&pod) =>
+                    {
+                        return Ok(Some(pod));
+                    }
+                    WatchEvent::Error(e) => return Err(kube::Error::Api(e)),
+                    _ => {}
+                }
+            }
+            Ok(None)
+        })
+        .await;
+
+        let waited = started.elapsed();
+        self.ctx.metrics.ready_wait.observe(waited);
+        let ready = match ready {
+            Ok(pod) => pod.map_err(lookup_failed("watch", "pods"))?,
+            Err(_elapsed) => None,
+        };
+        info!(?waited, ready = ready.is_some(), "waited for a ready pod");
+
+        // The watch ending early is the API server timing it out, so that too is a timeout
+        ready.map(Some).ok_or_else(|| Errors::ReadyWaitTimedOut {
+            selector: labels.into(),
+            waited,
+        })
+    }
+
+    #[instrument(skip(self), fields(namespace = Empty, pod = Empty))]
+    async fn resolve_pod(&self, segments: &[&str], port: u16) -> Result<Target, Errors> {
+        if let [pod_name] = segments {
+            if self.ctx.config.allow_cross_namespace_pod {
+                return self.resolve_pod_in_any_namespace(pod_name, port).await;
+            }
+        }
+
+        if segments.len() != 2 {
+            return Err(Errors::UnsupportedAddress(format!(
+                "{}.pod.{}",
+                segments.join("."),
+                self.ctx.config.cluster_domain
+            )));
+        }
+
+        let pod_name = segments[0];
+        let namespace = segments[1];
+        self.check_namespace_allowed(namespace)?;
+
+        let span = Span::current();
+        span.record("namespace", namespace);
+
+        // As in cluster DNS, `10-1-2-3` is the pod with that IP rather than a pod by that name
+        if let Some(ip) = hyphenated_ip(pod_name) {
+            return self.resolve_pod_ip_in(namespace, ip, port).await;
+        }
+        span.record("pod", pod_name);
+
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+
+        match pods
+            .get_opt(pod_name)
+            .await
+            .map_err(lookup_failed("get", "pods"))?
+        {
+            Some(pod) => {
+                self.declared_port(&pod, Target::with_default_port(&pod, namespace, port)?)
+            }
+            None => Err(Errors::PodNotFound {
+                namespace: namespace.into(),
+                pod: pod_name.into(),
+            }),
+        }
+    }
+
+    /// Finds the ready pod in `namespace` with IP `ip`, for `<ip-with-dashes>.<namespace>.pod`.
+    async fn resolve_pod_ip_in(
+        &self,
+        namespace: &str,
+        ip: IpAddr,
+        port: u16,
+    ) -> Result<Target, Errors> {
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+        let pod = pods
+            .list(&ListParams::default().fields(&format!("status.podIP={ip}")))
+            .await
+            .map_err(lookup_failed("list", "pods"))?
+            .items
+            .into_iter()
+            .find(|p| !is_host_network(p) && is_ready(p, &self.ctx.config))
+            .ok_or_else(|| Errors::PodIpNotFoundInNamespace {
+                namespace: namespace.into(),
+                ip,
+            })?;
+
+        let target = Target::with_default_port(&pod, namespace, port)?;
+        Span::current().record("pod", target.pod.as_str());
+        Ok(target)
+    }
+
+    /// Checks one of the pod's containers declares the target's port, unless
+    /// `allow-undeclared-ports` is set. A port-forward reaches any port something listens on, but
+    /// one the pod doesn't declare is more likely a typo than a process the spec leaves out.
+    fn declared_port(&self, pod: &Pod, target: Target) -> Result<Target, Errors> {
+        // A denied pod is reported as such, whatever port was asked for
+        self.check_name_allowed("Pod", &target.namespace, &target.pod)?;
+
+        if self.ctx.config.allow_undeclared_ports || declares_port(pod, target.port) {
+            return Ok(target);
+        }
+
+        Err(Errors::PortNotFound(
+            target.namespace,
+            target.pod,
+            target.port,
+        ))
+    }
+
+    /// Finds the one pod named `pod_name` across all namespaces, for `--allow-cross-namespace-pod`.
+    async fn resolve_pod_in_any_namespace(
+        &self,
+        pod_name: &str,
+        port: u16,
+    ) -> Result<Target, Errors> {
+        Span::current().record("pod", pod_name);
+
+        let pods: Api<Pod> = Api::all(self.client.clone());
+        let pods = pods
+            .list(&ListParams::default().fields(&format!("metadata.name={pod_name}")))
+            .await
+            .map_err(lookup_failed("list", "pods"))?
+            .items;
+
+        match pods.as_slice() {
+            [pod] => {
+                let namespace = pod.metadata.namespace.clone().unwrap_or_default();
+                Span::current().record("namespace", namespace.as_str());
+                self.declared_port(pod, Target::with_default_port(pod, &namespace, port)?)
+            }
+            [] => Err(Errors::PodNotFound {
+                namespace: "*".into(),
+                pod: pod_name.into(),
+            }),
+            many => Err(Errors::PodAmbiguous {
+                pod: pod_name.into(),
+                namespaces: many
+                    .iter()
+                    .filter_map(|p| p.metadata.namespace.clone())
+                    .collect(),
+            }),
+        }
+    }
+}
+
+/// Classifies an error the kubelet reported on a new forward. Nothing listening on the port is
+/// a refusal the client can act on, anything else is the forward itself failing.
+fn refused_or_failed(target: &Target, reason: String) -> Errors {
+    match reason.contains("connection refused") {
+        true => Errors::ConnectionRefused {
+            namespace: target.namespace.clone(),
+            pod: target.pod.clone(),
+            port: target.port,
+            reason,
+        },
+        false => Errors::ForwardFailed(anyhow::anyhow!(reason)),
+    }
+}
+
+/// Splits an address ending `.<name>.clusters` into the cluster's name and the address within it,
+/// which is the rest given under the cluster domain, eg. `web.apps.svc.prod.clusters` into
+/// `prod` and `web.apps.svc.cluster.local.`. The address is fully qualified, so no search domain
+/// is tried.
+pub(crate) fn split_cluster(address: &str, cluster_domain: &str) -> Option<(String, String)> {
+    let (address, _) = normalize_address(address);
+    let (within, name) = address.strip_suffix(".clusters")?.rsplit_once('.')?;
+
+    Some((name.into(), format!("{within}.{cluster_domain}.")))
+}
+
+/// Names are case-insensitive and may be written fully qualified with a trailing dot, so the
+/// address is lowercased and any trailing dot stripped. Returns whether it had one.
+pub(crate) fn normalize_address(address: &str) -> (String, bool) {
+    match address.strip_suffix('.') {
+        Some(stripped) => (stripped.to_ascii_lowercase(), true),
+        None => (address.to_ascii_lowercase(), false),
+    }
+}
+
+/// Whether `e` or anything that caused it is the connection to the pod or API server going away,
+/// rather than something unexpected like a protocol violation.
+fn is_disconnect(e: &(dyn std::error::Error + 'static)) -> bool {
+    use tokio_tungstenite::tungstenite::{error::ProtocolError, Error as WsError};
+
+    std::iter::successors(Some(e), |e| e.source()).any(|e| {
+        if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+                    | std::io::ErrorKind::NotConnected
+            );
+        }
+        if let Some(e) = e.downcast_ref::<WsError>() {
+            return matches!(
+                e,
+                WsError::ConnectionClosed
+                    | WsError::AlreadyClosed
+                    | WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake)
+            );
+        }
+        // The forwarder's internal channels close when the other end of a stream is dropped
+        e.downcast_ref::<futures::channel::mpsc::SendError>()
+            .is_some_and(|e| e.is_disconnected())
+    })
+}
+
+/// Whether `address` is empty or has an empty label, eg. `a..svc`, which would otherwise be looked
+/// up as an empty name or namespace.
+fn has_empty_label(address: &str) -> bool {
+    address.split('.').any(str::is_empty)
+}
+
+/// Maps a failed API call, surfacing a 403 as `Forbidden` rather than a generic lookup failure.
+/// What the API server said is logged at debug, to tell a 404 from a 403 from a 500.
+fn lookup_failed<'a>(
+    verb: &'static str,
+    resource: &'a str,
+) -> impl FnOnce(kube::Error) -> Errors + 'a {
+    move |e| {
+        if let kube::Error::Api(ref response) = e {
+            debug!(
+                verb,
+                resource,
+                code = response.code,
+                reason = response.reason,
+                message = response.message,
+                "API server failed the request"
+            );
+        }
+        forbidden(&e, verb, resource).unwrap_or(Errors::LookupFailed(e))
+    }
+}
+
+/// A missing RBAC permission won't fix itself on retry, so it's logged loudly for operators.
+fn forbidden(e: &kube::Error, verb: &'static str, resource: &str) -> Option<Errors> {
+    match e {
+        kube::Error::Api(response) if response.code == 403 => {
+            error!(
+                verb,
+                resource,
+                message = response.message,
+                "forbidden, check the service account's RBAC permissions"
+            );
+            Some(Errors::Forbidden {
+                verb,
+                resource: resource.into(),
+                message: response.message.clone(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Overrides `connect-timeout` for a pod, or the pods of a service.
+const CONNECT_TIMEOUT_ANNOTATION: &str = "kube-fwd-socks/connect-timeout";
+
+/// The object's [`CONNECT_TIMEOUT_ANNOTATION`], in seconds like `--connect-timeout` or with a
+/// unit like `500ms`. An invalid one is ignored, so a typo doesn't make the target unreachable.
+fn annotated_connect_timeout(metadata: &ObjectMeta) -> Option<Duration> {
+    let value = metadata
+        .annotations
+        .as_ref()?
+        .get(CONNECT_TIMEOUT_ANNOTATION)?;
+
+    match value
+        .parse()
+        .map(Duration::from_secs)
+        .or_else(|_| humantime::parse_duration(value))
+    {
+        Ok(timeout) if !timeout.is_zero() => Some(timeout),
+        _ => {
+            warn!(
+                name = metadata.name,
+                namespace = metadata.namespace,
+                value,
+                "ignoring invalid {CONNECT_TIMEOUT_ANNOTATION} annotation"
+            );
+            None
+        }
+    }
+}
+
+const EMPTY_CONTAINER_PORT_VEC: &Vec<ContainerPort> = &Vec::new();
+
+/// The API server caps watch timeouts at five minutes, the overall wait is enforced locally.
+const MAX_WATCH_TIMEOUT_SECS: u64 = 290;
+
+/// Longest selector summary recorded on a span, selectors can be arbitrarily large.
+const MAX_SELECTOR_SUMMARY_LEN: usize = 128;
+
+fn summarize_selector(selectors: &BTreeMap<String, String>) -> String {
+    let mut summary = String::new();
+
+    for (i, (key, value)) in selectors.iter().enumerate() {
+        let entry = format!("{}{key}={value}", if i == 0 { "" } else { "," });
+        if summary.len() + entry.len() > MAX_SELECTOR_SUMMARY_LEN {
+            summary.push_str(&format!(" (+{} more)", selectors.len() - i));
+            break;
+        }
+        summary.push_str(&entry);
+    }
+
+    summary
+}
+
+/// Converts an equality selector into a label selector query string, rejecting keys or values
+/// that aren't valid labels as they could change the meaning of the query.
+fn selector_into_labels(selectors: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut res = String::new();
+
+    for (key, value) in selectors {
+        validate_label_key(key)
+            .and_then(|_| validate_label_value(value))
+            .map_err(|reason| {
+                format!("selector {key:?}={value:?} is not a valid label - {reason}")
+            })?;
+
+        if !res.is_empty() {
+            res.push(',');
+        }
+        res.push_str(key);
+        res.push('=');
+        res.push_str(value);
+    }
+
+    Ok(res)
+}
+
+const MAX_LABEL_NAME_LEN: usize = 63;
+const MAX_LABEL_PREFIX_LEN: usize = 253;
+
+/// https://kubernetes.io/docs/concepts/overview/working-with-objects/labels/#syntax-and-character-set
+fn validate_label_key(key: &str) -> Result<(), &'static str> {
+    let name = match key.split_once('/') {
+        Some((prefix, name)) => {
+            let valid_prefix = !prefix.is_empty()
+                && prefix.len() <= MAX_LABEL_PREFIX_LEN
+                && prefix.split('.').all(|part| {
+                    !part.is_empty()
+                        && part
+                            .bytes()
+                            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+                        && !part.starts_with('-')
+                        && !part.ends_with('-')
+                });
+            if !valid_prefix {
+                return Err("key prefix must be a DNS subdomain");
+            }
+            name
+        }
+        None => key,
+    };
+
+    if name.is_empty() {
+        return Err("key name must not be empty");
+    }
+
+    validate_label_name(name)
+}
+
+fn validate_label_value(value: &str) -> Result<(), &'static str> {
+    if value.is_empty() {
+        return Ok(());
+    }
+
+    validate_label_name(value)
+}
+
+fn validate_label_name(name: &str) -> Result<(), &'static str> {
+    if name.len() > MAX_LABEL_NAME_LEN {
+        return Err("must be 63 characters or less");
+    }
+
+    if !name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+    {
+        return Err("must only contain alphanumerics, '-', '_' or '.'");
+    }
+
+    let starts_and_ends_alphanumeric = name
+        .bytes()
+        .next()
+        .is_some_and(|b| b.is_ascii_alphanumeric())
+        && name
+            .bytes()
+            .last()
+            .is_some_and(|b| b.is_ascii_alphanumeric());
+    if !starts_and_ends_alphanumeric {
+        return Err("must start and end with an alphanumeric character");
+    }
+
+    Ok(())
+}
+
+/// A controller whose pods are found through its `spec.selector`.
+trait Workload:
+    Resource<Scope = NamespaceResourceScope, DynamicType = ()>
+    + k8s_openapi::Resource
+    + Clone
+    + serde::de::DeserializeOwned
+    + std::fmt::Debug
+{
+    fn selector(&self) -> Option<&LabelSelector>;
+}
+
+impl Workload for Deployment {
+    fn selector(&self) -> Option<&LabelSelector> {
+        self.spec.as_ref().map(|s| &s.selector)
+    }
+}
+
+impl Workload for ReplicaSet {
+    fn selector(&self) -> Option<&LabelSelector> {
+        self.spec.as_ref().map(|s| &s.selector)
+    }
+}
+
+impl Workload for DaemonSet {
+    fn selector(&self) -> Option<&LabelSelector> {
+        self.spec.as_ref().map(|s| &s.selector)
+    }
+}
+
+impl Workload for StatefulSet {
+    fn selector(&self) -> Option<&LabelSelector> {
+        self.spec.as_ref().map(|s| &s.selector)
+    }
+}
+
+/// Converts a workload's label selector, both `matchLabels` and `matchExpressions`, into a label
+/// selector query string.
+fn label_selector_into_labels(selector: &LabelSelector) -> Result<String, String> {
+    let mut requirements = match selector.match_labels {
+        Some(ref labels) if !labels.is_empty() => vec![selector_into_labels(labels)?],
+        _ => vec![],
+    };
+
+    for expression in selector.match_expressions.iter().flatten() {
+        requirements.push(expression_into_requirement(expression)?);
+    }
+
+    if requirements.is_empty() {
+        return Err("spec.selector has neither matchLabels nor matchExpressions".into());
+    }
+
+    Ok(requirements.join(","))
+}
+
+fn expression_into_requirement(expression: &LabelSelectorRequirement) -> Result<String, String> {
+    let key = &expression.key;
+    validate_label_key(key).map_err(|reason| format!("expression key {key:?} - {reason}"))?;
+
+    let values = expression.values.as_deref().unwrap_or_default();
+    for value in values {
+        validate_label_value(value)
+            .map_err(|reason| format!("expression {key:?} value {value:?} - {reason}"))?;
+    }
+
+    let operator = expression.operator.as_str();
+    match (operator, values.is_empty()) {
+        ("In", false) => Ok(format!("{key} in ({})", values.join(","))),
+        ("NotIn", false) => Ok(format!("{key} notin ({})", values.join(","))),
+        ("Exists", true) => Ok(key.clone()),
+        ("DoesNotExist", true) => Ok(format!("!{key}")),
+        ("In" | "NotIn", true) => Err(format!("expression {key:?} {operator} requires values")),
+        ("Exists" | "DoesNotExist", false) => Err(format!(
+            "expression {key:?} {operator} must not have values"
+        )),
+        _ => Err(format!(
+            "expression {key:?} has unsupported operator {operator:?}"
+        )),
+    }
+}
+
+fn node_has_internal_ip(node: &Node, ip: IpAddr) -> bool {
+    node.status
+        .as_ref()
+        .and_then(|s| s.addresses.as_ref())
+        .is_some_and(|addresses| {
+            addresses
+                .iter()
+                .any(|a| a.type_ == "InternalIP" && a.address.parse() == Ok(ip))
+        })
+}
+
+/// Finds the pod a `<hostname>.<service>` address names. A pod whose `spec.hostname` matches
+/// exactly wins, as that's the name the service's DNS record would use, and only when none do
+/// is a pod with a matching `metadata.name` picked.
+fn find_by_hostname<'a>(pods: &'a [Pod], hostname: &str) -> Option<&'a Pod> {
+    pods.iter()
+        .find(|p| p.spec.as_ref().and_then(|s| s.hostname.as_deref()) == Some(hostname))
+        .or_else(|| {
+            pods.iter()
+                .find(|p| p.metadata.name.as_deref() == Some(hostname))
+        })
+}
+
+/// Whether the pod has the DNS record `<hostname>.<service>` under a headless service, which
+/// needs both its `spec.hostname` and its `spec.subdomain` to match.
+fn has_dns_hostname(pod: &Pod, hostname: &str, service: &str) -> bool {
+    pod.spec.as_ref().is_some_and(|s| {
+        s.hostname.as_deref() == Some(hostname) && s.subdomain.as_deref() == Some(service)
+    })
+}
+
+fn is_headless(service: &Service) -> bool {
+    service
+        .spec
+        .as_ref()
+        .is_some_and(|s| s.cluster_ip.as_deref() == Some("None"))
+}
+
+/// Whether the service sends each client to the same pod, with `sessionAffinity: ClientIP`.
+fn has_client_ip_affinity(service: &Service) -> bool {
+    service
+        .spec
+        .as_ref()
+        .is_some_and(|s| s.session_affinity.as_deref() == Some("ClientIP"))
+}
+
+/// The pod `client_ip` hashes to highest alongside each pod's name. Only clients of a pod that
+/// goes away move to another, the rest keep theirs as pods come and go.
+fn pick_affine(pods: Vec<Pod>, client_ip: IpAddr) -> Option<Pod> {
+    pods.into_iter().max_by_key(|pod| {
+        let mut hasher = std::hash::DefaultHasher::new();
+        (client_ip, &pod.metadata.name).hash(&mut hasher);
+        hasher.finish()
+    })
+}
+
+/// Whose turn it is next for each headless service, shared by every connection.
+#[derive(Default)]
+pub struct RoundRobin {
+    turns: Mutex<HashMap<(String, String), usize>>,
+}
+
+impl RoundRobin {
+    /// The turn for the service, counting up from 0 each time it's asked.
+    fn next(&self, namespace: &str, service: &str) -> usize {
+        let mut turns = self.turns.lock().unwrap();
+        let turn = turns
+            .entry((namespace.to_string(), service.to_string()))
+            .or_default();
+        let current = *turn;
+        *turn = turn.wrapping_add(1);
+        current
+    }
+}
+
+enum PortError {
+    NotFound,
+    Invalid(String),
+}
+
+/// Maps a service port to the port on one of its pods, following the matching service port's
+/// `targetPort`, which defaults to the service port when unset. A service without any ports
+/// can't map anything, so the requested port is used as is.
+fn service_pod_port(service: &Service, pod: &Pod, port: u16) -> Result<u16, PortError> {
+    let ports = match service.spec.as_ref().and_then(|s| s.ports.as_ref()) {
+        Some(ports) if !ports.is_empty() => ports,
+        _ => return Ok(port),
+    };
+
+    let service_port = ports
+        .iter()
+        .find(|p| p.port == i32::from(port))
+        .ok_or(PortError::NotFound)?;
+
+    match service_port.target_port {
+        Some(IntOrString::String(ref port_name)) => pod
+            .spec
+            .as_ref()
+            .and_then(|spec| {
+                spec.containers
+                    .iter()
+                    .flat_map(|c| c.ports.as_ref().unwrap_or(EMPTY_CONTAINER_PORT_VEC))
+                    .find(|p| p.name.as_ref() == Some(port_name))
+                    .and_then(|p| u16::try_from(p.container_port).ok())
+            })
+            .ok_or(PortError::NotFound),
+        Some(IntOrString::Int(0)) => Err(PortError::Invalid(format!(
+            "targetPort of port {port} is 0"
+        ))),
+        Some(IntOrString::Int(i)) => u16::try_from(i).map_err(|_| {
+            PortError::Invalid(format!("targetPort {i} of port {port} is outside 1-65535"))
+        }),
+        None => Ok(port),
+    }
+}
+
+/// Which endpoint of a service an `.ep` address asks for.
+#[derive(Debug, PartialEq)]
+enum EndpointRef {
+    /// Its IP, written with dashes like in pod addresses
+    Ip(IpAddr),
+    /// Its place among the service's endpoints ordered by IP, from 0
+    Index(usize),
+}
+
+impl EndpointRef {
+    fn parse(label: &str) -> Option<Self> {
+        match hyphenated_ip(label) {
+            Some(ip) => Some(EndpointRef::Ip(ip)),
+            None => label.parse().ok().map(EndpointRef::Index),
+        }
+    }
+
+    fn find<'e>(&self, endpoints: &'e [Endpoint]) -> Option<&'e Endpoint> {
+        match *self {
+            EndpointRef::Ip(ip) => endpoints.iter().find(|e| e.ip == ip),
+            EndpointRef::Index(i) => endpoints.get(i),
+        }
+    }
+}
+
+/// An address listed in a service's EndpointSlices.
+#[derive(Debug, PartialEq)]
+struct Endpoint {
+    ip: IpAddr,
+    /// Name of the pod it's for, unset for endpoints that aren't pods
+    pod: Option<String>,
+    /// The slice's number for the service port being forwarded to
+    port: Option<u16>,
+}
+
+/// Every address in `slices`, whether ready or not, ordered by IP. `port_name` is the name of
+/// the service port, empty if it has none, which slices list their port numbers under.
+fn slice_endpoints(slices: &[EndpointSlice], port_name: &str) -> Vec<Endpoint> {
+    let mut endpoints: Vec<Endpoint> = slices
+        .iter()
+        .flat_map(|slice| {
+            let port = slice
+                .ports
+                .iter()
+                .flatten()
+                .find(|p| p.name.as_deref().unwrap_or_default() == port_name)
+                .and_then(|p| p.port)
+                .and_then(|p| u16::try_from(p).ok());
+
+            slice.endpoints.iter().flat_map(move |e| {
+                let pod = e
+                    .target_ref
+                    .as_ref()
+                    .filter(|r| r.kind.as_deref() == Some("Pod"))
+                    .and_then(|r| r.name.clone());
+                e.addresses.iter().filter_map(move |a| {
+                    Some(Endpoint {
+                        ip: a.parse().ok()?,
+                        pod: pod.clone(),
+                        port,
+                    })
+                })
+            })
+        })
+        .collect();
+
+    endpoints.sort_by_key(|e| e.ip);
+    endpoints.dedup_by_key(|e| e.ip);
+    endpoints
+}
+
+/// The name the service port `port` gives as its `targetPort`, if it names one.
+fn named_target_port(service: &Service, port: u16) -> Option<&str> {
+    service
+        .spec
+        .iter()
+        .flat_map(|s| s.ports.iter().flatten())
+        .find(|p| p.port == i32::from(port))
+        .and_then(|p| match p.target_port {
+            Some(IntOrString::String(ref name)) => Some(name.as_str()),
+            _ => None,
+        })
+}
+
+/// The `appProtocol` declared on the service port `port`, if any.
+fn service_app_protocol(service: &Service, port: u16) -> Option<String> {
+    service
+        .spec
+        .iter()
+        .flat_map(|s| s.ports.iter().flatten())
+        .find(|p| p.port == i32::from(port))
+        .and_then(|p| p.app_protocol.clone())
+}
+
+fn first_container_port(pod: &Pod) -> Option<u16> {
+    pod.spec
+        .iter()
+        .flat_map(|s| s.containers.iter())
+        .flat_map(|c| c.ports.iter().flatten())
+        .find_map(|p| u16::try_from(p.container_port).ok().filter(|p| *p != 0))
+}
+
+fn declares_port(pod: &Pod, port: u16) -> bool {
+    pod.spec
+        .iter()
+        .flat_map(|s| s.containers.iter())
+        .flat_map(|c| c.ports.iter().flatten())
+        .any(|p| p.container_port == i32::from(port))
+}
+
+/// The service port named `name`, matched case-insensitively as DNS names are.
+fn named_service_port(service: &Service, name: &str) -> Option<u16> {
+    service
+        .spec
+        .iter()
+        .flat_map(|s| s.ports.iter().flatten())
+        .find(|p| {
+            p.name
+                .as_deref()
+                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+        })
+        .and_then(|p| u16::try_from(p.port).ok())
+}
+
+fn first_service_port(service: &Service) -> Option<u16> {
+    service
+        .spec
+        .iter()
+        .flat_map(|s| s.ports.iter().flatten())
+        .find_map(|p| u16::try_from(p.port).ok().filter(|p| *p != 0))
+}
+
+/// The IP a pod DNS label like `10-1-2-3`, or `fd00--7` for IPv6, spells out with dashes.
+fn hyphenated_ip(label: &str) -> Option<IpAddr> {
+    if !label.contains('-') {
+        return None;
+    }
+
+    let v4 = label.replace('-', ".").parse::<Ipv4Addr>().map(IpAddr::V4);
+    let v6 = || label.replace('-', ":").parse::<Ipv6Addr>().map(IpAddr::V6);
+    v4.or_else(|_| v6()).ok()
+}
+
+fn is_host_network(pod: &Pod) -> bool {
+    pod.spec
+        .as_ref()
+        .is_some_and(|s| s.host_network == Some(true))
+}
+
+/// Whether the pod has the configured readiness condition, or its `readiness-container` is
+/// ready, or with `ignore-readiness` it's just running.
+fn is_ready(pod: &Pod, config: &Config) -> bool {
+    pod.status.as_ref().is_some_and(|s| {
+        if config.ignore_readiness {
+            return s.phase.as_deref() == Some("Running");
+        }
+
+        if let Some(ref container) = config.readiness_container {
+            return s
+                .container_statuses
+                .iter()
+                .flatten()
+                .any(|c| &c.name == container && c.ready);
+        }
+
+        s.conditions.as_ref().is_some_and(|cs| {
+            cs.iter()
+                .any(|c| c.type_ == config.readiness_condition && c.status == "True")
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests;
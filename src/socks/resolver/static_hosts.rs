@@ -0,0 +1,110 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tracing::{debug, instrument};
+
+use crate::config::Config;
+use crate::socks::resolver::{normalize_address, Destination, Errors, PodStream, Resolver, Target};
+
+/// Connects to fixed `host:port`s given by `static-hosts` over plain TCP, for local development
+/// without a cluster. Names that aren't mapped are unreachable, as are IP destinations.
+pub struct StaticResolver {
+    config: Arc<Config>,
+}
+
+impl StaticResolver {
+    pub fn new(config: Arc<Config>) -> Self {
+        StaticResolver { config }
+    }
+
+    fn lookup(&self, name: &str, port: u16) -> Result<(&str, u16), Errors> {
+        let (name, _) = normalize_address(name);
+        let mapped = self
+            .config
+            .static_hosts
+            .iter()
+            .find_map(|(k, v)| k.eq_ignore_ascii_case(&name).then_some(v))
+            .ok_or(Errors::HostNotMapped(name))?;
+
+        Ok(split_host_port(mapped, port))
+    }
+}
+
+impl Resolver for StaticResolver {
+    #[instrument(skip(self), err(Debug, level = "debug"))]
+    async fn forwarder(
+        &mut self,
+        destination: Destination<'_>,
+        port: u16,
+    ) -> Result<(Target, Box<dyn PodStream>), Errors> {
+        let (host, port) = match destination {
+            Destination::Dns(name) => self.lookup(name, port)?,
+            Destination::Ip(ip) => return Err(Errors::HostNotMapped(ip.to_string())),
+        };
+
+        let connect_timeout = Duration::from_secs(self.config.connect_timeout);
+        let stream = tokio::time::timeout(connect_timeout, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| {
+                Errors::ForwardFailed(anyhow::anyhow!(
+                    "timed out after {connect_timeout:?} connecting to {host}:{port}"
+                ))
+            })?
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::ConnectionRefused => Errors::ConnectionRefused {
+                    namespace: String::new(),
+                    pod: host.into(),
+                    port,
+                    reason: e.to_string(),
+                },
+                _ => Errors::ForwardFailed(e.into()),
+            })?;
+
+        let target = Target {
+            namespace: String::new(),
+            pod: host.into(),
+            port,
+            pod_ip: stream.peer_addr().ok().map(|a| a.ip()),
+            app_protocol: None,
+            cluster: None,
+            connect_timeout: None,
+            named_target_port: None,
+        };
+        debug!(?target, "connected to static host");
+
+        Ok((target, Box::new(stream)))
+    }
+
+    async fn forward_closed(&mut self) -> Option<String> {
+        // A plain TCP connection has no side channel, closing is seen on the stream itself
+        futures::future::pending().await
+    }
+
+    async fn join(self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// `host:port`, `[v6]:port` or just a host, which then takes the requested port.
+fn split_host_port(mapped: &str, port: u16) -> (&str, u16) {
+    let (host, mapped_port) = match mapped.rsplit_once(':') {
+        Some((host, p)) if !host.is_empty() && (!host.contains(':') || host.ends_with(']')) => {
+            match p.parse() {
+                Ok(p) => (host, p),
+                Err(_) => (mapped, port),
+            }
+        }
+        _ => (mapped, port),
+    };
+
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+
+    (host, mapped_port)
+}
+
+#[cfg(test)]
+mod tests;
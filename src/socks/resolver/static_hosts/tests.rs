@@ -0,0 +1,88 @@
+mod split_host_port {
+    use super::super::*;
+
+    #[test]
+    fn mapped_port_wins() {
+        assert_eq!(split_host_port("localhost:8080", 80), ("localhost", 8080));
+        assert_eq!(split_host_port("127.0.0.1:8080", 80), ("127.0.0.1", 8080));
+        assert_eq!(split_host_port("[::1]:8080", 80), ("::1", 8080));
+    }
+
+    #[test]
+    fn bare_host_takes_requested_port() {
+        assert_eq!(split_host_port("localhost", 80), ("localhost", 80));
+        assert_eq!(split_host_port("::1", 80), ("::1", 80));
+        assert_eq!(split_host_port("[::1]", 80), ("::1", 80));
+    }
+}
+
+mod forwarder {
+    use std::collections::BTreeMap;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+
+    fn resolver(hosts: &[(&str, String)]) -> StaticResolver {
+        StaticResolver::new(Arc::new(Config {
+            static_hosts: hosts
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect::<BTreeMap<_, _>>(),
+            ..Config::default()
+        }))
+    }
+
+    #[tokio::test]
+    async fn connects_to_mapped_host() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut resolver = resolver(&[("web", addr.to_string())]);
+
+        let (res, accepted) = tokio::join!(
+            resolver.forwarder(Destination::Dns("WEB."), 80),
+            listener.accept()
+        );
+        let (target, mut stream) = res.unwrap();
+        let (mut server, _) = accepted.unwrap();
+
+        assert_eq!(target.pod, "127.0.0.1");
+        assert_eq!(target.port, addr.port());
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn unmapped_names_fail() {
+        let res = resolver(&[])
+            .forwarder(Destination::Dns("web"), 80)
+            .await
+            .map(|(t, _)| t);
+
+        assert!(
+            matches!(res, Err(Errors::HostNotMapped(ref n)) if n == "web"),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_refused_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let res = resolver(&[("web", addr.to_string())])
+            .forwarder(Destination::Dns("web"), 80)
+            .await
+            .map(|(t, _)| t);
+
+        assert!(
+            matches!(res, Err(Errors::ConnectionRefused { .. })),
+            "{res:?}"
+        );
+    }
+}
@@ -0,0 +1,2609 @@
+use std::net::SocketAddr;
+
+use super::*;
+use crate::fake_api::serve_json;
+
+/// A resolver talking to a fake API server at `api_server`.
+fn pod_resolver(api_server: SocketAddr, config: Config) -> PodResolver {
+    let client = Client::try_from(kube::Config::new(
+        format!("http://{api_server}").parse().unwrap(),
+    ))
+    .unwrap();
+    let ctx = Context::new(Some(client.clone()), Arc::new(config)).unwrap();
+
+    PodResolver::new(ctx, Arc::new(KubeClient::new(client)))
+}
+
+mod selector_into_labels {
+    use super::super::*;
+
+    fn selector(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn joins_labels() {
+        let labels = selector_into_labels(&selector(&[
+            ("app.kubernetes.io/name", "web"),
+            ("tier", "front_end.v2"),
+            ("canary", ""),
+        ]));
+
+        assert_eq!(
+            labels,
+            Ok("app.kubernetes.io/name=web,canary=,tier=front_end.v2".into())
+        );
+    }
+
+    #[test]
+    fn rejects_values_that_would_alter_the_query() {
+        assert!(selector_into_labels(&selector(&[("app", "web,tier!=db")])).is_err());
+        assert!(selector_into_labels(&selector(&[("app", "web in (a)")])).is_err());
+        assert!(selector_into_labels(&selector(&[("app=x", "web")])).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_keys_and_values() {
+        assert!(selector_into_labels(&selector(&[("", "web")])).is_err());
+        assert!(selector_into_labels(&selector(&[("/app", "web")])).is_err());
+        assert!(selector_into_labels(&selector(&[("Example.com/app", "web")])).is_err());
+        assert!(selector_into_labels(&selector(&[("app", "-web")])).is_err());
+        assert!(selector_into_labels(&selector(&[("app", &"a".repeat(64))])).is_err());
+    }
+}
+
+mod summarize_selector {
+    use super::super::*;
+
+    #[test]
+    fn small_selector_is_unchanged() {
+        let selectors = BTreeMap::from([
+            ("app".to_string(), "web".to_string()),
+            ("tier".to_string(), "frontend".to_string()),
+        ]);
+
+        assert_eq!(summarize_selector(&selectors), "app=web,tier=frontend");
+    }
+
+    #[test]
+    fn large_selector_is_truncated() {
+        let selectors: BTreeMap<String, String> = (0..100)
+            .map(|i| (format!("key-{i:03}"), "value".to_string()))
+            .collect();
+
+        let summary = summarize_selector(&selectors);
+
+        assert!(summary.len() <= MAX_SELECTOR_SUMMARY_LEN + " (+100 more)".len());
+        assert!(summary.starts_with("key-000=value,"));
+        assert!(summary.ends_with(" more)"));
+    }
+}
+
+mod target_new {
+    use k8s_openapi::api::core::v1::PodStatus;
+    use kube::api::ObjectMeta;
+
+    use super::super::*;
+
+    fn pod(pod_ip: Option<&str>) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some("web-0".into()),
+                ..Default::default()
+            },
+            status: Some(PodStatus {
+                pod_ip: pod_ip.map(Into::into),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn includes_pod_ip() {
+        let target = Target::new(&pod(Some("10.0.0.12")), "default", 80);
+
+        assert_eq!(target.pod, "web-0");
+        assert_eq!(target.pod_ip, Some("10.0.0.12".parse().unwrap()));
+    }
+
+    #[test]
+    fn missing_or_invalid_pod_ip_is_none() {
+        assert_eq!(Target::new(&pod(None), "default", 80).pod_ip, None);
+        assert_eq!(Target::new(&pod(Some("nope")), "default", 80).pod_ip, None);
+    }
+}
+
+mod default_port {
+    use k8s_openapi::api::core::v1::{Container, PodSpec, ServicePort, ServiceSpec};
+    use kube::api::ObjectMeta;
+
+    use super::super::*;
+
+    fn pod(ports: &[&[i32]]) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some("web-0".into()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: ports
+                    .iter()
+                    .map(|ports| Container {
+                        ports: Some(
+                            ports
+                                .iter()
+                                .map(|p| ContainerPort {
+                                    container_port: *p,
+                                    ..Default::default()
+                                })
+                                .collect(),
+                        ),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn zero_uses_first_container_port() {
+        let target = Target::with_default_port(&pod(&[&[], &[8080, 9090]]), "default", 0).unwrap();
+
+        assert_eq!(target.port, 8080);
+    }
+
+    #[test]
+    fn non_zero_is_kept() {
+        let target = Target::with_default_port(&pod(&[&[8080]]), "default", 443).unwrap();
+
+        assert_eq!(target.port, 443);
+    }
+
+    #[test]
+    fn zero_without_container_ports_fails() {
+        let res = Target::with_default_port(&pod(&[&[]]), "default", 0);
+
+        assert!(matches!(res, Err(Errors::PortNotFound(_, _, 0))));
+    }
+
+    #[test]
+    fn first_service_port_is_used() {
+        let service = Service {
+            spec: Some(ServiceSpec {
+                ports: Some(vec![
+                    ServicePort {
+                        port: 443,
+                        ..Default::default()
+                    },
+                    ServicePort {
+                        port: 80,
+                        ..Default::default()
+                    },
+                ]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(first_service_port(&service), Some(443));
+        assert_eq!(first_service_port(&Service::default()), None);
+    }
+}
+
+mod label_selector_into_labels {
+    use super::super::*;
+
+    fn expression(key: &str, operator: &str, values: &[&str]) -> LabelSelectorRequirement {
+        LabelSelectorRequirement {
+            key: key.into(),
+            operator: operator.into(),
+            values: (!values.is_empty()).then(|| values.iter().map(|v| v.to_string()).collect()),
+        }
+    }
+
+    fn expressions(expressions: Vec<LabelSelectorRequirement>) -> LabelSelector {
+        LabelSelector {
+            match_expressions: Some(expressions),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn match_labels() {
+        let selector = LabelSelector {
+            match_labels: Some(BTreeMap::from([("app".to_string(), "web".to_string())])),
+            ..Default::default()
+        };
+
+        assert_eq!(label_selector_into_labels(&selector), Ok("app=web".into()));
+    }
+
+    #[test]
+    fn empty_selector_is_rejected() {
+        assert!(label_selector_into_labels(&LabelSelector::default()).is_err());
+    }
+
+    #[test]
+    fn in_expression() {
+        let selector = expressions(vec![expression("tier", "In", &["web", "api"])]);
+
+        assert_eq!(
+            label_selector_into_labels(&selector),
+            Ok("tier in (web,api)".into())
+        );
+    }
+
+    #[test]
+    fn not_in_expression() {
+        let selector = expressions(vec![expression("tier", "NotIn", &["db"])]);
+
+        assert_eq!(
+            label_selector_into_labels(&selector),
+            Ok("tier notin (db)".into())
+        );
+    }
+
+    #[test]
+    fn exists_expression() {
+        let selector = expressions(vec![expression("canary", "Exists", &[])]);
+
+        assert_eq!(label_selector_into_labels(&selector), Ok("canary".into()));
+    }
+
+    #[test]
+    fn does_not_exist_expression() {
+        let selector = expressions(vec![expression("canary", "DoesNotExist", &[])]);
+
+        assert_eq!(label_selector_into_labels(&selector), Ok("!canary".into()));
+    }
+
+    #[test]
+    fn labels_and_expressions_are_combined() {
+        let selector = LabelSelector {
+            match_labels: Some(BTreeMap::from([("app".to_string(), "web".to_string())])),
+            match_expressions: Some(vec![
+                expression("tier", "In", &["front"]),
+                expression("canary", "DoesNotExist", &[]),
+            ]),
+        };
+
+        assert_eq!(
+            label_selector_into_labels(&selector),
+            Ok("app=web,tier in (front),!canary".into())
+        );
+    }
+
+    #[test]
+    fn malformed_expressions_are_rejected() {
+        for e in [
+            expression("tier", "In", &[]),
+            expression("tier", "Exists", &["web"]),
+            expression("tier", "Gt", &["1"]),
+            expression("tier", "In", &["a,b"]),
+            expression("bad key", "Exists", &[]),
+        ] {
+            assert!(
+                label_selector_into_labels(&expressions(vec![e.clone()])).is_err(),
+                "{e:?}"
+            );
+        }
+    }
+}
+
+mod workload_selector {
+    use k8s_openapi::api::apps::v1::{
+        DaemonSetSpec, DeploymentSpec, ReplicaSetSpec, StatefulSetSpec,
+    };
+
+    use super::super::*;
+
+    fn selector() -> LabelSelector {
+        LabelSelector {
+            match_labels: Some(BTreeMap::from([("app".to_string(), "web".to_string())])),
+            ..Default::default()
+        }
+    }
+
+    fn assert_selects<K: Workload>(workload: K) {
+        assert_eq!(workload.selector(), Some(&selector()), "{}", K::KIND);
+    }
+
+    #[test]
+    fn every_kind_exposes_its_selector() {
+        assert_selects(Deployment {
+            spec: Some(DeploymentSpec {
+                selector: selector(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_selects(ReplicaSet {
+            spec: Some(ReplicaSetSpec {
+                selector: selector(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_selects(DaemonSet {
+            spec: Some(DaemonSetSpec {
+                selector: selector(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_selects(StatefulSet {
+            spec: Some(StatefulSetSpec {
+                selector: selector(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn missing_spec_has_no_selector() {
+        assert_eq!(Deployment::default().selector(), None);
+        assert_eq!(StatefulSet::default().selector(), None);
+    }
+}
+
+mod node_has_internal_ip {
+    use k8s_openapi::api::core::v1::{NodeAddress, NodeStatus};
+
+    use super::super::*;
+
+    fn node(addresses: &[(&str, &str)]) -> Node {
+        Node {
+            status: Some(NodeStatus {
+                addresses: Some(
+                    addresses
+                        .iter()
+                        .map(|(type_, address)| NodeAddress {
+                            type_: type_.to_string(),
+                            address: address.to_string(),
+                        })
+                        .collect(),
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matches_internal_ip() {
+        let node = node(&[("Hostname", "node-1"), ("InternalIP", "10.1.0.4")]);
+
+        assert!(node_has_internal_ip(&node, "10.1.0.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn ignores_external_ip() {
+        let node = node(&[("ExternalIP", "203.0.113.7"), ("InternalIP", "10.1.0.4")]);
+
+        assert!(!node_has_internal_ip(&node, "203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn matches_ipv6() {
+        let node = node(&[("InternalIP", "fd00::4")]);
+
+        assert!(node_has_internal_ip(&node, "fd00:0::4".parse().unwrap()));
+    }
+}
+
+mod is_ready {
+    use k8s_openapi::api::core::v1::{ContainerStatus, PodCondition, PodStatus};
+
+    use super::super::*;
+
+    fn pod(phase: &str, conditions: &[(&str, &str)]) -> Pod {
+        Pod {
+            status: Some(PodStatus {
+                phase: Some(phase.into()),
+                conditions: Some(
+                    conditions
+                        .iter()
+                        .map(|(type_, status)| PodCondition {
+                            type_: type_.to_string(),
+                            status: status.to_string(),
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ready_condition_by_default() {
+        let config = Config::default();
+
+        assert!(is_ready(&pod("Running", &[("Ready", "True")]), &config));
+        assert!(!is_ready(&pod("Running", &[("Ready", "False")]), &config));
+        assert!(!is_ready(&pod("Running", &[]), &config));
+    }
+
+    #[test]
+    fn custom_condition() {
+        let config = Config {
+            readiness_condition: "example.com/Serving".into(),
+            ..Default::default()
+        };
+
+        assert!(is_ready(
+            &pod(
+                "Running",
+                &[("Ready", "False"), ("example.com/Serving", "True")]
+            ),
+            &config
+        ));
+        assert!(!is_ready(&pod("Running", &[("Ready", "True")]), &config));
+    }
+
+    #[test]
+    fn readiness_container() {
+        let config = Config {
+            readiness_container: Some("app".into()),
+            ..Default::default()
+        };
+        let with_containers = |ready: &[(&str, bool)]| {
+            let mut pod = pod("Running", &[("Ready", "False")]);
+            pod.status.as_mut().unwrap().container_statuses = Some(
+                ready
+                    .iter()
+                    .map(|(name, ready)| ContainerStatus {
+                        name: name.to_string(),
+                        ready: *ready,
+                        ..Default::default()
+                    })
+                    .collect(),
+            );
+            pod
+        };
+
+        assert!(is_ready(
+            &with_containers(&[("app", true), ("sidecar", false)]),
+            &config
+        ));
+        assert!(!is_ready(
+            &with_containers(&[("app", false), ("sidecar", true)]),
+            &config
+        ));
+        assert!(!is_ready(&with_containers(&[("sidecar", true)]), &config));
+    }
+
+    #[test]
+    fn ignore_readiness_needs_running() {
+        let config = Config {
+            ignore_readiness: true,
+            ..Default::default()
+        };
+
+        assert!(is_ready(&pod("Running", &[("Ready", "False")]), &config));
+        assert!(!is_ready(&pod("Pending", &[]), &config));
+    }
+}
+
+mod forbidden {
+    use kube::core::ErrorResponse;
+
+    use super::super::*;
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(ErrorResponse {
+            status: "Failure".into(),
+            message: "pods is forbidden: User \"system:serviceaccount:proxy:default\" cannot list resource \"pods\"".into(),
+            reason: "Forbidden".into(),
+            code,
+        })
+    }
+
+    #[test]
+    fn forbidden_is_distinct() {
+        let err = lookup_failed("list", "pods")(api_error(403));
+
+        assert!(matches!(
+            err,
+            Errors::Forbidden { verb: "list", ref resource, .. } if resource == "pods"
+        ));
+    }
+
+    #[test]
+    fn other_errors_are_lookup_failures() {
+        let err = lookup_failed("list", "pods")(api_error(500));
+
+        assert!(matches!(err, Errors::LookupFailed(_)));
+    }
+}
+
+mod service_app_protocol {
+    use k8s_openapi::api::core::v1::{ServicePort, ServiceSpec};
+
+    use super::super::*;
+
+    fn service() -> Service {
+        Service {
+            spec: Some(ServiceSpec {
+                ports: Some(vec![
+                    ServicePort {
+                        port: 80,
+                        app_protocol: Some("http".into()),
+                        ..Default::default()
+                    },
+                    ServicePort {
+                        port: 9000,
+                        ..Default::default()
+                    },
+                ]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn declared_on_the_port() {
+        assert_eq!(
+            service_app_protocol(&service(), 80).as_deref(),
+            Some("http")
+        );
+    }
+
+    #[test]
+    fn not_declared() {
+        assert_eq!(service_app_protocol(&service(), 9000), None);
+        assert_eq!(service_app_protocol(&service(), 443), None);
+        assert_eq!(service_app_protocol(&Service::default(), 80), None);
+    }
+}
+
+mod named_target_port {
+    use k8s_openapi::api::core::v1::{ServicePort, ServiceSpec};
+
+    use super::super::*;
+
+    fn service() -> Service {
+        Service {
+            spec: Some(ServiceSpec {
+                ports: Some(vec![
+                    ServicePort {
+                        port: 80,
+                        target_port: Some(IntOrString::String("http".into())),
+                        ..Default::default()
+                    },
+                    ServicePort {
+                        port: 443,
+                        target_port: Some(IntOrString::Int(8443)),
+                        ..Default::default()
+                    },
+                    ServicePort {
+                        port: 9000,
+                        ..Default::default()
+                    },
+                ]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn named() {
+        assert_eq!(named_target_port(&service(), 80), Some("http"));
+    }
+
+    #[test]
+    fn numbered_unset_or_missing() {
+        assert_eq!(named_target_port(&service(), 443), None);
+        assert_eq!(named_target_port(&service(), 9000), None);
+        assert_eq!(named_target_port(&service(), 8080), None);
+    }
+}
+
+mod service_pod_port {
+    use k8s_openapi::api::core::v1::{Container, PodSpec, ServicePort, ServiceSpec};
+
+    use super::super::*;
+
+    fn service(ports: Option<Vec<(i32, Option<IntOrString>)>>) -> Service {
+        Service {
+            spec: Some(ServiceSpec {
+                ports: ports.map(|ports| {
+                    ports
+                        .into_iter()
+                        .map(|(port, target_port)| ServicePort {
+                            port,
+                            target_port,
+                            ..Default::default()
+                        })
+                        .collect()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn pod() -> Pod {
+        Pod {
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    ports: Some(vec![ContainerPort {
+                        name: Some("http".into()),
+                        container_port: 8080,
+                        ..Default::default()
+                    }]),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unset_target_port_defaults_to_port() {
+        let service = service(Some(vec![(80, None)]));
+
+        assert!(matches!(service_pod_port(&service, &pod(), 80), Ok(80)));
+    }
+
+    #[test]
+    fn numeric_target_port() {
+        let service = service(Some(vec![(443, None), (80, Some(IntOrString::Int(9090)))]));
+
+        assert!(matches!(service_pod_port(&service, &pod(), 80), Ok(9090)));
+    }
+
+    #[test]
+    fn named_target_port() {
+        let service = service(Some(vec![(80, Some(IntOrString::String("http".into())))]));
+
+        assert!(matches!(service_pod_port(&service, &pod(), 80), Ok(8080)));
+    }
+
+    #[test]
+    fn unknown_named_target_port() {
+        let service = service(Some(vec![(80, Some(IntOrString::String("grpc".into())))]));
+
+        assert!(matches!(
+            service_pod_port(&service, &pod(), 80),
+            Err(PortError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn port_not_on_service() {
+        let service = service(Some(vec![(80, None)]));
+
+        assert!(matches!(
+            service_pod_port(&service, &pod(), 443),
+            Err(PortError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn invalid_numeric_target_port() {
+        let service = service(Some(vec![(80, Some(IntOrString::Int(70000)))]));
+
+        assert!(matches!(
+            service_pod_port(&service, &pod(), 80),
+            Err(PortError::Invalid(_))
+        ));
+    }
+
+    fn invalid_reason(target_port: i32) -> String {
+        let service = service(Some(vec![(80, Some(IntOrString::Int(target_port)))]));
+
+        match service_pod_port(&service, &pod(), 80) {
+            Err(PortError::Invalid(reason)) => reason,
+            Err(PortError::NotFound) => panic!("{target_port}: not found"),
+            Ok(p) => panic!("{target_port}: mapped to {p}"),
+        }
+    }
+
+    #[test]
+    fn numeric_target_port_boundaries() {
+        let service = service(Some(vec![(80, Some(IntOrString::Int(65535)))]));
+        assert!(matches!(service_pod_port(&service, &pod(), 80), Ok(65535)));
+
+        assert_eq!(invalid_reason(0), "targetPort of port 80 is 0");
+        assert_eq!(
+            invalid_reason(65536),
+            "targetPort 65536 of port 80 is outside 1-65535"
+        );
+        assert_eq!(
+            invalid_reason(-1),
+            "targetPort -1 of port 80 is outside 1-65535"
+        );
+    }
+
+    #[test]
+    fn service_without_ports_passes_port_through() {
+        assert!(matches!(
+            service_pod_port(&service(None), &pod(), 5432),
+            Ok(5432)
+        ));
+        assert!(matches!(
+            service_pod_port(&service(Some(vec![])), &pod(), 5432),
+            Ok(5432)
+        ));
+    }
+}
+
+mod find_by_hostname {
+    use k8s_openapi::api::core::v1::PodSpec;
+    use kube::api::ObjectMeta;
+
+    use super::super::*;
+
+    fn pod(name: &str, hostname: Option<&str>) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                hostname: hostname.map(Into::into),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn found<'a>(pods: &'a [Pod], hostname: &str) -> Option<&'a str> {
+        find_by_hostname(pods, hostname).and_then(|p| p.metadata.name.as_deref())
+    }
+
+    #[test]
+    fn matches_spec_hostname() {
+        let pods = [pod("web-7d9f-abcde", Some("web-0"))];
+
+        assert_eq!(found(&pods, "web-0"), Some("web-7d9f-abcde"));
+    }
+
+    #[test]
+    fn falls_back_to_name() {
+        let pods = [pod("web-0", None)];
+
+        assert_eq!(found(&pods, "web-0"), Some("web-0"));
+    }
+
+    #[test]
+    fn name_matches_even_when_hostname_differs() {
+        let pods = [pod("web-0", Some("primary"))];
+
+        assert_eq!(found(&pods, "web-0"), Some("web-0"));
+        assert_eq!(found(&pods, "primary"), Some("web-0"));
+    }
+
+    #[test]
+    fn hostname_match_beats_earlier_name_match() {
+        let pods = [pod("db", None), pod("db-abcde", Some("db"))];
+
+        assert_eq!(found(&pods, "db"), Some("db-abcde"));
+    }
+
+    #[test]
+    fn no_match() {
+        let pods = [pod("web-0", Some("web-0")), pod("web-1", None)];
+
+        assert_eq!(found(&pods, "web-2"), None);
+    }
+}
+
+mod normalize_address {
+    use super::super::*;
+
+    #[test]
+    fn canonical_is_unchanged() {
+        assert_eq!(
+            normalize_address("web.apps.svc.cluster.local"),
+            ("web.apps.svc.cluster.local".into(), false)
+        );
+    }
+
+    #[test]
+    fn uppercase_matches_canonical() {
+        assert_eq!(
+            normalize_address("MyService.NS.SVC.Cluster.Local").0,
+            "myservice.ns.svc.cluster.local"
+        );
+    }
+
+    #[test]
+    fn trailing_dot_is_fully_qualified() {
+        assert_eq!(
+            normalize_address("MyService.NS.SVC.Cluster.Local."),
+            ("myservice.ns.svc.cluster.local".into(), true)
+        );
+    }
+}
+
+mod refused_or_failed {
+    use super::super::*;
+
+    fn target() -> Target {
+        Target {
+            namespace: "apps".into(),
+            pod: "web-0".into(),
+            port: 8080,
+            pod_ip: None,
+            app_protocol: None,
+            cluster: None,
+            connect_timeout: None,
+            named_target_port: None,
+        }
+    }
+
+    #[test]
+    fn refused_when_nothing_listens() {
+        let reason = "error forwarding port 8080 to pod 1234, uid : failed to execute portforward \
+            in network namespace \"/var/run/netns/cni-1\": failed to connect to localhost:8080 \
+            inside namespace \"1234\", IPv4: dial tcp4 127.0.0.1:8080: connect: connection \
+            refused IPv6 dial tcp6: address localhost: no suitable address";
+
+        assert!(matches!(
+            refused_or_failed(&target(), reason.into()),
+            Errors::ConnectionRefused { port: 8080, .. }
+        ));
+    }
+
+    #[test]
+    fn other_errors_fail_the_forward() {
+        let reason = "error forwarding port 8080 to pod 1234: container not running";
+
+        assert!(matches!(
+            refused_or_failed(&target(), reason.into()),
+            Errors::ForwardFailed(_)
+        ));
+    }
+}
+
+mod forward_ports {
+    use futures::SinkExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::super::*;
+    use super::pod_resolver;
+    use crate::fake_api::accept_portforward;
+
+    /// Speaks the API server's side of a port-forward for `ports`, answering each chunk sent to
+    /// a port with the port number and the chunk. Returns the request's query.
+    async fn fake_portforward(listener: TcpListener, ports: &[u16]) -> Option<String> {
+        let (mut ws, query) = accept_portforward(&listener, ports).await;
+
+        let mut answered = 0;
+        while answered < ports.len() {
+            let Message::Binary(frame) = ws.next().await.unwrap().unwrap() else {
+                continue;
+            };
+            let port = ports[frame[0] as usize / 2];
+
+            let mut reply = vec![frame[0]];
+            reply.extend_from_slice(format!("{port}:").as_bytes());
+            reply.extend_from_slice(&frame[1..]);
+            ws.send(Message::binary(reply)).await.unwrap();
+            answered += 1;
+        }
+
+        // The forwarder closes the connection once its streams are dropped, reading on answers
+        // the close
+        while let Some(Ok(_)) = ws.next().await {}
+
+        query
+    }
+
+    async fn read_reply(stream: &mut Box<dyn PodStream>, len: usize) -> String {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).await.unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[tokio::test]
+    async fn streams_each_port_over_one_forward() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut resolver = pod_resolver(
+            listener.local_addr().unwrap(),
+            Config {
+                forward_probe_ms: 0,
+                ..Config::default()
+            },
+        );
+        let target = Target {
+            namespace: "apps".into(),
+            pod: "web-0".into(),
+            port: 8080,
+            pod_ip: None,
+            app_protocol: None,
+            cluster: None,
+            connect_timeout: None,
+            named_target_port: None,
+        };
+
+        let server = tokio::spawn(fake_portforward(listener, &[8080, 9090]));
+
+        let mut streams = resolver
+            .forward_ports(&target, &[8080, 9090])
+            .await
+            .unwrap();
+        assert_eq!(streams.len(), 2);
+
+        streams[1].write_all(b"metrics").await.unwrap();
+        assert_eq!(read_reply(&mut streams[1], 12).await, "9090:metrics");
+        streams[0].write_all(b"http").await.unwrap();
+        assert_eq!(read_reply(&mut streams[0], 9).await, "8080:http");
+
+        drop(streams);
+        resolver.join().await.unwrap();
+
+        assert_eq!(server.await.unwrap().as_deref(), Some("&ports=8080%2C9090"));
+    }
+}
+
+mod degenerate_addresses {
+    use std::net::SocketAddr;
+
+    use super::super::*;
+    use super::pod_resolver;
+
+    /// Nothing listens here, so any address that gets as far as an API call fails to look up
+    /// rather than being unsupported
+    fn resolver() -> PodResolver {
+        pod_resolver(SocketAddr::from(([127, 0, 0, 1], 9)), Config::default())
+    }
+
+    async fn assert_unsupported(address: &str) {
+        let res = resolver().resolve(address, 80).await;
+        assert!(
+            matches!(res, Err(Errors::UnsupportedAddress(_))),
+            "{address:?}: {res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn empty() {
+        assert_unsupported("").await;
+    }
+
+    #[tokio::test]
+    async fn dots() {
+        assert_unsupported(".").await;
+        assert_unsupported("..").await;
+        assert_unsupported(".svc").await;
+        assert_unsupported(".cluster.local").await;
+    }
+
+    #[tokio::test]
+    async fn empty_labels() {
+        assert_unsupported("web..svc.cluster.local").await;
+        assert_unsupported(".default.svc.cluster.local").await;
+        assert_unsupported("web.default.svc..").await;
+    }
+
+    #[tokio::test]
+    async fn kind_alone() {
+        for kind in ["svc.", "pod.", "deploy.", "nsl.", "svc.cluster.local."] {
+            assert_unsupported(kind).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn too_many_segments() {
+        assert_unsupported("a.b.c.d.svc.cluster.local").await;
+        assert_unsupported("a.b.c.pod.cluster.local").await;
+        assert_unsupported("a.deploy.cluster.local").await;
+    }
+}
+
+mod is_disconnect {
+    use std::io::ErrorKind;
+
+    use tokio_tungstenite::tungstenite;
+
+    use super::super::*;
+
+    #[test]
+    fn connection_resets() {
+        let e = std::io::Error::from(ErrorKind::ConnectionReset);
+
+        assert!(is_disconnect(&e));
+    }
+
+    #[test]
+    fn closed_websockets() {
+        assert!(is_disconnect(&tungstenite::Error::ConnectionClosed));
+        assert!(is_disconnect(&tungstenite::Error::Protocol(
+            tungstenite::error::ProtocolError::ResetWithoutClosingHandshake
+        )));
+    }
+
+    #[test]
+    fn looks_through_sources() {
+        let e = anyhow::Error::new(std::io::Error::from(ErrorKind::BrokenPipe))
+            .context("failed to write bytes from Pod");
+
+        assert!(is_disconnect(e.as_ref()));
+    }
+
+    #[test]
+    fn other_errors_are_unexpected() {
+        let e = std::io::Error::from(ErrorKind::PermissionDenied);
+        assert!(!is_disconnect(&e));
+
+        let e = anyhow::anyhow!("received invalid channel 7");
+        assert!(!is_disconnect(e.as_ref()));
+
+        assert!(!is_disconnect(&tungstenite::Error::Protocol(
+            tungstenite::error::ProtocolError::InvalidOpcode(3)
+        )));
+    }
+}
+
+mod cross_namespace_pod {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+
+    fn pod_list(namespaces: &[&str]) -> String {
+        let items: Vec<String> = namespaces
+            .iter()
+            .map(|n| {
+                format!(
+                    r#"{{"metadata":{{"name":"web-0","namespace":"{n}"}},"spec":{{"containers":[{{"name":"web","ports":[{{"containerPort":80}}]}}]}}}}"#
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"apiVersion":"v1","kind":"PodList","metadata":{{}},"items":[{}]}}"#,
+            items.join(",")
+        )
+    }
+
+    async fn resolve(namespaces: &[&str]) -> (Result<Target, Errors>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(
+            listener.local_addr().unwrap(),
+            Config {
+                allow_cross_namespace_pod: true,
+                ..Config::default()
+            },
+        );
+
+        let pods = pod_list(namespaces);
+        tokio::join!(
+            resolver.resolve("web-0.pod.cluster.local", 80),
+            serve_json(&listener, &pods),
+        )
+    }
+
+    #[tokio::test]
+    async fn finds_unique_pod() {
+        let (res, path) = resolve(&["apps"]).await;
+
+        let target = res.unwrap();
+        assert_eq!(
+            (target.namespace.as_str(), target.pod.as_str()),
+            ("apps", "web-0")
+        );
+        assert_eq!(path, "/api/v1/pods?&fieldSelector=metadata.name%3Dweb-0");
+    }
+
+    #[tokio::test]
+    async fn lists_namespaces_when_ambiguous() {
+        let (res, _) = resolve(&["apps", "staging"]).await;
+
+        match res {
+            Err(Errors::PodAmbiguous { pod, namespaces }) => {
+                assert_eq!(pod, "web-0");
+                assert_eq!(namespaces, ["apps", "staging"]);
+            }
+            res => panic!("{res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn not_found() {
+        let (res, _) = resolve(&[]).await;
+
+        assert!(matches!(res, Err(Errors::PodNotFound { .. })), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn off_by_default() {
+        let resolver = pod_resolver(([127, 0, 0, 1], 9).into(), Config::default());
+
+        let res = resolver.resolve("web-0.pod.cluster.local.", 80).await;
+
+        assert!(matches!(res, Err(Errors::UnsupportedAddress(_))), "{res:?}");
+    }
+}
+
+mod find_pod {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+
+    fn pod(name: &str, ready: bool) -> String {
+        let status = if ready { "True" } else { "False" };
+        format!(
+            r#"{{"metadata":{{"name":"{name}","namespace":"apps"}},"status":{{"phase":"Running","conditions":[{{"type":"Ready","status":"{status}"}}]}}}}"#
+        )
+    }
+
+    fn page(pods: &[String], continue_token: &str) -> String {
+        format!(
+            r#"{{"apiVersion":"v1","kind":"PodList","metadata":{{"resourceVersion":"42","continue":"{continue_token}"}},"items":[{}]}}"#,
+            pods.join(",")
+        )
+    }
+
+    fn resolver(listener: &TcpListener) -> PodResolver {
+        pod_resolver(
+            listener.local_addr().unwrap(),
+            Config {
+                list_page_size: 2,
+                ..Config::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn fetches_pages_until_a_pod_is_ready() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(&listener);
+        let pods: Api<Pod> = Api::namespaced(resolver.client.clone(), "apps");
+
+        let first = page(&[pod("web-0", false), pod("web-1", false)], "next");
+        let second = page(&[pod("web-2", true)], "");
+        let (res, paths) = tokio::join!(resolver.ready_pod(&pods, "app=web"), async {
+            [
+                serve_json(&listener, &first).await,
+                serve_json(&listener, &second).await,
+            ]
+        });
+
+        assert_eq!(
+            res.unwrap().pod.unwrap().metadata.name.as_deref(),
+            Some("web-2")
+        );
+        assert!(paths[0].contains("limit=2"), "{}", paths[0]);
+        assert!(paths[1].contains("continue=next"), "{}", paths[1]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_ready_pod() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(&listener);
+        let pods: Api<Pod> = Api::namespaced(resolver.client.clone(), "apps");
+
+        // A request for the next page would never be answered
+        let first = page(&[pod("web-0", false), pod("web-1", true)], "next");
+        let (res, _) = tokio::join!(
+            resolver.ready_pod(&pods, "app=web"),
+            serve_json(&listener, &first)
+        );
+
+        assert_eq!(
+            res.unwrap().pod.unwrap().metadata.name.as_deref(),
+            Some("web-1")
+        );
+    }
+
+    #[tokio::test]
+    async fn no_ready_pod_on_any_page() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(&listener);
+        let pods: Api<Pod> = Api::namespaced(resolver.client.clone(), "apps");
+
+        let first = page(&[pod("web-0", false), pod("web-1", false)], "next");
+        let second = page(&[pod("web-2", false)], "");
+        let (res, _) = tokio::join!(resolver.ready_pod(&pods, "app=web"), async {
+            serve_json(&listener, &first).await;
+            serve_json(&listener, &second).await;
+        });
+
+        assert!(res.unwrap().pod.is_none());
+    }
+}
+
+mod wait_for_ready {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+    use crate::config::ErrorReply;
+
+    const NO_PODS: &str =
+        r#"{"apiVersion":"v1","kind":"PodList","metadata":{"resourceVersion":"42"},"items":[]}"#;
+
+    #[tokio::test]
+    async fn records_how_long_it_waited() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(
+            listener.local_addr().unwrap(),
+            Config {
+                wait_for_ready: 5,
+                ..Config::default()
+            },
+        );
+        let pods: Api<Pod> = Api::namespaced(resolver.client.clone(), "apps");
+
+        let ready = r#"{"type":"ADDED","object":{"apiVersion":"v1","kind":"Pod","metadata":{"name":"web-0","namespace":"apps"},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}}"#;
+        let (res, paths) = tokio::join!(resolver.ready_pod(&pods, "app=web"), async {
+            [
+                serve_json(&listener, NO_PODS).await,
+                serve_json(&listener, ready).await,
+            ]
+        });
+
+        assert_eq!(
+            res.unwrap().pod.unwrap().metadata.name.as_deref(),
+            Some("web-0")
+        );
+        assert!(paths[1].contains("watch=true"), "{}", paths[1]);
+        assert!(paths[1].contains("resourceVersion=42"), "{}", paths[1]);
+        let metrics = resolver.ctx.metrics.render();
+        assert!(
+            metrics.contains("socks_ready_wait_seconds_count 1\n"),
+            "{metrics}"
+        );
+    }
+
+    #[tokio::test]
+    async fn timing_out_is_its_own_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(
+            listener.local_addr().unwrap(),
+            Config {
+                wait_for_ready: 5,
+                ..Config::default()
+            },
+        );
+        let pods: Api<Pod> = Api::namespaced(resolver.client.clone(), "apps");
+
+        // The API server ending the watch without any events
+        let (res, _) = tokio::join!(resolver.ready_pod(&pods, "app=web"), async {
+            serve_json(&listener, NO_PODS).await;
+            serve_json(&listener, "").await;
+        });
+
+        match res {
+            Err(e @ Errors::ReadyWaitTimedOut { .. }) => {
+                assert_eq!(e.kind(), ErrorKind::ReadyWaitTimeout);
+                assert_eq!(e.kind().default_reply(), ErrorReply::TtlExpired);
+            }
+            res => panic!("expected ReadyWaitTimedOut, got {:?}", res.map(|f| f.pod)),
+        }
+        let metrics = resolver.ctx.metrics.render();
+        assert!(
+            metrics.contains("socks_ready_wait_seconds_count 1\n"),
+            "{metrics}"
+        );
+    }
+
+    #[tokio::test]
+    async fn not_waiting_records_nothing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+        let pods: Api<Pod> = Api::namespaced(resolver.client.clone(), "apps");
+
+        let (res, _) = tokio::join!(
+            resolver.ready_pod(&pods, "app=web"),
+            serve_json(&listener, NO_PODS)
+        );
+
+        assert!(res.unwrap().pod.is_none());
+        let metrics = resolver.ctx.metrics.render();
+        assert!(
+            metrics.contains("socks_ready_wait_seconds_count 0\n"),
+            "{metrics}"
+        );
+    }
+}
+
+mod deny_name {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+
+    fn resolver(listener: &TcpListener) -> PodResolver {
+        pod_resolver(
+            listener.local_addr().unwrap(),
+            Config {
+                deny_names: vec!["vault".parse().unwrap(), "etcd-*".parse().unwrap()],
+                ..Config::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn service_is_denied_before_picking_a_pod() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(&listener);
+
+        let service = r#"{"apiVersion":"v1","kind":"Service","metadata":{"name":"vault","namespace":"secrets"},"spec":{"selector":{"app":"vault"},"ports":[{"port":8200}]}}"#;
+        // A request to list the service's pods would never be answered
+        let (res, _) = tokio::join!(
+            resolver.resolve_destination(Destination::Dns("vault.secrets.svc.cluster.local"), 8200),
+            serve_json(&listener, service)
+        );
+
+        match res {
+            Err(
+                e @ Errors::NameDenied {
+                    kind: "Service", ..
+                },
+            ) => {
+                assert_eq!(e.kind(), ErrorKind::NameDenied);
+            }
+            res => panic!("expected NameDenied, got {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn pod_is_denied_by_glob() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(&listener);
+
+        let pod = r#"{"apiVersion":"v1","kind":"Pod","metadata":{"name":"etcd-0","namespace":"kube-system"},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}"#;
+        let (res, _) = tokio::join!(
+            resolver.resolve_destination(
+                Destination::Dns("etcd-0.kube-system.pod.cluster.local"),
+                2379
+            ),
+            serve_json(&listener, pod)
+        );
+
+        assert!(
+            matches!(res, Err(Errors::NameDenied { kind: "Pod", ref name, .. }) if name == "etcd-0"),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn other_names_resolve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(&listener);
+
+        let pod = r#"{"apiVersion":"v1","kind":"Pod","metadata":{"name":"etcd","namespace":"kube-system"},"spec":{"containers":[{"name":"etcd","ports":[{"containerPort":2379}]}]},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}"#;
+        let (res, _) = tokio::join!(
+            resolver
+                .resolve_destination(Destination::Dns("etcd.kube-system.pod.cluster.local"), 2379),
+            serve_json(&listener, pod)
+        );
+
+        assert_eq!(res.unwrap().pod, "etcd");
+    }
+}
+
+mod namespace_scoping {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+
+    const POD: &str = r#"{"apiVersion":"v1","kind":"Pod","metadata":{"name":"web-0","namespace":"apps"},"spec":{"containers":[{"name":"web","ports":[{"containerPort":8080}]}]},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}"#;
+
+    fn resolver(listener: &TcpListener, username: Option<&str>) -> PodResolver {
+        let mut resolver = pod_resolver(
+            listener.local_addr().unwrap(),
+            Config {
+                allow_namespaces: vec!["public".into()],
+                user_namespaces: BTreeMap::from([("alice".into(), vec!["apps".into()])]),
+                ..Config::default()
+            },
+        );
+        resolver.authenticated(username);
+        resolver
+    }
+
+    async fn resolve(resolver: &PodResolver, listener: &TcpListener) -> Result<Target, Errors> {
+        let (res, _) = tokio::join!(
+            resolver.resolve_destination(Destination::Dns("web-0.apps.pod.cluster.local"), 8080),
+            serve_json(listener, POD)
+        );
+        res
+    }
+
+    /// Resolves an address in a namespace the client may not use, which must be refused without
+    /// asking the API server anything.
+    async fn refuse(resolver: &PodResolver, listener: &TcpListener, address: &str) -> Errors {
+        let res = resolver
+            .resolve_destination(Destination::Dns(address), 8080)
+            .await;
+
+        assert!(listener.accept().now_or_never().is_none(), "{address}");
+        res.unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn user_reaches_their_namespace() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(&listener, Some("alice"));
+
+        assert_eq!(resolve(&resolver, &listener).await.unwrap().pod, "web-0");
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_client_gets_the_default_policy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(&listener, None);
+
+        match refuse(&resolver, &listener, "web-0.apps.pod.cluster.local").await {
+            e @ Errors::NamespaceDenied { username: None, .. } => {
+                assert_eq!(e.kind(), ErrorKind::NamespaceDenied);
+                assert_eq!(
+                    e.to_string(),
+                    "Namespace apps isn't allowed for unauthenticated clients"
+                );
+            }
+            e => panic!("expected NamespaceDenied, got {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn other_users_get_the_default_policy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(&listener, Some("bob"));
+
+        let e = refuse(&resolver, &listener, "web-0.apps.pod.cluster.local").await;
+
+        assert!(
+            matches!(e, Errors::NamespaceDenied { ref username, .. } if username.as_deref() == Some("bob")),
+            "{e:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn every_address_naming_its_namespace_is_refused_before_lookup() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(&listener, None);
+
+        for address in [
+            "web.apps.svc.cluster.local",
+            "_http._tcp.web.apps.svc.cluster.local",
+            "web-0.web.apps.svc.cluster.local",
+            "0.web.apps.ep.cluster.local",
+            "10-1-2-3.apps.pod.cluster.local",
+            "web.apps.deploy.cluster.local",
+            "web.apps.rs.cluster.local",
+            "web.apps.ds.cluster.local",
+            "web.apps.sts.cluster.local",
+        ] {
+            let e = refuse(&resolver, &listener, address).await;
+            assert!(
+                matches!(e, Errors::NamespaceDenied { .. }),
+                "{address}: {e:?}"
+            );
+        }
+    }
+}
+
+mod hyphenated_ip {
+    use super::super::*;
+
+    #[test]
+    fn ipv4() {
+        assert_eq!(hyphenated_ip("10-1-2-3"), Some([10, 1, 2, 3].into()));
+    }
+
+    #[test]
+    fn ipv6() {
+        assert_eq!(hyphenated_ip("fd00--7"), Some("fd00::7".parse().unwrap()));
+    }
+
+    #[test]
+    fn pod_names_are_not_ips() {
+        for label in ["web-0", "10-1-2", "10-1-2-3-4", "10-1-2-300", "10", "fd00"] {
+            assert_eq!(hyphenated_ip(label), None, "{label}");
+        }
+    }
+}
+
+mod pod_ip_names {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+
+    #[tokio::test]
+    async fn resolves_the_pod_with_that_ip_in_the_namespace() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let pods = r#"{"apiVersion":"v1","kind":"PodList","metadata":{},"items":[{"metadata":{"name":"web-0","namespace":"apps"},"spec":{"containers":[{"name":"web","ports":[{"containerPort":8080}]}]},"status":{"phase":"Running","podIP":"10.1.2.3","conditions":[{"type":"Ready","status":"True"}]}}]}"#;
+        let (res, path) = tokio::join!(
+            resolver.resolve_destination(Destination::Dns("10-1-2-3.apps.pod.cluster.local"), 0),
+            serve_json(&listener, pods)
+        );
+
+        let target = res.unwrap();
+        assert_eq!((target.pod.as_str(), target.port), ("web-0", 8080));
+        assert!(
+            path.starts_with("/api/v1/namespaces/apps/pods?")
+                && path.contains("fieldSelector=status.podIP%3D10.1.2.3"),
+            "{path}"
+        );
+    }
+
+    #[tokio::test]
+    async fn no_pod_with_that_ip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let no_pods = r#"{"apiVersion":"v1","kind":"PodList","metadata":{},"items":[]}"#;
+        let (res, _) = tokio::join!(
+            resolver.resolve_destination(Destination::Dns("10-1-2-3.apps.pod.cluster.local"), 80),
+            serve_json(&listener, no_pods)
+        );
+
+        match res {
+            Err(e @ Errors::PodIpNotFoundInNamespace { .. }) => {
+                assert_eq!(e.kind(), ErrorKind::PodIpNotFound);
+                assert_eq!(e.to_string(), "No Pod with IP 10.1.2.3 in Namespace apps");
+            }
+            res => panic!("expected PodIpNotFoundInNamespace, got {res:?}"),
+        }
+    }
+}
+
+mod endpoints {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+
+    const SERVICE: &str = r#"{"apiVersion":"v1","kind":"Service","metadata":{"name":"web","namespace":"apps"},"spec":{"selector":{"app":"web"},"ports":[{"name":"http","port":80,"targetPort":8080}]}}"#;
+    const SLICES: &str = r#"{"apiVersion":"discovery.k8s.io/v1","kind":"EndpointSliceList","metadata":{},"items":[
+        {"metadata":{"name":"web-b"},"addressType":"IPv4","ports":[{"name":"http","port":8080}],"endpoints":[
+            {"addresses":["10.0.0.9"],"targetRef":{"kind":"Pod","name":"web-2"}},
+            {"addresses":["10.0.0.5"],"conditions":{"ready":false},"targetRef":{"kind":"Pod","name":"web-1"}}]},
+        {"metadata":{"name":"web-a"},"addressType":"IPv4","ports":[{"name":"http","port":8080}],"endpoints":[
+            {"addresses":["10.0.0.7"],"targetRef":{"kind":"Pod","name":"web-0"}},
+            {"addresses":["192.0.2.1"]}]}]}"#;
+
+    fn slices() -> Vec<EndpointSlice> {
+        let list: kube::core::ObjectList<EndpointSlice> = serde_json::from_str(SLICES).unwrap();
+        list.items
+    }
+
+    #[test]
+    fn endpoint_refs() {
+        assert_eq!(
+            EndpointRef::parse("10-0-0-7"),
+            Some(EndpointRef::Ip([10, 0, 0, 7].into()))
+        );
+        assert_eq!(EndpointRef::parse("2"), Some(EndpointRef::Index(2)));
+        assert_eq!(EndpointRef::parse("web-0"), None);
+    }
+
+    #[test]
+    fn every_address_ordered_by_ip() {
+        let endpoints = slice_endpoints(&slices(), "http");
+
+        let ips: Vec<String> = endpoints.iter().map(|e| e.ip.to_string()).collect();
+        assert_eq!(ips, ["10.0.0.5", "10.0.0.7", "10.0.0.9", "192.0.2.1"]);
+        assert_eq!(endpoints[1].pod.as_deref(), Some("web-0"));
+        assert_eq!(endpoints[1].port, Some(8080));
+        assert_eq!(endpoints[3].pod, None);
+    }
+
+    #[test]
+    fn ports_are_matched_by_name() {
+        let endpoints = slice_endpoints(&slices(), "metrics");
+
+        assert!(endpoints.iter().all(|e| e.port.is_none()));
+    }
+
+    const POD: &str = r#"{"apiVersion":"v1","kind":"Pod","metadata":{"name":"web-2","namespace":"apps"},"status":{"phase":"Running","podIP":"10.0.0.9"}}"#;
+
+    #[tokio::test]
+    async fn by_ip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let (res, paths) = tokio::join!(
+            resolver
+                .resolve_destination(Destination::Dns("10-0-0-9.web.apps.ep.cluster.local"), 80),
+            async {
+                serve_json(&listener, SERVICE).await;
+                let slices = serve_json(&listener, SLICES).await;
+                let pod = serve_json(&listener, POD).await;
+                (slices, pod)
+            }
+        );
+
+        let target = res.unwrap();
+        assert_eq!((target.pod.as_str(), target.port), ("web-2", 8080));
+        let (slices, pod) = paths;
+        assert!(
+            slices.starts_with("/apis/discovery.k8s.io/v1/namespaces/apps/endpointslices?")
+                && slices.contains("labelSelector=kubernetes.io%2Fservice-name%3Dweb"),
+            "{slices}"
+        );
+        assert_eq!(pod, "/api/v1/namespaces/apps/pods/web-2");
+    }
+
+    #[tokio::test]
+    async fn by_index() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let (res, pod) = tokio::join!(
+            resolver.resolve_destination(Destination::Dns("2.web.apps.ep.cluster.local"), 0),
+            async {
+                serve_json(&listener, SERVICE).await;
+                serve_json(&listener, SLICES).await;
+                serve_json(&listener, POD).await
+            }
+        );
+
+        assert_eq!(res.unwrap().pod, "web-2");
+        assert_eq!(pod, "/api/v1/namespaces/apps/pods/web-2");
+    }
+
+    #[tokio::test]
+    async fn not_in_the_slices() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let (res, _) = tokio::join!(
+            resolver
+                .resolve_destination(Destination::Dns("10-0-0-8.web.apps.ep.cluster.local"), 80),
+            async {
+                serve_json(&listener, SERVICE).await;
+                serve_json(&listener, SLICES).await;
+            }
+        );
+
+        match res {
+            Err(e @ Errors::EndpointNotFound { .. }) => {
+                assert_eq!(e.kind(), ErrorKind::EndpointNotFound);
+                assert_eq!(
+                    e.to_string(),
+                    "No endpoint 10-0-0-8 in the EndpointSlices of Service apps/web"
+                );
+            }
+            res => panic!("expected EndpointNotFound, got {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn endpoints_that_are_not_pods_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let (res, _) = tokio::join!(
+            resolver.resolve_destination(Destination::Dns("3.web.apps.ep.cluster.local"), 80),
+            async {
+                serve_json(&listener, SERVICE).await;
+                serve_json(&listener, SLICES).await;
+            }
+        );
+
+        assert!(
+            matches!(res, Err(Errors::ServiceInvalid { ref reason, .. }) if reason == "endpoint 192.0.2.1 isn't a pod"),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn other_labels_are_unsupported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        for address in [
+            "web-0.web.apps.ep.cluster.local",
+            "web.apps.ep.cluster.local",
+        ] {
+            let res = resolver
+                .resolve_destination(Destination::Dns(address), 80)
+                .await;
+
+            assert!(matches!(res, Err(Errors::UnsupportedAddress(_))), "{res:?}");
+        }
+    }
+}
+
+mod connect_timeout {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::pod_resolver;
+    use crate::config::ErrorReply;
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_forward_times_out() {
+        // Connections are queued by the kernel but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(
+            listener.local_addr().unwrap(),
+            Config {
+                connect_timeout: 1,
+                ..Config::default()
+            },
+        );
+        let target = Target {
+            namespace: "apps".into(),
+            pod: "web-0".into(),
+            port: 80,
+            pod_ip: None,
+            app_protocol: None,
+            cluster: None,
+            connect_timeout: None,
+            named_target_port: None,
+        };
+
+        let res = resolver.port_forward(&target, &[80]).await.map(|_| ());
+
+        match res {
+            Err(e @ Errors::ConnectTimedOut { .. }) => {
+                assert_eq!(e.kind(), ErrorKind::ConnectTimeout);
+                assert_eq!(e.kind().default_reply(), ErrorReply::TtlExpired);
+            }
+            res => panic!("expected ConnectTimedOut, got {res:?}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn annotated_timeout_overrides_the_default() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+        let target = Target {
+            namespace: "apps".into(),
+            pod: "web-0".into(),
+            port: 80,
+            pod_ip: None,
+            app_protocol: None,
+            cluster: None,
+            connect_timeout: Some(Duration::from_millis(250)),
+            named_target_port: None,
+        };
+
+        let res = resolver.port_forward(&target, &[80]).await.map(|_| ());
+
+        match res {
+            Err(Errors::ConnectTimedOut { timeout, .. }) => {
+                assert_eq!(timeout, Duration::from_millis(250))
+            }
+            res => panic!("expected ConnectTimedOut, got {res:?}"),
+        }
+    }
+}
+
+mod annotated_connect_timeout {
+    use super::super::*;
+
+    fn metadata(value: Option<&str>) -> ObjectMeta {
+        ObjectMeta {
+            name: Some("web".into()),
+            annotations: value
+                .map(|v| BTreeMap::from([(CONNECT_TIMEOUT_ANNOTATION.into(), v.into())])),
+            ..ObjectMeta::default()
+        }
+    }
+
+    #[test]
+    fn seconds() {
+        assert_eq!(
+            annotated_connect_timeout(&metadata(Some("45"))),
+            Some(Duration::from_secs(45))
+        );
+    }
+
+    #[test]
+    fn with_a_unit() {
+        assert_eq!(
+            annotated_connect_timeout(&metadata(Some("500ms"))),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            annotated_connect_timeout(&metadata(Some("2m"))),
+            Some(Duration::from_secs(120))
+        );
+    }
+
+    #[test]
+    fn invalid_or_zero_is_ignored() {
+        assert_eq!(annotated_connect_timeout(&metadata(Some("soon"))), None);
+        assert_eq!(annotated_connect_timeout(&metadata(Some("-5"))), None);
+        assert_eq!(annotated_connect_timeout(&metadata(Some("0"))), None);
+    }
+
+    #[test]
+    fn not_annotated() {
+        assert_eq!(annotated_connect_timeout(&metadata(None)), None);
+        assert_eq!(annotated_connect_timeout(&ObjectMeta::default()), None);
+    }
+}
+
+mod circuit_breaker {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+
+    const SERVICE: &str = r#"{"apiVersion":"v1","kind":"Service","metadata":{"name":"web","namespace":"apps"},"spec":{"selector":{"app":"web"},"ports":[{"port":80}]}}"#;
+    const NOT_READY: &str = r#"{"apiVersion":"v1","kind":"PodList","metadata":{},"items":[{"metadata":{"name":"web-0","namespace":"apps"},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"False"}]}}]}"#;
+
+    #[tokio::test]
+    async fn open_breaker_skips_the_api_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut resolver = pod_resolver(
+            listener.local_addr().unwrap(),
+            Config {
+                circuit_breaker_failures: 1,
+                ..Config::default()
+            },
+        );
+
+        let (res, _) = tokio::join!(
+            resolver.forwarder(Destination::Dns("web.apps.svc.cluster.local"), 80),
+            async {
+                serve_json(&listener, SERVICE).await;
+                serve_json(&listener, NOT_READY).await;
+            }
+        );
+        let e = res.err().unwrap();
+        assert!(matches!(e, Errors::ServiceNoReadyPods { .. }), "{e:?}");
+
+        // Nothing is served, so this would hang if it asked the API server
+        let e = resolver
+            .forwarder(Destination::Dns("web.apps.svc.cluster.local."), 80)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::CircuitOpen);
+        assert!(
+            matches!(e, Errors::CircuitOpen { ref address, port: 80, .. } if address == "web.apps.svc.cluster.local"),
+            "{e:?}"
+        );
+    }
+}
+
+mod resolve_service {
+    use futures::SinkExt;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+    use crate::config::ErrorReply;
+    use crate::fake_api::accept_portforward;
+
+    #[tokio::test]
+    async fn target_carries_the_app_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let service = r#"{"apiVersion":"v1","kind":"Service","metadata":{"name":"web","namespace":"apps"},"spec":{"selector":{"app":"web"},"ports":[{"port":80,"targetPort":8080,"appProtocol":"kubernetes.io/h2c"}]}}"#;
+        let pods = r#"{"apiVersion":"v1","kind":"PodList","metadata":{},"items":[{"metadata":{"name":"web-0","namespace":"apps"},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}]}"#;
+        let (res, _) = tokio::join!(resolver.resolve_service(&["web", "apps"], 0), async {
+            serve_json(&listener, service).await;
+            serve_json(&listener, pods).await;
+        });
+
+        let target = res.unwrap();
+        assert_eq!(target.port, 8080);
+        assert_eq!(target.app_protocol.as_deref(), Some("kubernetes.io/h2c"));
+    }
+
+    const ANNOTATED_SERVICE: &str = r#"{"apiVersion":"v1","kind":"Service","metadata":{"name":"web","namespace":"apps","annotations":{"kube-fwd-socks/connect-timeout":"5"}},"spec":{"selector":{"app":"web"},"ports":[{"port":80}]}}"#;
+
+    #[tokio::test]
+    async fn connect_timeout_annotated_on_the_service() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let pods = r#"{"apiVersion":"v1","kind":"PodList","metadata":{},"items":[{"metadata":{"name":"web-0","namespace":"apps"},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}]}"#;
+        let (res, _) = tokio::join!(resolver.resolve_service(&["web", "apps"], 80), async {
+            serve_json(&listener, ANNOTATED_SERVICE).await;
+            serve_json(&listener, pods).await;
+        });
+
+        assert_eq!(res.unwrap().connect_timeout, Some(Duration::from_secs(5)));
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_annotated_on_the_pod_wins() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let pods = r#"{"apiVersion":"v1","kind":"PodList","metadata":{},"items":[{"metadata":{"name":"web-0","namespace":"apps","annotations":{"kube-fwd-socks/connect-timeout":"250ms"}},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}]}"#;
+        let (res, _) = tokio::join!(resolver.resolve_service(&["web", "apps"], 80), async {
+            serve_json(&listener, ANNOTATED_SERVICE).await;
+            serve_json(&listener, pods).await;
+        });
+
+        assert_eq!(
+            res.unwrap().connect_timeout,
+            Some(Duration::from_millis(250))
+        );
+    }
+
+    const SERVICE: &str = r#"{"apiVersion":"v1","kind":"Service","metadata":{"name":"web","namespace":"apps"},"spec":{"selector":{"app":"web"},"ports":[{"port":80}]}}"#;
+
+    async fn resolve_with_pods(items: &str) -> Result<Target, Errors> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let pods =
+            format!(r#"{{"apiVersion":"v1","kind":"PodList","metadata":{{}},"items":[{items}]}}"#);
+        let (res, _) = tokio::join!(resolver.resolve_service(&["web", "apps"], 80), async {
+            serve_json(&listener, SERVICE).await;
+            serve_json(&listener, &pods).await;
+        });
+        res
+    }
+
+    const NAMED_TARGET_PORT: &str = r#"{"apiVersion":"v1","kind":"Service","metadata":{"name":"web","namespace":"apps"},"spec":{"selector":{"app":"web"},"ports":[{"port":80,"targetPort":"http"}]}}"#;
+    const NAMED_PORT_PODS: &str = r#"{"apiVersion":"v1","kind":"PodList","metadata":{},"items":[{"metadata":{"name":"web-0","namespace":"apps"},"spec":{"containers":[{"name":"web","ports":[{"name":"http","containerPort":8080}]}]},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}]}"#;
+
+    #[tokio::test]
+    async fn named_target_ports_are_not_probed_by_default() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        // A probe's forward would never be answered
+        let (res, _) = tokio::join!(resolver.resolve_service(&["web", "apps"], 80), async {
+            serve_json(&listener, NAMED_TARGET_PORT).await;
+            serve_json(&listener, NAMED_PORT_PODS).await;
+        });
+
+        assert_eq!(res.unwrap().port, 8080);
+    }
+
+    #[tokio::test]
+    async fn probe_that_cant_be_opened_is_skipped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            probe_named_target_ports: true,
+            ..Config::default()
+        };
+        let resolver = pod_resolver(listener.local_addr().unwrap(), config);
+
+        let (target, _) = tokio::join!(resolver.resolve_service(&["web", "apps"], 80), async {
+            serve_json(&listener, NAMED_TARGET_PORT).await;
+            serve_json(&listener, NAMED_PORT_PODS).await;
+        });
+        let target = target.unwrap();
+        assert_eq!(
+            target.named_target_port,
+            Some(NamedTargetPort {
+                service: "web".into(),
+                port_name: "http".into(),
+            })
+        );
+
+        // Answering the forward's upgrade with plain JSON fails it
+        let (res, probed) = tokio::join!(
+            resolver.check_target_port(&target),
+            serve_json(&listener, "{}")
+        );
+
+        assert!(res.is_ok(), "{res:?}");
+        assert!(
+            probed.starts_with("/api/v1/namespaces/apps/pods/web-0/portforward?"),
+            "{probed}"
+        );
+    }
+
+    /// Accepts a port-forward to `port` on `listener` and reports `reason` on its error channel.
+    async fn refuse_portforward(listener: &TcpListener, port: u16, reason: &str) {
+        let (mut ws, _) = accept_portforward(listener, &[port]).await;
+        let mut frame = vec![1];
+        frame.extend_from_slice(reason.as_bytes());
+        ws.send(Message::binary(frame)).await.unwrap();
+
+        while let Some(Ok(_)) = ws.next().await {}
+    }
+
+    #[tokio::test]
+    async fn unserved_target_port_refuses_the_forward() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            probe_named_target_ports: true,
+            ..Config::default()
+        };
+        let mut resolver = pod_resolver(listener.local_addr().unwrap(), config);
+
+        let (res, _) = tokio::join!(
+            resolver.forwarder(Destination::Dns("web.apps.svc"), 80),
+            async {
+                serve_json(&listener, NAMED_TARGET_PORT).await;
+                serve_json(&listener, NAMED_PORT_PODS).await;
+                refuse_portforward(&listener, 8080, "connect: connection refused").await;
+            }
+        );
+
+        let e = res.err().unwrap();
+        assert!(
+            matches!(
+                e,
+                Errors::TargetPortNotServed { ref port_name, port: 8080, .. } if port_name == "http"
+            ),
+            "{e:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn denied_pods_are_not_probed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            probe_named_target_ports: true,
+            deny_names: vec!["web-*".parse().unwrap()],
+            ..Config::default()
+        };
+        let mut resolver = pod_resolver(listener.local_addr().unwrap(), config);
+
+        let (res, _) = tokio::join!(
+            resolver.forwarder(Destination::Dns("web.apps.svc"), 80),
+            async {
+                serve_json(&listener, NAMED_TARGET_PORT).await;
+                serve_json(&listener, NAMED_PORT_PODS).await;
+            }
+        );
+
+        let e = res.err().unwrap();
+        assert!(matches!(e, Errors::NameDenied { .. }), "{e:?}");
+        assert!(listener.accept().now_or_never().is_none());
+    }
+
+    #[test]
+    fn unserved_target_port_is_refused() {
+        let e = Errors::TargetPortNotServed {
+            namespace: "apps".into(),
+            service: "web".into(),
+            port_name: "http".into(),
+            pod: "web-0".into(),
+            port: 8080,
+            reason: "connection refused".into(),
+        };
+
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(
+            e.to_string(),
+            "Pod apps/web-0 doesn't listen on port 8080, targetPort http of Service web, it may \
+             only be declared for probes - connection refused"
+        );
+    }
+
+    #[tokio::test]
+    async fn no_matching_pods() {
+        let e = resolve_with_pods("").await.unwrap_err();
+
+        assert!(matches!(e, Errors::ServiceNoMatchingPods { .. }), "{e:?}");
+        assert_eq!(
+            Config::default().error_reply(e.kind()),
+            ErrorReply::HostUnreachable
+        );
+    }
+
+    #[tokio::test]
+    async fn matching_pods_not_ready() {
+        let not_ready = r#"{"metadata":{"name":"web-0","namespace":"apps"},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"False"}]}}"#;
+
+        let e = resolve_with_pods(not_ready).await.unwrap_err();
+
+        assert!(matches!(e, Errors::ServiceNoReadyPods { .. }), "{e:?}");
+        assert_eq!(
+            Config::default().error_reply(e.kind()),
+            ErrorReply::ConnectionRefused
+        );
+    }
+}
+
+mod headless_service {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+
+    const SERVICE: &str = r#"{"apiVersion":"v1","kind":"Service","metadata":{"name":"web","namespace":"apps"},"spec":{"clusterIP":"None","selector":{"app":"web"},"ports":[{"port":80}]}}"#;
+
+    fn pod(name: &str, hostname: Option<&str>, ready: bool) -> String {
+        let status = if ready { "True" } else { "False" };
+        let spec = match hostname {
+            Some(hostname) => {
+                format!(r#"{{"hostname":"{hostname}","subdomain":"web","containers":[]}}"#)
+            }
+            None => r#"{"containers":[]}"#.to_string(),
+        };
+        format!(
+            r#"{{"metadata":{{"name":"{name}","namespace":"apps"}},"spec":{spec},"status":{{"phase":"Running","conditions":[{{"type":"Ready","status":"{status}"}}]}}}}"#
+        )
+    }
+
+    fn pods(pods: &[String]) -> String {
+        format!(
+            r#"{{"apiVersion":"v1","kind":"PodList","metadata":{{}},"items":[{}]}}"#,
+            pods.join(",")
+        )
+    }
+
+    async fn resolve(
+        resolver: &PodResolver,
+        listener: &TcpListener,
+        segments: &[&str],
+        pod_list: &str,
+    ) -> Result<Target, Errors> {
+        let (res, _) = tokio::join!(resolver.resolve_service(segments, 80), async {
+            serve_json(listener, SERVICE).await;
+            serve_json(listener, pod_list).await;
+        });
+        res
+    }
+
+    #[tokio::test]
+    async fn pod_names_resolve_to_that_pod() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+        let list = pods(&[
+            pod("web-0", Some("web-0"), true),
+            pod("web-1", Some("web-1"), true),
+        ]);
+
+        let target = resolve(&resolver, &listener, &["web-1", "web", "apps"], &list).await;
+
+        assert_eq!(target.unwrap().pod, "web-1");
+    }
+
+    #[tokio::test]
+    async fn pod_names_need_a_dns_record() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        // Named web-0, but without a hostname and subdomain DNS has no record for it
+        let list = pods(&[pod("web-0", None, true)]);
+        let res = resolve(&resolver, &listener, &["web-0", "web", "apps"], &list).await;
+        assert!(
+            matches!(res, Err(Errors::NamedServicePodsNotFound { .. })),
+            "{res:?}"
+        );
+
+        // Nor for pods that aren't ready
+        let list = pods(&[pod("web-0", Some("web-0"), false)]);
+        let res = resolve(&resolver, &listener, &["web-0", "web", "apps"], &list).await;
+        assert!(
+            matches!(res, Err(Errors::NamedServicePodsNotFound { .. })),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn service_name_takes_turns() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+        // Listed out of order, and with a pod that isn't ready
+        let list = pods(&[
+            pod("web-1", Some("web-1"), true),
+            pod("web-2", Some("web-2"), false),
+            pod("web-0", Some("web-0"), true),
+        ]);
+
+        let mut picked = Vec::new();
+        for _ in 0..3 {
+            let target = resolve(&resolver, &listener, &["web", "apps"], &list).await;
+            picked.push(target.unwrap().pod);
+        }
+
+        assert_eq!(picked, ["web-0", "web-1", "web-0"]);
+    }
+}
+
+mod has_dns_hostname {
+    use k8s_openapi::api::core::v1::PodSpec;
+
+    use super::super::*;
+
+    fn pod(hostname: Option<&str>, subdomain: Option<&str>) -> Pod {
+        Pod {
+            spec: Some(PodSpec {
+                hostname: hostname.map(Into::into),
+                subdomain: subdomain.map(Into::into),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn needs_hostname_and_subdomain() {
+        assert!(has_dns_hostname(
+            &pod(Some("web-0"), Some("web")),
+            "web-0",
+            "web"
+        ));
+        assert!(!has_dns_hostname(&pod(Some("web-0"), None), "web-0", "web"));
+        assert!(!has_dns_hostname(
+            &pod(Some("web-0"), Some("db")),
+            "web-0",
+            "web"
+        ));
+        assert!(!has_dns_hostname(&pod(None, Some("web")), "web-0", "web"));
+    }
+}
+
+mod split_cluster {
+    use super::super::*;
+
+    #[test]
+    fn splits_off_the_cluster() {
+        assert_eq!(
+            split_cluster("web.apps.svc.prod.clusters", "cluster.local"),
+            Some(("prod".into(), "web.apps.svc.cluster.local.".into()))
+        );
+        assert_eq!(
+            split_cluster("Web-0.Apps.Pod.Prod.Clusters.", "corp.internal"),
+            Some(("prod".into(), "web-0.apps.pod.corp.internal.".into()))
+        );
+    }
+
+    #[test]
+    fn other_addresses() {
+        for address in [
+            "web.apps.svc.cluster.local",
+            "web",
+            "prod.clusters",
+            "web.apps.svc.prod.clusters.local",
+        ] {
+            assert_eq!(split_cluster(address, "cluster.local"), None, "{address}");
+        }
+    }
+}
+
+mod clusters {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::serve_json;
+
+    fn client(api_server: &TcpListener) -> Client {
+        let url = format!("http://{}", api_server.local_addr().unwrap());
+        Client::try_from(kube::Config::new(url.parse().unwrap())).unwrap()
+    }
+
+    /// A resolver whose primary cluster is `primary`, with `prod` at `prod`.
+    fn resolver(primary: &TcpListener, prod: &TcpListener) -> PodResolver {
+        let ctx = Context::new(Some(client(primary)), Arc::new(Config::default()))
+            .unwrap()
+            .with_clusters(BTreeMap::from([(
+                "prod".into(),
+                KubeClient::new(client(prod)),
+            )]));
+
+        PodResolver::new(ctx, Arc::new(KubeClient::new(client(primary))))
+    }
+
+    #[tokio::test]
+    async fn resolves_in_the_named_cluster() {
+        let primary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let prod = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut resolver = resolver(&primary, &prod);
+
+        let pod = r#"{"apiVersion":"v1","kind":"Pod","metadata":{"name":"web-0","namespace":"apps"},"spec":{"containers":[{"name":"web","ports":[{"containerPort":80}]}]},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}"#;
+        resolver.use_cluster("prod".into()).unwrap();
+        let (res, path) = tokio::join!(
+            resolver.resolve_destination(Destination::Dns("web-0.apps.pod.cluster.local."), 80),
+            serve_json(&prod, pod)
+        );
+
+        let target = res.unwrap();
+        assert_eq!(target.pod, "web-0");
+        assert_eq!(target.cluster.as_deref(), Some("prod"));
+        assert_eq!(path, "/api/v1/namespaces/apps/pods/web-0");
+    }
+
+    #[tokio::test]
+    async fn unknown_cluster() {
+        let primary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let prod = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut resolver = resolver(&primary, &prod);
+
+        let res = resolver
+            .forwarder(Destination::Dns("web.apps.svc.staging.clusters"), 80)
+            .await;
+
+        match res {
+            Err(e @ Errors::ClusterNotFound(_)) => {
+                assert_eq!(e.kind(), ErrorKind::ClusterNotFound);
+                assert_eq!(
+                    e.to_string(),
+                    "No cluster named staging, add it with --context"
+                );
+            }
+            Err(e) => panic!("expected ClusterNotFound, got {e:?}"),
+            Ok(_) => panic!("expected ClusterNotFound"),
+        }
+    }
+
+    #[tokio::test]
+    async fn primary_cluster_by_default() {
+        let primary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let prod = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(&primary, &prod);
+
+        let pod = r#"{"apiVersion":"v1","kind":"Pod","metadata":{"name":"web-0","namespace":"apps"},"spec":{"containers":[{"name":"web","ports":[{"containerPort":80}]}]},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}"#;
+        let (res, _) = tokio::join!(
+            resolver.resolve_destination(Destination::Dns("web-0.apps.pod.cluster.local"), 80),
+            serve_json(&primary, pod)
+        );
+
+        assert_eq!(res.unwrap().cluster, None);
+    }
+}
+
+mod pick_affine {
+    use std::collections::BTreeSet;
+
+    use super::super::*;
+
+    fn pods(names: &[&str]) -> Vec<Pod> {
+        names
+            .iter()
+            .map(|name| Pod {
+                metadata: kube::api::ObjectMeta {
+                    name: Some(name.to_string()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn picked(names: &[&str], client_ip: [u8; 4]) -> String {
+        pick_affine(pods(names), IpAddr::from(client_ip))
+            .and_then(|p| p.metadata.name)
+            .unwrap()
+    }
+
+    #[test]
+    fn same_client_same_pod() {
+        let names = ["web-0", "web-1", "web-2"];
+        let first = picked(&names, [10, 0, 0, 1]);
+
+        assert_eq!(picked(&names, [10, 0, 0, 1]), first);
+        // Whatever order they're listed in
+        assert_eq!(picked(&["web-2", "web-0", "web-1"], [10, 0, 0, 1]), first);
+    }
+
+    #[test]
+    fn clients_spread_across_pods() {
+        let names = ["web-0", "web-1", "web-2"];
+
+        let picked: BTreeSet<_> = (0..32).map(|i| picked(&names, [10, 0, 0, i])).collect();
+
+        assert_eq!(picked.len(), names.len());
+    }
+
+    #[test]
+    fn only_clients_of_a_removed_pod_move() {
+        let names = ["web-0", "web-1", "web-2"];
+
+        for i in 0..32 {
+            let before = picked(&names, [10, 0, 0, i]);
+            let remaining: Vec<_> = names.into_iter().filter(|n| *n != "web-1").collect();
+            let after = picked(&remaining, [10, 0, 0, i]);
+
+            if before != "web-1" {
+                assert_eq!(after, before);
+            }
+        }
+    }
+
+    #[test]
+    fn no_pods() {
+        assert!(pick_affine(vec![], IpAddr::from([10, 0, 0, 1])).is_none());
+    }
+}
+
+mod session_affinity {
+    use std::collections::BTreeSet;
+    use std::net::SocketAddr;
+
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+
+    const PODS: &str = r#"{"apiVersion":"v1","kind":"PodList","metadata":{},"items":[{"metadata":{"name":"web-0","namespace":"apps"},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}},{"metadata":{"name":"web-1","namespace":"apps"},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}},{"metadata":{"name":"web-2","namespace":"apps"},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}]}"#;
+
+    fn service(session_affinity: &str) -> String {
+        format!(
+            r#"{{"apiVersion":"v1","kind":"Service","metadata":{{"name":"web","namespace":"apps"}},"spec":{{"selector":{{"app":"web"}},"sessionAffinity":"{session_affinity}","ports":[{{"port":80}}]}}}}"#
+        )
+    }
+
+    /// The pod the client at `peer_addr` is sent to.
+    async fn resolve(session_affinity: &str, peer_addr: PeerAddr) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver =
+            pod_resolver(listener.local_addr().unwrap(), Config::default()).for_client(&peer_addr);
+
+        let service = service(session_affinity);
+        let (res, _) = tokio::join!(resolver.resolve_service(&["web", "apps"], 80), async {
+            serve_json(&listener, &service).await;
+            serve_json(&listener, PODS).await;
+        });
+        res.unwrap().pod
+    }
+
+    fn client(i: u8) -> PeerAddr {
+        PeerAddr::Tcp(SocketAddr::from(([10, 0, 0, i], 40000 + u16::from(i))))
+    }
+
+    #[tokio::test]
+    async fn client_ip_follows_the_client() {
+        let mut picked = BTreeSet::new();
+        for i in 0..16 {
+            let pod = resolve("ClientIP", client(i)).await;
+            // Whichever port it connects from
+            let again = PeerAddr::Tcp(SocketAddr::from(([10, 0, 0, i], 50000)));
+            assert_eq!(resolve("ClientIP", again).await, pod);
+            picked.insert(pod);
+        }
+
+        assert!(picked.len() > 1, "{picked:?}");
+    }
+
+    #[tokio::test]
+    async fn otherwise_the_first_ready_pod() {
+        for i in 0..4 {
+            assert_eq!(resolve("None", client(i)).await, "web-0");
+        }
+        assert_eq!(resolve("ClientIP", PeerAddr::Unix(None)).await, "web-0");
+    }
+}
+
+mod undeclared_ports {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+
+    const POD: &str = r#"{"apiVersion":"v1","kind":"Pod","metadata":{"name":"web-0","namespace":"apps"},"spec":{"containers":[{"name":"web","ports":[{"containerPort":8080}]},{"name":"metrics","ports":[{"containerPort":9090}]}]}}"#;
+
+    async fn resolve(config: Config, port: u16) -> Result<Target, Errors> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), config);
+
+        let (res, _) = tokio::join!(
+            resolver.resolve("web-0.apps.pod.cluster.local", port),
+            serve_json(&listener, POD)
+        );
+        res
+    }
+
+    #[tokio::test]
+    async fn declared_ports_of_any_container() {
+        assert_eq!(resolve(Config::default(), 8080).await.unwrap().port, 8080);
+        assert_eq!(resolve(Config::default(), 9090).await.unwrap().port, 9090);
+        assert_eq!(resolve(Config::default(), 0).await.unwrap().port, 8080);
+    }
+
+    #[tokio::test]
+    async fn undeclared_ports_are_rejected() {
+        let res = resolve(Config::default(), 8081).await;
+
+        assert!(
+            matches!(res, Err(Errors::PortNotFound(ref n, ref p, 8081)) if n == "apps" && p == "web-0"),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn allowed_with_allow_undeclared_ports() {
+        let config = Config {
+            allow_undeclared_ports: true,
+            ..Config::default()
+        };
+
+        assert_eq!(resolve(config, 8081).await.unwrap().port, 8081);
+    }
+}
+
+mod srv_names {
+    use tokio::net::TcpListener;
+
+    use super::super::*;
+    use super::{pod_resolver, serve_json};
+
+    const SERVICE: &str = r#"{"apiVersion":"v1","kind":"Service","metadata":{"name":"web","namespace":"apps"},"spec":{"selector":{"app":"web"},"ports":[{"name":"http","port":80,"targetPort":8080},{"name":"metrics","port":9090}]}}"#;
+    const PODS: &str = r#"{"apiVersion":"v1","kind":"PodList","metadata":{},"items":[{"metadata":{"name":"web-0","namespace":"apps"},"status":{"phase":"Running","conditions":[{"type":"Ready","status":"True"}]}}]}"#;
+
+    async fn resolve(address: &str, port: u16) -> Result<Target, Errors> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let (res, _) = tokio::join!(resolver.resolve(address, port), async {
+            serve_json(&listener, SERVICE).await;
+            serve_json(&listener, PODS).await;
+        });
+        res
+    }
+
+    #[tokio::test]
+    async fn picks_the_named_port() {
+        let target = resolve("_http._tcp.web.apps.svc.cluster.local", 0)
+            .await
+            .unwrap();
+        assert_eq!(target.port, 8080);
+
+        // Over whatever port was requested
+        let target = resolve("_metrics._tcp.web.apps.svc.cluster.local", 80)
+            .await
+            .unwrap();
+        assert_eq!(target.port, 9090);
+    }
+
+    #[tokio::test]
+    async fn under_a_search_domain() {
+        let config = Config {
+            default_namespace: "apps".into(),
+            ..Config::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), config);
+
+        let (res, _) = tokio::join!(resolver.resolve("_http._tcp.web", 0), async {
+            serve_json(&listener, SERVICE).await;
+            serve_json(&listener, PODS).await;
+        });
+
+        assert_eq!(res.unwrap().port, 8080);
+    }
+
+    #[tokio::test]
+    async fn unknown_port_name() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = pod_resolver(listener.local_addr().unwrap(), Config::default());
+
+        let (res, _) = tokio::join!(
+            resolver.resolve("_grpc._tcp.web.apps.svc.cluster.local", 0),
+            serve_json(&listener, SERVICE)
+        );
+
+        let e = res.unwrap_err();
+
+        assert!(
+            matches!(e, Errors::PortNameNotFound { ref port_name, .. } if port_name == "grpc"),
+            "{e:?}"
+        );
+        assert_eq!(e.kind(), ErrorKind::PortNotFound);
+    }
+
+    #[tokio::test]
+    async fn only_tcp() {
+        let resolver = pod_resolver("127.0.0.1:1".parse().unwrap(), Config::default());
+
+        for address in [
+            "_dns._udp.web.apps.svc.cluster.local",
+            "_http.tcp.web.apps.svc.cluster.local",
+            "http._tcp.web.apps.svc.cluster.local",
+            "_._tcp.web.apps.svc.cluster.local",
+            "_http._tcp.web-0.web.apps.svc.cluster.local",
+        ] {
+            let res = resolver.resolve(address, 0).await;
+
+            assert!(
+                matches!(res, Err(Errors::UnsupportedAddress(_))),
+                "{address}: {res:?}"
+            );
+        }
+    }
+}
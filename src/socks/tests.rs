@@ -0,0 +1,1399 @@
+mod until_disconnect {
+    use tokio::io::AsyncWriteExt;
+
+    use super::super::*;
+
+    #[tokio::test]
+    async fn returns_none_when_client_closes() {
+        let (mut client, server) = tokio::io::duplex(64);
+        drop(server);
+
+        let mut early = Vec::new();
+        let res = until_disconnect(&mut client, &mut early, std::future::pending::<()>()).await;
+
+        assert!(res.is_none());
+    }
+
+    #[tokio::test]
+    async fn buffers_early_data() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        server.write_all(b"GET /").await.unwrap();
+
+        let mut early = Vec::new();
+        let res = until_disconnect(&mut client, &mut early, async {
+            tokio::task::yield_now().await;
+            42
+        })
+        .await;
+
+        assert_eq!(res, Some(42));
+        assert_eq!(early, b"GET /");
+    }
+}
+
+mod detect_http {
+    use super::super::*;
+
+    #[test]
+    fn detects_http_methods() {
+        assert_eq!(detect_http(b"GET / HT"), Some("GET"));
+        assert_eq!(detect_http(b"CONNECT "), Some("CONNECT"));
+        assert_eq!(detect_http(b"POST /ap"), Some("POST"));
+    }
+
+    #[test]
+    fn detects_partial_peek() {
+        assert_eq!(detect_http(b"CONN"), Some("CONNECT"));
+    }
+
+    #[test]
+    fn ignores_socks_and_garbage() {
+        assert_eq!(detect_http(&[v5::VERSION, 1, 0]), None);
+        assert_eq!(detect_http(&[SOCKS4_VERSION, 1, 0, 80]), None);
+        assert_eq!(detect_http(b"G"), None);
+        assert_eq!(detect_http(b"GETS"), None);
+        assert_eq!(detect_http(b""), None);
+    }
+}
+
+mod copy_bidirectional {
+    use std::pin::Pin;
+    use std::task::Poll;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadBuf};
+
+    use super::super::*;
+    use crate::config::DEFAULT_BUFFER_SIZE;
+
+    /// Fails every read, to stand in for a connection reset
+    struct Broken;
+
+    impl AsyncRead for Broken {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    impl AsyncWrite for Broken {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Counts the writes made to the wrapped stream
+    struct CountingWrites<S> {
+        inner: S,
+        writes: usize,
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for CountingWrites<S> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for CountingWrites<S> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if res.is_ready() {
+                self.writes += 1;
+            }
+            res
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Sends `len` bytes from the client through a `buffer_size` copy, returning how many writes
+    /// it took to hand them to the pod.
+    async fn writes_to_pod(len: usize, buffer_size: usize) -> usize {
+        let (mut client, mut client_remote) = tokio::io::duplex(len);
+        let (pod, mut pod_remote) = tokio::io::duplex(len);
+        let mut pod = CountingWrites {
+            inner: pod,
+            writes: 0,
+        };
+
+        client_remote.write_all(&vec![7; len]).await.unwrap();
+        client_remote.shutdown().await.unwrap();
+        drop(client_remote);
+
+        let remote = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            pod_remote.read_to_end(&mut buf).await.unwrap();
+            buf.len()
+        });
+
+        let closed = copy_bidirectional(&mut client, &mut pod, buffer_size)
+            .await
+            .unwrap();
+        drop(pod.inner);
+
+        assert_eq!(closed.to_pod, len as u64);
+        assert_eq!(remote.await.unwrap(), len);
+        pod.writes
+    }
+
+    #[tokio::test]
+    async fn bigger_buffers_take_fewer_writes() {
+        const LEN: usize = 1024 * 1024;
+
+        assert_eq!(writes_to_pod(LEN, 1024).await, 1024);
+        assert_eq!(writes_to_pod(LEN, DEFAULT_BUFFER_SIZE).await, 128);
+        assert_eq!(writes_to_pod(LEN, 64 * 1024).await, 16);
+    }
+
+    #[tokio::test]
+    async fn tiny_buffers_still_copy_everything() {
+        assert_eq!(writes_to_pod(100, 1).await, 100);
+    }
+
+    /// Writes to `sender` until it would block for a second, returning how much it took.
+    async fn accepted_before_blocking(sender: &mut (impl AsyncWrite + Unpin)) -> usize {
+        let chunk = [7; 1024];
+        let mut accepted = 0;
+        while let Ok(written) =
+            tokio::time::timeout(Duration::from_secs(1), sender.write(&chunk)).await
+        {
+            accepted += written.unwrap();
+        }
+        accepted
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_pod_holds_back_the_client() {
+        const PIPE: usize = 4 * 1024;
+        const BUFFER_SIZE: usize = 16 * 1024;
+        let (mut client, mut client_remote) = tokio::io::duplex(PIPE);
+        // Never read from, like a pod that's stopped keeping up
+        let (mut pod, _pod_remote) = tokio::io::duplex(PIPE);
+
+        let copying = tokio::spawn(async move {
+            let _ = copy_bidirectional(&mut client, &mut pod, BUFFER_SIZE).await;
+        });
+
+        // What's in flight is bounded by the pipes either side and the one buffer between them
+        let accepted = accepted_before_blocking(&mut client_remote).await;
+        assert!(
+            (PIPE..=2 * PIPE + BUFFER_SIZE).contains(&accepted),
+            "{accepted}"
+        );
+        copying.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_client_holds_back_the_pod() {
+        const PIPE: usize = 4 * 1024;
+        const BUFFER_SIZE: usize = 16 * 1024;
+        let (mut client, _client_remote) = tokio::io::duplex(PIPE);
+        let (mut pod, mut pod_remote) = tokio::io::duplex(PIPE);
+
+        let copying = tokio::spawn(async move {
+            let _ = copy_bidirectional(&mut client, &mut pod, BUFFER_SIZE).await;
+        });
+
+        let accepted = accepted_before_blocking(&mut pod_remote).await;
+        assert!(
+            (PIPE..=2 * PIPE + BUFFER_SIZE).contains(&accepted),
+            "{accepted}"
+        );
+        copying.abort();
+    }
+
+    #[tokio::test]
+    async fn client_closing_first() {
+        let (mut client, mut client_remote) = tokio::io::duplex(64);
+        let (mut pod, mut pod_remote) = tokio::io::duplex(64);
+
+        let remotes = tokio::spawn(async move {
+            client_remote.write_all(b"ping").await.unwrap();
+            client_remote.shutdown().await.unwrap();
+
+            let mut buf = Vec::new();
+            pod_remote.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"ping");
+            pod_remote.write_all(b"pong!").await.unwrap();
+            drop(pod_remote);
+
+            buf.clear();
+            client_remote.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"pong!");
+        });
+
+        let closed = copy_bidirectional(&mut client, &mut pod, DEFAULT_BUFFER_SIZE)
+            .await
+            .unwrap();
+        remotes.await.unwrap();
+
+        assert_eq!(
+            closed,
+            Closed {
+                first: Side::Client,
+                to_pod: 4,
+                to_client: 5,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn pod_closing_first() {
+        let (mut client, client_remote) = tokio::io::duplex(64);
+        let (mut pod, pod_remote) = tokio::io::duplex(64);
+        drop(pod_remote);
+
+        let closer = tokio::spawn(async move {
+            let mut client_remote = client_remote;
+            let mut buf = Vec::new();
+            client_remote.read_to_end(&mut buf).await.unwrap();
+        });
+
+        let closed = copy_bidirectional(&mut client, &mut pod, DEFAULT_BUFFER_SIZE).await;
+        closer.await.unwrap();
+
+        assert_eq!(
+            closed.unwrap(),
+            Closed {
+                first: Side::Pod,
+                to_pod: 0,
+                to_client: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn attributes_pod_errors() {
+        let (mut client, _client_remote) = tokio::io::duplex(64);
+
+        let res = copy_bidirectional(&mut client, &mut Broken, DEFAULT_BUFFER_SIZE).await;
+
+        assert!(matches!(res, Err(Errors::PodConnection(_))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn attributes_client_errors() {
+        let (mut pod, _pod_remote) = tokio::io::duplex(64);
+
+        let res = copy_bidirectional(&mut Broken, &mut pod, DEFAULT_BUFFER_SIZE).await;
+
+        assert!(matches!(res, Err(Errors::ClientConnection(_))), "{res:?}");
+    }
+}
+
+mod success_reply {
+    use std::net::IpAddr;
+
+    use super::super::*;
+
+    fn target() -> Target {
+        Target {
+            namespace: "apps".into(),
+            pod: "web-0".into(),
+            port: 8080,
+            pod_ip: Some(IpAddr::from([10, 0, 0, 7])),
+            app_protocol: None,
+            cluster: None,
+            connect_timeout: None,
+            named_target_port: None,
+        }
+    }
+
+    fn bytes(res: v5::ConnectResponse) -> Vec<u8> {
+        res.into()
+    }
+
+    #[test]
+    fn pod_ip_by_default() {
+        let res = success_reply(
+            v5::Address::Dns("web.apps".into()),
+            80,
+            &target(),
+            ReplyAddress::PodIp,
+        );
+
+        assert_eq!(bytes(res), [5, 0, 0, 1, 10, 0, 0, 7, 0x1f, 0x90]);
+    }
+
+    #[test]
+    fn echoes_requested_name() {
+        let res = success_reply(
+            v5::Address::Dns("web".into()),
+            80,
+            &target(),
+            ReplyAddress::Requested,
+        );
+
+        assert_eq!(bytes(res), [5, 0, 0, 3, 3, b'w', b'e', b'b', 0, 80]);
+    }
+
+    #[test]
+    fn ip_requests_always_get_pod_ip() {
+        let res = success_reply(
+            v5::Address::IpAddr(IpAddr::from([10, 0, 0, 9])),
+            80,
+            &target(),
+            ReplyAddress::Requested,
+        );
+
+        assert_eq!(bytes(res), [5, 0, 0, 1, 10, 0, 0, 7, 0x1f, 0x90]);
+    }
+}
+
+mod correlation_id {
+    use super::super::*;
+
+    #[test]
+    fn passes_printable_ids_through() {
+        assert_eq!(correlation_id("req-42").as_deref(), Some("req-42"));
+    }
+
+    #[test]
+    fn empty_is_none() {
+        assert_eq!(correlation_id(""), None);
+        assert_eq!(correlation_id("\n\r"), None);
+    }
+
+    #[test]
+    fn strips_control_characters() {
+        assert_eq!(
+            correlation_id("req\n42\u{1b}[0m").as_deref(),
+            Some("req42[0m")
+        );
+    }
+
+    #[test]
+    fn truncates() {
+        let id = correlation_id(&"a".repeat(1000)).unwrap();
+
+        assert_eq!(id.len(), MAX_CORRELATION_ID_LEN);
+    }
+}
+
+mod authenticate_v5 {
+    use std::collections::BTreeMap;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::super::*;
+
+    fn config() -> Config {
+        Config {
+            auth_methods: vec![AuthMethod::UserPass],
+            users: BTreeMap::from([("alice".into(), "hunter2".into())]),
+            ..Config::default()
+        }
+    }
+
+    /// Offers only "no authentication required", returning whether the client was accepted and
+    /// the method the server selected.
+    async fn offer_no_auth(identity: Option<&str>) -> (bool, [u8; 2]) {
+        let config = config();
+        let credentials = Credentials::new(&config.users).unwrap();
+        let mut attempt = Attempt::new(None, 0, PeerAddr::Unix(None));
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let accepted = authenticate_v5(&mut server, &config, &credentials, &mut attempt, identity)
+            .await
+            .unwrap();
+
+        let mut selected = [0; 2];
+        client.read_exact(&mut selected).await.unwrap();
+        (accepted, selected)
+    }
+
+    #[tokio::test]
+    async fn requires_configured_method() {
+        assert_eq!(offer_no_auth(None).await, (false, [5, 0xff]));
+    }
+
+    #[tokio::test]
+    async fn identified_clients_needn_t_authenticate_again() {
+        assert_eq!(offer_no_auth(Some("alice")).await, (true, [5, 0]));
+    }
+
+    /// Sends a greeting listing `methods`, returning the method the server selected and why the
+    /// attempt was rejected, if it was.
+    async fn offer(methods: &[u8]) -> ([u8; 2], Option<String>) {
+        let config = config();
+        let credentials = Credentials::new(&config.users).unwrap();
+        let mut attempt = Attempt::new(None, 0, PeerAddr::Unix(None));
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        client.write_all(&[5, methods.len() as u8]).await.unwrap();
+        client.write_all(methods).await.unwrap();
+        let accepted = authenticate_v5(&mut server, &config, &credentials, &mut attempt, None)
+            .await
+            .unwrap();
+        assert!(!accepted);
+
+        let mut selected = [0; 2];
+        client.read_exact(&mut selected).await.unwrap();
+        (selected, attempt.reason)
+    }
+
+    #[tokio::test]
+    async fn no_methods_offered() {
+        let (selected, reason) = offer(&[]).await;
+
+        assert_eq!(selected, [5, 0xff]);
+        assert_eq!(reason.as_deref(), Some(NO_AUTH_METHODS_OFFERED));
+    }
+
+    #[tokio::test]
+    async fn no_acceptable_methods_offered() {
+        let (selected, reason) = offer(&[0, 0x80]).await;
+
+        assert_eq!(selected, [5, 0xff]);
+        assert_eq!(reason.as_deref(), Some("no acceptable auth methods"));
+    }
+}
+
+mod error_response {
+    use super::super::*;
+
+    fn bytes(reply: ErrorReply) -> Vec<u8> {
+        error_response(reply, v5::Address::Dns("web".into()), 80).into()
+    }
+
+    #[test]
+    fn reports_the_configured_reply() {
+        assert_eq!(bytes(ErrorReply::HostUnreachable)[1], 4);
+        assert_eq!(bytes(ErrorReply::ConnectionRefused)[1], 5);
+        assert_eq!(bytes(ErrorReply::TtlExpired)[1], 6);
+    }
+
+    #[test]
+    fn override_replaces_default() {
+        let config = Config {
+            error_replies: [(
+                crate::config::ErrorKind::PodNotFound,
+                ErrorReply::ConnectionRefused,
+            )]
+            .into(),
+            ..Config::default()
+        };
+        let e = resolver::Errors::PodNotFound {
+            namespace: "apps".into(),
+            pod: "web-0".into(),
+        };
+
+        assert_eq!(
+            Config::default().error_reply(e.kind()),
+            ErrorReply::HostUnreachable
+        );
+        assert_eq!(config.error_reply(e.kind()), ErrorReply::ConnectionRefused);
+    }
+}
+
+mod handle_v5 {
+    use std::net::IpAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::super::*;
+    use crate::socks::echo::{connect_request, Unresolved};
+    use crate::socks::resolver::PodStream;
+
+    /// Hands out one in-memory pipe as the pod stream, recording what was asked for.
+    struct FakeResolver {
+        pod: Option<DuplexStream>,
+        requested: Option<(String, u16)>,
+        /// The forward closing as soon as it's opened, with this reason
+        closed: Option<String>,
+        /// Who the client authenticated as
+        username: Option<String>,
+    }
+
+    impl Resolver for FakeResolver {
+        async fn forwarder(
+            &mut self,
+            destination: Destination<'_>,
+            port: u16,
+        ) -> Result<(Target, Box<dyn PodStream>), resolver::Errors> {
+            let Destination::Dns(address) = destination else {
+                panic!("expected a DNS address");
+            };
+            self.requested = Some((address.to_string(), port));
+
+            let target = Target {
+                namespace: "apps".into(),
+                pod: "web-0".into(),
+                port: 8080,
+                pod_ip: Some(IpAddr::from([10, 0, 0, 7])),
+                app_protocol: None,
+                cluster: None,
+                connect_timeout: None,
+                named_target_port: None,
+            };
+            Ok((target, Box::new(self.pod.take().unwrap())))
+        }
+
+        async fn forward_closed(&mut self) -> Option<String> {
+            match self.closed.take() {
+                Some(reason) => Some(reason),
+                None => futures::future::pending().await,
+            }
+        }
+
+        async fn join(self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn authenticated(&mut self, username: Option<&str>) {
+            self.username = username.map(Into::into);
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_and_copies_both_ways() {
+        let client = tokio_test::io::Builder::new()
+            .read(&[5, 1, 0])
+            .write(&[5, 0])
+            .read(&connect_request("web.apps.svc.cluster.local", 80))
+            .write(&[5, 0, 0, 1, 10, 0, 0, 7, 0x1f, 0x90])
+            .read(b"ping")
+            .write(b"pong")
+            .build();
+
+        let (pod, mut pod_remote) = tokio::io::duplex(64);
+        let pod_side = tokio::spawn(async move {
+            let mut ping = [0; 4];
+            pod_remote.read_exact(&mut ping).await.unwrap();
+            pod_remote.write_all(b"pong").await.unwrap();
+
+            // Then the client finishing closes the pod's side too
+            let mut rest = Vec::new();
+            pod_remote.read_to_end(&mut rest).await.unwrap();
+            (ping, rest)
+        });
+
+        let ctx = Context::new(None, Arc::new(Config::default())).unwrap();
+        let conn = ctx.registry.register(PeerAddr::Unix(None));
+        let mut attempt = Attempt::new(None, conn.id(), PeerAddr::Unix(None));
+        let mut resolver = FakeResolver {
+            pod: Some(pod),
+            requested: None,
+            closed: None,
+            username: None,
+        };
+
+        handle_v5(client, &ctx, &conn, &mut attempt, None, &mut resolver)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            resolver.requested,
+            Some(("web.apps.svc.cluster.local".into(), 80))
+        );
+        let (ping, rest) = pod_side.await.unwrap();
+        assert_eq!(&ping, b"ping");
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolver_is_told_who_authenticated() {
+        let client = tokio_test::io::Builder::new()
+            .read(&[5, 1, 2])
+            .write(&[5, 2])
+            .read(b"\x01\x05alice\x07hunter2")
+            .write(&[1, 0])
+            .read(&connect_request("web.apps.svc.cluster.local", 80))
+            .write(&[5, 0, 0, 1, 10, 0, 0, 7, 0x1f, 0x90])
+            .build();
+
+        let config = Config {
+            auth_methods: vec![AuthMethod::UserPass],
+            users: [("alice".into(), "hunter2".into())].into(),
+            ..Config::default()
+        };
+        let ctx = Context::new(None, Arc::new(config)).unwrap();
+        let conn = ctx.registry.register(PeerAddr::Unix(None));
+        let mut attempt = Attempt::new(None, conn.id(), PeerAddr::Unix(None));
+        // Nothing is relayed, the pod hangs up straight away
+        let (pod, _) = tokio::io::duplex(64);
+        let mut resolver = FakeResolver {
+            pod: Some(pod),
+            requested: None,
+            closed: None,
+            username: None,
+        };
+
+        handle_v5(client, &ctx, &conn, &mut attempt, None, &mut resolver)
+            .await
+            .unwrap();
+
+        assert_eq!(resolver.username.as_deref(), Some("alice"));
+    }
+
+    /// Connects through a forward that closes with `closed` before the reply, returning the
+    /// reply's status byte.
+    async fn connect_through_closed(closed: Option<&str>, pod: DuplexStream) -> u8 {
+        let (mut client, client_conn) = tokio::io::duplex(64);
+        let ctx = Context::new(None, Arc::new(Config::default())).unwrap();
+        let conn = ctx.registry.register(PeerAddr::Unix(None));
+        let mut attempt = Attempt::new(None, conn.id(), PeerAddr::Unix(None));
+        let mut resolver = FakeResolver {
+            pod: Some(pod),
+            requested: None,
+            closed: closed.map(String::from),
+            username: None,
+        };
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client
+            .write_all(&connect_request("web.apps.svc.cluster.local", 80))
+            .await
+            .unwrap();
+        let handled = tokio::spawn(async move {
+            handle_v5(client_conn, &ctx, &conn, &mut attempt, None, &mut resolver).await
+        });
+
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0], "auth reply");
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[0], 5);
+        drop(client);
+
+        handled.await.unwrap().unwrap();
+        reply[1]
+    }
+
+    #[tokio::test]
+    async fn forward_closed_before_the_reply_fails() {
+        let (pod, _pod_remote) = tokio::io::duplex(64);
+
+        let status = connect_through_closed(Some("pod deleted"), pod).await;
+
+        assert_eq!(status, 1, "general failure");
+    }
+
+    #[tokio::test]
+    async fn open_forward_succeeds() {
+        let (pod, pod_remote) = tokio::io::duplex(64);
+        // Lets the copy finish once the client hangs up
+        drop(pod_remote);
+
+        assert_eq!(connect_through_closed(None, pod).await, 0);
+    }
+
+    #[tokio::test]
+    async fn client_disconnecting_abandons_resolution() {
+        let (mut client, client_conn) = tokio::io::duplex(64);
+        let ctx = Context::new(None, Arc::new(Config::default())).unwrap();
+        let conn = ctx.registry.register(PeerAddr::Unix(None));
+        let mut attempt = Attempt::new(None, conn.id(), PeerAddr::Unix(None));
+        let (resolving, abandoned) = tokio::sync::oneshot::channel();
+        let mut resolver = Unresolved {
+            resolving: Some(resolving),
+        };
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client
+            .write_all(&connect_request("web.apps.svc.cluster.local", 80))
+            .await
+            .unwrap();
+        let hang_up = async move {
+            let mut reply = [0; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            drop(client);
+        };
+
+        let (handled, ()) = tokio::join!(
+            handle_v5(client_conn, &ctx, &conn, &mut attempt, None, &mut resolver),
+            hang_up
+        );
+        handled.unwrap();
+
+        assert!(abandoned.await.is_err(), "resolution still running");
+        assert!(resolver.resolving.is_none(), "resolution never started");
+    }
+
+    /// Handles a client that offers no authentication then fails to read the reply with `kind`.
+    async fn hang_up(kind: std::io::ErrorKind) -> anyhow::Result<()> {
+        let client = tokio_test::io::Builder::new()
+            .read(&[5, 1, 0])
+            .write_error(std::io::Error::from(kind))
+            .build();
+        let ctx = Context::new(None, Arc::new(Config::default())).unwrap();
+        let resolver = FakeResolver {
+            pod: None,
+            requested: None,
+            closed: None,
+            username: None,
+        };
+
+        handle_with(client, PeerAddr::Unix(None), None, ctx, resolver).await
+    }
+
+    #[tokio::test]
+    async fn client_hanging_up_is_not_an_error() {
+        hang_up(std::io::ErrorKind::BrokenPipe).await.unwrap();
+        hang_up(std::io::ErrorKind::ConnectionReset).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_write_errors_are() {
+        let e = hang_up(std::io::ErrorKind::PermissionDenied)
+            .await
+            .unwrap_err();
+
+        assert_eq!(error_code(&e), Some("client_connection"));
+    }
+}
+
+mod is_tls_record {
+    use super::super::*;
+
+    #[test]
+    fn client_hello() {
+        assert!(is_tls_record(&[0x16, 0x03, 0x01, 0x02, 0x00]));
+        // Only the first byte has arrived, which no SOCKS request starts with
+        assert!(is_tls_record(&[0x16]));
+    }
+
+    #[test]
+    fn socks_and_others() {
+        assert!(!is_tls_record(&[SOCKS4_VERSION, 1]));
+        assert!(!is_tls_record(&[v5::VERSION, 1, 0]));
+        assert!(!is_tls_record(&[0x16, 0x01]));
+        assert!(!is_tls_record(b"GET / HTTP/1.1"));
+        assert!(!is_tls_record(&[]));
+    }
+}
+
+mod accept {
+    use std::path::PathBuf;
+
+    use rcgen::{CertificateParams, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_rustls::rustls::{self, pki_types::ServerName, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    use super::super::*;
+
+    /// A context with TLS set up from a fresh self-signed certificate for `localhost`, and that
+    /// certificate for clients to trust.
+    fn context(name: &str, auto_detect: bool) -> (Context, RootCertStore) {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+
+        let dir: PathBuf = std::env::temp_dir().join(format!(
+            "kube-fwd-socks-accept-{name}-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("tls.crt"), cert.pem()).unwrap();
+        std::fs::write(dir.join("tls.key"), key.serialize_pem()).unwrap();
+
+        let config = Config {
+            tls_cert: Some(dir.join("tls.crt")),
+            tls_key: Some(dir.join("tls.key")),
+            tls_auto_detect: auto_detect,
+            ..Config::default()
+        };
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+
+        (Context::new(None, Arc::new(config)).unwrap(), roots)
+    }
+
+    fn peer() -> PeerAddr {
+        std::net::SocketAddr::from((Ipv4Addr::LOCALHOST, 50000)).into()
+    }
+
+    /// Sends a SOCKS5 greeting offering no authentication, returning the method selection.
+    async fn greet(conn: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> [u8; 2] {
+        conn.write_all(&[v5::VERSION, 1, 0]).await.unwrap();
+        let mut selection = [0; 2];
+        conn.read_exact(&mut selection).await.unwrap();
+        selection
+    }
+
+    async fn connect_tls(roots: RootCertStore, conn: DuplexStream) -> impl AsyncRead + AsyncWrite {
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), conn)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn detects_tls() {
+        let (ctx, roots) = context("detects-tls", true);
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(accept(server, peer(), ctx));
+
+        let mut client = connect_tls(roots, client).await;
+        assert_eq!(greet(&mut client).await, [v5::VERSION, 0]);
+
+        drop(client);
+        let _ = server.await;
+    }
+
+    #[tokio::test]
+    async fn detects_plaintext() {
+        let (ctx, _) = context("detects-plaintext", true);
+        let (mut client, server) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(accept(server, peer(), ctx));
+
+        assert_eq!(greet(&mut client).await, [v5::VERSION, 0]);
+
+        drop(client);
+        let _ = server.await;
+    }
+
+    #[tokio::test]
+    async fn requires_tls_without_auto_detect() {
+        let (ctx, _) = context("requires-tls", false);
+        let (mut client, server) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(accept(server, peer(), ctx));
+
+        client.write_all(&[v5::VERSION, 1, 0]).await.unwrap();
+        let res = server.await.unwrap();
+
+        assert!(res.is_err());
+    }
+}
+
+mod pipe {
+    use futures::future::pending;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::super::*;
+    use crate::socks::echo::Unresolved;
+
+    fn config(max_connection_lifetime: u64) -> Config {
+        Config {
+            max_connection_lifetime,
+            ..Config::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_both_sides_at_max_lifetime() {
+        let (mut client, mut client_remote) = tokio::io::duplex(64);
+        let (mut pod, mut pod_remote) = tokio::io::duplex(64);
+
+        let started = tokio::time::Instant::now();
+        let piped = tokio::spawn(async move {
+            pipe(
+                &mut client,
+                &mut pod,
+                &mut Unresolved::default(),
+                &config(60),
+                pending(),
+            )
+            .await
+        });
+
+        // Still busy, which doesn't keep it open past its lifetime
+        client_remote.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        pod_remote.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+
+        piped.await.unwrap().unwrap();
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+
+        let mut rest = Vec::new();
+        assert_eq!(client_remote.read_to_end(&mut rest).await.unwrap(), 0);
+        assert_eq!(pod_remote.read_to_end(&mut rest).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_both_sides_when_drained() {
+        let (mut client, mut client_remote) = tokio::io::duplex(64);
+        let (mut pod, mut pod_remote) = tokio::io::duplex(64);
+
+        let started = tokio::time::Instant::now();
+        let drained = tokio::time::sleep(Duration::from_secs(5));
+        pipe(
+            &mut client,
+            &mut pod,
+            &mut Unresolved::default(),
+            &config(0),
+            drained,
+        )
+        .await
+        .unwrap();
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+
+        let mut rest = Vec::new();
+        assert_eq!(client_remote.read_to_end(&mut rest).await.unwrap(), 0);
+        assert_eq!(pod_remote.read_to_end(&mut rest).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_limit_by_default() {
+        let (mut client, _client_remote) = tokio::io::duplex(64);
+        let (mut pod, _pod_remote) = tokio::io::duplex(64);
+
+        let res = tokio::time::timeout(
+            Duration::from_secs(24 * 60 * 60),
+            pipe(
+                &mut client,
+                &mut pod,
+                &mut Unresolved::default(),
+                &config(0),
+                pending(),
+            ),
+        )
+        .await;
+
+        assert!(res.is_err(), "pipe ended by itself");
+    }
+}
+
+mod error_code {
+    use super::super::*;
+
+    #[test]
+    fn found_through_context() {
+        let e = anyhow::Error::new(Errors::UnsupportedVersion(9)).context("handling connection");
+
+        assert_eq!(error_code(&e), Some("unsupported_version"));
+    }
+
+    #[test]
+    fn resolver_errors() {
+        let e = anyhow::Error::new(resolver::Errors::UnsupportedAddress("web".into()));
+
+        assert_eq!(error_code(&e), Some("unsupported_address"));
+    }
+
+    #[test]
+    fn other_errors_have_none() {
+        let e = anyhow::anyhow!("something else");
+
+        assert_eq!(error_code(&e), None);
+    }
+}
+
+mod disable_socks4 {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::super::*;
+
+    /// Sends a SOCKS4a request for `web:80`, returning everything the proxy replies with.
+    async fn reply(disable_socks4: bool) -> Vec<u8> {
+        let config = Arc::new(Config {
+            disable_socks4,
+            ..Config::default()
+        });
+        let ctx = Context::new(None, config.clone()).unwrap();
+        let (mut client, client_conn) = tokio::io::duplex(64);
+
+        client
+            .write_all(&[4, 1, 0, 80, 0, 0, 0, 1, 0, b'w', b'e', b'b', 0])
+            .await
+            .unwrap();
+        handle_with(
+            client_conn,
+            PeerAddr::Unix(None),
+            None,
+            ctx,
+            StaticResolver::new(config),
+        )
+        .await
+        .unwrap();
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[cfg(feature = "socks4")]
+    #[tokio::test]
+    async fn enabled_by_default() {
+        // Rejected, as `web` isn't a static host
+        assert_eq!(
+            reply(false).await,
+            v4::Response::rejected_or_failed(80, v4::SOCKS4A_ADDRESS).to_buf()
+        );
+    }
+
+    #[tokio::test]
+    async fn closes_without_a_reply() {
+        assert!(reply(true).await.is_empty());
+    }
+
+    #[cfg(not(feature = "socks4"))]
+    #[tokio::test]
+    async fn closes_when_not_built_in() {
+        assert!(reply(false).await.is_empty());
+    }
+}
+
+mod connection_slot {
+    #[cfg(feature = "socks4")]
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::super::*;
+
+    fn context(reject_when_full: bool) -> Context {
+        let config = Config {
+            max_connections: 1,
+            reject_when_full,
+            ..Config::default()
+        };
+        Context::new(None, Arc::new(config)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unlimited_by_default() {
+        let ctx = Context::new(None, Arc::new(Config::default())).unwrap();
+
+        assert!(matches!(ctx.connection_slot().await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_free_slot() {
+        let ctx = context(false);
+
+        let first = ctx.connection_slot().await.unwrap();
+        assert!(first.is_some());
+        let second = ctx.connection_slot();
+        tokio::pin!(second);
+        assert!((&mut second).now_or_never().is_none());
+
+        drop(first);
+        assert!(matches!(second.await, Ok(Some(_))));
+    }
+
+    #[tokio::test]
+    async fn rejects_when_full() {
+        let ctx = context(true);
+
+        let _first = ctx.connection_slot().await.unwrap();
+
+        assert!(matches!(
+            ctx.connection_slot().await,
+            Err(resolver::Errors::TooManyConnections(1))
+        ));
+    }
+
+    #[cfg(feature = "socks4")]
+    #[tokio::test]
+    async fn full_connections_get_a_failure_reply() {
+        let ctx = context(true);
+        let _first = ctx.connection_slot().await.unwrap();
+        let (mut client, client_conn) = tokio::io::duplex(64);
+
+        client
+            .write_all(&[4, 1, 0, 80, 0, 0, 0, 1, 0, b'w', b'e', b'b', 0])
+            .await
+            .unwrap();
+        handle(client_conn, PeerAddr::Unix(None), None, ctx.clone())
+            .await
+            .unwrap();
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(
+            reply,
+            v4::Response::rejected_or_failed(80, v4::SOCKS4A_ADDRESS).to_buf()
+        );
+        assert!(ctx
+            .metrics
+            .render()
+            .contains("socks_connection_errors_total{code=\"too_many_connections\"} 1"));
+    }
+}
+
+mod drain {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::super::*;
+    use crate::socks::echo::{connect_request, Unresolved};
+
+    fn context(shutdown_grace: u64) -> Context {
+        let config = Config {
+            shutdown_grace,
+            ..Config::default()
+        };
+        Context::new(None, Arc::new(config)).unwrap()
+    }
+
+    /// Waits for `count` connections to be being served.
+    async fn served(ctx: &Context, count: usize) {
+        while ctx.draining.receiver_count() < count {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn nothing_to_wait_for() {
+        let ctx = context(30);
+
+        tokio::time::timeout(Duration::from_millis(100), ctx.drain())
+            .await
+            .unwrap();
+        assert!(!*ctx.draining.borrow());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_connections_to_finish() {
+        let ctx = context(30);
+        let held = ctx.draining.subscribe();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            drop(held);
+        });
+
+        let started = tokio::time::Instant::now();
+        ctx.drain().await;
+
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+        assert!(!*ctx.draining.borrow());
+    }
+
+    #[tokio::test]
+    async fn waiting_clients_get_a_failure_reply() {
+        let ctx = context(0);
+        let (mut client, client_conn) = tokio::io::duplex(64);
+        let handled = tokio::spawn(handle_with(
+            client_conn,
+            PeerAddr::Unix(None),
+            None,
+            ctx.clone(),
+            Unresolved::default(),
+        ));
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut auth = [0; 2];
+        client.read_exact(&mut auth).await.unwrap();
+        client.write_all(&connect_request("web", 80)).await.unwrap();
+
+        ctx.draining.send_replace(true);
+        handled.await.unwrap().unwrap();
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, v5::RESP_GENERAL_FAILURE, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(ctx
+            .metrics
+            .render()
+            .contains("socks_connection_errors_total{code=\"shutting_down\"} 1"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_connections_left_after_the_grace_period() {
+        let ctx = context(10);
+        // Never sends anything, so it's not waiting for a reply nor forwarding
+        let (mut client, client_conn) = tokio::io::duplex(64);
+        let served_conn = tokio::spawn(serve(
+            Frontend::Socks,
+            client_conn,
+            PeerAddr::Unix(None),
+            ctx.clone(),
+        ));
+        served(&ctx, 1).await;
+
+        let started = tokio::time::Instant::now();
+        ctx.drain().await;
+
+        assert_eq!(started.elapsed(), Duration::from_secs(10) + DRAIN_TIMEOUT);
+        assert!(*ctx.draining.borrow());
+        served_conn.await.unwrap().unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(client.read_to_end(&mut rest).await.unwrap(), 0);
+    }
+}
+
+mod end_to_end {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::super::*;
+    use crate::config::ProxyProtocol;
+    use crate::socks::echo::{connect_request, EchoServer};
+
+    /// Connects through a SOCKS5 handshake on `client`, returning the reply's status byte.
+    async fn socks5_connect(client: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> u8 {
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut auth = [0; 2];
+        client.read_exact(&mut auth).await.unwrap();
+        assert_eq!(auth, [5, 0]);
+
+        client
+            .write_all(&connect_request("echo.apps.svc.cluster.local", 7))
+            .await
+            .unwrap();
+        // An IPv4 bound address, as the echo server listens on localhost
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!((reply[0], reply[3]), (5, 1));
+        reply[1]
+    }
+
+    async fn echoed(client: &mut (impl AsyncRead + AsyncWrite + Unpin), data: &[u8]) -> Vec<u8> {
+        client.write_all(data).await.unwrap();
+        let mut echoed = vec![0; data.len()];
+        client.read_exact(&mut echoed).await.unwrap();
+        echoed
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_relays_to_the_backend() {
+        let echo = EchoServer::start().await;
+        let ctx = Context::new(None, Arc::new(Config::default())).unwrap();
+        let conn = ctx.registry.register(PeerAddr::Unix(None));
+        let mut attempt = Attempt::new(None, conn.id(), PeerAddr::Unix(None));
+        let mut resolver = echo.resolver();
+        let (mut client, client_conn) = tokio::io::duplex(1024);
+
+        let proxy = handle_v5(client_conn, &ctx, &conn, &mut attempt, None, &mut resolver);
+        let client_side = async {
+            assert_eq!(socks5_connect(&mut client).await, 0);
+            assert_eq!(echoed(&mut client, b"ping").await, b"ping");
+            assert_eq!(echoed(&mut client, b"pong").await, b"pong");
+            drop(client);
+        };
+        let (res, ()) = tokio::join!(proxy, client_side);
+
+        res.unwrap();
+        assert_eq!(
+            resolver.requested,
+            Some(("echo.apps.svc.cluster.local".into(), 7))
+        );
+    }
+
+    #[tokio::test]
+    async fn udp_associate_is_refused_without_a_relay_address() {
+        let echo = EchoServer::start().await;
+        let ctx = Context::new(None, Arc::new(Config::default())).unwrap();
+        let conn = ctx.registry.register(PeerAddr::Unix(None));
+        let mut attempt = Attempt::new(None, conn.id(), PeerAddr::Unix(None));
+        let mut resolver = echo.resolver();
+        let (mut client, client_conn) = tokio::io::duplex(1024);
+
+        let proxy = handle_v5(client_conn, &ctx, &conn, &mut attempt, None, &mut resolver);
+        let client_side = async {
+            client.write_all(&[5, 1, 0]).await.unwrap();
+            // Where the client would send its datagrams from
+            client
+                .write_all(&[5, v5::CMD_UDP_ASSOCIATE, 0, 1, 192, 0, 2, 20, 0x13, 0x88])
+                .await
+                .unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            reply
+        };
+        let (res, reply) = tokio::join!(proxy, client_side);
+
+        res.unwrap();
+        assert_eq!(reply, [5, 0, 5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(resolver.requested, None);
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_header_comes_first() {
+        let echo = EchoServer::start().await;
+        let config = Config {
+            send_proxy_protocol: Some(ProxyProtocol::V1),
+            ..Config::default()
+        };
+        let ctx = Context::new(None, Arc::new(config)).unwrap();
+        let (mut client, client_conn) = tokio::io::duplex(1024);
+        let peer_addr = PeerAddr::Tcp("192.0.2.20:51234".parse().unwrap());
+        let resolver = echo.resolver();
+        let pod_port = resolver.addr.port();
+        let proxy = tokio::spawn(handle_with(client_conn, peer_addr, None, ctx, resolver));
+
+        assert_eq!(socks5_connect(&mut client).await, 0);
+        let header = format!("PROXY TCP4 192.0.2.20 127.0.0.1 51234 {pod_port}\r\n");
+        let mut echoed_header = vec![0; header.len()];
+        client.read_exact(&mut echoed_header).await.unwrap();
+        assert_eq!(String::from_utf8(echoed_header).unwrap(), header);
+        assert_eq!(echoed(&mut client, b"ping").await, b"ping");
+
+        drop(client);
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn large_transfers_arrive_intact() {
+        let echo = EchoServer::start().await;
+        let ctx = Context::new(None, Arc::new(Config::default())).unwrap();
+        let (mut client, client_conn) = tokio::io::duplex(16 * 1024);
+        let proxy = tokio::spawn(handle_with(
+            client_conn,
+            PeerAddr::Unix(None),
+            None,
+            ctx,
+            echo.resolver(),
+        ));
+
+        assert_eq!(socks5_connect(&mut client).await, 0);
+        let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        let (mut read, mut write) = tokio::io::split(client);
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            write.write_all(&sent).await.unwrap();
+            write.shutdown().await.unwrap();
+        });
+        let mut received = Vec::new();
+        read.read_to_end(&mut received).await.unwrap();
+        writer.await.unwrap();
+
+        assert!(
+            received == data,
+            "{} of {} bytes",
+            received.len(),
+            data.len()
+        );
+        proxy.await.unwrap().unwrap();
+    }
+}
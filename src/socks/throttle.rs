@@ -0,0 +1,134 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Caps the bytes per second read from and written to `S`, each direction with its own bucket.
+pub struct Throttled<S> {
+    inner: S,
+    read: Option<Bucket>,
+    write: Option<Bucket>,
+}
+
+impl<S> Throttled<S> {
+    /// A `bytes_per_sec` of 0 leaves the stream unthrottled.
+    pub fn new(inner: S, bytes_per_sec: u64) -> Self {
+        let bucket = || (bytes_per_sec > 0).then(|| Bucket::new(bytes_per_sec));
+
+        Throttled {
+            inner,
+            read: bucket(),
+            write: bucket(),
+        }
+    }
+}
+
+/// Token bucket holding up to a second's worth of bytes.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Bucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        Bucket {
+            rate: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            last: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    /// How many of `want` bytes may be transferred now, at least one, waiting for a refill if the
+    /// bucket is empty.
+    fn poll_allowed(&mut self, cx: &mut std::task::Context<'_>, want: usize) -> Poll<usize> {
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+            self.last = now;
+
+            if self.tokens >= 1.0 {
+                return Poll::Ready(want.min(self.tokens as usize).max(1));
+            }
+
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let Some(bucket) = this.read.as_mut() else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        let allowed = ready!(bucket.poll_allowed(cx, buf.remaining()));
+        let mut limited = buf.take(allowed);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+
+        // SAFETY: the inner reader initialised and filled `read` bytes of `buf`'s unfilled part
+        unsafe { buf.assume_init(read) };
+        buf.advance(read);
+        bucket.consume(read);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let Some(bucket) = this.write.as_mut() else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+
+        let allowed = ready!(bucket.poll_allowed(cx, buf.len()));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+        bucket.consume(written);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,57 @@
+mod throttled {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+
+    use super::super::*;
+
+    async fn transfer_time(bytes_per_sec: u64, len: usize, write: bool) -> Duration {
+        let (local, mut remote) = tokio::io::duplex(64 * 1024);
+        let mut throttled = Throttled::new(local, bytes_per_sec);
+        let data = vec![7_u8; len];
+
+        let start = Instant::now();
+        let mut buf = vec![0_u8; len];
+        if write {
+            let reader = tokio::spawn(async move {
+                remote.read_exact(&mut buf).await.unwrap();
+            });
+            throttled.write_all(&data).await.unwrap();
+            reader.await.unwrap();
+        } else {
+            remote.write_all(&data).await.unwrap();
+            throttled.read_exact(&mut buf).await.unwrap();
+        }
+
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_writes() {
+        // The first second's worth goes out immediately, the remaining 2000 bytes take ~2s
+        let elapsed = transfer_time(1000, 3000, true).await;
+
+        assert!(
+            elapsed >= Duration::from_millis(1900) && elapsed <= Duration::from_millis(2100),
+            "{elapsed:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_reads() {
+        let elapsed = transfer_time(1000, 3000, false).await;
+
+        assert!(
+            elapsed >= Duration::from_millis(1900) && elapsed <= Duration::from_millis(2100),
+            "{elapsed:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_is_unlimited() {
+        let elapsed = transfer_time(0, 32 * 1024, true).await;
+
+        assert_eq!(elapsed, Duration::ZERO);
+    }
+}
@@ -1,7 +1,9 @@
 // https://www.openssh.com/txt/socks4.protocol
 // https://www.openssh.com/txt/socks4a.protocol
 
-pub const VERSION: u8 = 4;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::socks::{Request as RequestTrait, Response as ResponseTrait};
 
 pub const METHOD_CONNECT: u8 = 1;
 pub const METHOD_BIND: u8 = 2;
@@ -12,6 +14,72 @@ const RESP_VERSION: u8 = 0;
 const RESP_CODE_GRANTED: u8 = 90;
 const RESP_CODE_REJECT_OR_FAILED: u8 = 91;
 
+/// Longest user ID or SOCKS4a hostname read before giving up on the request.
+const MAX_FIELD_LEN: usize = 255;
+
+/// A CONNECT or BIND request, with the SOCKS4a hostname when the address is `0.0.0.x`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub version: u8,
+    pub command: u8,
+    pub dest_port: u16,
+    pub dest_ip: [u8; 4],
+    pub user_id: String,
+    pub hostname: Option<String>,
+}
+
+impl Request {
+    pub fn is_socks4a(dest_ip: [u8; 4]) -> bool {
+        dest_ip[..3] == SOCKS4A_ADDRESS[..3] && dest_ip[3] != 0
+    }
+}
+
+impl RequestTrait for Request {
+    type Error = std::io::Error;
+
+    async fn parse(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, Self::Error> {
+        let version = stream.read_u8().await?;
+        let command = stream.read_u8().await?;
+        let dest_port = stream.read_u16().await?;
+        let mut dest_ip = [0_u8; 4];
+        stream.read_exact(&mut dest_ip).await?;
+
+        let user_id = read_until_null(stream).await?;
+        let hostname = if Request::is_socks4a(dest_ip) {
+            Some(read_until_null(stream).await?)
+        } else {
+            None
+        };
+
+        Ok(Request {
+            version,
+            command,
+            dest_port,
+            dest_ip,
+            user_id,
+            hostname,
+        })
+    }
+}
+
+async fn read_until_null(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<String> {
+    let mut resp: String = String::new();
+
+    let mut next: u8 = stream.read_u8().await?;
+    while next != 0 {
+        if resp.len() >= MAX_FIELD_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("field longer than {MAX_FIELD_LEN} bytes"),
+            ));
+        }
+        resp.push(next.into());
+        next = stream.read_u8().await?;
+    }
+
+    Ok(resp)
+}
+
 pub struct Response {
     pub version: u8,
     pub result: u8,
@@ -51,3 +119,12 @@ impl Response {
         ]
     }
 }
+
+impl ResponseTrait for Response {
+    async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+        stream.write_all(&self.to_buf()).await
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,174 @@
+mod request_parse {
+    use tokio_test::io;
+
+    use super::super::*;
+    use crate::socks::SOCKS4_VERSION;
+
+    #[tokio::test]
+    async fn parse_connect() {
+        let mut stream = io::Builder::new()
+            .read(&[SOCKS4_VERSION, METHOD_CONNECT])
+            .read(&[0x1F, 0x90])
+            .read(&[10, 244, 1, 7])
+            .read(b"alice\0")
+            .build();
+
+        let req = Request::parse(&mut stream).await.unwrap();
+
+        assert_eq!(
+            req,
+            Request {
+                version: SOCKS4_VERSION,
+                command: METHOD_CONNECT,
+                dest_port: 8080,
+                dest_ip: [10, 244, 1, 7],
+                user_id: "alice".into(),
+                hostname: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_socks4a() {
+        let mut stream = io::Builder::new()
+            .read(&[SOCKS4_VERSION, METHOD_CONNECT, 0x00, 0x50])
+            .read(&SOCKS4A_ADDRESS)
+            .read(b"\0")
+            .read(b"web.default.svc\0")
+            .build();
+
+        let req = Request::parse(&mut stream).await.unwrap();
+
+        assert_eq!(req.dest_port, 80);
+        assert_eq!(req.user_id, "");
+        assert_eq!(req.hostname.as_deref(), Some("web.default.svc"));
+    }
+
+    #[tokio::test]
+    async fn parse_socks4a_any_last_octet() {
+        let mut stream = io::Builder::new()
+            .read(&[SOCKS4_VERSION, METHOD_CONNECT, 0x00, 0x50, 0, 0, 0, 255])
+            .read(b"\0web\0")
+            .build();
+
+        let req = Request::parse(&mut stream).await.unwrap();
+
+        assert_eq!(req.hostname.as_deref(), Some("web"));
+    }
+
+    #[tokio::test]
+    async fn error_if_truncated() {
+        let mut stream = io::Builder::new()
+            .read(&[SOCKS4_VERSION, METHOD_CONNECT, 0x00, 0x50, 10, 244])
+            .build();
+
+        let req_res = Request::parse(&mut stream).await;
+
+        assert_eq!(
+            req_res.unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[tokio::test]
+    async fn fields_are_bounded() {
+        let longest = "a".repeat(MAX_FIELD_LEN);
+        let mut stream = io::Builder::new()
+            .read(&[SOCKS4_VERSION, METHOD_CONNECT, 0x00, 0x50])
+            .read(&SOCKS4A_ADDRESS)
+            .read(longest.as_bytes())
+            .read(b"\0")
+            .read(longest.as_bytes())
+            .read(b"\0")
+            .build();
+
+        let req = Request::parse(&mut stream).await.unwrap();
+
+        assert_eq!(req.user_id, longest);
+        assert_eq!(req.hostname.as_deref(), Some(longest.as_str()));
+    }
+
+    #[tokio::test]
+    async fn error_if_user_id_too_long() {
+        let mut stream = io::Builder::new()
+            .read(&[SOCKS4_VERSION, METHOD_CONNECT, 0x00, 0x50, 10, 244, 1, 7])
+            .read(&[b'a'; MAX_FIELD_LEN + 1])
+            .build();
+
+        let req_res = Request::parse(&mut stream).await;
+
+        assert_eq!(req_res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn error_if_hostname_too_long() {
+        let mut stream = io::Builder::new()
+            .read(&[SOCKS4_VERSION, METHOD_CONNECT, 0x00, 0x50])
+            .read(&SOCKS4A_ADDRESS)
+            .read(b"\0")
+            .read(&[b'a'; MAX_FIELD_LEN + 1])
+            .build();
+
+        let req_res = Request::parse(&mut stream).await;
+
+        assert_eq!(req_res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn is_socks4a() {
+        assert!(Request::is_socks4a(SOCKS4A_ADDRESS));
+        assert!(!Request::is_socks4a([0, 0, 0, 0]));
+        assert!(!Request::is_socks4a([0, 0, 1, 1]));
+        assert!(!Request::is_socks4a([10, 0, 0, 1]));
+    }
+}
+
+mod response_to_buf {
+    use super::super::*;
+
+    #[test]
+    fn granted() {
+        let resp = Response::granted(8080, [10, 244, 1, 7]);
+
+        assert_eq!(
+            resp.to_buf(),
+            [RESP_VERSION, RESP_CODE_GRANTED, 0x1F, 0x90, 10, 244, 1, 7]
+        );
+    }
+
+    #[test]
+    fn rejected_or_failed() {
+        let resp = Response::rejected_or_failed(80, [0, 0, 0, 1]);
+
+        assert_eq!(
+            resp.to_buf(),
+            [
+                RESP_VERSION,
+                RESP_CODE_REJECT_OR_FAILED,
+                0x00,
+                0x50,
+                0,
+                0,
+                0,
+                1
+            ]
+        );
+    }
+}
+
+mod response_write {
+    use tokio_test::io;
+
+    use super::super::*;
+
+    #[tokio::test]
+    async fn writes_the_whole_reply() {
+        let mut stream = io::Builder::new()
+            .write(&[RESP_VERSION, RESP_CODE_GRANTED, 0x1F, 0x90, 10, 244, 1, 7])
+            .build();
+
+        ResponseTrait::write(&Response::granted(8080, [10, 244, 1, 7]), &mut stream)
+            .await
+            .unwrap();
+    }
+}
@@ -2,7 +2,9 @@
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use super::Request;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{Request, Response, BIND_UNSUPPORTED};
 
 pub const VERSION: u8 = 5;
 
@@ -11,6 +13,11 @@ pub const AUTH_GSSAPI: u8 = 0x01;
 pub const AUTH_USER_PASS: u8 = 0x02;
 pub const AUTH_NONE: u8 = 0xFF;
 
+// https://www.rfc-editor.org/rfc/rfc1929
+pub const USER_PASS_VERSION: u8 = 0x01;
+pub const USER_PASS_SUCCESS: u8 = 0x00;
+pub const USER_PASS_FAILURE: u8 = 0x01;
+
 pub const CMD_CONNECT: u8 = 0x01;
 pub const CMD_BIND: u8 = 0x02;
 pub const CMD_UDP_ASSOCIATE: u8 = 0x3;
@@ -21,12 +28,10 @@ pub const ATYPE_DNS: u8 = 0x03;
 
 pub const RESP_SUCCEEDED: u8 = 0x00;
 pub const RESP_GENERAL_FAILURE: u8 = 0x01;
-#[allow(dead_code)]
 pub const RESP_DENIED: u8 = 0x02;
 pub const RESP_NETWORK_UNREACHABLE: u8 = 0x03;
 pub const RESP_HOST_UNREACHABLE: u8 = 0x04;
 pub const RESP_CONNECTION_REFUSED: u8 = 0x05;
-#[allow(dead_code)]
 pub const RESP_TTL_EXPIRED: u8 = 0x06;
 pub const RESP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
 pub const RESP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
@@ -42,6 +47,18 @@ pub enum Errors {
     UnsupportedAddressType(u8) = RESP_ADDRESS_NOT_SUPPORTED,
 }
 
+impl Errors {
+    /// A short identifier for metric labels and the audit log, which stays the same however the
+    /// message is worded.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Errors::General(_) => "general_failure",
+            Errors::UnsupportedCommand(_) => "unsupported_command",
+            Errors::UnsupportedAddressType(_) => "unsupported_address_type",
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ParseError {
     #[error(transparent)]
@@ -52,6 +69,17 @@ pub enum ParseError {
     String(#[from] std::string::FromUtf8Error),
 }
 
+impl ParseError {
+    /// Like [`Errors::code`], with `io` for the connection failing mid request.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::ProtocolError(e) => e.code(),
+            ParseError::Io(_) => "io",
+            ParseError::String(_) => "invalid_utf8",
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq, int_enum::IntEnum)]
 pub enum AuthMethods {
@@ -65,23 +93,50 @@ pub enum AuthMethods {
 #[derive(Clone, Copy, Debug, Eq, PartialEq, int_enum::IntEnum)]
 pub enum Command {
     Connect = CMD_CONNECT,
+    /// Always rejected, see `BIND_UNSUPPORTED`
     Bind = CMD_BIND,
+    /// Always rejected, there's no UDP relay to report in the reply since port-forwards only
+    /// carry TCP
     UdpAssociate = CMD_UDP_ASSOCIATE,
 }
 
+impl Command {
+    /// Why the proxy can't carry out the command, `None` when it can.
+    pub fn unsupported_reason(self) -> Option<&'static str> {
+        match self {
+            Command::Connect => None,
+            Command::Bind => Some(BIND_UNSUPPORTED),
+            Command::UdpAssociate => Some("port-forwards only carry TCP"),
+        }
+    }
+}
+
 pub struct AuthRequest {
     requests: Vec<AuthMethods>,
+    /// How many methods the client listed, including any this proxy doesn't know
+    offered: u8,
 }
 
 impl AuthRequest {
+    /// Whether the client listed no methods at all, which no well-behaved client does since it
+    /// leaves nothing to negotiate.
+    pub fn is_empty(&self) -> bool {
+        self.offered == 0
+    }
+
     pub fn contains(&self, method: &AuthMethods) -> bool {
         self.requests.contains(method)
     }
+
+    /// The first of the server's `preferred` methods the client also offered.
+    pub fn select(&self, preferred: &[AuthMethods]) -> Option<AuthMethods> {
+        preferred.iter().copied().find(|m| self.contains(m))
+    }
 }
 
 impl Request for AuthRequest {
     type Error = anyhow::Error;
-    async fn parse(stream: &mut (impl tokio::io::AsyncReadExt + Unpin)) -> anyhow::Result<Self>
+    async fn parse(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Self>
     where
         Self: std::marker::Sized,
     {
@@ -92,7 +147,10 @@ impl Request for AuthRequest {
 
         let method_count = stream.read_u8().await?;
         if method_count == 0 {
-            return Ok(AuthRequest { requests: vec![] });
+            return Ok(AuthRequest {
+                requests: vec![],
+                offered: 0,
+            });
         }
 
         let mut buf: Vec<u8> = vec![0; method_count as usize];
@@ -103,7 +161,10 @@ impl Request for AuthRequest {
             .filter_map(|v| AuthMethods::try_from(v).ok())
             .collect();
 
-        Ok(AuthRequest { requests })
+        Ok(AuthRequest {
+            requests,
+            offered: method_count,
+        })
     }
 }
 
@@ -118,6 +179,10 @@ impl AuthResponse {
         }
     }
 
+    pub fn selected(method: AuthMethods) -> AuthResponse {
+        AuthResponse { method }
+    }
+
     pub fn none() -> AuthResponse {
         AuthResponse {
             method: AuthMethods::None,
@@ -125,12 +190,75 @@ impl AuthResponse {
     }
 }
 
-impl From<AuthResponse> for Vec<u8> {
-    fn from(value: AuthResponse) -> Self {
-        vec![VERSION, value.method as u8]
+impl Response for AuthResponse {
+    async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+        stream.write_all(&[VERSION, self.method as u8]).await
+    }
+}
+
+pub struct UserPassRequest {
+    pub username: String,
+    pub password: String,
+}
+
+impl Request for UserPassRequest {
+    type Error = ParseError;
+    async fn parse(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, ParseError>
+    where
+        Self: std::marker::Sized,
+    {
+        let ver = stream.read_u8().await?;
+        if ver != USER_PASS_VERSION {
+            return Err(Errors::General(super::Errors::UnsupportedVersion(ver).into()).into());
+        }
+
+        let username = read_short_string(stream).await?;
+        let password = read_short_string(stream).await?;
+
+        Ok(UserPassRequest { username, password })
+    }
+}
+
+impl std::fmt::Debug for UserPassRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserPassRequest")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+pub struct UserPassResponse {
+    pub status: u8,
+}
+
+impl UserPassResponse {
+    pub fn success() -> UserPassResponse {
+        UserPassResponse {
+            status: USER_PASS_SUCCESS,
+        }
+    }
+
+    pub fn failure() -> UserPassResponse {
+        UserPassResponse {
+            status: USER_PASS_FAILURE,
+        }
+    }
+}
+
+impl Response for UserPassResponse {
+    async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+        stream.write_all(&[USER_PASS_VERSION, self.status]).await
     }
 }
 
+/// Reads a single byte length prefixed string
+async fn read_short_string(stream: &mut (impl AsyncRead + Unpin)) -> Result<String, ParseError> {
+    let size = stream.read_u8().await?;
+    let mut buf = vec![0; size as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(String::from_utf8(buf)?)
+}
+
 #[derive(Debug)]
 pub enum Address {
     IpAddr(IpAddr),
@@ -139,6 +267,12 @@ pub enum Address {
 
 impl From<Address> for Vec<u8> {
     fn from(value: Address) -> Self {
+        (&value).into()
+    }
+}
+
+impl From<&Address> for Vec<u8> {
+    fn from(value: &Address) -> Self {
         match value {
             Address::IpAddr(IpAddr::V4(a)) => [vec![ATYPE_IPV4], a.octets().into()].concat(),
             Address::IpAddr(IpAddr::V6(a)) => [vec![ATYPE_IPV6], a.octets().into()].concat(),
@@ -172,7 +306,7 @@ pub struct CommandRequest {
 
 impl Request for CommandRequest {
     type Error = ParseError;
-    async fn parse(stream: &mut (impl tokio::io::AsyncReadExt + Unpin)) -> Result<Self, ParseError>
+    async fn parse(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, ParseError>
     where
         Self: std::marker::Sized,
     {
@@ -181,8 +315,8 @@ impl Request for CommandRequest {
             return Err(Errors::General(super::Errors::UnsupportedVersion(ver).into()).into());
         }
 
-        let command = Command::try_from(stream.read_u8().await?)
-            .map_err(Errors::UnsupportedCommand)?;
+        let command =
+            Command::try_from(stream.read_u8().await?).map_err(Errors::UnsupportedCommand)?;
 
         // This next byte is very literally a unused reserved byte, just read and discard
         let _rsv = stream.read_u8().await?;
@@ -199,12 +333,7 @@ impl Request for CommandRequest {
                 stream.read_exact(&mut addr).await?;
                 Ok(Ipv6Addr::from(addr).into())
             }
-            ATYPE_DNS => {
-                let size = stream.read_u8().await?;
-                let mut buf = vec![0; size as usize];
-                stream.read_exact(&mut buf).await?;
-                Ok(Address::Dns(String::from_utf8(buf)?))
-            }
+            ATYPE_DNS => Ok(Address::Dns(read_short_string(stream).await?)),
             t => Err(Errors::UnsupportedAddressType(t)),
         }?;
 
@@ -227,14 +356,26 @@ pub struct ConnectResponse {
 
 impl From<ConnectResponse> for Vec<u8> {
     fn from(value: ConnectResponse) -> Self {
+        (&value).into()
+    }
+}
+
+impl From<&ConnectResponse> for Vec<u8> {
+    fn from(value: &ConnectResponse) -> Self {
         let mut resp = vec![VERSION, value.reply, 0x0_u8];
-        resp.append(&mut value.address.into());
+        resp.append(&mut (&value.address).into());
         resp.extend_from_slice(&value.port.to_be_bytes());
 
         resp
     }
 }
 
+impl Response for ConnectResponse {
+    async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+        stream.write_all(&Vec::from(self)).await
+    }
+}
+
 impl ConnectResponse {
     pub fn success(address: Address, port: u16) -> ConnectResponse {
         ConnectResponse {
@@ -252,6 +393,14 @@ impl ConnectResponse {
         }
     }
 
+    pub fn not_allowed() -> ConnectResponse {
+        ConnectResponse {
+            reply: RESP_DENIED,
+            address: Ipv4Addr::UNSPECIFIED.into(),
+            port: 0,
+        }
+    }
+
     pub fn network_unreachable(address: Address, port: u16) -> ConnectResponse {
         ConnectResponse {
             reply: RESP_NETWORK_UNREACHABLE,
@@ -276,6 +425,14 @@ impl ConnectResponse {
         }
     }
 
+    pub fn ttl_expired() -> ConnectResponse {
+        ConnectResponse {
+            reply: RESP_TTL_EXPIRED,
+            address: Ipv4Addr::UNSPECIFIED.into(),
+            port: 0,
+        }
+    }
+
     pub fn unsupported_address() -> ConnectResponse {
         ConnectResponse {
             reply: RESP_ADDRESS_NOT_SUPPORTED,
@@ -21,6 +21,21 @@ mod auth_request_parse {
         let req = req_res.unwrap();
 
         assert_eq!(req.requests.len(), 0);
+        assert!(req.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_unknown_auth_options_are_not_empty() {
+        let mut stream = io::Builder::new()
+            .read(&[VERSION])
+            .read(&[0x01_u8])
+            .read(&[0x80])
+            .build();
+
+        let req = AuthRequest::parse(&mut stream).await.unwrap();
+
+        assert_eq!(req.requests, vec![]);
+        assert!(!req.is_empty());
     }
 
     #[tokio::test]
@@ -60,6 +75,85 @@ mod auth_request_parse {
         );
     }
 }
+mod auth_request_select {
+    use super::super::*;
+
+    fn offered(methods: &[AuthMethods]) -> AuthRequest {
+        AuthRequest {
+            requests: methods.to_vec(),
+            offered: methods.len() as u8,
+        }
+    }
+
+    #[test]
+    fn server_preference_wins() {
+        let req = offered(&[AuthMethods::NotRequired, AuthMethods::Basic]);
+
+        assert_eq!(
+            req.select(&[AuthMethods::Basic, AuthMethods::NotRequired]),
+            Some(AuthMethods::Basic)
+        );
+    }
+
+    #[test]
+    fn skips_methods_not_offered() {
+        let req = offered(&[AuthMethods::NotRequired]);
+
+        assert_eq!(
+            req.select(&[AuthMethods::Basic, AuthMethods::NotRequired]),
+            Some(AuthMethods::NotRequired)
+        );
+    }
+
+    #[test]
+    fn none_when_no_overlap() {
+        let req = offered(&[AuthMethods::NotRequired]);
+
+        assert_eq!(req.select(&[AuthMethods::Basic]), None);
+    }
+}
+
+mod user_pass_request_parse {
+    use tokio_test::io;
+
+    use super::super::*;
+
+    #[tokio::test]
+    async fn parse_credentials() {
+        let mut stream = io::Builder::new()
+            .read(&[USER_PASS_VERSION])
+            .read(&[5])
+            .read(b"alice")
+            .read(&[7])
+            .read(b"hunter2")
+            .build();
+
+        let req = UserPassRequest::parse(&mut stream).await.unwrap();
+
+        assert_eq!(req.username, "alice");
+        assert_eq!(req.password, "hunter2");
+    }
+
+    #[tokio::test]
+    async fn error_if_wrong_version() {
+        let mut stream = io::Builder::new().read(&[VERSION]).build();
+
+        let req_res = UserPassRequest::parse(&mut stream).await;
+
+        assert!(req_res.is_err());
+    }
+
+    #[test]
+    fn debug_hides_password() {
+        let req = UserPassRequest {
+            username: "alice".into(),
+            password: "hunter2".into(),
+        };
+
+        assert!(!format!("{req:?}").contains("hunter2"));
+    }
+}
+
 mod address_parse {
     use tokio_test::io;
 
@@ -75,7 +169,131 @@ mod address_parse {
     }
 
     #[tokio::test]
-    async fn parse_ipv4() {}
+    async fn parse_ipv4() {
+        let mut stream = io::Builder::new()
+            .read(&[VERSION, CMD_CONNECT, 0x00, ATYPE_IPV4])
+            .read(&[192, 0, 2, 20])
+            .read(&[0x1F, 0x90])
+            .build();
+
+        let req = CommandRequest::parse(&mut stream).await.unwrap();
+
+        assert_eq!(req.command, Command::Connect);
+        assert!(matches!(
+            req.address,
+            Address::IpAddr(IpAddr::V4(a)) if a == Ipv4Addr::new(192, 0, 2, 20)
+        ));
+        assert_eq!(req.port, 8080);
+    }
+
+    #[tokio::test]
+    async fn parse_dns() {
+        let mut stream = io::Builder::new()
+            .read(&[VERSION, CMD_CONNECT, 0x00, ATYPE_DNS, 3])
+            .read(b"web")
+            .read(&[0x00, 0x50])
+            .build();
+
+        let req = CommandRequest::parse(&mut stream).await.unwrap();
+
+        assert!(matches!(req.address, Address::Dns(ref a) if a == "web"));
+        assert_eq!(req.port, 80);
+    }
+
+    #[tokio::test]
+    async fn error_if_unknown_address_type() {
+        let mut stream = io::Builder::new()
+            .read(&[VERSION, CMD_CONNECT, 0x00, 0x09])
+            .build();
+
+        let req_res = CommandRequest::parse(&mut stream).await;
+
+        assert!(matches!(
+            req_res,
+            Err(ParseError::ProtocolError(Errors::UnsupportedAddressType(
+                0x09
+            )))
+        ));
+    }
+}
+
+mod parse_arbitrary_input {
+    use proptest::prelude::*;
+
+    use super::super::*;
+
+    fn is_expected_error(e: &ParseError) -> bool {
+        match e {
+            ParseError::ProtocolError(_) | ParseError::String(_) => true,
+            ParseError::Io(e) => e.kind() == std::io::ErrorKind::UnexpectedEof,
+        }
+    }
+
+    /// Input that starts out as a well formed command so the fuzzing gets past the first bytes
+    fn command_prefixed_bytes() -> impl Strategy<Value = Vec<u8>> {
+        (
+            prop_oneof![
+                Just(CMD_CONNECT),
+                Just(CMD_BIND),
+                Just(CMD_UDP_ASSOCIATE),
+                any::<u8>()
+            ],
+            prop_oneof![
+                Just(ATYPE_IPV4),
+                Just(ATYPE_IPV6),
+                Just(ATYPE_DNS),
+                any::<u8>()
+            ],
+            proptest::collection::vec(any::<u8>(), 0..300),
+        )
+            .prop_map(|(cmd, atype, rest)| [vec![VERSION, cmd, 0x00, atype], rest].concat())
+    }
+
+    proptest! {
+        #[test]
+        fn auth_request_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..300)) {
+            let res = tokio_test::block_on(AuthRequest::parse(&mut bytes.as_slice()));
+
+            if let Err(e) = res {
+                let expected = e.downcast_ref::<Errors>().is_some()
+                    || e
+                        .downcast_ref::<std::io::Error>()
+                        .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof);
+                prop_assert!(expected, "unexpected error {e:?}");
+            }
+        }
+
+        #[test]
+        fn auth_request_with_version_never_panics(
+            bytes in proptest::collection::vec(any::<u8>(), 0..300)
+        ) {
+            let input = [vec![VERSION], bytes].concat();
+
+            let res = tokio_test::block_on(AuthRequest::parse(&mut input.as_slice()));
+
+            if let Ok(req) = res {
+                prop_assert!(req.requests.len() <= input[1] as usize);
+            }
+        }
+
+        #[test]
+        fn command_request_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..300)) {
+            let res = tokio_test::block_on(CommandRequest::parse(&mut bytes.as_slice()));
+
+            if let Err(ref e) = res {
+                prop_assert!(is_expected_error(e), "unexpected error {e:?}");
+            }
+        }
+
+        #[test]
+        fn command_request_with_prefix_never_panics(bytes in command_prefixed_bytes()) {
+            let res = tokio_test::block_on(CommandRequest::parse(&mut bytes.as_slice()));
+
+            if let Err(ref e) = res {
+                prop_assert!(is_expected_error(e), "unexpected error {e:?}");
+            }
+        }
+    }
 }
 
 mod address_into_vec_u8 {
@@ -118,3 +336,141 @@ mod address_into_vec_u8 {
         );
     }
 }
+
+mod connect_response_into_vec_u8 {
+    use std::net::Ipv4Addr;
+
+    use super::super::*;
+
+    #[test]
+    fn bound_address_and_port() {
+        let resp = ConnectResponse::success(Ipv4Addr::from([10, 0, 0, 7]).into(), 5353);
+
+        let res: Vec<u8> = resp.into();
+
+        assert_eq!(
+            res,
+            vec![
+                VERSION,
+                RESP_SUCCEEDED,
+                0,
+                ATYPE_IPV4,
+                10,
+                0,
+                0,
+                7,
+                0x14,
+                0xe9
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_command_carries_no_relay_address() {
+        // UDP ASSOCIATE is refused with this, so a client mustn't be given an address to send
+        // datagrams to
+        let res: Vec<u8> = ConnectResponse::unsupported_command().into();
+
+        assert_eq!(
+            res,
+            vec![
+                VERSION,
+                RESP_COMMAND_NOT_SUPPORTED,
+                0,
+                ATYPE_IPV4,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0
+            ]
+        );
+    }
+}
+
+mod response_write {
+    use std::net::Ipv4Addr;
+
+    use tokio_test::io;
+
+    use super::super::*;
+
+    #[tokio::test]
+    async fn auth_response() {
+        let mut stream = io::Builder::new().write(&[VERSION, AUTH_USER_PASS]).build();
+
+        AuthResponse::selected(AuthMethods::Basic)
+            .write(&mut stream)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn user_pass_response() {
+        let mut stream = io::Builder::new()
+            .write(&[USER_PASS_VERSION, USER_PASS_FAILURE])
+            .build();
+
+        UserPassResponse::failure()
+            .write(&mut stream)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_response_is_written_whole() {
+        let mut stream = io::Builder::new()
+            .write(&[VERSION, RESP_SUCCEEDED, 0, ATYPE_DNS, 3])
+            .write(b"web")
+            .write(&[0x00, 0x50])
+            .build();
+
+        ConnectResponse::success(Address::Dns("web".into()), 80)
+            .write(&mut stream)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn write_errors_are_returned() {
+        let mut stream = io::Builder::new()
+            .write_error(std::io::ErrorKind::BrokenPipe.into())
+            .build();
+
+        let res = ConnectResponse::success(Ipv4Addr::UNSPECIFIED.into(), 0)
+            .write(&mut stream)
+            .await;
+
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+    }
+}
+
+mod command_unsupported_reason {
+    use super::super::*;
+
+    #[test]
+    fn only_connect_is_supported() {
+        assert_eq!(Command::Connect.unsupported_reason(), None);
+        assert_eq!(Command::Bind.unsupported_reason(), Some(BIND_UNSUPPORTED));
+        assert!(Command::UdpAssociate.unsupported_reason().is_some());
+    }
+}
+
+mod error_code {
+    use super::super::*;
+
+    #[test]
+    fn wrapped_protocol_errors_keep_their_code() {
+        let e = ParseError::from(Errors::UnsupportedAddressType(9));
+
+        assert_eq!(e.code(), "unsupported_address_type");
+    }
+
+    #[test]
+    fn io_errors() {
+        let e = ParseError::from(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+
+        assert_eq!(e.code(), "io");
+    }
+}
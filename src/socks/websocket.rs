@@ -0,0 +1,106 @@
+//! Forwards through a companion endpoint reached over a WebSocket, for clusters that can only be
+//! reached through an HTTP(S) ingress. The endpoint is told the pod to connect to by the
+//! `namespace`, `pod` and `port` query parameters, and relays the stream as binary messages.
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, Instrument};
+
+use crate::config::redact_userinfo;
+use crate::socks::resolver::Target;
+
+/// Buffered between the client connection and the WebSocket, and the most read into one message.
+const BUF_LEN: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Errors {
+    #[error("Invalid WebSocket URL {0}")]
+    InvalidUrl(String),
+    #[error("WebSocket Failed {0}")]
+    WebSocket(#[source] Box<tokio_tungstenite::tungstenite::Error>),
+}
+
+/// Connects to the endpoint at `url` for `target`, the returned stream is relayed over it.
+pub async fn connect(url: &str, target: &Target) -> Result<DuplexStream, Errors> {
+    let url = target_url(url, target)?;
+    debug!(
+        url = redact_userinfo(&url),
+        "connecting to websocket forward endpoint"
+    );
+
+    let (ws, _response) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(|e| Errors::WebSocket(Box::new(e)))?;
+
+    let (stream, relayed) = tokio::io::duplex(BUF_LEN);
+    tokio::spawn(relay(ws, relayed).in_current_span());
+
+    Ok(stream)
+}
+
+/// `url` with the target's query parameters appended.
+fn target_url(url: &str, target: &Target) -> Result<String, Errors> {
+    if !url.starts_with("ws://") && !url.starts_with("wss://") {
+        return Err(Errors::InvalidUrl(redact_userinfo(url)));
+    }
+
+    let separator = match url.contains('?') {
+        true => '&',
+        false => '?',
+    };
+
+    Ok(format!(
+        "{url}{separator}namespace={}&pod={}&port={}",
+        target.namespace, target.pod, target.port
+    ))
+}
+
+/// Pumps between the WebSocket and the stream handed out, until both directions are closed.
+async fn relay<S>(ws: S, mut stream: DuplexStream)
+where
+    S: futures::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>>
+        + futures::Sink<Message, Error = tokio_tungstenite::tungstenite::Error>
+        + Unpin,
+{
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let mut buf = vec![0_u8; BUF_LEN];
+    let (mut to_ws_open, mut from_ws_open) = (true, true);
+
+    while to_ws_open || from_ws_open {
+        tokio::select! {
+            read = stream.read(&mut buf), if to_ws_open => match read {
+                Ok(0) | Err(_) => {
+                    to_ws_open = false;
+                    let _ = ws_tx.send(Message::Close(None)).await;
+                }
+                Ok(n) => {
+                    if let Err(e) = ws_tx.send(Message::binary(buf[..n].to_vec())).await {
+                        debug!(error = ?e, "websocket send failed");
+                        return;
+                    }
+                }
+            },
+            message = ws_rx.next(), if from_ws_open => match message {
+                Some(Ok(Message::Binary(data))) => {
+                    if stream.write_all(&data).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    from_ws_open = false;
+                    let _ = stream.shutdown().await;
+                }
+                // Pings are answered by tungstenite itself, text isn't part of the protocol
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    debug!(error = ?e, "websocket receive failed");
+                    return;
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
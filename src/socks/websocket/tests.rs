@@ -0,0 +1,85 @@
+fn target() -> super::Target {
+    super::Target {
+        namespace: "apps".into(),
+        pod: "web-0".into(),
+        port: 8080,
+        pod_ip: None,
+        app_protocol: None,
+        cluster: None,
+        connect_timeout: None,
+        named_target_port: None,
+    }
+}
+
+mod target_url {
+    use super::super::*;
+    use super::target;
+
+    #[test]
+    fn appends_target() {
+        assert_eq!(
+            target_url("wss://proxy.example.com/forward", &target()).unwrap(),
+            "wss://proxy.example.com/forward?namespace=apps&pod=web-0&port=8080"
+        );
+    }
+
+    #[test]
+    fn keeps_existing_query() {
+        assert_eq!(
+            target_url("ws://localhost/forward?cluster=a", &target()).unwrap(),
+            "ws://localhost/forward?cluster=a&namespace=apps&pod=web-0&port=8080"
+        );
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        assert!(target_url("https://proxy.example.com/forward", &target()).is_err());
+    }
+}
+
+mod connect {
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    use super::super::*;
+    use super::target;
+
+    // The handshake callback's error type is tungstenite's, not ours to shrink
+    #[allow(clippy::result_large_err)]
+    #[tokio::test]
+    async fn relays_both_ways() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/forward", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut query = None;
+            let mut ws =
+                tokio_tungstenite::accept_hdr_async(tcp, |req: &Request, res: Response| {
+                    query = req.uri().query().map(str::to_string);
+                    Ok(res)
+                })
+                .await
+                .unwrap();
+
+            let ping = ws.next().await.unwrap().unwrap();
+            assert_eq!(ping, Message::binary(b"ping".to_vec()));
+            ws.send(Message::binary(b"pong".to_vec())).await.unwrap();
+            ws.close(None).await.unwrap();
+
+            query
+        });
+
+        let mut stream = connect(&url, &target()).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"pong");
+
+        assert_eq!(
+            server.await.unwrap().as_deref(),
+            Some("namespace=apps&pod=web-0&port=8080")
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{self, pki_types::PrivateKeyDer, RootCertStore, ServerConnection};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+
+/// Builds an acceptor from a PEM certificate chain and private key. With `client_ca_path` clients
+/// must present a certificate signed by one of the CAs in that PEM bundle.
+pub fn acceptor(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: Option<&Path>,
+) -> anyhow::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut read(cert_path)?.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("failed to parse certificates in {}", cert_path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", cert_path.display());
+    }
+
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut read(key_path)?.as_slice())
+        .with_context(|| format!("failed to parse private key in {}", key_path.display()))?
+        .with_context(|| format!("no private key found in {}", key_path.display()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let config = match client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in rustls_pemfile::certs(&mut read(ca_path)?.as_slice()) {
+                let ca = ca.with_context(|| {
+                    format!("failed to parse certificates in {}", ca_path.display())
+                })?;
+                roots
+                    .add(ca)
+                    .with_context(|| format!("invalid CA certificate in {}", ca_path.display()))?;
+            }
+            if roots.is_empty() {
+                anyhow::bail!("no certificates found in {}", ca_path.display());
+            }
+
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    }
+    .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The identity of the client on `conn`, from the certificate it authenticated with if any.
+pub fn client_identity(conn: &ServerConnection) -> Option<String> {
+    identity(conn.peer_certificates()?.first()?)
+}
+
+/// The first DNS, URI (eg. a SPIFFE ID) or email subject alternative name of the DER encoded
+/// `cert`, falling back to its subject common name.
+fn identity(cert: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+
+    let san = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .and_then(|san| {
+            san.value.general_names.iter().find_map(|name| match name {
+                GeneralName::DNSName(name)
+                | GeneralName::URI(name)
+                | GeneralName::RFC822Name(name) => Some(name.to_string()),
+                _ => None,
+            })
+        });
+
+    san.or_else(|| {
+        cert.subject()
+            .iter_common_name()
+            .find_map(|cn| cn.as_str().ok())
+            .map(str::to_string)
+    })
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests;
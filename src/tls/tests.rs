@@ -0,0 +1,198 @@
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair,
+    SanType,
+};
+
+/// Params for a certificate with `names` as DNS subject alternative names and `common_name` as
+/// the subject.
+fn params(names: &[&str], common_name: Option<&str>) -> CertificateParams {
+    let mut params =
+        CertificateParams::new(names.iter().map(|n| n.to_string()).collect::<Vec<_>>()).unwrap();
+    // rcgen gives every certificate a placeholder common name otherwise
+    params.distinguished_name = DistinguishedName::new();
+    if let Some(common_name) = common_name {
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+    }
+    params
+}
+
+/// A certificate for `params`, its key, and the CA that signed it.
+fn signed(params: CertificateParams) -> (Certificate, KeyPair, Certificate) {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "kube-fwd-socks test CA");
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let key = KeyPair::generate().unwrap();
+    let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+
+    (cert, key, ca)
+}
+
+mod identity {
+    use super::super::*;
+    use super::*;
+
+    #[test]
+    fn prefers_subject_alternative_name() {
+        let (cert, _, _) = signed(params(&["client.example.com"], Some("client")));
+
+        assert_eq!(identity(cert.der()).as_deref(), Some("client.example.com"));
+    }
+
+    #[test]
+    fn uri_names() {
+        let mut params = params(&[], None);
+        params.subject_alt_names = vec![SanType::URI(
+            "spiffe://cluster.local/ns/apps/sa/web".try_into().unwrap(),
+        )];
+        let (cert, _, _) = signed(params);
+
+        assert_eq!(
+            identity(cert.der()).as_deref(),
+            Some("spiffe://cluster.local/ns/apps/sa/web")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_common_name() {
+        let (cert, _, _) = signed(params(&[], Some("alice")));
+
+        assert_eq!(identity(cert.der()).as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn nothing_to_identify() {
+        let (cert, _, _) = signed(params(&[], None));
+
+        assert_eq!(identity(cert.der()), None);
+        assert_eq!(identity(b"not a certificate"), None);
+    }
+}
+
+mod acceptor {
+    use std::path::PathBuf;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::{PrivateKeyDer, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    use super::super::*;
+    use super::*;
+
+    /// The server's certificate and key, and the CA for client certificates, written out as PEM.
+    struct Files {
+        cert: PathBuf,
+        key: PathBuf,
+        client_ca: PathBuf,
+    }
+
+    impl Files {
+        fn write(name: &str, cert: &Certificate, key: &KeyPair, client_ca: &Certificate) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("kube-fwd-socks-tls-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let files = Files {
+                cert: dir.join("tls.crt"),
+                key: dir.join("tls.key"),
+                client_ca: dir.join("clients.crt"),
+            };
+            std::fs::write(&files.cert, cert.pem()).unwrap();
+            std::fs::write(&files.key, key.serialize_pem()).unwrap();
+            std::fs::write(&files.client_ca, client_ca.pem()).unwrap();
+            files
+        }
+    }
+
+    /// Connects to `acceptor` trusting `server_ca`, presenting `client` as the client certificate
+    /// if given, returning the identity the server saw.
+    async fn handshake(
+        acceptor: TlsAcceptor,
+        server_ca: &Certificate,
+        client: Option<(&Certificate, &KeyPair)>,
+    ) -> anyhow::Result<Option<String>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(server_ca.der().clone())?;
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+        let config = match client {
+            Some((cert, key)) => builder.with_client_auth_cert(
+                vec![cert.der().clone()],
+                PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+            )?,
+            None => builder.with_no_client_auth(),
+        };
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move {
+            let mut conn = acceptor.accept(server_io).await?;
+            let identity = client_identity(conn.get_ref().1);
+            conn.write_all(b"ok").await?;
+            conn.shutdown().await?;
+            Ok::<_, std::io::Error>(identity)
+        });
+
+        let mut conn = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost")?, client_io)
+            .await?;
+        let mut buf = Vec::new();
+        conn.read_to_end(&mut buf).await?;
+
+        Ok(server.await??)
+    }
+
+    #[tokio::test]
+    async fn client_certificate_identifies_the_client() {
+        let (server_cert, server_key, server_ca) = signed(params(&["localhost"], None));
+        let (client_cert, client_key, client_ca) = signed(params(&[], Some("alice")));
+        let files = Files::write("identifies", &server_cert, &server_key, &client_ca);
+        let acceptor = acceptor(&files.cert, &files.key, Some(&files.client_ca)).unwrap();
+
+        let identity = handshake(acceptor, &server_ca, Some((&client_cert, &client_key)))
+            .await
+            .unwrap();
+
+        assert_eq!(identity.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn client_certificate_is_required() {
+        let (server_cert, server_key, server_ca) = signed(params(&["localhost"], None));
+        let (_, _, client_ca) = signed(params(&[], Some("alice")));
+        let files = Files::write("required", &server_cert, &server_key, &client_ca);
+        let acceptor = acceptor(&files.cert, &files.key, Some(&files.client_ca)).unwrap();
+
+        assert!(handshake(acceptor, &server_ca, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_certificate_from_another_ca_is_rejected() {
+        let (server_cert, server_key, server_ca) = signed(params(&["localhost"], None));
+        let (_, _, client_ca) = signed(params(&[], Some("alice")));
+        let (mallory_cert, mallory_key, _) = signed(params(&[], Some("mallory")));
+        let files = Files::write("other-ca", &server_cert, &server_key, &client_ca);
+        let acceptor = acceptor(&files.cert, &files.key, Some(&files.client_ca)).unwrap();
+
+        let res = handshake(acceptor, &server_ca, Some((&mallory_cert, &mallory_key))).await;
+
+        assert!(res.is_err(), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn no_identity_without_client_ca() {
+        let (server_cert, server_key, server_ca) = signed(params(&["localhost"], None));
+        let files = Files::write("anonymous", &server_cert, &server_key, &server_ca);
+        let acceptor = acceptor(&files.cert, &files.key, None).unwrap();
+
+        assert_eq!(handshake(acceptor, &server_ca, None).await.unwrap(), None);
+    }
+}